@@ -0,0 +1,76 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use datumaro_rust_api::{
+    coco_page_mapper::CocoPageMapper,
+    selection::FieldSelection,
+    testing::{AnnsPerImage, CocoGenerator, SegmentationStyle},
+};
+
+fn bench_scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    for segmentation in [SegmentationStyle::None, SegmentationStyle::Polygon] {
+        let data = CocoGenerator::new(0)
+            .target_bytes(32 << 20)
+            .anns_per_image(AnnsPerImage::Uniform { min: 1, max: 10 })
+            .segmentation(segmentation)
+            .to_bytes();
+        group.throughput(Throughput::Bytes(data.len() as u64));
+
+        let id = format!("{:?}", segmentation);
+        group.bench_with_input(BenchmarkId::new("plain", &id), &data, |b, data| {
+            b.iter(|| CocoPageMapper::from_bytes(data).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("compact", &id), &data, |b, data| {
+            b.iter(|| {
+                CocoPageMapper::builder()
+                    .compact_index(true)
+                    .build(Cursor::new(data))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_get_anns(c: &mut Criterion) {
+    let data = CocoGenerator::new(0).images(20_000).to_bytes();
+    let mapper = CocoPageMapper::from_bytes(&data).unwrap();
+    let img_ids: Vec<i64> = mapper.get_img_ids().iter().step_by(97).copied().collect();
+
+    c.bench_function("get_anns_batch", |b| {
+        b.iter(|| mapper.get_anns_batch(&img_ids, Cursor::new(&data)).unwrap())
+    });
+}
+
+fn bench_get_anns_selected(c: &mut Criterion) {
+    let data = CocoGenerator::new(0)
+        .images(2_000)
+        .anns_per_image(AnnsPerImage::Fixed(10))
+        .segmentation(SegmentationStyle::Polygon)
+        .to_bytes();
+    let mapper = CocoPageMapper::from_bytes(&data).unwrap();
+    let img_ids = mapper.get_img_ids().clone();
+    let exclude = FieldSelection::exclude(&["segmentation"]);
+
+    let mut group = c.benchmark_group("get_anns_polygons");
+    group.bench_function("all", |b| {
+        b.iter(|| mapper.get_anns_batch(&img_ids, Cursor::new(&data)).unwrap())
+    });
+    group.bench_function("no_segmentation", |b| {
+        b.iter(|| {
+            mapper
+                .get_anns_batch_selected(&img_ids, Cursor::new(&data), &exclude)
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_scan, bench_get_anns, bench_get_anns_selected);
+criterion_main!(benches);
@@ -0,0 +1,590 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io::{self, BufReader, Read, Seek},
+    mem::size_of,
+    path::Path,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    coco_page_mapper::CocoPageMapper,
+    compression::PositionedFile,
+    memory::json_bytes,
+    offset_index::{IoTuning, ReadScratch},
+    page_maps::JsonDict,
+};
+
+/// Limit of a `CachedPageMapper` cache, per kind of cached value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheBudget {
+    Entries(usize),
+    /// Heap bytes of the cached values, estimated as in `IndexMemory`.
+    Bytes(usize),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Prefetches in flight at once, a further `prefetch` waits for the oldest.
+const MAX_PENDING_PREFETCHES: usize = 4;
+
+#[derive(Debug)]
+struct LruCache<V> {
+    /// Value, last use and size of every entry.
+    entries: HashMap<i64, (V, u64, usize)>,
+    /// Keys by last use.
+    order: BTreeMap<u64, i64>,
+    budget: CacheBudget,
+    bytes: usize,
+    clock: u64,
+}
+
+impl<V> LruCache<V> {
+    fn new(budget: CacheBudget) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            budget,
+            bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /// False for a zero budget, which keeps nothing.
+    fn is_enabled(&self) -> bool {
+        !matches!(self.budget, CacheBudget::Entries(0) | CacheBudget::Bytes(0))
+    }
+
+    fn get(&mut self, key: i64) -> Option<&V> {
+        let (value, used, _) = self.entries.get_mut(&key)?;
+        self.order.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.order.insert(self.clock, key);
+        Some(value)
+    }
+
+    fn contains(&self, key: i64) -> bool {
+        self.entries.contains_key(&key)
+    }
+
+    fn insert(&mut self, key: i64, value: V, size: usize) {
+        self.remove(key);
+        self.clock += 1;
+        self.entries.insert(key, (value, self.clock, size));
+        self.order.insert(self.clock, key);
+        self.bytes += size;
+
+        while match self.budget {
+            CacheBudget::Entries(n) => self.entries.len() > n,
+            CacheBudget::Bytes(n) => self.bytes > n,
+        } {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, _, size)) = self.entries.remove(&oldest) {
+                self.bytes -= size;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: i64) {
+        if let Some((_, used, size)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+            self.bytes -= size;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Bytes hashed at each of the start, middle and end of a file by
+/// `content_fingerprint`.
+const FINGERPRINT_SAMPLE: u64 = 64 * 1024;
+
+/// A hash of the length of the file at `path` and of its first, middle and
+/// last `FINGERPRINT_SAMPLE` bytes, so files of up to three samples are hashed
+/// whole. Larger files edited only outside the samples, keeping their length,
+/// get the same fingerprint. This is the source file, not the decompressed
+/// copy a compressed file is mapped from.
+pub fn content_fingerprint(path: &Path) -> Result<u64, io::Error> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    len.hash(&mut hasher);
+    let mut buf = vec![0; len.min(FINGERPRINT_SAMPLE) as usize];
+    let middle = (len / 2).saturating_sub(FINGERPRINT_SAMPLE / 2);
+    for start in [0, middle, len.saturating_sub(FINGERPRINT_SAMPLE)] {
+        file.seek(io::SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        hasher.write(&buf);
+    }
+    Ok(hasher.finish())
+}
+
+/// The dict and annotations of every prefetched image.
+type Prefetched = Vec<(i64, JsonDict, Vec<JsonDict>)>;
+
+/// A prefetch running on a background thread.
+#[derive(Debug)]
+struct PendingPrefetch {
+    img_ids: HashSet<i64>,
+    handle: JoinHandle<Result<Prefetched, io::Error>>,
+}
+
+/// Opens readers for the prefetch threads.
+struct Prefetcher<R> {
+    source: Arc<dyn Fn() -> Result<R, io::Error> + Send + Sync>,
+    pending: VecDeque<PendingPrefetch>,
+}
+
+impl<R> fmt::Debug for Prefetcher<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Prefetcher")
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+fn fetch(
+    mapper: &CocoPageMapper,
+    img_ids: &[i64],
+    mut reader: impl Read + Seek,
+) -> Result<Prefetched, io::Error> {
+    let items = mapper.get_item_dicts(img_ids, &mut reader)?;
+    let anns = mapper.get_anns_batch(img_ids, &mut reader)?;
+    Ok(img_ids
+        .iter()
+        .zip(items)
+        .zip(anns)
+        .map(|((img_id, item), anns)| (*img_id, item, anns))
+        .collect())
+}
+
+/// A `CocoPageMapper` owning its reader, which keeps the recently parsed
+/// images and annotation lists. The cache is dropped whenever the content
+/// hash given to `set_content_hash` changes.
+#[derive(Debug)]
+pub struct CachedPageMapper<R> {
+    mapper: CocoPageMapper,
+    reader: R,
+    items: LruCache<JsonDict>,
+    anns: LruCache<Vec<JsonDict>>,
+    stats: CacheStats,
+    content_hash: Option<u64>,
+    scratch: ReadScratch,
+    prefetcher: Option<Prefetcher<R>>,
+}
+
+impl<R: Read + Seek> CachedPageMapper<R> {
+    pub fn new(mapper: CocoPageMapper, reader: R, budget: CacheBudget) -> Self {
+        Self {
+            mapper,
+            reader,
+            items: LruCache::new(budget),
+            anns: LruCache::new(budget),
+            stats: CacheStats::default(),
+            content_hash: None,
+            scratch: ReadScratch::default(),
+            prefetcher: None,
+        }
+    }
+
+    /// Sizes the read buffers for images of up to `max_anns` annotations, so
+    /// that they do not grow on the first reads.
+    pub fn with_capacity_hint(mut self, max_anns: usize) -> Self {
+        self.scratch = ReadScratch::with_capacity(max_anns);
+        self
+    }
+
+    /// See `CocoPageMapper::set_io_tuning`.
+    pub fn set_io_tuning(&mut self, io_tuning: IoTuning) {
+        self.mapper.set_io_tuning(io_tuning);
+    }
+
+    pub fn mapper(&self) -> &CocoPageMapper {
+        &self.mapper
+    }
+
+    pub fn reader(&self) -> &R {
+        &self.reader
+    }
+
+    /// False if the budget is zero, then values are neither kept nor
+    /// prefetched.
+    pub fn is_caching(&self) -> bool {
+        self.items.is_enabled()
+    }
+
+    /// The reader and the mapper, for calls which bypass the cache.
+    pub fn parts_mut(&mut self) -> (&CocoPageMapper, &mut R) {
+        (&self.mapper, &mut self.reader)
+    }
+
+    pub fn get_item_dict(&mut self, img_id: i64) -> Result<JsonDict, io::Error> {
+        self.collect_prefetches(Some(img_id));
+        if let Some(item) = self.items.get(img_id) {
+            self.stats.hits += 1;
+            return Ok(item.clone());
+        }
+        self.stats.misses += 1;
+        let item = self.mapper.get_item_dict(img_id, &mut self.reader)?;
+        if self.items.is_enabled() {
+            self.insert_item(img_id, item.clone());
+        }
+        Ok(item)
+    }
+
+    pub fn get_anns_dict(&mut self, img_id: i64) -> Result<Vec<JsonDict>, io::Error> {
+        self.collect_prefetches(Some(img_id));
+        if let Some(anns) = self.anns.get(img_id) {
+            self.stats.hits += 1;
+            return Ok(anns.clone());
+        }
+        self.stats.misses += 1;
+        let anns = self
+            .mapper
+            .get_anns_dict_with(img_id, &mut self.reader, &mut self.scratch)?;
+        if self.anns.is_enabled() {
+            self.insert_anns(img_id, anns.clone());
+        }
+        Ok(anns)
+    }
+
+    /// Reads the dicts and annotations of `img_ids` which are not cached yet
+    /// into the cache, in file order.
+    pub fn prefetch_blocking(&mut self, img_ids: &[i64]) -> Result<(), io::Error> {
+        if !self.is_caching() {
+            return Ok(());
+        }
+        self.collect_prefetches(None);
+        let img_ids = self.uncached(img_ids);
+        if !img_ids.is_empty() {
+            let fetched = fetch(&self.mapper, &img_ids, &mut self.reader)?;
+            self.insert_prefetched(fetched);
+        }
+        Ok(())
+    }
+
+    fn uncached(&self, img_ids: &[i64]) -> Vec<i64> {
+        img_ids
+            .iter()
+            .filter(|img_id| !self.items.contains(**img_id) || !self.anns.contains(**img_id))
+            .copied()
+            .collect()
+    }
+
+    /// Moves the finished prefetches into the cache, after waiting for the
+    /// ones reading `img_id`. Failed prefetches are dropped, the images are
+    /// then read on access.
+    fn collect_prefetches(&mut self, img_id: Option<i64>) {
+        let Some(prefetcher) = &mut self.prefetcher else {
+            return;
+        };
+        let mut done = vec![];
+        let mut idx = 0;
+        while idx < prefetcher.pending.len() {
+            let pending = &prefetcher.pending[idx];
+            if pending.handle.is_finished() || img_id.is_some_and(|v| pending.img_ids.contains(&v))
+            {
+                done.extend(prefetcher.pending.remove(idx));
+            } else {
+                idx += 1;
+            }
+        }
+        for pending in done {
+            if let Ok(Ok(fetched)) = pending.handle.join() {
+                self.insert_prefetched(fetched);
+            }
+        }
+    }
+
+    fn insert_prefetched(&mut self, fetched: Prefetched) {
+        for (img_id, item, anns) in fetched {
+            self.insert_item(img_id, item);
+            self.insert_anns(img_id, anns);
+        }
+    }
+
+    fn insert_item(&mut self, img_id: i64, item: JsonDict) {
+        let size = size_of::<JsonDict>() + json_bytes(&item);
+        self.items.insert(img_id, item, size);
+    }
+
+    fn insert_anns(&mut self, img_id: i64, anns: Vec<JsonDict>) {
+        let size = anns
+            .iter()
+            .map(|ann| size_of::<JsonDict>() + json_bytes(ann))
+            .sum();
+        self.anns.insert(img_id, anns, size);
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Also drops the results of the prefetches in flight.
+    pub fn clear_cache(&mut self) {
+        self.items.clear();
+        self.anns.clear();
+        if let Some(prefetcher) = &mut self.prefetcher {
+            prefetcher.pending.clear();
+        }
+    }
+
+    /// Clears the cache if `hash` differs from the previous one.
+    pub fn set_content_hash(&mut self, hash: u64) {
+        if self.content_hash.is_some_and(|v| v != hash) {
+            self.clear_cache();
+        }
+        self.content_hash = Some(hash);
+    }
+}
+
+impl CachedPageMapper<BufReader<PositionedFile>> {
+    /// Maps the file at `path` like `CocoPageMapper::from_path`, with
+    /// prefetches reading through clones of the file the mapper reads, which
+    /// is the decompressed copy for compressed files.
+    pub fn open(path: &Path, budget: CacheBudget) -> Result<Self, io::Error> {
+        let (mapper, reader) = CocoPageMapper::from_path(path)?;
+        let file = PositionedFile::new(reader.into_inner());
+        let source = file.try_clone()?;
+        Ok(Self::new(mapper, BufReader::new(file), budget)
+            .with_prefetch_source(move || Ok(BufReader::new(source.try_clone()?))))
+    }
+}
+
+impl<R: Read + Seek + Send + 'static> CachedPageMapper<R> {
+    /// Enables background prefetches, reading through readers of the same
+    /// file from `source`.
+    pub fn with_prefetch_source(
+        mut self,
+        source: impl Fn() -> Result<R, io::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.prefetcher = Some(Prefetcher {
+            source: Arc::new(source),
+            pending: VecDeque::new(),
+        });
+        self
+    }
+
+    /// Reads the dicts and annotations of `img_ids` into the cache on a
+    /// background thread and returns at once. The next gets of these images
+    /// wait for it if it is still running. Without a prefetch source, this
+    /// is `prefetch_blocking`.
+    pub fn prefetch(&mut self, img_ids: &[i64]) -> Result<(), io::Error> {
+        if self.prefetcher.is_none() || !self.is_caching() {
+            return self.prefetch_blocking(img_ids);
+        }
+        self.collect_prefetches(None);
+        let img_ids = self.uncached(img_ids);
+        if img_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut done = vec![];
+        let prefetcher = self.prefetcher.as_mut().unwrap();
+        while prefetcher.pending.len() >= MAX_PENDING_PREFETCHES {
+            done.extend(prefetcher.pending.pop_front());
+        }
+        let mapper = self.mapper.clone_index();
+        let source = prefetcher.source.clone();
+        let pending = PendingPrefetch {
+            img_ids: img_ids.iter().copied().collect(),
+            handle: thread::spawn(move || fetch(&mapper, &img_ids, source()?)),
+        };
+        prefetcher.pending.push_back(pending);
+
+        for pending in done {
+            if let Ok(Ok(fetched)) = pending.handle.join() {
+                self.insert_prefetched(fetched);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{write_example, CountingReader, EXAMPLE};
+
+    fn new_cached(
+        budget: CacheBudget,
+    ) -> CachedPageMapper<CountingReader<io::Cursor<&'static [u8]>>> {
+        let mapper = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+        let reader = CountingReader::new(io::Cursor::new(EXAMPLE.as_bytes()));
+        CachedPageMapper::new(mapper, reader, budget)
+    }
+
+    #[test]
+    fn test_cache_hits() {
+        let mut cached = new_cached(CacheBudget::Entries(8));
+
+        let item = cached.get_item_dict(5).unwrap();
+        let anns = cached.get_anns_dict(5).unwrap();
+        let reads = cached.reader().reads;
+        assert!(reads > 0);
+        assert_eq!(cached.get_item_dict(5).unwrap(), item);
+        assert_eq!(cached.get_anns_dict(5).unwrap(), anns);
+        assert_eq!(cached.reader().reads, reads);
+        assert_eq!(cached.cache_stats(), CacheStats { hits: 2, misses: 2 });
+
+        cached.set_content_hash(1);
+        cached.get_item_dict(5).unwrap();
+        assert_eq!(cached.reader().reads, reads);
+        cached.set_content_hash(2);
+        cached.get_item_dict(5).unwrap();
+        assert!(cached.reader().reads > reads);
+
+        let reads = cached.reader().reads;
+        cached.clear_cache();
+        cached.get_anns_dict(5).unwrap();
+        assert!(cached.reader().reads > reads);
+        assert_eq!(cached.cache_stats(), CacheStats { hits: 3, misses: 4 });
+    }
+
+    #[test]
+    fn test_capacity_hint() {
+        let mut cached = new_cached(CacheBudget::Entries(0)).with_capacity_hint(3);
+        let expected = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+        for img_id in [5, 6, 5, 7] {
+            assert_eq!(
+                cached.get_anns_dict(img_id).unwrap(),
+                expected
+                    .get_anns_dict(img_id, io::Cursor::new(EXAMPLE.as_bytes()))
+                    .unwrap()
+            );
+        }
+        assert_eq!(cached.cache_stats().hits, 0);
+        assert!(cached.items.entries.is_empty() && cached.anns.entries.is_empty());
+
+        // Prefetches are dropped as well.
+        let source = || Ok(CountingReader::new(io::Cursor::new(EXAMPLE.as_bytes())));
+        let mut cached = new_cached(CacheBudget::Bytes(0)).with_prefetch_source(source);
+        assert!(!cached.is_caching());
+        cached.prefetch(&[5, 6]).unwrap();
+        cached.prefetch_blocking(&[5, 6]).unwrap();
+        assert_eq!(cached.reader().reads, 0);
+        assert!(cached.prefetcher.as_ref().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let source = || Ok(CountingReader::new(io::Cursor::new(EXAMPLE.as_bytes())));
+        let mut cached = new_cached(CacheBudget::Entries(8)).with_prefetch_source(source);
+        cached.prefetch(&[5, 6]).unwrap();
+        for img_id in [5, 6] {
+            cached.get_item_dict(img_id).unwrap();
+            cached.get_anns_dict(img_id).unwrap();
+        }
+        assert_eq!((cached.reader().reads, cached.reader().seeks), (0, 0));
+        assert_eq!(cached.cache_stats(), CacheStats { hits: 4, misses: 0 });
+
+        let mut cached = new_cached(CacheBudget::Entries(8));
+        cached.prefetch(&[5, 6]).unwrap();
+        let reads = cached.reader().reads;
+        cached.get_item_dict(6).unwrap();
+        cached.get_anns_dict(5).unwrap();
+        assert_eq!(cached.reader().reads, reads);
+        assert!(cached.prefetch_blocking(&[7]).is_err());
+
+        // Prefetched values are evicted like the others.
+        let mut cached = new_cached(CacheBudget::Entries(1)).with_prefetch_source(source);
+        cached.prefetch(&[5, 6]).unwrap();
+        cached.get_item_dict(6).unwrap();
+        assert_eq!(cached.cache_stats().hits, 1);
+        assert_eq!(cached.items.entries.len(), 1);
+        assert_eq!(cached.anns.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_prefetch_compressed() {
+        use flate2::{write::GzEncoder, Compression as GzLevel};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(vec![], GzLevel::default());
+        encoder.write_all(EXAMPLE.as_bytes()).unwrap();
+        let path = write_example("");
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let expected = new_cached(CacheBudget::Entries(0))
+            .get_anns_dict(6)
+            .unwrap();
+        let mut cached = CachedPageMapper::open(&path, CacheBudget::Entries(8)).unwrap();
+        cached.prefetch(&[5, 6]).unwrap();
+        cached.collect_prefetches(Some(6));
+        assert_eq!(cached.anns.entries.len(), 2);
+        assert_eq!(cached.get_anns_dict(6).unwrap(), expected);
+        assert_eq!(cached.get_item_dict(5).unwrap()["file_name"], "a.jpg");
+        assert_eq!(cached.cache_stats(), CacheStats { hits: 2, misses: 0 });
+
+        // The source file is fingerprinted, not its decompressed copy.
+        let fingerprint = content_fingerprint(&path).unwrap();
+        let mut encoder = GzEncoder::new(vec![], GzLevel::default());
+        encoder
+            .write_all(EXAMPLE.replace("a.jpg", "b.jpg").as_bytes())
+            .unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+        assert_ne!(content_fingerprint(&path).unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn test_content_fingerprint() {
+        let path = write_example("");
+        let mut content = vec![b' '; 1 << 20];
+        std::fs::write(&path, &content).unwrap();
+        let fingerprint = content_fingerprint(&path).unwrap();
+
+        // Same length and modification time, edited in the middle sample.
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        content[1 << 19] = b'x';
+        std::fs::write(&path, &content).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let edited = content_fingerprint(&path).unwrap();
+        assert_ne!(edited, fingerprint);
+
+        // Outside the samples, the edit is missed.
+        content[FINGERPRINT_SAMPLE as usize * 2] = b'x';
+        std::fs::write(&path, &content).unwrap();
+        assert_eq!(content_fingerprint(&path).unwrap(), edited);
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let mut cached = new_cached(CacheBudget::Entries(1));
+        cached.get_item_dict(5).unwrap();
+        cached.get_item_dict(6).unwrap();
+        let reads = cached.reader().reads;
+        cached.get_item_dict(6).unwrap();
+        assert_eq!(cached.reader().reads, reads);
+        cached.get_item_dict(5).unwrap();
+        assert!(cached.reader().reads > reads);
+
+        // Values over the budget are not kept.
+        let mut cached = new_cached(CacheBudget::Bytes(64));
+        cached.get_anns_dict(5).unwrap();
+        assert!(cached.anns.entries.is_empty());
+        assert!(cached.get_item_dict(7).is_err());
+        assert!(cached.items.entries.is_empty());
+    }
+}
@@ -2,70 +2,1129 @@
 //
 //  SPDX-License-Identifier: MIT
 
+use serde::{Deserialize, Deserializer};
 use std::{
-    io::{self, Read, Seek},
-    str::FromStr,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, Cursor, Read, Seek},
+    path::Path,
+    rc::Rc,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
 };
 use strum::EnumString;
 
 use crate::{
-    page_maps::{AnnPageMap, ImgPageMap, JsonDict},
-    utils::{invalid_data, parse_serde_json_value, read_skipping_ws},
+    compression::{open_path, BgzfReader, DEFAULT_MAX_DECOMPRESSED_BYTES},
+    duplicates::{dedup_anns, find_duplicates, DuplicateGroup},
+    error::CocoError,
+    filter::AnnFilter,
+    geometry::bbox_union,
+    intern::{InternedValue, StringInterner},
+    memory::{json_bytes, IndexMemory, InterningMemory},
+    offset_index::{IoTuning, ReadScratch, RecordSource},
+    page_maps::{
+        AnnEntry, AnnPageMap, AnnotationFlavor, CategoriesPageMap, ImgPageMap, JsonDict,
+        LazySection, ScanOptions, SegKind, SegmentInfo,
+    },
+    profiling::{Call, ProfileReport, Profiling},
+    progress::{ProgressCallback, ProgressHook, SharedCallback, DEFAULT_PROGRESS_INTERVAL},
+    selection::FieldSelection,
+    utils::{invalid_data, is_crowd, read_limited, stream_error, JsonTokenizer, ScanBuffer, Token},
 };
 
+/// Longest section kept in memory, unless changed with
+/// `CocoPageMapperBuilder::max_section_bytes`.
+pub const DEFAULT_MAX_SECTION_BYTES: u64 = 1 << 30;
+
+/// Images read per `get_anns_batch` call by the passes over all images.
+pub(crate) const BATCH_SIZE: usize = 256;
+const DEDUP_EPS: f64 = 1e-6;
+
+#[derive(Deserialize)]
+struct BboxRecord {
+    #[serde(default, deserialize_with = "deserialize_bbox")]
+    bbox: Option<[f64; 4]>,
+    category_id: Option<i64>,
+}
+
+/// CVAT exports keep custom annotation attributes in `attributes`.
+#[derive(Deserialize)]
+struct AttributesRecord {
+    attributes: Option<JsonDict>,
+}
+
+#[derive(Deserialize)]
+struct IsCrowdRecord {
+    iscrowd: Option<JsonDict>,
+}
+
+fn deserialize_bbox<'de, D>(deserializer: D) -> Result<Option<[f64; 4]>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values: Option<Vec<f64>> = Option::deserialize(deserializer)?;
+    Ok(values.and_then(|v| v.try_into().ok()))
+}
+
+/// Common fields of the `info` section. Numbers given for text fields are kept
+/// as text, a `year` which is not an integer is `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CocoInfo {
+    pub description: Option<String>,
+    pub url: Option<String>,
+    pub version: Option<String>,
+    pub year: Option<i64>,
+    pub contributor: Option<String>,
+    pub date_created: Option<String>,
+}
+
+impl CocoInfo {
+    pub fn from_json(info: &JsonDict) -> Self {
+        let text = |key: &str| match info.get(key)? {
+            JsonDict::String(v) => Some(v.clone()),
+            JsonDict::Number(v) => Some(v.to_string()),
+            _ => None,
+        };
+        let year = match info.get("year") {
+            Some(JsonDict::Number(v)) => v.as_i64(),
+            Some(JsonDict::String(v)) => v.trim().parse().ok(),
+            _ => None,
+        };
+
+        Self {
+            description: text("description"),
+            url: text("url"),
+            version: text("version"),
+            year,
+            contributor: text("contributor"),
+            date_created: text("date_created"),
+        }
+    }
+}
+
+/// Download locations of an image. Empty strings are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageUrls {
+    pub coco_url: Option<String>,
+    pub flickr_url: Option<String>,
+}
+
+impl ImageUrls {
+    pub fn from_json(item: &JsonDict) -> Self {
+        let url = |key: &str| {
+            item.get(key)
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_owned())
+        };
+        Self {
+            coco_url: url("coco_url"),
+            flickr_url: url("flickr_url"),
+        }
+    }
+}
+
+/// A `categories` entry with its strings interned, see `categories_typed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CocoCategory {
+    pub id: i64,
+    pub name: Arc<str>,
+    pub supercategory: Option<Arc<str>>,
+    /// Keypoint names of person keypoints categories.
+    pub keypoints: Vec<Arc<str>>,
+}
+
+impl CocoCategory {
+    /// `None` if the entry has no integer `id`.
+    pub fn from_json(category: &JsonDict, interner: &mut StringInterner) -> Option<Self> {
+        let mut text = |v: &JsonDict| v.as_str().map(|v| interner.intern(v));
+        Some(Self {
+            id: category.get("id")?.as_i64()?,
+            name: category
+                .get("name")
+                .and_then(&mut text)
+                .unwrap_or_else(|| Arc::from("")),
+            supercategory: category.get("supercategory").and_then(&mut text),
+            keypoints: category
+                .get("keypoints")
+                .and_then(|v| v.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(&mut text)
+                .collect(),
+        })
+    }
+}
+
+/// Task of the dataset as guessed by `CocoPageMapper::task_hint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaskHint {
+    Detection,
+    InstanceSegmentation,
+    Panoptic,
+    Keypoints,
+    Captions,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(EnumString, Debug)]
 pub enum CocoJsonSection {
     #[strum(ascii_case_insensitive)]
-    LICENSES(JsonDict),
+    LICENSES(LazySection),
     #[strum(ascii_case_insensitive)]
-    INFO(JsonDict),
+    INFO(LazySection),
     #[strum(ascii_case_insensitive)]
-    CATEGORIES(JsonDict),
+    CATEGORIES(CategoriesPageMap),
     #[strum(ascii_case_insensitive)]
-    IMAGES(ImgPageMap),
+    IMAGES(ImgPageMap<i64>),
     #[strum(ascii_case_insensitive)]
     ANNOTATIONS(AnnPageMap),
 }
 
+impl CocoJsonSection {
+    pub fn kind(&self) -> CocoSectionKind {
+        match self {
+            CocoJsonSection::LICENSES(_) => CocoSectionKind::Licenses,
+            CocoJsonSection::INFO(_) => CocoSectionKind::Info,
+            CocoJsonSection::CATEGORIES(_) => CocoSectionKind::Categories,
+            CocoJsonSection::IMAGES(_) => CocoSectionKind::Images,
+            CocoJsonSection::ANNOTATIONS(_) => CocoSectionKind::Annotations,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CocoSectionKind {
+    Licenses,
+    Info,
+    Categories,
+    Images,
+    Annotations,
+}
+
+impl CocoSectionKind {
+    const ALL: [CocoSectionKind; 5] = [
+        CocoSectionKind::Licenses,
+        CocoSectionKind::Info,
+        CocoSectionKind::Categories,
+        CocoSectionKind::Images,
+        CocoSectionKind::Annotations,
+    ];
+
+    /// Matches a section key, ignoring ASCII case like `CocoJsonSection::from_str`.
+    pub fn from_key(key: &[u8]) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str().as_bytes().eq_ignore_ascii_case(key))
+    }
+
+    /// The key of the section.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CocoSectionKind::Licenses => "licenses",
+            CocoSectionKind::Info => "info",
+            CocoSectionKind::Categories => "categories",
+            CocoSectionKind::Images => "images",
+            CocoSectionKind::Annotations => "annotations",
+        }
+    }
+}
+
+/// An image id and its dict and annotations, see `CocoPageMapper::fetch_parallel`.
+pub type FetchedItem = (i64, Result<(JsonDict, Vec<JsonDict>), io::Error>);
+
+/// Where the time of a scan went, see `CocoPageMapper::new_with_profile`.
+#[derive(Debug, Clone, Default)]
+pub struct OpenProfile {
+    pub bytes_scanned_total: u64,
+    /// Bytes and scan time of every section, from after its key up to the
+    /// end of its value.
+    pub per_section: HashMap<CocoSectionKind, (u64, Duration)>,
+}
+
 #[derive(Debug)]
-pub struct CocoPageMapper {
-    licenses: JsonDict,
-    info: JsonDict,
-    categories: JsonDict,
-    images: ImgPageMap,
+pub struct CocoParts {
+    pub licenses: JsonDict,
+    pub info: JsonDict,
+    pub categories: JsonDict,
+    pub images: ImgPageMap<i64>,
+    pub annotations: AnnPageMap,
+}
+
+/// Page maps whose allocations are reused by the next scan.
+#[derive(Default)]
+struct SpareMaps {
+    images: ImgPageMap<i64>,
     annotations: AnnPageMap,
 }
 
+/// `ScanOptions` kept by a mapper, without the progress hook.
+#[derive(Debug)]
+struct StoredScanOptions(ScanOptions);
+
+impl StoredScanOptions {
+    fn new(options: &ScanOptions) -> Self {
+        Self(ScanOptions {
+            progress: None,
+            ..options.clone()
+        })
+    }
+}
+
+// SAFETY: The progress hook is the only part of `ScanOptions` which is neither
+// `Send` nor `Sync`, and it is always `None` here.
+unsafe impl Send for StoredScanOptions {}
+unsafe impl Sync for StoredScanOptions {}
+
+/// The sections and page maps are shared between the mappers returned by
+/// `clone_index`.
+#[derive(Debug)]
+pub struct CocoPageMapper {
+    licenses: Arc<LazySection>,
+    info: Arc<LazySection>,
+    categories: Arc<CategoriesPageMap>,
+    images: Arc<ImgPageMap<i64>>,
+    annotations: Arc<AnnPageMap>,
+    /// Positions in `licenses` by license id, built on first use.
+    license_positions: OnceLock<HashMap<i64, usize>>,
+    /// Options of the scan, for `reset`.
+    scan_options: Arc<StoredScanOptions>,
+    profiling: Option<Profiling>,
+}
+
 impl CocoPageMapper {
-    pub fn licenses(&self) -> &JsonDict {
-        return &self.licenses;
+    /// Read from `reader` and parsed on the first call, like `info`.
+    pub fn licenses(&self, reader: impl Read + Seek) -> Result<&JsonDict, io::Error> {
+        self.licenses.get(reader)
+    }
+    pub fn info(&self, reader: impl Read + Seek) -> Result<&JsonDict, io::Error> {
+        self.info.get(reader)
+    }
+    pub fn info_typed(&self, reader: impl Read + Seek) -> Result<CocoInfo, io::Error> {
+        self.info(reader).map(CocoInfo::from_json)
+    }
+    /// The whole `categories` section, parsed on the first call.
+    pub fn categories(&self) -> Result<&JsonDict, io::Error> {
+        self.categories.all()
     }
-    pub fn info(&self) -> &JsonDict {
-        return &self.info;
+    /// Reads a single category, without parsing the whole section.
+    pub fn get_category(
+        &self,
+        cat_id: i64,
+        mut reader: impl Read + Seek,
+    ) -> Result<Option<JsonDict>, io::Error> {
+        self.categories.get_category(cat_id, &mut reader)
+    }
+    /// Number of category objects parsed so far. The section is only scanned
+    /// for ids at open time, and parsed on the first `categories` call.
+    pub fn parsed_category_count(&self) -> usize {
+        self.categories.parsed_count()
+    }
+    /// Which of the `licenses` and `info` sections were parsed so far. They are
+    /// only checked at open time, and parsed on the first access.
+    pub fn parsed_sections(&self) -> Vec<CocoSectionKind> {
+        [
+            (CocoSectionKind::Licenses, &self.licenses),
+            (CocoSectionKind::Info, &self.info),
+        ]
+        .into_iter()
+        .filter(|(_, section)| section.is_parsed())
+        .map(|(kind, _)| kind)
+        .collect()
     }
-    pub fn categories(&self) -> &JsonDict {
-        return &self.categories;
+    /// The categories with an integer `id`, sharing equal names and
+    /// supercategories between entries.
+    pub fn categories_typed(&self) -> Result<Vec<CocoCategory>, io::Error> {
+        let mut interner = StringInterner::new();
+        Ok(self
+            .categories()?
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|v| CocoCategory::from_json(v, &mut interner))
+            .collect())
     }
     pub fn get_img_ids(&self) -> &Vec<i64> {
         self.images.ids()
     }
+    /// Ids assigned to image objects lacking an `id`, see
+    /// [`CocoPageMapperBuilder::synthesize_missing_ids`].
+    pub fn synthesized_img_ids(&self) -> &Vec<i64> {
+        self.images.synthesized_ids()
+    }
+    /// Ids referred to by annotations which match no indexed image, sorted.
+    /// These annotations are still returned by `get_anns_dict`.
+    pub fn orphan_ann_img_ids(&self) -> Vec<i64> {
+        let mut img_ids: Vec<i64> = self
+            .annotations
+            .img_ids()
+            .filter(|img_id| self.images.get_page(img_id).is_none())
+            .collect();
+        img_ids.sort_unstable();
+        img_ids
+    }
+    /// `CocoError::MixedIdTypes` if the image ids, in the `images` section
+    /// and in the `image_id` of annotations, are integers in some records and
+    /// strings in others. Such records are rejected or skipped by the scan, so
+    /// their annotations do not resolve.
+    pub fn mixed_id_types(&self) -> Option<CocoError> {
+        let counts = self.images.id_types() + self.annotations.img_id_types();
+        counts.is_mixed().then_some(CocoError::MixedIdTypes {
+            integers: counts.integers,
+            strings: counts.strings,
+        })
+    }
+    /// Returns `(width, height)` of the image recorded while indexing.
+    pub fn image_size(&self, img_id: i64) -> Option<(i64, i64)> {
+        let page = self.images.get_page(&img_id)?;
+        page.width.zip(page.height)
+    }
+    /// The categories with an integer `id`, in declaration order. Of
+    /// categories repeating an id, only the first is kept.
+    pub fn unique_categories(&self) -> Result<Vec<&JsonDict>, io::Error> {
+        let mut seen = HashSet::new();
+        Ok(self
+            .categories()?
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|v| {
+                v.get("id")
+                    .and_then(|v| v.as_i64())
+                    .is_some_and(|v| seen.insert(v))
+            })
+            .collect())
+    }
+    /// Maps category ids to contiguous labels `0..N`, the positions of the
+    /// categories in `unique_categories`.
+    pub fn contiguous_labels(&self) -> Result<HashMap<i64, usize>, io::Error> {
+        Ok(self
+            .unique_categories()?
+            .into_iter()
+            .enumerate()
+            .filter_map(|(label, v)| Some((v.get("id")?.as_i64()?, label)))
+            .collect())
+    }
     pub fn get_item_dict(
         &self,
         img_id: i64,
         mut reader: impl Read + Seek,
     ) -> Result<JsonDict, io::Error> {
-        self.images.get_dict(&mut reader, img_id)
+        match &self.profiling {
+            Some(profiling) => profiling.time_call(Call::GetItemDict, reader, |reader| {
+                self.images.get_dict(reader, img_id)
+            }),
+            None => self.images.get_dict(&mut reader, img_id),
+        }
+    }
+    /// Like `get_item_dict` for many images, in the order of `img_ids`. The
+    /// records are read in file order, with a single read for records close
+    /// to each other. Fails if any id is not on the page map.
+    pub fn get_item_dicts(
+        &self,
+        img_ids: &[i64],
+        mut reader: impl RecordSource,
+    ) -> Result<Vec<JsonDict>, io::Error> {
+        let (dicts, missing) = self.images.get_dicts(&mut reader, img_ids)?;
+        if let Some(img_id) = missing.first() {
+            let msg = format!("Image id: {} is not on the page map", img_id);
+            return Err(invalid_data(msg.as_str()));
+        }
+        Ok(dicts.into_iter().map(|(_, dict)| dict).collect())
+    }
+    /// Like `get_item_dict`, with the raw bytes of the record, read once, e.g.
+    /// to forward them. Synthesized ids are only in the dict.
+    pub fn get_item_raw_and_parsed(
+        &self,
+        img_id: i64,
+        mut reader: impl Read + Seek,
+    ) -> Result<(Vec<u8>, JsonDict), io::Error> {
+        self.images.get_raw_and_dict(&mut reader, img_id)
+    }
+    /// Like `get_item_dict`, with the record sliced from `data`, the whole
+    /// scanned input, see `MmapSource`.
+    pub fn get_item_dict_from_bytes(
+        &self,
+        img_id: i64,
+        data: &[u8],
+    ) -> Result<JsonDict, io::Error> {
+        self.images.get_dict_from_bytes(data, img_id)
+    }
+    pub fn get_anns_dict_from_bytes(
+        &self,
+        img_id: i64,
+        data: &[u8],
+    ) -> Result<Vec<JsonDict>, io::Error> {
+        self.annotations.get_anns_from_bytes(data, img_id)
+    }
+    pub fn image_urls(
+        &self,
+        img_id: i64,
+        reader: impl Read + Seek,
+    ) -> Result<ImageUrls, io::Error> {
+        let item = self.get_item_dict(img_id, reader)?;
+        Ok(ImageUrls::from_json(&item))
+    }
+    /// The entry of `licenses` whose `id` is the `license` of the image.
+    pub fn image_license(
+        &self,
+        img_id: i64,
+        mut reader: impl Read + Seek,
+    ) -> Result<Option<JsonDict>, io::Error> {
+        let item = self.get_item_dict(img_id, &mut reader)?;
+        let Some(license_id) = item.get("license").and_then(|v| v.as_i64()) else {
+            return Ok(None);
+        };
+        let licenses = self
+            .licenses(reader)?
+            .as_array()
+            .map_or(&[][..], |v| v.as_slice());
+        let positions = self.license_positions.get_or_init(|| {
+            let mut positions = HashMap::new();
+            for (position, license) in licenses.iter().enumerate() {
+                if let Some(id) = license.get("id").and_then(|v| v.as_i64()) {
+                    positions.entry(id).or_insert(position);
+                }
+            }
+            positions
+        });
+        Ok(positions
+            .get(&license_id)
+            .map(|position| licenses[*position].clone()))
     }
     pub fn get_anns_dict(
         &self,
         img_id: i64,
         mut reader: impl Read + Seek,
     ) -> Result<Vec<JsonDict>, io::Error> {
-        self.annotations.get_anns(&mut reader, img_id)
+        match &self.profiling {
+            Some(profiling) => profiling.time_call(Call::GetAnnsDict, reader, |reader| {
+                self.annotations.get_anns(reader, img_id)
+            }),
+            None => self.annotations.get_anns(&mut reader, img_id),
+        }
+    }
+    /// Like `get_anns_dict`, but only with the top-level keys picked by
+    /// `selection`, e.g. without `segmentation` to draw the boxes. The other
+    /// values are skipped while parsing, which saves time and memory.
+    pub fn get_anns_dict_selected(
+        &self,
+        img_id: i64,
+        mut reader: impl Read + Seek,
+        selection: &FieldSelection,
+    ) -> Result<Vec<JsonDict>, io::Error> {
+        match &self.profiling {
+            Some(profiling) => profiling.time_call(Call::GetAnnsDict, reader, |reader| {
+                self.annotations
+                    .get_anns_selected(reader, img_id, selection)
+            }),
+            None => self
+                .annotations
+                .get_anns_selected(&mut reader, img_id, selection),
+        }
+    }
+
+    /// A single `segments_info` entry of the panoptic annotation of `img_id`.
+    pub fn get_segment(
+        &self,
+        img_id: i64,
+        segment_id: i64,
+        mut reader: impl Read + Seek,
+    ) -> Result<SegmentInfo, io::Error> {
+        self.annotations
+            .get_segment(&mut reader, img_id, segment_id)
+    }
+
+    /// The read sizes in effect, see `CocoPageMapperBuilder::io_tuning`.
+    pub fn io_tuning(&self) -> IoTuning {
+        self.images.io_tuning()
+    }
+
+    /// Changes how the images and annotations are read from now on. Page maps
+    /// shared with `clone_index` are copied first.
+    pub fn set_io_tuning(&mut self, io_tuning: IoTuning) {
+        Arc::make_mut(&mut self.images).set_io_tuning(io_tuning);
+        Arc::make_mut(&mut self.annotations).set_io_tuning(io_tuning);
+    }
+
+    /// Construction and lookup timings, if enabled by
+    /// `CocoPageMapperBuilder::profiling`.
+    pub fn profile(&self) -> Option<ProfileReport> {
+        self.profiling.as_ref().map(Profiling::report)
+    }
+    /// Like `get_anns_dict`, but reads through the buffers of `scratch`.
+    pub fn get_anns_dict_with(
+        &self,
+        img_id: i64,
+        mut reader: impl Read + Seek,
+        scratch: &mut ReadScratch,
+    ) -> Result<Vec<JsonDict>, io::Error> {
+        self.annotations
+            .get_anns_as_with(&mut reader, img_id, scratch)
+    }
+    /// Like `get_anns_dict`, with the offset of every annotation in the file,
+    /// e.g. to edit the raw record.
+    pub fn get_anns_with_offsets(
+        &self,
+        img_id: i64,
+        mut reader: impl Read + Seek,
+    ) -> Result<Vec<(u64, JsonDict)>, io::Error> {
+        self.annotations.get_anns_with_offsets(&mut reader, img_id)
+    }
+    pub fn ann_seg_kind(&self, ann_id: i64) -> Option<SegKind> {
+        self.annotations.get_page(ann_id).map(|page| page.seg_kind)
+    }
+    pub fn seg_kind_counts(&self) -> HashMap<SegKind, usize> {
+        self.annotations.seg_kind_counts()
+    }
+    /// Categories referenced by at least one annotation or panoptic segment.
+    pub fn used_category_ids(&self) -> HashSet<i64> {
+        self.annotations.category_ids()
+    }
+    /// Images with at least one annotation of the given categories, found
+    /// without reading the file. Panoptic segments are not considered.
+    pub fn img_ids_with_categories(&self, category_ids: &HashSet<i64>) -> HashSet<i64> {
+        self.annotations.img_ids_with_categories(category_ids)
+    }
+    /// Declared categories which no annotation refers to.
+    pub fn unused_category_ids(&self) -> Result<HashSet<i64>, io::Error> {
+        let used = self.used_category_ids();
+        Ok(self
+            .categories()?
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.get("id").and_then(|v| v.as_i64()))
+            .filter(|id| !used.contains(id))
+            .collect())
+    }
+    /// Heap bytes held by the index, including the opt-in secondary indexes.
+    pub fn index_memory_bytes(&self) -> IndexMemory {
+        let images = self.images.memory_bytes();
+        let annotations = self.annotations.memory_bytes();
+        let sections = self.licenses.memory_bytes()
+            + self.info.memory_bytes()
+            + self.categories.memory_bytes();
+        IndexMemory {
+            images,
+            annotations,
+            ann_offsets: self.annotations.offset_bytes(),
+            sections,
+            total: images + annotations + sections,
+        }
+    }
+    /// Releases the spare capacity left in the index by the scan. Parts shared
+    /// with other mappers through `clone_index` are left as they are.
+    pub fn shrink_to_fit(&mut self) {
+        if let Some(licenses) = Arc::get_mut(&mut self.licenses) {
+            licenses.shrink_to_fit();
+        }
+        if let Some(info) = Arc::get_mut(&mut self.info) {
+            info.shrink_to_fit();
+        }
+        if let Some(categories) = Arc::get_mut(&mut self.categories) {
+            categories.shrink_to_fit();
+        }
+        if let Some(images) = Arc::get_mut(&mut self.images) {
+            images.shrink_to_fit();
+        }
+        if let Some(annotations) = Arc::get_mut(&mut self.annotations) {
+            annotations.shrink_to_fit();
+        }
+    }
+    /// A mapper sharing the index of this one, without copying it. Lookups
+    /// still take a reader of their own. With profiling enabled, the clone
+    /// counts its calls separately.
+    pub fn clone_index(&self) -> CocoPageMapper {
+        CocoPageMapper {
+            licenses: self.licenses.clone(),
+            info: self.info.clone(),
+            categories: self.categories.clone(),
+            images: self.images.clone(),
+            annotations: self.annotations.clone(),
+            license_positions: self.license_positions.clone(),
+            scan_options: self.scan_options.clone(),
+            profiling: self.profiling.as_ref().map(|profiling| {
+                Profiling::new(ProfileReport {
+                    get_item_dict: Default::default(),
+                    get_anns_dict: Default::default(),
+                    ..profiling.report()
+                })
+            }),
+        }
+    }
+    /// `None` if the file has no annotations.
+    pub fn annotation_flavor(&self) -> Option<AnnotationFlavor> {
+        self.annotations.flavor()
+    }
+    /// Guesses the task from the annotation flavor, then from the fields of the
+    /// categories (`isthing` for panoptic, `keypoints` for pose), then from the
+    /// presence of segmentations. Falls back to `Detection`.
+    pub fn task_hint(&self) -> Result<TaskHint, io::Error> {
+        match self.annotation_flavor() {
+            Some(AnnotationFlavor::Panoptic) => return Ok(TaskHint::Panoptic),
+            Some(AnnotationFlavor::Captions) => return Ok(TaskHint::Captions),
+            Some(AnnotationFlavor::PersonKeypoints) => return Ok(TaskHint::Keypoints),
+            Some(AnnotationFlavor::Instances) | None => {}
+        }
+
+        let categories = self
+            .categories()?
+            .as_array()
+            .map_or(&[][..], |v| v.as_slice());
+        if categories.iter().any(|v| v.get("isthing").is_some()) {
+            return Ok(TaskHint::Panoptic);
+        }
+        if categories.iter().any(|v| {
+            v.get("keypoints")
+                .and_then(|v| v.as_array())
+                .is_some_and(|v| !v.is_empty())
+        }) {
+            return Ok(TaskHint::Keypoints);
+        }
+
+        if self
+            .seg_kind_counts()
+            .keys()
+            .any(|kind| *kind != SegKind::None)
+        {
+            Ok(TaskHint::InstanceSegmentation)
+        } else {
+            Ok(TaskHint::Detection)
+        }
+    }
+    /// The `caption` strings of an image. Empty unless this is a captions file.
+    pub fn get_captions(
+        &self,
+        img_id: i64,
+        reader: impl Read + Seek,
+    ) -> Result<Vec<String>, io::Error> {
+        if self.annotation_flavor() != Some(AnnotationFlavor::Captions) {
+            return Ok(vec![]);
+        }
+        let anns = self.get_anns_dict(img_id, reader)?;
+        Ok(anns
+            .iter()
+            .filter_map(|ann| ann.get("caption").and_then(|v| v.as_str()))
+            .map(|v| v.to_owned())
+            .collect())
+    }
+    /// Offsets of the annotation objects skipped with
+    /// [`CocoPageMapperBuilder::skip_malformed_anns`].
+    pub fn malformed_ann_positions(&self) -> &Vec<u64> {
+        self.annotations.malformed_positions()
+    }
+    /// Number of objects in the images array, including the skipped ones.
+    pub fn scanned_image_count(&self) -> usize {
+        self.images.scanned_count()
+    }
+    /// Number of image objects skipped with
+    /// [`CocoPageMapperBuilder::skip_malformed_images`]. Images are looked up
+    /// by id, so these cannot be fetched.
+    pub fn skipped_image_count(&self) -> usize {
+        self.images.skipped_count()
+    }
+    /// Per-annotation scalars of an image in file order, empty unless built
+    /// with [`CocoPageMapperBuilder::rich_index`].
+    pub fn ann_entries(&self, img_id: i64) -> &[AnnEntry] {
+        self.annotations.entries(img_id)
+    }
+    pub fn get_ann_count(&self, img_id: i64) -> usize {
+        self.annotations.count(img_id)
+    }
+    pub fn annotation_count(&self) -> usize {
+        self.annotations.len()
+    }
+    /// Every annotation once, in file order regardless of the image.
+    pub fn iter_all_anns<'a, R>(
+        &'a self,
+        reader: R,
+    ) -> impl Iterator<Item = Result<JsonDict, io::Error>> + 'a
+    where
+        R: Read + Seek + 'a,
+    {
+        self.annotations.iter_all(reader)
+    }
+    /// Like `iter_all_anns`, with the object keys shared through `interner`
+    /// instead of allocated for every annotation, to keep many of them in
+    /// less memory.
+    pub fn iter_all_anns_interned<'a, R>(
+        &'a self,
+        reader: R,
+        interner: &'a mut StringInterner,
+    ) -> impl Iterator<Item = Result<InternedValue, io::Error>> + 'a
+    where
+        R: Read + Seek + 'a,
+    {
+        self.annotations.iter_all_interned(reader, interner)
+    }
+    /// Like `get_anns_dict`, see `iter_all_anns_interned`.
+    pub fn get_anns_interned(
+        &self,
+        img_id: i64,
+        mut reader: impl Read + Seek,
+        interner: &mut StringInterner,
+    ) -> Result<Vec<InternedValue>, io::Error> {
+        self.annotations
+            .get_anns_interned(&mut reader, img_id, interner)
+    }
+    /// Reads every annotation to measure the heap bytes all of them would
+    /// take once parsed, with and without interned keys.
+    pub fn anns_interning_memory<R: Read + Seek>(
+        &self,
+        mut reader: R,
+    ) -> Result<InterningMemory, io::Error> {
+        let mut interner = StringInterner::new();
+        let mut memory = InterningMemory::default();
+        for ann in self.iter_all_anns(&mut reader) {
+            let ann = ann?;
+            let interned = InternedValue::from_json(&ann, &mut interner);
+            memory.plain += std::mem::size_of::<JsonDict>() + json_bytes(&ann);
+            memory.interned += std::mem::size_of::<InternedValue>() + interned.heap_bytes();
+        }
+        memory.keys = interner.heap_bytes();
+        memory.interned += memory.keys;
+        Ok(memory)
+    }
+    /// Images with their annotations in chunks of `batch_size`, in the order
+    /// of `get_img_ids`. The last batch may be smaller. Every batch is fetched
+    /// with `get_anns_batch` and a coalesced read of the image records.
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn iter_items_batched<'a, R>(
+        &'a self,
+        mut reader: R,
+        batch_size: usize,
+    ) -> impl Iterator<Item = Result<Vec<(i64, JsonDict, Vec<JsonDict>)>, io::Error>> + 'a
+    where
+        R: Read + Seek + 'a,
+    {
+        self.get_img_ids().chunks(batch_size).map(move |img_ids| {
+            let (items, _) = self.images.get_dicts(&mut reader, img_ids)?;
+            let anns = self.annotations.get_anns_batch(&mut reader, img_ids)?;
+            Ok(items
+                .into_iter()
+                .zip(anns)
+                .map(|((img_id, item), anns)| (img_id, item, anns))
+                .collect())
+        })
+    }
+    /// Returns the boxes of an image as a flat `N x 4` array in `[x, y, w, h]`
+    /// order and the parallel `category_id` labels. Annotations without a valid
+    /// `bbox` or `category_id` are skipped, their number is
+    /// `get_ann_count(img_id) - labels.len()`.
+    pub fn get_bbox_tensor(
+        &self,
+        img_id: i64,
+        mut reader: impl Read + Seek,
+    ) -> Result<(Vec<f32>, Vec<i64>), io::Error> {
+        let records: Vec<BboxRecord> = self.annotations.get_anns_as(&mut reader, img_id)?;
+
+        let mut boxes = Vec::with_capacity(4 * records.len());
+        let mut labels = Vec::with_capacity(records.len());
+        for record in records {
+            if let (Some(bbox), Some(label)) = (record.bbox, record.category_id) {
+                boxes.extend(bbox.iter().map(|v| *v as f32));
+                labels.push(label);
+            }
+        }
+        Ok((boxes, labels))
+    }
+    /// The smallest `[x, y, w, h]` box containing the boxes of the given
+    /// annotations, `None` if none of them has a valid `bbox`.
+    pub fn union_bbox(
+        &self,
+        ann_ids: &[i64],
+        mut reader: impl Read + Seek,
+    ) -> Result<Option<[f64; 4]>, io::Error> {
+        let records: Vec<Option<BboxRecord>> =
+            self.annotations.get_by_ids_as(&mut reader, ann_ids)?;
+
+        let mut union: Option<[f64; 4]> = None;
+        for (ann_id, record) in ann_ids.iter().zip(records) {
+            let record = record.ok_or_else(|| {
+                invalid_data(format!("Annotation id: {} is not on the page map", ann_id).as_str())
+            })?;
+            if let Some(bbox) = record.bbox {
+                union = Some(union.map_or(bbox, |union| bbox_union(&union, &bbox)));
+            }
+        }
+        Ok(union)
+    }
+    /// The `attributes` object of an annotation, `None` if it has none.
+    pub fn ann_attributes(
+        &self,
+        ann_id: i64,
+        mut reader: impl Read + Seek,
+    ) -> Result<Option<JsonDict>, io::Error> {
+        let mut records: Vec<Option<AttributesRecord>> =
+            self.annotations.get_by_ids_as(&mut reader, &[ann_id])?;
+        let record = records.pop().flatten().ok_or_else(|| {
+            invalid_data(format!("Annotation id: {} is not on the page map", ann_id).as_str())
+        })?;
+        Ok(record.attributes.filter(|v| v.is_object()))
+    }
+    /// Whether an annotation is marked as crowd, see `is_crowd`. A missing
+    /// value is `false`.
+    pub fn ann_is_crowd(
+        &self,
+        ann_id: i64,
+        mut reader: impl Read + Seek,
+    ) -> Result<bool, io::Error> {
+        let mut records: Vec<Option<IsCrowdRecord>> =
+            self.annotations.get_by_ids_as(&mut reader, &[ann_id])?;
+        let record = records.pop().flatten().ok_or_else(|| {
+            invalid_data(format!("Annotation id: {} is not on the page map", ann_id).as_str())
+        })?;
+        match record.iscrowd {
+            None => Ok(false),
+            Some(value) => is_crowd(&value).ok_or_else(|| {
+                let msg = format!(
+                    "The iscrowd value {} of annotation id: {} is not 0, 1 or a boolean",
+                    value, ann_id
+                );
+                invalid_data(msg.as_str())
+            }),
+        }
+    }
+    /// A value of an annotation by RFC 6901 JSON pointer, e.g.
+    /// `/attributes/occluded` or `/bbox/0`. `None` if nothing is there.
+    pub fn get_ann_field(
+        &self,
+        ann_id: i64,
+        mut reader: impl Read + Seek,
+        pointer: &str,
+    ) -> Result<Option<JsonDict>, io::Error> {
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            let msg = format!("The JSON pointer {:?} does not start with '/'", pointer);
+            return Err(invalid_data(msg.as_str()));
+        }
+        let mut records: Vec<Option<JsonDict>> =
+            self.annotations.get_by_ids_as(&mut reader, &[ann_id])?;
+        let mut ann = records.pop().flatten().ok_or_else(|| {
+            invalid_data(format!("Annotation id: {} is not on the page map", ann_id).as_str())
+        })?;
+        Ok(ann.pointer_mut(pointer).map(JsonDict::take))
+    }
+    pub fn get_anns_dedup(
+        &self,
+        img_id: i64,
+        reader: impl Read + Seek,
+    ) -> Result<(Vec<JsonDict>, usize), io::Error> {
+        let anns = self.get_anns_dict(img_id, reader)?;
+        Ok(dedup_anns(anns, DEDUP_EPS))
+    }
+    pub fn get_anns_filtered(
+        &self,
+        img_id: i64,
+        reader: impl Read + Seek,
+        filter: &AnnFilter,
+    ) -> Result<Vec<JsonDict>, io::Error> {
+        let mut anns = self.get_anns_dict(img_id, reader)?;
+        anns.retain(|ann| filter.matches(ann));
+        Ok(anns)
+    }
+    /// Reads through `reader` or a batching backend, e.g. `UringSource`.
+    pub fn get_anns_batch(
+        &self,
+        img_ids: &[i64],
+        mut reader: impl RecordSource,
+    ) -> Result<Vec<Vec<JsonDict>>, io::Error> {
+        self.annotations.get_anns_batch(&mut reader, img_ids)
+    }
+    /// Like `get_anns_batch`, see `get_anns_dict_selected`.
+    pub fn get_anns_batch_selected(
+        &self,
+        img_ids: &[i64],
+        mut reader: impl RecordSource,
+        selection: &FieldSelection,
+    ) -> Result<Vec<Vec<JsonDict>>, io::Error> {
+        self.annotations
+            .get_anns_batch_selected(&mut reader, img_ids, selection)
+    }
+    /// Fetches the dict and annotations of every image on `threads` threads,
+    /// each reading through its own reader from `reader_factory`. The ids are
+    /// split by file offset, so that every thread reads a mostly contiguous
+    /// region. Results are in the order of `img_ids`, with an error per id.
+    /// The readers may be a batching backend, e.g. `UringSource`.
+    pub fn fetch_parallel<R, F>(
+        &self,
+        img_ids: &[i64],
+        reader_factory: F,
+        threads: usize,
+    ) -> Vec<FetchedItem>
+    where
+        R: RecordSource,
+        F: Fn() -> R + Sync,
+    {
+        let mut order: Vec<usize> = (0..img_ids.len()).collect();
+        order.sort_by_key(|idx| {
+            self.images
+                .get_page(&img_ids[*idx])
+                .map_or(u64::MAX, |page| page.offset)
+        });
+
+        let chunk_size = order.len().div_ceil(threads.max(1)).max(1);
+        let mut results: Vec<Option<FetchedItem>> = Vec::new();
+        results.resize_with(img_ids.len(), || None);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = order
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    let reader_factory = &reader_factory;
+                    scope.spawn(move || {
+                        let mut reader = reader_factory();
+                        chunk
+                            .iter()
+                            .map(|idx| {
+                                let img_id = img_ids[*idx];
+                                (*idx, (img_id, self.fetch_one(img_id, &mut reader)))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            for worker in workers {
+                for (idx, item) in worker.join().unwrap() {
+                    results[idx] = Some(item);
+                }
+            }
+        });
+        results.into_iter().flatten().collect()
+    }
+    fn fetch_one(
+        &self,
+        img_id: i64,
+        reader: &mut impl RecordSource,
+    ) -> Result<(JsonDict, Vec<JsonDict>), io::Error> {
+        let (mut dicts, _) = self.images.get_dicts(reader, &[img_id])?;
+        let (_, item) = dicts.pop().ok_or_else(|| {
+            invalid_data(format!("Image id: {} is not on the page map", img_id).as_str())
+        })?;
+        let mut anns = self.annotations.get_anns_batch(reader, &[img_id])?;
+        Ok((item, anns.pop().unwrap_or_default()))
+    }
+    pub fn find_duplicate_anns(
+        &self,
+        mut reader: impl Read + Seek,
+        iou_threshold: f64,
+    ) -> Result<Vec<DuplicateGroup>, io::Error> {
+        let mut groups = vec![];
+
+        for img_ids in self.get_img_ids().chunks(BATCH_SIZE) {
+            let anns_batch = self.get_anns_batch(img_ids, &mut reader)?;
+
+            for (img_id, anns) in img_ids.iter().zip(anns_batch.iter()) {
+                groups.extend(find_duplicates(*img_id, anns, iou_threshold));
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// `reader` is needed for the `licenses` and `info` sections, if they
+    /// were not read yet.
+    pub fn into_parts(self, mut reader: impl Read + Seek) -> Result<CocoParts, io::Error> {
+        // Parts shared with clones are copied.
+        let mut section = |section: Arc<LazySection>| match Arc::try_unwrap(section) {
+            Ok(section) => section.into_json(&mut reader),
+            Err(shared) => shared.get(&mut reader).cloned(),
+        };
+        Ok(CocoParts {
+            licenses: section(self.licenses)?,
+            info: section(self.info)?,
+            categories: match Arc::try_unwrap(self.categories) {
+                Ok(categories) => categories.into_json()?,
+                Err(shared) => shared.all()?.clone(),
+            },
+            images: Arc::unwrap_or_clone(self.images),
+            annotations: Arc::unwrap_or_clone(self.annotations),
+        })
+    }
+
+    pub fn new(reader: impl Read + Seek) -> Result<Self, io::Error> {
+        CocoPageMapperBuilder::new().build(reader)
+    }
+
+    /// Like `new`, but also reports the bytes and time spent on every section.
+    pub fn new_with_profile(reader: impl Read + Seek) -> Result<(Self, OpenProfile), io::Error> {
+        CocoPageMapperBuilder::new().build_with_profile(reader)
+    }
+
+    /// Maps a plain, gzip or zstd compressed file. The returned reader has to be
+    /// used for the later lookups, as it may point at a decompressed copy.
+    pub fn from_path(path: &Path) -> Result<(Self, BufReader<File>), io::Error> {
+        CocoPageMapperBuilder::new().build_from_path(path)
+    }
+
+    /// Maps a bgzip compressed file through its `.gzi` index without
+    /// decompressing it as a whole. The returned reader has to be used for the
+    /// later lookups, it decompresses the blocks holding the requested records.
+    pub fn from_bgzip<R: Read + Seek>(
+        data: R,
+        index: impl Read,
+    ) -> Result<(Self, BgzfReader<R>), io::Error> {
+        CocoPageMapperBuilder::new().build_from_bgzip(data, index)
+    }
+
+    /// Maps a file held in memory. Later lookups take a fresh
+    /// `io::Cursor::new(data)` as the reader.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, io::Error> {
+        CocoPageMapperBuilder::new().build(Cursor::new(data))
+    }
+
+    pub fn builder() -> CocoPageMapperBuilder {
+        CocoPageMapperBuilder::new()
+    }
+
+    /// Rebuilds the mapper from another file, reusing the allocations of the
+    /// page maps. Scans with the options of the builder this mapper came from,
+    /// without its progress callback. On failure the mapper is left empty.
+    pub fn reset(&mut self, mut reader: impl Read + Seek) -> Result<(), io::Error> {
+        // Maps shared with clones are left to them.
+        let mut spare = SpareMaps {
+            images: Arc::try_unwrap(std::mem::take(&mut self.images)).unwrap_or_default(),
+            annotations: Arc::try_unwrap(std::mem::take(&mut self.annotations)).unwrap_or_default(),
+        };
+        let start = reader.stream_position()?;
+        let options = self.scan_options.clone();
+        match Self::from_reader_with_options(
+            reader,
+            start,
+            &options.0,
+            &mut spare,
+            &mut OpenProfile::default(),
+        ) {
+            Ok(mapper) => {
+                *self = mapper;
+                Ok(())
+            }
+            Err(e) => {
+                self.licenses = Arc::new(LazySection::from_json(JsonDict::Array(vec![])));
+                self.license_positions = OnceLock::new();
+                self.info = Arc::new(LazySection::from_json(JsonDict::Object(Default::default())));
+                self.categories = Arc::default();
+                Err(e)
+            }
+        }
     }
 
-    pub fn new(mut reader: impl Read + Seek) -> Result<Self, io::Error> {
-        let sections = Self::parse_json(&mut reader)?;
+    /// Scans the document starting at `start`, where `reader` must be.
+    fn from_reader_with_options(
+        reader: impl Read + Seek,
+        start: u64,
+        options: &ScanOptions,
+        spare: &mut SpareMaps,
+        profile: &mut OpenProfile,
+    ) -> Result<Self, io::Error> {
+        // The sections are scanned through the buffer, so that the small reads
+        // of `serde_json` and of the nested scanners do not reach the reader,
+        // and the buffer counts the absolute offsets from `start` on.
+        let mut reader = ScanBuffer::at(reader, start, options.io_tuning.scan_buffer_bytes);
+        let sections = Self::parse_json(&mut reader, options, spare, profile)?;
 
         let mut licenses = None;
         let mut info = None;
@@ -97,163 +1156,421 @@ impl CocoPageMapper {
         let info = info.ok_or(invalid_data("Cannot find the info section."))?;
         let categories = categories.ok_or(invalid_data("Cannot find the categories section."))?;
         let images = images.ok_or(invalid_data("Cannot find the images section."))?;
-        let annotations =
-            annotations.ok_or(invalid_data("Cannot find the annotations section."))?;
-
-        Ok(CocoPageMapper {
-            licenses,
-            info,
-            categories,
-            images,
-            annotations,
-        })
-    }
+        // Minimal caption files may come without annotations.
+        let annotations = annotations.unwrap_or_default();
+        if options.verify_offsets {
+            annotations.verify_offsets(&mut reader)?;
+        }
 
-    fn parse_json(mut reader: impl Read + Seek) -> Result<Vec<CocoJsonSection>, io::Error> {
-        let mut brace_level = 0;
+        // The sections are indexed independently and only linked here, so the
+        // annotations may come before the images in the file.
+        let mapper = CocoPageMapper {
+            licenses: Arc::new(licenses),
+            info: Arc::new(info),
+            categories: Arc::new(categories),
+            images: Arc::new(images),
+            annotations: Arc::new(annotations),
+            license_positions: OnceLock::new(),
+            scan_options: Arc::new(StoredScanOptions::new(options)),
+            profiling: None,
+        };
+        if options.reject_orphan_anns {
+            let orphans = mapper.orphan_ann_img_ids();
+            if !orphans.is_empty() {
+                let msg = format!("Annotations refer to missing images: {:?}", orphans);
+                return Err(invalid_data(msg.as_str()));
+            }
+        }
+        if options.reject_mixed_id_types {
+            if let Some(err) = mapper.mixed_id_types() {
+                return Err(err.into());
+            }
+        }
+        Ok(mapper)
+    }
+
+    fn parse_json(
+        reader: impl Read + Seek,
+        options: &ScanOptions,
+        spare: &mut SpareMaps,
+        profile: &mut OpenProfile,
+    ) -> Result<Vec<CocoJsonSection>, io::Error> {
+        let mut tokens = JsonTokenizer::new(reader)?;
         let mut coco_json_sections = Vec::new();
+        let start = tokens.position();
 
-        while let Ok(c) = read_skipping_ws(&mut reader) {
-            match c {
-                b'{' => brace_level += 1,
-                b'"' => {
-                    let mut buf_key = Vec::new();
-                    while let Ok(c) = read_skipping_ws(&mut reader) {
-                        if c == b'"' {
-                            break;
-                        }
-                        buf_key.push(c);
-                    }
-                    match String::from_utf8(buf_key.clone()) {
-                        Ok(key) => {
-                            let section = Self::parse_section_from_key(key, &mut reader)?;
-                            coco_json_sections.push(section);
-                        }
-                        Err(e) => {
-                            let cur_pos = reader.stream_position()?;
-                            let msg = format!(
-                                "Section key buffer, {:?} is invalid at pos: {}. {}",
-                                buf_key, cur_pos, e
-                            );
-                            let err = invalid_data(msg.as_str());
-                            return Err(err);
-                        }
-                    }
+        tokens.expect_token(Token::BeginObject)?;
+        loop {
+            if let Some((b'"', _)) = tokens.peek_byte()? {
+                // Only unknown keys are copied, for the error message.
+                let (kind, span) = tokens.next_key_with(|key| {
+                    CocoSectionKind::from_key(key)
+                        .ok_or_else(|| String::from_utf8_lossy(key).into_owned())
+                })?;
+                let kind = kind.map_err(|key| {
+                    stream_error(format!("Unknown key: {:?}", key).as_str(), span.start)
+                })?;
+                tokens.expect_token(Token::Colon)?;
+                if let Some(progress) = &options.progress {
+                    progress.start_section(kind.as_str(), tokens.position());
                 }
-                b',' => {
-                    continue;
-                }
-                b'}' => {
-                    brace_level -= 1;
-                    if brace_level == 0 {
-                        break;
+                let (section_start, started) = (tokens.position(), Instant::now());
+                let section = match kind {
+                    CocoSectionKind::Images | CocoSectionKind::Annotations => {
+                        Self::parse_section(kind, tokens.get_mut()?, options, spare)?
                     }
+                    _ => {
+                        let limit = options
+                            .max_section_bytes
+                            .unwrap_or(DEFAULT_MAX_SECTION_BYTES);
+                        read_limited(tokens.get_mut()?, limit, |reader| {
+                            Self::parse_section(kind, reader, options, spare)
+                        })?
+                    }
+                };
+                tokens.resync()?;
+                let (bytes, elapsed) = profile.per_section.entry(kind).or_default();
+                *bytes += tokens.position() - section_start;
+                *elapsed += started.elapsed();
+                coco_json_sections.push(section);
+                continue;
+            }
+            match tokens.next_token()? {
+                Some((Token::Comma, _)) => continue,
+                Some((Token::EndObject, _)) => break,
+                Some((token, span)) => {
+                    let msg = format!("Expected a section key, found {:?}", token);
+                    return Err(stream_error(msg.as_str(), span.start));
                 }
-                _ => {
-                    let cur_pos = reader.stream_position()?;
-                    let msg = format!("{} is invalid character at pos: {}", c, cur_pos);
-                    let err = invalid_data(msg.as_str());
-                    return Err(err);
-                }
+                None => return Err(stream_error("Unexpected end of input", tokens.position())),
             }
         }
+        profile.bytes_scanned_total = tokens.position() - start;
+        if let Some(progress) = &options.progress {
+            progress.finish(tokens.position());
+        }
         Ok(coco_json_sections)
     }
 
-    fn parse_section_from_key(
-        buf_key: String,
-        mut reader: impl Read + Seek,
+    fn parse_section(
+        kind: CocoSectionKind,
+        reader: impl Read + Seek,
+        options: &ScanOptions,
+        spare: &mut SpareMaps,
     ) -> Result<CocoJsonSection, io::Error> {
-        match CocoJsonSection::from_str(buf_key.as_str()) {
-            Ok(curr_key) => {
-                while let Ok(c) = read_skipping_ws(&mut reader) {
-                    if c == b':' {
-                        break;
-                    }
-                }
-                match curr_key {
-                    CocoJsonSection::LICENSES(_) => {
-                        let v = parse_serde_json_value(reader)?;
-                        Ok(CocoJsonSection::LICENSES(v))
-                    }
-                    CocoJsonSection::INFO(_) => {
-                        let v = parse_serde_json_value(reader)?;
-                        Ok(CocoJsonSection::INFO(v))
-                    }
-                    CocoJsonSection::CATEGORIES(_) => {
-                        let v = parse_serde_json_value(reader)?;
-                        Ok(CocoJsonSection::CATEGORIES(v))
-                    }
-                    CocoJsonSection::IMAGES(_) => {
-                        let v = ImgPageMap::from_reader(reader)?;
-                        Ok(CocoJsonSection::IMAGES(v))
-                    }
-                    CocoJsonSection::ANNOTATIONS(_) => {
-                        let v = AnnPageMap::from_reader(reader)?;
-                        Ok(CocoJsonSection::ANNOTATIONS(v))
-                    }
-                }
+        match kind {
+            CocoSectionKind::Licenses => {
+                let v = LazySection::from_reader(reader, options)?;
+                Ok(CocoJsonSection::LICENSES(v))
             }
-            Err(e) => {
-                let cur_pos = reader.stream_position()?;
-                let msg = format!("Unknown key: {} at pos: {}", e, cur_pos);
-                Err(invalid_data(msg.as_str()))
+            CocoSectionKind::Info => {
+                let v = LazySection::from_reader(reader, options)?;
+                Ok(CocoJsonSection::INFO(v))
+            }
+            CocoSectionKind::Categories => {
+                let v = CategoriesPageMap::from_reader(reader, options)?;
+                Ok(CocoJsonSection::CATEGORIES(v))
+            }
+            CocoSectionKind::Images => {
+                let mut v = std::mem::take(&mut spare.images);
+                v.rebuild(reader, options)?;
+                Ok(CocoJsonSection::IMAGES(v))
+            }
+            CocoSectionKind::Annotations => {
+                let mut v = std::mem::take(&mut spare.annotations);
+                v.rebuild(reader, options)?;
+                Ok(CocoJsonSection::ANNOTATIONS(v))
             }
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        env::temp_dir,
-        fs::{File, OpenOptions},
-        io::{BufReader, Write},
-    };
+#[derive(Debug, Clone)]
+pub struct CocoPageMapperBuilder {
+    scan_options: ScanOptions,
+    start_offset: Option<u64>,
+    max_decompressed_bytes: Option<u64>,
+    on_progress: Option<SharedCallback>,
+    progress_interval: u64,
+    profiling: bool,
+}
 
-    use super::*;
+impl Default for CocoPageMapperBuilder {
+    fn default() -> Self {
+        Self {
+            scan_options: ScanOptions::default(),
+            start_offset: None,
+            max_decompressed_bytes: Some(DEFAULT_MAX_DECOMPRESSED_BYTES),
+            on_progress: None,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            profiling: false,
+        }
+    }
+}
 
-    fn prepare(example: &str) -> (BufReader<File>, CocoPageMapper) {
-        let filepath = temp_dir().join("tmp.json");
-
-        let mut f = OpenOptions::new()
-            .read(false)
-            .write(true)
-            .create(true)
-            .open(&filepath)
-            .expect("cannot open file");
-        let _ = f.write_all(example.as_bytes());
-        let f = File::open(&filepath).expect("cannot open file");
-        let mut reader = BufReader::new(f);
-        let coco_page_mapper = CocoPageMapper::new(&mut reader).unwrap();
+impl CocoPageMapperBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept trailing commas before a closing `]` or `}`, as found in hand-edited files.
+    ///
+    /// This is slower than the default parsing: every record is buffered and
+    /// scanned once more to strip the commas before it is handed to `serde_json`,
+    /// both while indexing and on every `get_item_dict`/`get_anns_dict` call.
+    pub fn allow_trailing_commas(mut self, value: bool) -> Self {
+        self.scan_options.allow_trailing_commas = value;
+        self
+    }
+
+    /// Keep the id, category, area and crowd flag of every annotation in
+    /// memory, about 24 bytes each, so that `ann_entries` can answer filtered
+    /// queries without reading the file.
+    pub fn rich_index(mut self, value: bool) -> Self {
+        self.scan_options.rich_index = value;
+        self
+    }
+
+    /// Keep the annotation offsets as delta encoded varints, about 3 bytes
+    /// instead of 16 per annotation, at the cost of decoding them on access.
+    pub fn compact_index(mut self, value: bool) -> Self {
+        self.scan_options.compact_index = value;
+        self
+    }
+
+    /// Skip annotation objects which are malformed or lack an integer
+    /// `image_id` instead of failing, see `malformed_ann_positions`. Scanning
+    /// resumes after the closing bracket of the skipped object.
+    pub fn skip_malformed_anns(mut self, value: bool) -> Self {
+        self.scan_options.skip_malformed_anns = value;
+        self
+    }
+
+    /// Skip image objects which are malformed or lack a valid `id` instead of
+    /// failing, see `skipped_image_count`.
+    pub fn skip_malformed_images(mut self, value: bool) -> Self {
+        self.scan_options.skip_malformed_images = value;
+        self
+    }
+
+    /// Skip building the annotation index when only the images are needed.
+    /// The annotations are still checked to be well formed, but reading them
+    /// fails with an "annotation index disabled" error.
+    pub fn index_annotations(mut self, value: bool) -> Self {
+        self.scan_options.skip_annotations = !value;
+        self
+    }
+
+    /// Fetch records with a single read if they are at most this many bytes
+    /// apart, e.g. the annotations of an image. Larger gaps waste fewer bytes
+    /// per seek saved. Defaults to `MAX_COALESCE_GAP`.
+    pub fn coalesce_gap(mut self, value: u64) -> Self {
+        self.scan_options.io_tuning.coalesce_gap_bytes = value;
+        self
+    }
+
+    /// All the read sizes at once, see `IoTuning`.
+    pub fn io_tuning(mut self, value: IoTuning) -> Self {
+        self.scan_options.io_tuning = value;
+        self
+    }
+
+    /// Parse the images and annotations on a thread pool: the array elements
+    /// are delimited by bracket counting first, then parsed in parallel and
+    /// indexed in file order, which gives the same index as a sequential scan.
+    #[cfg(feature = "rayon")]
+    pub fn parallel(mut self, value: bool) -> Self {
+        self.scan_options.parallel_threads = match value {
+            true => Some(self.scan_options.parallel_threads.unwrap_or(0)),
+            false => None,
+        };
+        self
+    }
+
+    /// Threads of the parallel scan, all cores for 0. Implies `parallel(true)`.
+    #[cfg(feature = "rayon")]
+    pub fn num_threads(mut self, threads: usize) -> Self {
+        self.scan_options.parallel_threads = Some(threads);
+        self
+    }
+
+    /// Calls `callback` while scanning, every `progress_interval` bytes, when
+    /// a section starts, and once more with the section `"done"` at the end.
+    pub fn on_progress(mut self, callback: ProgressCallback) -> Self {
+        self.on_progress = Some(SharedCallback(Rc::new(RefCell::new(callback))));
+        self
+    }
+
+    /// Bytes between two calls of the `on_progress` callback, defaults to
+    /// `DEFAULT_PROGRESS_INTERVAL`.
+    pub fn progress_interval(mut self, bytes: u64) -> Self {
+        self.progress_interval = bytes;
+        self
+    }
+
+    /// Record the time and bytes read of the construction phases and of the
+    /// `get_item_dict`/`get_anns_dict` calls, see `CocoPageMapper::profile`.
+    pub fn profiling(mut self, value: bool) -> Self {
+        self.profiling = value;
+        self
+    }
+
+    /// Fail if an annotation refers to an image id missing from the `images`
+    /// section, e.g. one skipped with `skip_malformed_images`.
+    pub fn reject_orphan_anns(mut self, value: bool) -> Self {
+        self.scan_options.reject_orphan_anns = value;
+        self
+    }
+
+    /// Fail with `CocoError::RecordTooLarge` on image, annotation and category
+    /// records longer than `value` bytes, or skip them with
+    /// `skip_malformed_images` and `skip_malformed_anns`. Defaults to
+    /// `DEFAULT_MAX_RECORD_BYTES`, and values above `u32::MAX` are lowered to
+    /// it.
+    pub fn max_record_bytes(mut self, value: u64) -> Self {
+        self.scan_options.max_record_bytes = Some(value);
+        self
+    }
+
+    /// Fail with `CocoError::RecordTooLarge` on a `licenses`, `info` or
+    /// `categories` section longer than `value` bytes. Defaults to
+    /// `DEFAULT_MAX_SECTION_BYTES`.
+    pub fn max_section_bytes(mut self, value: u64) -> Self {
+        self.scan_options.max_section_bytes = Some(value);
+        self
+    }
+
+    /// Fail with `CocoError::MixedIdTypes` instead of only reporting it in
+    /// `CocoPageMapper::mixed_id_types`.
+    pub fn reject_mixed_id_types(mut self, value: bool) -> Self {
+        self.scan_options.reject_mixed_id_types = value;
+        self
+    }
+
+    /// Check after the scan that every annotation offset points at an object,
+    /// which costs a seek per annotation.
+    pub fn verify_offsets(mut self, value: bool) -> Self {
+        self.scan_options.verify_offsets = value;
+        self
+    }
+
+    /// Index only the images with these ids and their annotations, e.g. for
+    /// the shard of a worker. Other images are reported missing on lookup.
+    pub fn restrict_to_img_ids(mut self, img_ids: HashSet<i64>) -> Self {
+        self.scan_options.restrict_to_img_ids = Some(img_ids);
+        self
+    }
+
+    /// Assign ids to image objects lacking an `id` from their position in the
+    /// `images` array, starting at 0 unless changed with `synthesized_id_start`.
+    ///
+    /// Annotations are matched by comparing their `image_id` with the synthesized
+    /// id as is, so this only works if the annotations refer to images by array
+    /// position using the same base. Nothing in the file says which base is
+    /// intended, hence it is disabled by default. The affected ids are reported by
+    /// `CocoPageMapper::synthesized_img_ids` and as warnings by `validate`.
+    pub fn synthesize_missing_ids(mut self, value: bool) -> Self {
+        self.scan_options.synthesize_missing_ids = match value {
+            true => Some(self.scan_options.synthesize_missing_ids.unwrap_or(0)),
+            false => None,
+        };
+        self
+    }
+
+    pub fn synthesized_id_start(mut self, start: i64) -> Self {
+        self.scan_options.synthesize_missing_ids = Some(start);
+        self
+    }
+
+    /// Index the document starting `value` bytes into the reader instead of
+    /// at its current position, e.g. a COCO file embedded in a larger one.
+    /// The recorded offsets are absolute, so lookups take the same reader, and
+    /// whatever follows the document is not read.
+    pub fn start_offset(mut self, value: u64) -> Self {
+        self.start_offset = Some(value);
+        self
+    }
+
+    /// Abort `build_from_path` once a compressed file decompresses to more than
+    /// this many bytes. Defaults to 8 GiB, `None` removes the limit.
+    pub fn max_decompressed_bytes(mut self, value: Option<u64>) -> Self {
+        self.max_decompressed_bytes = value;
+        self
+    }
+
+    pub fn build(&self, reader: impl Read + Seek) -> Result<CocoPageMapper, io::Error> {
+        self.build_with_profile(reader).map(|(mapper, _)| mapper)
+    }
+
+    /// See `CocoPageMapper::new_with_profile`.
+    pub fn build_with_profile(
+        &self,
+        mut reader: impl Read + Seek,
+    ) -> Result<(CocoPageMapper, OpenProfile), io::Error> {
+        let started = Instant::now();
+        let start = match self.start_offset {
+            Some(offset) => reader.seek(io::SeekFrom::Start(offset))?,
+            None => reader.stream_position()?,
+        };
+        let mut options = Cow::Borrowed(&self.scan_options);
+        if let Some(callback) = &self.on_progress {
+            let total_bytes = reader
+                .seek(io::SeekFrom::End(0))
+                .ok()
+                .map(|end| end.saturating_sub(start));
+            reader.seek(io::SeekFrom::Start(start))?;
+            options.to_mut().progress = Some(ProgressHook::new(
+                callback.clone(),
+                self.progress_interval,
+                start,
+                total_bytes,
+            ));
+        }
+
+        let mut profile = OpenProfile::default();
+        let mut mapper = CocoPageMapper::from_reader_with_options(
+            reader,
+            start,
+            &options,
+            &mut SpareMaps::default(),
+            &mut profile,
+        )?;
+        if self.profiling {
+            let report = ProfileReport::from_open_profile(&profile, started.elapsed());
+            mapper.profiling = Some(Profiling::new(report));
+        }
+        Ok((mapper, profile))
+    }
+
+    pub fn build_from_path(
+        &self,
+        path: &Path,
+    ) -> Result<(CocoPageMapper, BufReader<File>), io::Error> {
+        let (mut reader, _) = open_path(path, self.max_decompressed_bytes)?;
+        let mapper = self.build(&mut reader)?;
+        Ok((mapper, reader))
+    }
 
-        (reader, coco_page_mapper)
+    pub fn build_from_bgzip<R: Read + Seek>(
+        &self,
+        data: R,
+        index: impl Read,
+    ) -> Result<(CocoPageMapper, BgzfReader<R>), io::Error> {
+        let mut reader = BgzfReader::new(data, index)?;
+        let mapper = self.build(&mut reader)?;
+        Ok((mapper, reader))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{coco_error, CocoError};
+    use crate::test_utils::{allocations, prepare, write_example, CountingReader, EXAMPLE};
+    use crate::testing::{AnnsPerImage, CocoGenerator, SegmentationStyle};
 
     #[test]
     fn test_instance() {
-        const EXAMPLE: &str = r#"
-        {
-            "licenses":[{"name":"","id":0,"url":""}],
-            "info":{"contributor":"","date_created":"","description":"","url":"","version":"","year":""},
-            "categories":[
-                {"id":1,"name":"a","supercategory":""},
-                {"id":2,"name":"b","supercategory":""},
-                {"id":4,"name":"c","supercategory":""}
-            ],
-            "images":[
-                {"id":5,"width":10,"height":5,"file_name":"a.jpg","license":0,"flickr_url":"","coco_url":"","date_captured":0},
-                {"id":6,"width":10,"height":5,"file_name":"b.jpg","license":0,"flickr_url":"","coco_url":"","date_captured":0}
-            ],
-            "annotations":[
-                {"id":1,"image_id":5,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0},
-                {"id":2,"image_id":5,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0},
-                {"id":3,"image_id":5,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0},
-                {"id":4,"image_id":6,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0},
-                {"id":5,"image_id":6,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0}
-            ]
-        }"#;
-
         let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
 
         println!("{:?}", coco_page_mapper);
@@ -264,7 +1581,7 @@ mod tests {
             assert_eq!(item["id"].as_i64(), Some(img_id));
 
             let anns = coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap();
-            assert!(anns.len() > 0);
+            assert!(!anns.is_empty());
 
             for ann in anns {
                 assert_eq!(ann["image_id"].as_i64(), Some(img_id));
@@ -278,7 +1595,7 @@ mod tests {
         {"licenses": [{"name": "", "id": 0, "url": ""}], "info": {"contributor": "", "date_created": "", "description": "", "url": "", "version": "", "year": ""}, "categories": [], "images": [{"id": 1, "width": 2, "height": 4, "file_name": "1.jpg", "license": 0, "flickr_url": "", "coco_url": "", "date_captured": 0}], "annotations": []}
         "#;
 
-        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let (_, coco_page_mapper) = prepare(EXAMPLE);
 
         println!("{:?}", coco_page_mapper);
     }
@@ -292,5 +1609,2225 @@ mod tests {
         let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
 
         println!("{:?}", coco_page_mapper);
+
+        assert_eq!(coco_page_mapper.used_category_ids(), HashSet::from([5]));
+
+        let segment = coco_page_mapper.get_segment(1, 3, &mut reader).unwrap();
+        assert_eq!(
+            segment,
+            SegmentInfo {
+                id: 3,
+                category_id: 5,
+                area: Some(5.0),
+                bbox: Some([1.0, 0.0, 2.0, 2.0]),
+                iscrowd: Some(0),
+            }
+        );
+        assert!(coco_page_mapper.get_segment(1, 4, &mut reader).is_err());
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let CocoParts {
+            licenses,
+            info,
+            categories,
+            images,
+            annotations,
+        } = coco_page_mapper.into_parts(&mut reader).unwrap();
+
+        assert_eq!(licenses.as_array().map(|v| v.len()), Some(1));
+        assert!(info.is_object());
+        assert_eq!(categories.as_array().map(|v| v.len()), Some(3));
+        assert_eq!(images.len(), 2);
+        assert_eq!(images.ids(), &vec![5, 6]);
+        assert_eq!(annotations.len(), 5);
+    }
+
+    #[test]
+    fn test_find_duplicate_anns() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let mut groups = coco_page_mapper
+            .find_duplicate_anns(&mut reader, 0.9)
+            .unwrap();
+        for group in groups.iter_mut() {
+            group.ann_ids.sort();
+        }
+
+        assert_eq!(
+            groups,
+            vec![
+                DuplicateGroup {
+                    img_id: 5,
+                    ann_ids: vec![1, 2, 3]
+                },
+                DuplicateGroup {
+                    img_id: 6,
+                    ann_ids: vec![4, 5]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_allow_trailing_commas() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[{"name":"","id":0,"url":""},],
+            "info":{"contributor":"","date_created":"","description":"","url":"","version":"","year":"",},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[
+                {"id":5,"width":10,"height":5,"file_name":"a.jpg"},
+            ],
+            "annotations":[
+                {"id":1,"image_id":5,"category_id":1,"bbox":[2.0,2.0,3.0,1.0],},
+                {"id":2,"image_id":5,"category_id":1,"bbox":[2.0,2.0,3.0,1.0,]},
+            ]
+        }"#;
+
+        let filepath = write_example(EXAMPLE);
+        let mut reader = BufReader::new(File::open(filepath).unwrap());
+
+        assert!(CocoPageMapper::new(&mut reader).is_err());
+
+        reader.rewind().unwrap();
+        let coco_page_mapper = CocoPageMapper::builder()
+            .allow_trailing_commas(true)
+            .build(&mut reader)
+            .unwrap();
+
+        assert_eq!(
+            coco_page_mapper
+                .licenses(&mut reader)
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5]);
+
+        let item = coco_page_mapper.get_item_dict(5, &mut reader).unwrap();
+        assert_eq!(item["file_name"], "a.jpg");
+
+        let anns = coco_page_mapper.get_anns_dict(5, &mut reader).unwrap();
+        assert_eq!(anns.len(), 2);
+        for ann in anns {
+            assert_eq!(ann["bbox"].as_array().unwrap().len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_synthesize_missing_ids() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[
+                {"width":10,"height":5,"file_name":"a.jpg"},
+                {"width":10,"height":5,"file_name":"b.jpg"}
+            ],
+            "annotations":[
+                {"id":1,"image_id":1,"category_id":1,"bbox":[2.0,2.0,3.0,1.0]}
+            ]
+        }"#;
+
+        let filepath = write_example(EXAMPLE);
+        let mut reader = BufReader::new(File::open(filepath).unwrap());
+
+        assert!(CocoPageMapper::new(&mut reader).is_err());
+
+        reader.rewind().unwrap();
+        let coco_page_mapper = CocoPageMapper::builder()
+            .synthesize_missing_ids(true)
+            .build(&mut reader)
+            .unwrap();
+
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![0, 1]);
+        assert_eq!(coco_page_mapper.synthesized_img_ids(), &vec![0, 1]);
+
+        let item = coco_page_mapper.get_item_dict(1, &mut reader).unwrap();
+        assert_eq!(item["file_name"], "b.jpg");
+        assert_eq!(item["id"], 1);
+        assert_eq!(coco_page_mapper.get_ann_count(1), 1);
+
+        reader.rewind().unwrap();
+        let coco_page_mapper = CocoPageMapper::builder()
+            .synthesize_missing_ids(true)
+            .synthesized_id_start(1)
+            .build(&mut reader)
+            .unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![1, 2]);
+        let item = coco_page_mapper.get_item_dict(1, &mut reader).unwrap();
+        assert_eq!(item["file_name"], "a.jpg");
+    }
+
+    #[test]
+    fn test_get_anns_dedup() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""},{"id":2,"name":"b","supercategory":""}],
+            "images":[{"id":5,"width":10,"height":5,"file_name":"a.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":5,"category_id":2,"bbox":[2.0,2.0,3.0,1.0]},
+                {"id":2,"image_id":5,"category_id":2,"bbox":[2.0,2.0,3.0,1.0000001]},
+                {"id":3,"image_id":5,"category_id":1,"bbox":[2.0,2.0,3.0,1.0]}
+            ]
+        }"#;
+
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let (anns, removed) = coco_page_mapper.get_anns_dedup(5, &mut reader).unwrap();
+
+        assert_eq!(removed, 1);
+        assert_eq!(anns.len(), 2);
+        let categories: HashSet<i64> = anns
+            .iter()
+            .map(|v| v["category_id"].as_i64().unwrap())
+            .collect();
+        assert_eq!(categories, HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn test_field_parse_error_context() {
+        let mut reader = Cursor::new(EXAMPLE.as_bytes().to_vec());
+        let coco_page_mapper = CocoPageMapper::new(&mut reader).unwrap();
+
+        // Corrupt the second annotation after indexing, as if the file changed on disk.
+        let pos = EXAMPLE.find(r#"{"id":2,"image_id":5"#).unwrap();
+        reader.get_mut()[pos + 1] = 0xff;
+
+        let err = coco_page_mapper.get_anns_dict(5, &mut reader).unwrap_err();
+
+        match coco_error(&err) {
+            Some(CocoError::FieldParse {
+                img_id,
+                ann_id,
+                pos: err_pos,
+                ..
+            }) => {
+                assert_eq!(*img_id, 5);
+                assert_eq!(*ann_id, Some(2));
+                assert!(*err_pos <= pos as u64 && *err_pos > pos as u64 - 20);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+        assert!(err.to_string().contains("annotation id: Some(2)"));
+
+        assert!(coco_page_mapper.get_item_dict(5, &mut reader).is_ok());
+    }
+
+    #[test]
+    fn test_get_bbox_tensor() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[{"id":5,"width":10,"height":5,"file_name":"a.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":5,"category_id":1,"bbox":[1.0,2.0,3.0,4.0],"segmentation":[[0,0,1,1,2,2]]},
+                {"id":2,"image_id":5,"category_id":1,"segmentation":{"size":[5,10],"counts":[5,45]}},
+                {"id":3,"image_id":5,"category_id":1,"bbox":[5.0,6.0,7.0,8.0]}
+            ]
+        }"#;
+
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let (boxes, labels) = coco_page_mapper.get_bbox_tensor(5, &mut reader).unwrap();
+        assert_eq!(boxes.len(), 4 * labels.len());
+        assert_eq!(labels, vec![1, 1]);
+        assert_eq!(coco_page_mapper.get_ann_count(5) - labels.len(), 1);
+
+        let (mut reader, coco_page_mapper) = prepare(crate::test_utils::EXAMPLE);
+        let (boxes, labels) = coco_page_mapper.get_bbox_tensor(5, &mut reader).unwrap();
+        assert_eq!(boxes.len(), 4 * 3);
+        assert_eq!(&boxes[..4], &[2.0, 2.0, 3.0, 1.0]);
+        assert_eq!(labels, vec![2, 2, 2]);
+    }
+
+    #[test]
+    fn test_anns_in_file_order() {
+        let filepath = write_example(EXAMPLE);
+
+        for _ in 0..3 {
+            let mut reader = BufReader::new(File::open(&filepath).unwrap());
+            let coco_page_mapper = CocoPageMapper::new(&mut reader).unwrap();
+
+            for (img_id, expected) in [(5, vec![1, 2, 3]), (6, vec![4, 5])] {
+                let anns = coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap();
+                let ids: Vec<i64> = anns.iter().map(|v| v["id"].as_i64().unwrap()).collect();
+                assert_eq!(ids, expected);
+
+                let batch = coco_page_mapper
+                    .get_anns_batch(&[img_id], &mut reader)
+                    .unwrap();
+                assert_eq!(batch[0], anns);
+            }
+        }
+    }
+
+    #[test]
+    fn test_info_typed() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let info = coco_page_mapper.info_typed(&mut reader).unwrap();
+        assert_eq!(info.description.as_deref(), Some(""));
+        assert_eq!(info.year, None);
+
+        let info = CocoInfo::from_json(&serde_json::json!({
+            "description": "COCO 2017 Dataset",
+            "version": 1.0,
+            "year": "2017",
+            "extra": {"tool": "x"}
+        }));
+        assert_eq!(info.description.as_deref(), Some("COCO 2017 Dataset"));
+        assert_eq!(info.version.as_deref(), Some("1.0"));
+        assert_eq!(info.year, Some(2017));
+        assert_eq!(info.contributor, None);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let coco_page_mapper = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 6]);
+        let item = coco_page_mapper
+            .get_item_dict(6, Cursor::new(EXAMPLE.as_bytes()))
+            .unwrap();
+        assert_eq!(item["file_name"], "b.jpg");
+    }
+
+    #[test]
+    fn test_embedded_document() {
+        let mut container = "#".repeat(1000);
+        container.push_str(EXAMPLE);
+        container.push_str("\ntrailing bytes of the container");
+        let filepath = write_example(&container);
+        let plain = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+        let expected = |img_id| {
+            let mut reader = Cursor::new(EXAMPLE.as_bytes());
+            let item = plain.get_item_dict(img_id, &mut reader).unwrap();
+            (item, plain.get_anns_dict(img_id, &mut reader).unwrap())
+        };
+
+        let mut reader = CountingReader::new(BufReader::new(File::open(&filepath).unwrap()));
+        let mapper = CocoPageMapper::builder()
+            .start_offset(1000)
+            .build(&mut reader)
+            .unwrap();
+        // A single seek to the document, the offsets are counted from there.
+        assert_eq!(reader.seeks, 1);
+        for img_id in [5, 6] {
+            let item = mapper.get_item_dict(img_id, &mut reader).unwrap();
+            let anns = mapper.get_anns_dict(img_id, &mut reader).unwrap();
+            assert_eq!((item, anns), expected(img_id));
+        }
+
+        let mut reader = BufReader::new(File::open(&filepath).unwrap());
+        reader.seek(io::SeekFrom::Start(1000)).unwrap();
+        let mapper = CocoPageMapper::new(&mut reader).unwrap();
+        let anns = mapper.get_anns_dict(6, &mut reader).unwrap();
+        assert_eq!(anns, expected(6).1);
+
+        let malformed = container.replacen(r#""id":4,"image_id""#, r#""id":x,"image_id""#, 1);
+        let pos = malformed.find(r#""id":x"#).unwrap() + 5;
+        let err = CocoPageMapper::builder()
+            .start_offset(1000)
+            .build(Cursor::new(malformed.as_bytes()))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains(&format!("offset={}", pos)),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_iter_all_anns() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let anns: Vec<JsonDict> = coco_page_mapper
+            .iter_all_anns(&mut reader)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(anns.len(), coco_page_mapper.annotation_count());
+        let ids: Vec<i64> = anns.iter().map(|v| v["id"].as_i64().unwrap()).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_interned_anns() {
+        let bytes = CocoGenerator::new(4)
+            .images(30)
+            .anns_per_image(AnnsPerImage::Uniform { min: 0, max: 6 })
+            .segmentation(SegmentationStyle::Rle)
+            .to_bytes();
+        let mapper = CocoPageMapper::from_bytes(&bytes).unwrap();
+
+        let mut interner = StringInterner::new();
+        let interned: Vec<JsonDict> = mapper
+            .iter_all_anns_interned(Cursor::new(&bytes), &mut interner)
+            .map(|v| v.unwrap().to_json())
+            .collect();
+        let plain: Vec<JsonDict> = mapper
+            .iter_all_anns(Cursor::new(&bytes))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(interned, plain);
+        assert_eq!(interner.len(), 9);
+
+        for img_id in mapper.get_img_ids() {
+            let anns = mapper
+                .get_anns_interned(*img_id, Cursor::new(&bytes), &mut interner)
+                .unwrap();
+            let anns: Vec<JsonDict> = anns.iter().map(InternedValue::to_json).collect();
+            assert_eq!(
+                anns,
+                mapper.get_anns_dict(*img_id, Cursor::new(&bytes)).unwrap()
+            );
+        }
+        assert_eq!(interner.len(), 9);
+
+        let memory = mapper.anns_interning_memory(Cursor::new(&bytes)).unwrap();
+        assert_eq!(memory.keys, interner.heap_bytes());
+        assert!(memory.interned < memory.plain, "{:?}", memory);
+    }
+
+    #[test]
+    fn test_used_category_ids() {
+        let (_, coco_page_mapper) = prepare(EXAMPLE);
+
+        assert_eq!(coco_page_mapper.used_category_ids(), HashSet::from([2]));
+        assert_eq!(
+            coco_page_mapper.unused_category_ids().unwrap(),
+            HashSet::from([1, 4])
+        );
+    }
+
+    #[test]
+    fn test_seg_kind() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[{"id":5,"width":10,"height":5,"file_name":"a.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":5,"category_id":1,"segmentation":[]},
+                {"id":2,"image_id":5,"category_id":1,"segmentation":[[0,0,1,1,2,2]]},
+                {"id":3,"image_id":5,"category_id":1,"segmentation":{"size":[5,10],"counts":[5,45]}},
+                {"id":4,"image_id":5,"category_id":1,"segmentation":{"size":[5,10],"counts":"52203"}},
+                {"id":5,"image_id":5,"category_id":1,"segmentation":[[1,1,2,2,3,3]]}
+            ]
+        }"#;
+
+        let (_, coco_page_mapper) = prepare(EXAMPLE);
+
+        assert_eq!(coco_page_mapper.ann_seg_kind(1), Some(SegKind::None));
+        assert_eq!(coco_page_mapper.ann_seg_kind(2), Some(SegKind::Polygon));
+        assert_eq!(coco_page_mapper.ann_seg_kind(3), Some(SegKind::Rle));
+        assert_eq!(
+            coco_page_mapper.ann_seg_kind(4),
+            Some(SegKind::CompressedRle)
+        );
+        assert_eq!(coco_page_mapper.ann_seg_kind(6), None);
+        assert_eq!(
+            coco_page_mapper.seg_kind_counts(),
+            HashMap::from([
+                (SegKind::None, 1),
+                (SegKind::Polygon, 2),
+                (SegKind::Rle, 1),
+                (SegKind::CompressedRle, 1)
+            ])
+        );
+    }
+
+    fn generate_example(n_images: i64) -> String {
+        let images: Vec<String> = (0..n_images)
+            .map(|id| {
+                format!(
+                    r#"{{"id":{},"width":4,"height":4,"file_name":"{}.jpg"}}"#,
+                    id, id
+                )
+            })
+            .collect();
+        let anns: Vec<String> = (0..3 * n_images)
+            .map(|id| {
+                format!(
+                    r#"{{"id":{},"image_id":{},"category_id":1,"bbox":[0,0,1,1],"area":1}}"#,
+                    id,
+                    id / 3
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"licenses":[],"info":{{}},"categories":[{{"id":1,"name":"a"}}],"images":[{}],"annotations":[{}]}}"#,
+            images.join(","),
+            anns.join(",")
+        )
+    }
+
+    #[test]
+    fn test_index_memory_bytes() {
+        let coco_page_mapper = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+        let memory = coco_page_mapper.index_memory_bytes();
+        assert!(memory.images > 0 && memory.annotations > 0 && memory.sections > 0);
+        assert_eq!(
+            memory.total,
+            memory.images + memory.annotations + memory.sections
+        );
+
+        let small = CocoPageMapper::from_bytes(generate_example(10).as_bytes()).unwrap();
+        let mut large = CocoPageMapper::from_bytes(generate_example(1000).as_bytes()).unwrap();
+        let before = large.index_memory_bytes();
+        assert!(before.total > small.index_memory_bytes().total);
+
+        large.shrink_to_fit();
+        let after = large.index_memory_bytes();
+        assert!(after.total <= before.total);
+        assert!(after.images <= before.images && after.annotations <= before.annotations);
+        assert_eq!(large.get_img_ids().len(), 1000);
+    }
+
+    #[test]
+    fn test_rich_index_memory() {
+        let data = generate_example(100);
+        let plain = CocoPageMapper::from_bytes(data.as_bytes()).unwrap();
+        let rich = CocoPageMapper::builder()
+            .rich_index(true)
+            .build(Cursor::new(data.as_bytes()))
+            .unwrap();
+        assert!(rich.index_memory_bytes().annotations > plain.index_memory_bytes().annotations);
+    }
+
+    #[test]
+    fn test_union_bbox() {
+        const BOXES: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[{"id":5,"width":100,"height":100,"file_name":"5.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":5,"category_id":1,"bbox":[10,20,30,5]},
+                {"id":2,"image_id":5,"category_id":1,"bbox":[25.5,5,10,10]},
+                {"id":3,"image_id":5,"category_id":1,"segmentation":[[0,0,1,0,1,1]]}
+            ]
+        }"#;
+        let (mut reader, coco_page_mapper) = prepare(BOXES);
+
+        assert_eq!(
+            coco_page_mapper.union_bbox(&[1, 2], &mut reader).unwrap(),
+            Some([10.0, 5.0, 30.0, 20.0])
+        );
+        assert_eq!(
+            coco_page_mapper.union_bbox(&[2, 3], &mut reader).unwrap(),
+            Some([25.5, 5.0, 10.0, 10.0])
+        );
+        assert_eq!(
+            coco_page_mapper.union_bbox(&[3], &mut reader).unwrap(),
+            None
+        );
+        assert_eq!(coco_page_mapper.union_bbox(&[], &mut reader).unwrap(), None);
+        assert!(coco_page_mapper.union_bbox(&[1, 42], &mut reader).is_err());
+    }
+
+    #[test]
+    fn test_compact_index() {
+        let n_images = 1000;
+        let images: Vec<String> = (0..n_images)
+            .map(|id| format!(r#"{{"id":{},"file_name":"{}.jpg"}}"#, id, id))
+            .collect();
+        // Images interleave so that their annotations are spread over the file.
+        let anns: Vec<String> = (0..100_000)
+            .map(|id| {
+                format!(
+                    r#"{{"id":{},"image_id":{},"category_id":1,"bbox":[0,0,{},1]}}"#,
+                    id,
+                    (id * 7) % n_images,
+                    id % 97
+                )
+            })
+            .collect();
+        let data = format!(
+            r#"{{"licenses":[],"info":{{}},"categories":[{{"id":1,"name":"a"}}],"images":[{}],"annotations":[
+{}
+]}}"#,
+            images.join(","),
+            anns.join(",\n")
+        );
+
+        let mut reader = Cursor::new(data.as_bytes());
+        let plain = CocoPageMapper::from_bytes(data.as_bytes()).unwrap();
+        let compact = CocoPageMapper::builder()
+            .compact_index(true)
+            .build(&mut reader)
+            .unwrap();
+
+        for img_id in [0, 1, 499, 999] {
+            assert_eq!(
+                compact.get_anns_dict(img_id, &mut reader).unwrap(),
+                plain.get_anns_dict(img_id, &mut reader).unwrap()
+            );
+        }
+        let img_ids: Vec<i64> = (0..n_images).step_by(37).collect();
+        assert_eq!(
+            compact.get_anns_batch(&img_ids, &mut reader).unwrap(),
+            plain.get_anns_batch(&img_ids, &mut reader).unwrap()
+        );
+
+        let (plain_memory, compact_memory) =
+            (plain.index_memory_bytes(), compact.index_memory_bytes());
+        assert!(compact_memory.ann_offsets * 3 < plain_memory.ann_offsets);
+        assert!(compact_memory.annotations < plain_memory.annotations);
+
+        let (plain, compact) = (
+            plain.into_parts(&mut reader).unwrap(),
+            compact.into_parts(&mut reader).unwrap(),
+        );
+        assert!(plain
+            .annotations
+            .iter_offsets()
+            .eq(compact.annotations.iter_offsets()));
+        assert!(plain
+            .annotations
+            .offsets_for(7)
+            .eq(compact.annotations.offsets_for(7)));
+    }
+
+    #[test]
+    fn test_compact_index_uneven_anns() {
+        let data = CocoGenerator::new(11)
+            .images(2000)
+            .anns_per_image(AnnsPerImage::Uniform { min: 0, max: 40 })
+            .segmentation(SegmentationStyle::Polygon)
+            .to_bytes();
+        let mut reader = Cursor::new(data.as_slice());
+        let plain = CocoPageMapper::from_bytes(&data).unwrap();
+        let compact = CocoPageMapper::builder()
+            .compact_index(true)
+            .build(&mut reader)
+            .unwrap();
+
+        for img_ids in plain.get_img_ids().chunks(BATCH_SIZE) {
+            assert_eq!(
+                compact.get_anns_batch(img_ids, &mut reader).unwrap(),
+                plain.get_anns_batch(img_ids, &mut reader).unwrap()
+            );
+        }
+        assert!(compact.index_memory_bytes().ann_offsets < plain.index_memory_bytes().ann_offsets);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_scan_segmentations() {
+        for segmentation in [SegmentationStyle::Polygon, SegmentationStyle::Rle] {
+            let data = CocoGenerator::new(5)
+                .target_bytes(4 << 20)
+                .segmentation(segmentation)
+                .to_bytes();
+            let mut reader = Cursor::new(data.as_slice());
+            let sequential = CocoPageMapper::from_bytes(&data).unwrap();
+            let parallel = CocoPageMapper::builder()
+                .num_threads(4)
+                .build(&mut reader)
+                .unwrap();
+
+            assert_eq!(parallel.get_img_ids(), sequential.get_img_ids());
+            assert_eq!(parallel.annotation_flavor(), sequential.annotation_flavor());
+            let (parallel, sequential) = (
+                parallel.into_parts(&mut reader).unwrap(),
+                sequential.into_parts(&mut reader).unwrap(),
+            );
+            assert!(parallel
+                .annotations
+                .iter_offsets()
+                .eq(sequential.annotations.iter_offsets()));
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_scan() {
+        // 25k images and 75k annotations.
+        let example = generate_example(25_000);
+        let mut reader = io::Cursor::new(example.as_bytes());
+        for builder in [
+            CocoPageMapper::builder().parallel(true),
+            CocoPageMapper::builder().num_threads(3),
+        ] {
+            reader.rewind().unwrap();
+            let sequential = CocoPageMapper::new(&mut reader).unwrap();
+            reader.rewind().unwrap();
+            let parallel = builder.build(&mut reader).unwrap();
+            assert_eq!(parallel.get_img_ids(), sequential.get_img_ids());
+            assert_eq!(
+                parallel.get_anns_dict(17, &mut reader).unwrap(),
+                sequential.get_anns_dict(17, &mut reader).unwrap()
+            );
+
+            let (parallel, sequential) = (
+                parallel.into_parts(&mut reader).unwrap(),
+                sequential.into_parts(&mut reader).unwrap(),
+            );
+            for img_id in sequential.images.ids() {
+                let (a, b) = (
+                    parallel.images.get_page(img_id).unwrap(),
+                    sequential.images.get_page(img_id).unwrap(),
+                );
+                assert_eq!((a.offset, a.size), (b.offset, b.size));
+            }
+            assert!(parallel
+                .annotations
+                .iter_offsets()
+                .eq(sequential.annotations.iter_offsets()));
+        }
+    }
+
+    #[test]
+    fn test_skip_malformed_anns() {
+        const MALFORMED: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[{"id":1,"width":4,"height":4,"file_name":"1.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":1,"category_id":1,"bbox":[0,0,1,1]},
+                {"id":2,"image_id":1,"category_id":1,,"bbox":[0,0,1,1]},
+                {"id":3,"image_id":1,"category_id":1,"bbox":[0,0,1,1],"note":"}{ ,"},
+                {"id":4,"image_id":1,"iscrowd":tru,"bbox":[[0,0],"x"]},
+                {"id":5,"category_id":1},
+                {"id":6,"image_id":1,"category_id":1,"bbox":[0,0,1,1]}
+            ]
+        }"#;
+        assert!(CocoPageMapper::from_bytes(MALFORMED.as_bytes()).is_err());
+
+        let mut reader = Cursor::new(MALFORMED.as_bytes());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .skip_malformed_anns(true)
+            .build(&mut reader)
+            .unwrap();
+
+        let anns = coco_page_mapper.get_anns_dict(1, &mut reader).unwrap();
+        assert_eq!(
+            anns.iter()
+                .map(|v| v["id"].as_i64().unwrap())
+                .collect::<Vec<_>>(),
+            vec![1, 3, 6]
+        );
+        let expected: Vec<u64> = [r#"{"id":2,"#, r#"{"id":4,"#, r#"{"id":5,"#]
+            .iter()
+            .map(|v| MALFORMED.find(v).unwrap() as u64)
+            .collect();
+        assert_eq!(coco_page_mapper.malformed_ann_positions(), &expected);
+    }
+
+    #[test]
+    fn test_annotations_before_images() {
+        const EXAMPLE: &str = r#"{"annotations": [
+            {"id": 1, "image_id": 6, "category_id": 1},
+            {"id": 2, "image_id": 5, "category_id": 1},
+            {"id": 3, "image_id": 6, "category_id": 1}
+        ], "images": [{"id": 5}, {"id": 6}, {"id": 7}],
+        "licenses": [], "info": {}, "categories": []}"#;
+
+        let mut reader = io::Cursor::new(EXAMPLE.as_bytes());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .reject_orphan_anns(true)
+            .build(&mut reader)
+            .unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 6, 7]);
+        assert!(coco_page_mapper.orphan_ann_img_ids().is_empty());
+
+        let ann_ids = |img_id| -> Vec<i64> {
+            let mut reader = io::Cursor::new(EXAMPLE.as_bytes());
+            let anns = coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap();
+            anns.iter().map(|ann| ann["id"].as_i64().unwrap()).collect()
+        };
+        assert_eq!(ann_ids(5), [2]);
+        assert_eq!(ann_ids(6), [1, 3]);
+        assert!(ann_ids(7).is_empty());
+
+        let orphaned = EXAMPLE.replace("\"image_id\": 5", "\"image_id\": 9");
+        let coco_page_mapper = CocoPageMapper::from_bytes(orphaned.as_bytes()).unwrap();
+        assert_eq!(coco_page_mapper.orphan_ann_img_ids(), [9]);
+        let err = CocoPageMapper::builder()
+            .reject_orphan_anns(true)
+            .build(io::Cursor::new(orphaned.as_bytes()))
+            .unwrap_err();
+        assert!(err.to_string().contains("missing images: [9]"));
+    }
+
+    #[test]
+    fn test_mixed_id_types() {
+        const MIXED: &str = r#"{"licenses": [], "info": {}, "categories": [],
+        "images": [{"id": 5}, {"id": "6"}, {"id": 7}],
+        "annotations": [
+            {"id": 1, "image_id": 5},
+            {"id": 2, "image_id": "6"},
+            {"id": 3, "image_id": 7}
+        ]}"#;
+
+        let build = |reject| {
+            CocoPageMapper::builder()
+                .skip_malformed_images(true)
+                .skip_malformed_anns(true)
+                .reject_mixed_id_types(reject)
+                .build(io::Cursor::new(MIXED.as_bytes()))
+        };
+        let coco_page_mapper = build(false).unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 7]);
+        assert!(matches!(
+            coco_page_mapper.mixed_id_types(),
+            Some(CocoError::MixedIdTypes {
+                integers: 4,
+                strings: 2
+            })
+        ));
+
+        let err = build(true).unwrap_err();
+        assert!(matches!(
+            coco_error(&err),
+            Some(CocoError::MixedIdTypes { .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Mixed image id types: 4 integers and 2 strings."
+        );
+
+        let coco_page_mapper = CocoPageMapper::builder()
+            .reject_mixed_id_types(true)
+            .build(io::Cursor::new(EXAMPLE.as_bytes()))
+            .unwrap();
+        assert!(coco_page_mapper.mixed_id_types().is_none());
+    }
+
+    #[test]
+    fn test_get_item_dicts() {
+        let example = generate_example(100);
+        let coco_page_mapper = CocoPageMapper::from_bytes(example.as_bytes()).unwrap();
+        let mut reader = CountingReader::new(Cursor::new(example.as_bytes()));
+
+        let img_ids: Vec<i64> = (0..100).rev().collect();
+        let dicts = coco_page_mapper
+            .get_item_dicts(&img_ids, &mut reader)
+            .unwrap();
+        // The images are adjacent, so they are read at once.
+        assert_eq!((reader.reads, reader.seeks), (1, 1));
+        let ids: Vec<i64> = dicts.iter().map(|v| v["id"].as_i64().unwrap()).collect();
+        assert_eq!(ids, img_ids);
+        for (img_id, dict) in img_ids.iter().zip(&dicts) {
+            assert_eq!(
+                dict,
+                &coco_page_mapper
+                    .get_item_dict(*img_id, &mut reader)
+                    .unwrap()
+            );
+        }
+
+        let err = coco_page_mapper
+            .get_item_dicts(&[3, 100, 1], &mut reader)
+            .unwrap_err();
+        assert!(err.to_string().contains("Image id: 100"));
+    }
+
+    #[test]
+    fn test_lazy_sections() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        coco_page_mapper.get_item_dict(5, &mut reader).unwrap();
+        assert!(coco_page_mapper.parsed_sections().is_empty());
+
+        assert_eq!(
+            coco_page_mapper.info(&mut reader).unwrap()["contributor"],
+            ""
+        );
+        assert_eq!(coco_page_mapper.parsed_sections(), [CocoSectionKind::Info]);
+        assert_eq!(
+            coco_page_mapper.image_license(5, &mut reader).unwrap(),
+            Some(serde_json::json!({"name": "", "id": 0, "url": ""}))
+        );
+        assert_eq!(
+            coco_page_mapper.parsed_sections(),
+            [CocoSectionKind::Licenses, CocoSectionKind::Info]
+        );
+
+        // The sections are still checked while scanning.
+        let malformed = EXAMPLE.replace(r#""url":"","version""#, r#""url":"" "version""#);
+        let err = CocoPageMapper::from_bytes(malformed.as_bytes()).unwrap_err();
+        let offset = malformed.find(r#""version""#).unwrap();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("[Parse error, offset={}]", offset)));
+
+        let trailing = EXAMPLE.replace(r#""year":""}"#, r#""year":"",}"#);
+        assert!(CocoPageMapper::from_bytes(trailing.as_bytes()).is_err());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .allow_trailing_commas(true)
+            .build(Cursor::new(trailing.as_bytes()))
+            .unwrap();
+        assert!(coco_page_mapper.parsed_sections().is_empty());
+        let mut reader = Cursor::new(trailing.as_bytes());
+        assert_eq!(coco_page_mapper.info(&mut reader).unwrap()["year"], "");
+
+        // Lenient scanning still rejects what the parser would not read.
+        for info in [
+            r#""year":"",,}"#,
+            r#""year":"" "url":""}"#,
+            r#""year":[1,,]}"#,
+        ] {
+            let malformed = EXAMPLE.replace(r#""year":""}"#, info);
+            assert!(CocoPageMapper::builder()
+                .allow_trailing_commas(true)
+                .build(Cursor::new(malformed.as_bytes()))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_crlf_and_tabs() {
+        // No string of the example holds these characters.
+        let windows = EXAMPLE
+            .replace('\n', "\r\n")
+            .replace("    ", "\t")
+            .replace(',', "\t,\r\n\t")
+            .replace(':', "\r\n:\t")
+            .replace('[', "[\r\n")
+            .replace('{', "{\t");
+
+        let builders = [
+            CocoPageMapper::builder(),
+            CocoPageMapper::builder().allow_trailing_commas(true),
+            #[cfg(feature = "rayon")]
+            CocoPageMapper::builder().num_threads(2),
+        ];
+
+        let expected = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+        let mut expected_reader = Cursor::new(EXAMPLE.as_bytes());
+        for builder in builders {
+            let mut reader = Cursor::new(windows.as_bytes());
+            let coco_page_mapper = builder.build(&mut reader).unwrap();
+            assert_eq!(
+                coco_page_mapper.licenses(&mut reader).unwrap(),
+                expected.licenses(&mut expected_reader).unwrap()
+            );
+            assert_eq!(
+                coco_page_mapper.info(&mut reader).unwrap(),
+                expected.info(&mut expected_reader).unwrap()
+            );
+            assert_eq!(
+                coco_page_mapper.categories().unwrap(),
+                expected.categories().unwrap()
+            );
+            assert_eq!(coco_page_mapper.get_img_ids(), expected.get_img_ids());
+            for img_id in [5, 6] {
+                assert_eq!(
+                    coco_page_mapper.get_item_dict(img_id, &mut reader).unwrap(),
+                    expected
+                        .get_item_dict(img_id, &mut expected_reader)
+                        .unwrap()
+                );
+                assert_eq!(
+                    coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap(),
+                    expected
+                        .get_anns_dict(img_id, &mut expected_reader)
+                        .unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_tiny_io_tuning() {
+        let tiny = IoTuning {
+            scan_buffer_bytes: 16,
+            record_read_chunk: 16,
+            coalesce_gap_bytes: 0,
+        };
+        let generated = CocoGenerator::new(5)
+            .images(30)
+            .anns_per_image(AnnsPerImage::Uniform { min: 0, max: 4 })
+            .segmentation(SegmentationStyle::Polygon)
+            .to_bytes();
+
+        for data in [EXAMPLE.as_bytes(), &generated] {
+            let expected = CocoPageMapper::from_bytes(data).unwrap();
+            let img_ids = expected.get_img_ids().clone();
+            let anns = expected
+                .get_anns_batch(&img_ids, Cursor::new(data))
+                .unwrap();
+
+            let builders = [
+                CocoPageMapper::builder(),
+                CocoPageMapper::builder().compact_index(true),
+                #[cfg(feature = "rayon")]
+                CocoPageMapper::builder().num_threads(2),
+            ];
+            for builder in builders {
+                let mut reader = CountingReader::new(Cursor::new(data));
+                let coco_page_mapper = builder.io_tuning(tiny).build(&mut reader).unwrap();
+                assert_eq!(coco_page_mapper.io_tuning(), tiny);
+                assert_eq!(
+                    coco_page_mapper.licenses(&mut reader).unwrap(),
+                    expected.licenses(Cursor::new(data)).unwrap()
+                );
+                assert_eq!(
+                    coco_page_mapper.info(&mut reader).unwrap(),
+                    expected.info(Cursor::new(data)).unwrap()
+                );
+                assert_eq!(
+                    coco_page_mapper.categories().unwrap(),
+                    expected.categories().unwrap()
+                );
+                assert_eq!(coco_page_mapper.get_img_ids(), &img_ids);
+
+                for (img_id, anns) in img_ids.iter().zip(&anns) {
+                    assert_eq!(
+                        coco_page_mapper
+                            .get_item_dict(*img_id, &mut reader)
+                            .unwrap(),
+                        expected.get_item_dict(*img_id, Cursor::new(data)).unwrap()
+                    );
+                    assert_eq!(
+                        &coco_page_mapper
+                            .get_anns_dict(*img_id, &mut reader)
+                            .unwrap(),
+                        anns
+                    );
+                }
+                (reader.reads, reader.seeks) = (0, 0);
+                assert_eq!(
+                    coco_page_mapper
+                        .get_anns_batch(&img_ids, &mut reader)
+                        .unwrap(),
+                    anns
+                );
+                // Every record is read on its own.
+                let n_anns = anns.iter().map(Vec::len).sum::<usize>();
+                assert_eq!(reader.seeks, n_anns);
+                assert_eq!(
+                    coco_page_mapper
+                        .get_item_dicts(&img_ids, &mut reader)
+                        .unwrap(),
+                    expected
+                        .get_item_dicts(&img_ids, Cursor::new(data))
+                        .unwrap()
+                );
+            }
+        }
+
+        let (mut reader, mut coco_page_mapper) = prepare(EXAMPLE);
+        coco_page_mapper.set_io_tuning(tiny);
+        let mut reader = CountingReader::new(&mut reader);
+        coco_page_mapper.get_anns_dict(5, &mut reader).unwrap();
+        assert_eq!(reader.seeks, 3);
+    }
+
+    #[test]
+    fn test_clone_index() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let mut clone = coco_page_mapper.clone_index();
+
+        assert!(Arc::ptr_eq(&coco_page_mapper.images, &clone.images));
+        assert!(Arc::ptr_eq(
+            &coco_page_mapper.annotations,
+            &clone.annotations
+        ));
+        assert!(Arc::ptr_eq(&coco_page_mapper.categories, &clone.categories));
+        assert!(Arc::ptr_eq(&coco_page_mapper.licenses, &clone.licenses));
+
+        let mut clone_reader = io::Cursor::new(EXAMPLE.as_bytes());
+        for img_id in [5, 6] {
+            assert_eq!(
+                clone.get_item_dict(img_id, &mut clone_reader).unwrap(),
+                coco_page_mapper.get_item_dict(img_id, &mut reader).unwrap()
+            );
+            assert_eq!(
+                clone.get_anns_dict(img_id, &mut clone_reader).unwrap(),
+                coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap()
+            );
+        }
+        assert_eq!(
+            clone.categories().unwrap(),
+            coco_page_mapper.categories().unwrap()
+        );
+
+        clone.shrink_to_fit();
+        drop(coco_page_mapper);
+        assert_eq!(clone.get_img_ids(), &vec![5, 6]);
+        assert_eq!(
+            clone
+                .into_parts(&mut clone_reader)
+                .unwrap()
+                .annotations
+                .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_max_record_bytes() {
+        let head = r#"{"licenses": [], "info": {}, "categories": [], "images": [{"id": 5}],
+        "annotations": [{"id": 1, "image_id": 5}, {"id": 2, "image_id": 5, "caption": ""#;
+        let mut data = head.as_bytes().to_vec();
+        data.resize(data.len() + (16 << 20), b'a');
+
+        let mut reader = io::Cursor::new(data.as_slice());
+        let err = CocoPageMapper::builder()
+            .max_record_bytes(1000)
+            .build(&mut reader)
+            .unwrap_err();
+        let offset = head.find(r#"{"id": 2"#).unwrap() as u64;
+        assert!(matches!(
+            coco_error(&err),
+            Some(CocoError::RecordTooLarge {
+                offset: o,
+                limit: 1000
+            }) if *o == offset
+        ));
+        // The scan stops within a read-ahead window of the limit.
+        assert!(reader.position() < offset + 1000 + (128 << 10));
+
+        let long = EXAMPLE.replacen(
+            r#""iscrowd":0"#,
+            &format!(r#""iscrowd":0,"pad":"{}""#, "a".repeat(2000)),
+            1,
+        );
+        let offset = long.find(&"a".repeat(2000)).unwrap();
+        let offset = long[..offset].rfind("{").unwrap() as u64;
+        let coco_page_mapper = CocoPageMapper::builder()
+            .max_record_bytes(1000)
+            .skip_malformed_anns(true)
+            .build(io::Cursor::new(long.as_bytes()))
+            .unwrap();
+        assert_eq!(coco_page_mapper.malformed_ann_positions(), &vec![offset]);
+        assert!(CocoPageMapper::builder()
+            .max_record_bytes(3000)
+            .build(io::Cursor::new(long.as_bytes()))
+            .is_ok());
+
+        let long = EXAMPLE.replacen(
+            r#""info":{"#,
+            &format!(r#""info":{{"pad":"{}","#, "a".repeat(2000)),
+            1,
+        );
+        let err = CocoPageMapper::builder()
+            .max_section_bytes(1000)
+            .build(io::Cursor::new(long.as_bytes()))
+            .unwrap_err();
+        assert!(matches!(
+            coco_error(&err),
+            Some(CocoError::RecordTooLarge { limit: 1000, .. })
+        ));
+        assert!(CocoPageMapper::builder()
+            .max_section_bytes(3000)
+            .build(io::Cursor::new(long.as_bytes()))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_skip_malformed_images() {
+        const MALFORMED: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[],
+            "images":[
+                {"id":1,"width":4,"height":4,"file_name":"1.jpg"},
+                {"id":2,"width":4,,"file_name":"2.jpg"},
+                {"id":"3","file_name":"3.jpg"},
+                {"id":4,"width":4,"height":4,"file_name":"4.jpg"}
+            ],
+            "annotations":[]
+        }"#;
+        assert!(CocoPageMapper::from_bytes(MALFORMED.as_bytes()).is_err());
+
+        let (_, coco_page_mapper) = prepare(EXAMPLE);
+        assert_eq!(coco_page_mapper.scanned_image_count(), 2);
+        assert_eq!(coco_page_mapper.skipped_image_count(), 0);
+
+        let mut reader = Cursor::new(MALFORMED.as_bytes());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .skip_malformed_images(true)
+            .build(&mut reader)
+            .unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![1, 4]);
+        assert_eq!(coco_page_mapper.scanned_image_count(), 4);
+        assert_eq!(coco_page_mapper.skipped_image_count(), 2);
+        let item = coco_page_mapper.get_item_dict(4, &mut reader).unwrap();
+        assert_eq!(item["file_name"], "4.jpg");
+    }
+
+    #[test]
+    fn test_task_hint() {
+        let (_, coco_page_mapper) = prepare(EXAMPLE);
+        assert_eq!(coco_page_mapper.task_hint().unwrap(), TaskHint::Detection);
+
+        const PANOPTIC: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[
+                {"id":1,"name":"person","supercategory":"person","isthing":1},
+                {"id":2,"name":"sky","supercategory":"sky","isthing":0}
+            ],
+            "images":[{"id":1,"width":4,"height":4,"file_name":"1.jpg"}],
+            "annotations":[]
+        }"#;
+        let (_, coco_page_mapper) = prepare(PANOPTIC);
+        assert_eq!(coco_page_mapper.annotation_flavor(), None);
+        assert_eq!(coco_page_mapper.task_hint().unwrap(), TaskHint::Panoptic);
+
+        const KEYPOINTS: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[
+                {"id":1,"name":"person","supercategory":"person",
+                 "keypoints":["nose","left_eye"],"skeleton":[[1,2]]}
+            ],
+            "images":[{"id":1,"width":4,"height":4,"file_name":"1.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":1,"category_id":1,"bbox":[0,0,2,2],"area":4}
+            ]
+        }"#;
+        let (_, coco_page_mapper) = prepare(KEYPOINTS);
+        assert_eq!(
+            coco_page_mapper.annotation_flavor(),
+            Some(AnnotationFlavor::Instances)
+        );
+        assert_eq!(coco_page_mapper.task_hint().unwrap(), TaskHint::Keypoints);
+
+        const SEGMENTATION: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[{"id":1,"width":4,"height":4,"file_name":"1.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":1,"category_id":1,"segmentation":[[0,0,2,0,2,2]]}
+            ]
+        }"#;
+        let (_, coco_page_mapper) = prepare(SEGMENTATION);
+        assert_eq!(
+            coco_page_mapper.task_hint().unwrap(),
+            TaskHint::InstanceSegmentation
+        );
+    }
+
+    #[test]
+    fn test_captions() {
+        const CAPTIONS: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[],
+            "images":[
+                {"id":1,"width":4,"height":4,"file_name":"1.jpg"},
+                {"id":2,"width":4,"height":4,"file_name":"2.jpg"}
+            ],
+            "annotations":[
+                {"id":1,"image_id":1,"caption":"A cat on a mat."},
+                {"id":2,"image_id":2,"caption":"A dog."},
+                {"id":3,"image_id":1,"caption":"A sleeping cat."}
+            ]
+        }"#;
+        let (mut reader, coco_page_mapper) = prepare(CAPTIONS);
+
+        assert_eq!(
+            coco_page_mapper.annotation_flavor(),
+            Some(AnnotationFlavor::Captions)
+        );
+        assert_eq!(
+            coco_page_mapper.get_captions(1, &mut reader).unwrap(),
+            vec!["A cat on a mat.", "A sleeping cat."]
+        );
+        assert!(coco_page_mapper
+            .get_captions(3, &mut reader)
+            .unwrap()
+            .is_empty());
+
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        assert_eq!(
+            coco_page_mapper.annotation_flavor(),
+            Some(AnnotationFlavor::Instances)
+        );
+        assert!(coco_page_mapper
+            .get_captions(5, &mut reader)
+            .unwrap()
+            .is_empty());
+
+        let no_anns = CAPTIONS
+            .split(",\n            \"annotations\"")
+            .next()
+            .unwrap();
+        let (mut reader, coco_page_mapper) = prepare(&format!("{}}}", no_anns));
+        assert_eq!(coco_page_mapper.annotation_flavor(), None);
+        assert_eq!(coco_page_mapper.annotation_count(), 0);
+        assert!(coco_page_mapper
+            .get_captions(1, &mut reader)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_categories_typed() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[
+                {"id":1,"name":"cat","supercategory":"animal"},
+                {"id":2,"name":"dog","supercategory":"animal"},
+                {"id":3,"name":"person","supercategory":"person","keypoints":["nose","eye"]},
+                {"name":"no id"}
+            ],
+            "images":[],
+            "annotations":[]
+        }"#;
+        let (_, coco_page_mapper) = prepare(EXAMPLE);
+
+        let categories = coco_page_mapper.categories_typed().unwrap();
+        assert_eq!(
+            categories.iter().map(|v| v.id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        let (cat, dog, person) = (&categories[0], &categories[1], &categories[2]);
+        assert_eq!(cat.supercategory.as_deref(), Some("animal"));
+        assert!(Arc::ptr_eq(
+            cat.supercategory.as_ref().unwrap(),
+            dog.supercategory.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            &person.name,
+            person.supercategory.as_ref().unwrap()
+        ));
+        assert_eq!(
+            person.keypoints,
+            vec![Arc::from("nose"), Arc::from("eye")] as Vec<Arc<str>>
+        );
+    }
+
+    #[test]
+    fn test_lazy_categories() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[
+                {"id":1,"name":"cat","supercategory":"animal",},
+                {"id":2,"name":"dog","supercategory":"animal"},
+                {"name":"no id"},
+            ],
+            "images":[],
+            "annotations":[]
+        }"#;
+        let filepath = write_example(EXAMPLE);
+        let mut reader = BufReader::new(File::open(filepath).unwrap());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .allow_trailing_commas(true)
+            .build(&mut reader)
+            .unwrap();
+        assert_eq!(coco_page_mapper.parsed_category_count(), 0);
+
+        let dog = coco_page_mapper.get_category(2, &mut reader).unwrap();
+        assert_eq!(dog.unwrap()["name"], "dog");
+        let cat = coco_page_mapper.get_category(1, &mut reader).unwrap();
+        assert_eq!(cat.unwrap()["name"], "cat");
+        assert!(coco_page_mapper
+            .get_category(3, &mut reader)
+            .unwrap()
+            .is_none());
+        assert_eq!(coco_page_mapper.parsed_category_count(), 2);
+
+        assert_eq!(
+            coco_page_mapper
+                .categories()
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(coco_page_mapper.categories().unwrap()[2]["name"], "no id");
+        assert_eq!(coco_page_mapper.parsed_category_count(), 5);
+    }
+
+    #[test]
+    fn test_categories_parsed_on_demand() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        for img_id in coco_page_mapper.get_img_ids() {
+            coco_page_mapper
+                .get_item_dict(*img_id, &mut reader)
+                .unwrap();
+            coco_page_mapper
+                .get_anns_dict(*img_id, &mut reader)
+                .unwrap();
+        }
+        assert_eq!(coco_page_mapper.parsed_category_count(), 0);
+
+        let categories = coco_page_mapper.categories_typed().unwrap();
+        assert_eq!(categories.len(), 3);
+        assert_eq!(coco_page_mapper.parsed_category_count(), 3);
+        coco_page_mapper.categories().unwrap();
+        assert_eq!(coco_page_mapper.parsed_category_count(), 3);
+    }
+
+    #[test]
+    fn test_categories_parse_error() {
+        // The scan skips the fields other than `id` without parsing numbers.
+        let invalid = EXAMPLE.replacen(r#""name":"c""#, r#""name":"c","scale":1e400"#, 1);
+        assert_ne!(invalid, EXAMPLE);
+        let (mut reader, coco_page_mapper) = prepare(&invalid);
+        assert_eq!(
+            coco_page_mapper
+                .get_category(1, &mut reader)
+                .unwrap()
+                .unwrap()["name"],
+            "a"
+        );
+        assert!(coco_page_mapper.categories().is_err());
+        assert!(coco_page_mapper.categories().is_err());
+        assert!(coco_page_mapper.task_hint().is_err());
+        assert_eq!(coco_page_mapper.parsed_category_count(), 1);
+    }
+
+    #[test]
+    fn test_json_error_offset() {
+        let json_offset = |err: &io::Error| match coco_error(err) {
+            Some(CocoError::Json { offset, .. }) => *offset,
+            Some(CocoError::FieldParse { source, .. }) => match coco_error(source) {
+                Some(CocoError::Json { offset, .. }) => *offset,
+                _ => panic!("unexpected error: {:?}", err),
+            },
+            _ => panic!("unexpected error: {:?}", err),
+        };
+        let ann = r#"{"id":4,"image_id":6,"category_id":2,"segmentation":[],"area"3"#;
+        let corrupt = EXAMPLE.replacen(&ann.replace("\"3", "\":3"), ann, 1);
+        let pos = corrupt.find(ann).unwrap() + ann.len() - 1;
+
+        let err = CocoPageMapper::new(Cursor::new(corrupt.as_bytes())).unwrap_err();
+        assert_eq!(json_offset(&err), pos as u64);
+        assert!(err.to_string().contains(&format!("offset={}", pos)));
+
+        let corrupt = EXAMPLE.replacen(r#""name":"c""#, r#""name":c"#, 1);
+        let pos = corrupt.find(r#""name":c"#).unwrap() + 7;
+        let err = CocoPageMapper::new(Cursor::new(corrupt.as_bytes())).unwrap_err();
+        assert_eq!(json_offset(&err), pos as u64);
+
+        let mut reader = Cursor::new(EXAMPLE.as_bytes().to_vec());
+        let coco_page_mapper = CocoPageMapper::new(&mut reader).unwrap();
+        let pos = EXAMPLE.find(r#""image_id":6"#).unwrap() + 11;
+        reader.get_mut()[pos] = b'x';
+        let err = coco_page_mapper.get_anns_dict(6, &mut reader).unwrap_err();
+        assert_eq!(json_offset(&err), pos as u64);
+    }
+
+    #[test]
+    fn test_image_urls() {
+        let example = EXAMPLE.replacen(
+            r#""flickr_url":"","coco_url":"""#,
+            r#""flickr_url":"http://farm1.staticflickr.com/a.jpg","coco_url":"http://images.cocodataset.org/a.jpg""#,
+            1,
+        );
+        let (mut reader, coco_page_mapper) = prepare(&example);
+
+        assert_eq!(
+            coco_page_mapper.image_urls(5, &mut reader).unwrap(),
+            ImageUrls {
+                coco_url: Some("http://images.cocodataset.org/a.jpg".to_owned()),
+                flickr_url: Some("http://farm1.staticflickr.com/a.jpg".to_owned()),
+            }
+        );
+        assert_eq!(
+            coco_page_mapper.image_urls(6, &mut reader).unwrap(),
+            ImageUrls::default()
+        );
+        assert!(coco_page_mapper.image_urls(7, &mut reader).is_err());
+    }
+
+    #[test]
+    fn test_image_license() {
+        let example = EXAMPLE.replacen(r#""license":0"#, r#""license":3"#, 1);
+        let (mut reader, coco_page_mapper) = prepare(&example);
+
+        assert_eq!(
+            coco_page_mapper.image_license(5, &mut reader).unwrap(),
+            None
+        );
+        assert_eq!(
+            coco_page_mapper.image_license(6, &mut reader).unwrap(),
+            Some(serde_json::json!({"name":"","id":0,"url":""}))
+        );
+        assert!(coco_page_mapper.image_license(7, &mut reader).is_err());
+    }
+
+    #[test]
+    fn test_string_image_sizes() {
+        let stringified =
+            EXAMPLE.replace(r#""width":10,"height":5"#, r#""width":"10","height":" 5""#);
+        assert_ne!(stringified, EXAMPLE);
+
+        let expected = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+        let coco_page_mapper = CocoPageMapper::from_bytes(stringified.as_bytes()).unwrap();
+        for img_id in [5, 6] {
+            assert_eq!(
+                coco_page_mapper.image_size(img_id),
+                expected.image_size(img_id)
+            );
+        }
+        assert_eq!(coco_page_mapper.image_size(6), Some((10, 5)));
+
+        let invalid = EXAMPLE.replace(r#""width":10,"#, r#""width":"ten","#);
+        let err = CocoPageMapper::from_bytes(invalid.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("not a number: \"ten\""));
+        let absent = EXAMPLE.replace(r#""width":10,"#, r#""width":null,"#);
+        let coco_page_mapper = CocoPageMapper::from_bytes(absent.as_bytes()).unwrap();
+        assert_eq!(coco_page_mapper.image_size(5), None);
+    }
+
+    #[test]
+    fn test_index_annotations_disabled() {
+        let filepath = write_example(EXAMPLE);
+        let mut reader = BufReader::new(File::open(filepath).unwrap());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .index_annotations(false)
+            .build(&mut reader)
+            .unwrap();
+
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 6]);
+        assert_eq!(coco_page_mapper.image_size(6), Some((10, 5)));
+        let item = coco_page_mapper.get_item_dict(6, &mut reader).unwrap();
+        assert_eq!(item["file_name"], "b.jpg");
+
+        let err = coco_page_mapper.get_anns_dict(5, &mut reader).unwrap_err();
+        assert!(err.to_string().contains("annotation index is disabled"));
+        assert!(coco_page_mapper
+            .iter_all_anns(&mut reader)
+            .next()
+            .unwrap()
+            .is_err());
+
+        // The annotations are still validated.
+        let corrupt = EXAMPLE.replacen(r#""iscrowd":0}"#, r#""iscrowd":0"#, 1);
+        assert!(CocoPageMapper::builder()
+            .index_annotations(false)
+            .build(Cursor::new(corrupt.as_bytes()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_iter_items_batched() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let ann_ids = |anns: &Vec<JsonDict>| {
+            anns.iter()
+                .map(|ann| ann["id"].as_i64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let batches: Vec<_> = coco_page_mapper
+            .iter_items_batched(&mut reader, 1)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), 1);
+        let (img_id, item, anns) = &batches[1][0];
+        assert_eq!(*img_id, 6);
+        assert_eq!(item["file_name"], "b.jpg");
+        assert_eq!(ann_ids(anns), vec![4, 5]);
+
+        let batches: Vec<_> = coco_page_mapper
+            .iter_items_batched(&mut reader, 2)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.iter().map(|v| v.0).collect::<Vec<_>>(), vec![5, 6]);
+        assert_eq!(batch[0].1["file_name"], "a.jpg");
+        assert_eq!(ann_ids(&batch[0].2), vec![1, 2, 3]);
+        assert_eq!(ann_ids(&batch[1].2), vec![4, 5]);
+    }
+
+    #[test]
+    fn test_restrict_to_img_ids() {
+        let (mut reader, _) = prepare(EXAMPLE);
+        reader.rewind().unwrap();
+        let coco_page_mapper = CocoPageMapper::builder()
+            .restrict_to_img_ids(HashSet::from([6]))
+            .build(&mut reader)
+            .unwrap();
+
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![6]);
+        assert_eq!(coco_page_mapper.annotation_count(), 2);
+        let anns = coco_page_mapper.get_anns_dict(6, &mut reader).unwrap();
+        assert_eq!(
+            anns.iter().map(|v| v["id"].clone()).collect::<Vec<_>>(),
+            [4, 5]
+        );
+
+        let err = coco_page_mapper.get_item_dict(5, &mut reader).unwrap_err();
+        assert!(err.to_string().contains("not on the page map"));
+        assert!(coco_page_mapper
+            .get_anns_dict(5, &mut reader)
+            .unwrap()
+            .is_empty());
+        assert!(coco_page_mapper.ann_seg_kind(1).is_none());
+
+        let example = generate_example(100);
+        let mut full = CocoPageMapper::from_bytes(example.as_bytes()).unwrap();
+        let mut shard = CocoPageMapper::builder()
+            .restrict_to_img_ids((0..100).step_by(10).collect())
+            .build(Cursor::new(example.as_bytes()))
+            .unwrap();
+        assert_eq!(shard.get_img_ids().len(), 10);
+        assert_eq!(shard.annotation_count(), 30);
+        full.shrink_to_fit();
+        shard.shrink_to_fit();
+        let (memory, full_memory) = (shard.index_memory_bytes(), full.index_memory_bytes());
+        assert!(memory.images * 5 < full_memory.images);
+        assert!(memory.annotations * 5 < full_memory.annotations);
+    }
+
+    #[test]
+    fn test_ann_attributes() {
+        let example = EXAMPLE
+            .replacen(
+                r#""iscrowd":0}"#,
+                r#""iscrowd":0,"attributes":{"occluded":true,"track_id":3}}"#,
+                1,
+            )
+            .replacen(
+                r#""id":2,"image_id":5"#,
+                r#""id":2,"attributes":null,"image_id":5"#,
+                1,
+            );
+        let (mut reader, coco_page_mapper) = prepare(&example);
+
+        let attributes = coco_page_mapper.ann_attributes(1, &mut reader).unwrap();
+        assert_eq!(
+            attributes,
+            Some(serde_json::json!({"occluded": true, "track_id": 3}))
+        );
+        assert_eq!(
+            coco_page_mapper.ann_attributes(2, &mut reader).unwrap(),
+            None
+        );
+        assert_eq!(
+            coco_page_mapper.ann_attributes(3, &mut reader).unwrap(),
+            None
+        );
+        assert!(coco_page_mapper.ann_attributes(42, &mut reader).is_err());
+    }
+
+    #[test]
+    fn test_get_anns_selected() {
+        let data = CocoGenerator::new(2)
+            .images(20)
+            .anns_per_image(AnnsPerImage::Uniform { min: 0, max: 5 })
+            .segmentation(SegmentationStyle::Rle)
+            .to_bytes();
+        let coco_page_mapper = CocoPageMapper::from_bytes(&data).unwrap();
+        let img_ids = coco_page_mapper.get_img_ids().clone();
+        let mut reader = Cursor::new(&data);
+        let expected = coco_page_mapper
+            .get_anns_batch(&img_ids, &mut reader)
+            .unwrap();
+
+        let without = |anns: &[JsonDict], keys: &[&str]| -> Vec<JsonDict> {
+            let mut anns = anns.to_vec();
+            for ann in anns.iter_mut() {
+                let ann = ann.as_object_mut().unwrap();
+                ann.retain(|key, _| !keys.contains(&key.as_str()));
+            }
+            anns
+        };
+        let exclude = FieldSelection::exclude(&["segmentation"]);
+        let include = FieldSelection::include(&["id", "bbox"]);
+        let rest = ["image_id", "category_id", "segmentation", "area", "iscrowd"];
+
+        for (img_id, anns) in img_ids.iter().zip(&expected) {
+            let selected = coco_page_mapper
+                .get_anns_dict_selected(*img_id, &mut reader, &exclude)
+                .unwrap();
+            assert!(selected.iter().all(|ann| ann.get("segmentation").is_none()));
+            assert_eq!(selected, without(anns, &["segmentation"]));
+
+            let selected = coco_page_mapper
+                .get_anns_dict_selected(*img_id, &mut reader, &include)
+                .unwrap();
+            assert_eq!(selected, without(anns, &rest));
+            assert_eq!(
+                coco_page_mapper
+                    .get_anns_dict_selected(*img_id, &mut reader, &FieldSelection::All)
+                    .unwrap(),
+                *anns
+            );
+        }
+
+        let batch = coco_page_mapper
+            .get_anns_batch_selected(&img_ids, &mut reader, &exclude)
+            .unwrap();
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|anns| without(anns, &["segmentation"]))
+            .collect();
+        assert_eq!(batch, expected);
+    }
+
+    #[test]
+    fn test_ann_is_crowd() {
+        let example = EXAMPLE
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":1}"#, 1)
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":true}"#, 1)
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":false}"#, 1)
+            .replacen(r#","iscrowd":0}"#, "}", 1)
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":"yes"}"#, 1);
+        let (mut reader, coco_page_mapper) = prepare(&example);
+        let mut is_crowd = |ann_id| coco_page_mapper.ann_is_crowd(ann_id, &mut reader);
+
+        assert!(is_crowd(1).unwrap());
+        assert!(is_crowd(2).unwrap());
+        assert!(!is_crowd(3).unwrap());
+        assert!(!is_crowd(4).unwrap());
+        assert!(is_crowd(5).is_err());
+        assert!(is_crowd(42).is_err());
+
+        let example = EXAMPLE
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":1.0}"#, 1)
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":0.0}"#, 1)
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":0.5}"#, 1);
+        let (mut reader, coco_page_mapper) = prepare(&example);
+        let mut is_crowd = |ann_id| coco_page_mapper.ann_is_crowd(ann_id, &mut reader);
+
+        assert!(is_crowd(1).unwrap());
+        assert!(!is_crowd(2).unwrap());
+        assert!(is_crowd(3).is_err());
+    }
+
+    #[test]
+    fn test_get_ann_field() {
+        let example = EXAMPLE.replacen(
+            r#""iscrowd":0}"#,
+            r#""iscrowd":0,"attributes":{"occluded":true,"a/b":{"~c":[4,5]}}}"#,
+            1,
+        );
+        let (mut reader, coco_page_mapper) = prepare(&example);
+        let mut field =
+            |ann_id, pointer| coco_page_mapper.get_ann_field(ann_id, &mut reader, pointer);
+
+        assert_eq!(field(1, "/bbox/2").unwrap(), Some(serde_json::json!(3.0)));
+        assert_eq!(
+            field(1, "/attributes/occluded").unwrap(),
+            Some(serde_json::json!(true))
+        );
+        assert_eq!(
+            field(1, "/attributes/a~1b/~0c/1").unwrap(),
+            Some(serde_json::json!(5))
+        );
+        assert_eq!(field(1, "").unwrap().unwrap()["id"], 1);
+        assert_eq!(field(1, "/bbox/4").unwrap(), None);
+        assert_eq!(field(2, "/attributes/occluded").unwrap(), None);
+        assert!(field(1, "bbox").is_err());
+        assert!(field(42, "/bbox").is_err());
+    }
+
+    #[test]
+    fn test_open_profile() {
+        let (coco_page_mapper, profile) =
+            CocoPageMapper::new_with_profile(io::Cursor::new(EXAMPLE.as_bytes())).unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 6]);
+
+        let (ann_bytes, _) = profile.per_section[&CocoSectionKind::Annotations];
+        assert!(ann_bytes > 0);
+        assert_eq!(profile.per_section.len(), 5);
+        let section_bytes: u64 = profile.per_section.values().map(|(bytes, _)| bytes).sum();
+        assert!(section_bytes < profile.bytes_scanned_total);
+        assert!(profile.bytes_scanned_total <= EXAMPLE.len() as u64);
+    }
+
+    #[test]
+    fn test_profiling() {
+        let data = generate_example(5_000);
+        let mut reader = io::Cursor::new(data.as_bytes());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .profiling(true)
+            .build(&mut reader)
+            .unwrap();
+
+        for img_id in [0, 1, 2] {
+            coco_page_mapper.get_item_dict(img_id, &mut reader).unwrap();
+            coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap();
+        }
+        assert!(coco_page_mapper.get_item_dict(-1, &mut reader).is_err());
+
+        let report = coco_page_mapper.profile().unwrap();
+        assert_eq!(report.construction.bytes, data.trim_end().len() as u64);
+        let phases = [report.sections, report.images, report.annotations];
+        let bytes: u64 = phases.iter().map(|phase| phase.bytes).sum();
+        let time: Duration = phases.iter().map(|phase| phase.time).sum();
+        assert!(bytes < report.construction.bytes);
+        assert!(report.images.bytes > 0 && report.annotations.bytes > report.images.bytes);
+        assert!(time <= report.construction.time);
+        assert!(time >= report.construction.time / 2);
+
+        assert_eq!(report.get_item_dict.count, 4);
+        assert_eq!(report.get_anns_dict.count, 3);
+        assert!(report.get_anns_dict.bytes > report.get_item_dict.bytes);
+        assert!(serde_json::to_value(&report).unwrap()["images"]["bytes"].is_u64());
+
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        coco_page_mapper.get_item_dict(5, &mut reader).unwrap();
+        assert!(coco_page_mapper.profile().is_none());
+    }
+
+    #[test]
+    fn test_get_item_raw_and_parsed() {
+        let (reader, coco_page_mapper) = prepare(EXAMPLE);
+        let mut reader = CountingReader::new(reader);
+
+        for img_id in [5, 6] {
+            let (reads, seeks) = (reader.reads, reader.seeks);
+            let (bytes, dict) = coco_page_mapper
+                .get_item_raw_and_parsed(img_id, &mut reader)
+                .unwrap();
+            assert_eq!(reader.seeks - seeks, 1);
+            assert_eq!(reader.reads - reads, 1);
+            assert_eq!(dict, serde_json::from_slice::<JsonDict>(&bytes).unwrap());
+            assert_eq!(
+                dict,
+                coco_page_mapper.get_item_dict(img_id, &mut reader).unwrap()
+            );
+        }
+        assert!(coco_page_mapper
+            .get_item_raw_and_parsed(7, &mut reader)
+            .is_err());
+    }
+
+    #[test]
+    fn test_fetch_parallel() {
+        let data = generate_example(500);
+        let coco_page_mapper = CocoPageMapper::from_bytes(data.as_bytes()).unwrap();
+        let mut img_ids: Vec<i64> = (0..500).rev().step_by(3).collect();
+        img_ids.insert(10, -1);
+        img_ids.push(7);
+
+        let fetch = |threads| {
+            coco_page_mapper
+                .fetch_parallel(&img_ids, || io::Cursor::new(data.as_bytes()), threads)
+                .into_iter()
+                .map(|(img_id, item)| (img_id, item.map_err(|e| e.to_string())))
+                .collect::<Vec<_>>()
+        };
+        let sequential = fetch(1);
+        assert_eq!(sequential, fetch(4));
+        assert_eq!(sequential, fetch(1000));
+
+        let fetched_ids: Vec<i64> = sequential.iter().map(|(img_id, _)| *img_id).collect();
+        assert_eq!(fetched_ids, img_ids);
+        assert!(sequential[10].1.is_err());
+        let mut reader = io::Cursor::new(data.as_bytes());
+        let (item, anns) = sequential.last().unwrap().1.as_ref().unwrap();
+        assert_eq!(
+            item,
+            &coco_page_mapper.get_item_dict(7, &mut reader).unwrap()
+        );
+        assert_eq!(
+            anns,
+            &coco_page_mapper.get_anns_dict(7, &mut reader).unwrap()
+        );
+        assert_eq!(anns.len(), 3);
+
+        assert!(coco_page_mapper
+            .fetch_parallel(&[], || io::Cursor::new(data.as_bytes()), 4)
+            .is_empty());
+    }
+
+    /// Returns at most `limit` bytes per read, so that records straddle windows.
+    struct ShortReader<R> {
+        inner: R,
+        limit: usize,
+    }
+
+    impl<R: Read> Read for ShortReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.limit);
+            self.inner.read(&mut buf[..n])
+        }
+    }
+
+    impl<R: Seek> Seek for ShortReader<R> {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_buffered_scan_offsets() {
+        let example = generate_example(100);
+        let expected = CocoPageMapper::from_bytes(example.as_bytes()).unwrap();
+        let mut reader = ShortReader {
+            inner: Cursor::new(example.as_bytes()),
+            limit: 7,
+        };
+        let coco_page_mapper = CocoPageMapper::new(&mut reader).unwrap();
+
+        let (parts, expected) = (
+            coco_page_mapper.into_parts(&mut reader).unwrap(),
+            expected
+                .into_parts(Cursor::new(example.as_bytes()))
+                .unwrap(),
+        );
+        assert_eq!(parts.images.ids(), expected.images.ids());
+        for img_id in expected.images.ids() {
+            let (page, expected_page) = (
+                parts.images.get_page(img_id).unwrap(),
+                expected.images.get_page(img_id).unwrap(),
+            );
+            assert_eq!(
+                (page.offset, page.size),
+                (expected_page.offset, expected_page.size)
+            );
+        }
+        assert!(parts
+            .annotations
+            .iter_offsets()
+            .eq(expected.annotations.iter_offsets()));
+        let (_, offset, size) = parts.annotations.iter_offsets().nth(10).unwrap();
+        let record = &example.as_bytes()[offset as usize..(offset + size as u64) as usize];
+        let ann: JsonDict = serde_json::from_slice(record).unwrap();
+        assert_eq!(ann["id"], 10);
+
+        // The dict sections no longer cost a read per byte.
+        let mut reader = CountingReader::new(Cursor::new(EXAMPLE.as_bytes()));
+        CocoPageMapper::new(&mut reader).unwrap();
+        assert!(reader.reads < 10, "{} reads", reader.reads);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_unbuffered_open() {
+        let example = generate_example(20_000).replacen(
+            r#""info":{}"#,
+            &format!(r#""info":{{"description":"{}"}}"#, "x".repeat(1 << 20)),
+            1,
+        );
+        let filepath = write_example(&example);
+
+        let mut reader = CountingReader::new(File::open(&filepath).unwrap());
+        let started = std::time::Instant::now();
+        CocoPageMapper::new(&mut reader).unwrap();
+        let elapsed = started.elapsed();
+        println!(
+            "{} MiB from an unbuffered file in {:?}, {} reads",
+            example.len() >> 20,
+            elapsed,
+            reader.reads
+        );
+    }
+
+    #[test]
+    fn test_section_keys() {
+        let example = EXAMPLE.replacen(r#""images""#, r#""Images""#, 1);
+        let coco_page_mapper = CocoPageMapper::from_bytes(example.as_bytes()).unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 6]);
+        let example = EXAMPLE.replacen(r#""info""#, r#""\u0069nfo""#, 1);
+        assert!(CocoPageMapper::from_bytes(example.as_bytes()).is_ok());
+
+        for (key, expected) in [
+            (r#""extra""#, r#"Unknown key: "extra""#),
+            (r#""ex\"tra""#, r#"Unknown key: "ex\"tra""#),
+            (r#""\u00e9t\u00e9""#, r#"Unknown key: "été""#),
+        ] {
+            let example = EXAMPLE.replacen(r#""info""#, key, 1);
+            let err = CocoPageMapper::from_bytes(example.as_bytes()).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+
+        let err = CocoPageMapper::from_bytes(br#"{"info": {} "images": []}"#).unwrap_err();
+        assert!(err.to_string().contains("Expected a key"));
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_record_fields() {
+        let example = generate_example(100_000);
+        let before = allocations();
+        let started = std::time::Instant::now();
+        let coco_page_mapper = CocoPageMapper::from_bytes(example.as_bytes()).unwrap();
+        let elapsed = started.elapsed();
+        let records = coco_page_mapper.get_img_ids().len() + coco_page_mapper.annotations.len();
+        println!(
+            "{} records in {:?}, {:.2} allocations per record",
+            records,
+            elapsed,
+            (allocations() - before) as f64 / records as f64
+        );
+    }
+
+    #[test]
+    fn test_get_anns_with_offsets() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let anns = coco_page_mapper
+            .get_anns_with_offsets(5, &mut reader)
+            .unwrap();
+        assert_eq!(
+            anns.iter().map(|(_, ann)| ann.clone()).collect::<Vec<_>>(),
+            coco_page_mapper.get_anns_dict(5, &mut reader).unwrap()
+        );
+        assert_eq!(anns.len(), 3);
+        for (offset, ann) in anns {
+            reader.seek(io::SeekFrom::Start(offset)).unwrap();
+            let mut stream = serde_json::Deserializer::from_reader(&mut reader).into_iter();
+            let raw: JsonDict = stream.next().unwrap().unwrap();
+            assert_eq!(raw, ann);
+        }
+        assert!(coco_page_mapper
+            .get_anns_with_offsets(7, &mut reader)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_on_progress() {
+        let calls = Rc::new(RefCell::new(vec![]));
+        let sink = calls.clone();
+        let builder = CocoPageMapper::builder()
+            .progress_interval(1)
+            .on_progress(Box::new(move |progress| sink.borrow_mut().push(progress)));
+        builder.build(Cursor::new(EXAMPLE.as_bytes())).unwrap();
+
+        let calls = calls.borrow();
+        assert!(calls.len() > 7);
+        for pair in calls.windows(2) {
+            assert!(pair[0].bytes_read <= pair[1].bytes_read);
+            assert!(pair[0].images_indexed <= pair[1].images_indexed);
+        }
+        assert!(calls
+            .iter()
+            .all(|v| v.total_bytes == Some(EXAMPLE.len() as u64)));
+        assert!(calls.iter().any(|v| v.section == "images"));
+
+        let last = calls.last().unwrap();
+        assert_eq!(last.section, "done");
+        assert_eq!(last.bytes_read, EXAMPLE.trim_end().len() as u64);
+        assert_eq!((last.images_indexed, last.annotations_indexed), (2, 5));
+
+        // Only the sections and the end with the default interval.
+        let count = Rc::new(RefCell::new(0));
+        let sink = count.clone();
+        CocoPageMapper::builder()
+            .on_progress(Box::new(move |_| *sink.borrow_mut() += 1))
+            .build(Cursor::new(EXAMPLE.as_bytes()))
+            .unwrap();
+        assert_eq!(*count.borrow(), 6);
+    }
+
+    #[test]
+    fn test_crlf_and_whitespace_padding() {
+        let pad = format!("\r\n{}\r\n", " \t".repeat(40_000));
+        let padded = EXAMPLE
+            .replace('\n', "\r\n")
+            .replace(",\r\n", &format!(",{}", pad))
+            .replace(":[", &format!(":{}[", pad));
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let (mut padded_reader, padded_mapper) = prepare(&padded);
+
+        assert_eq!(padded_mapper.get_img_ids(), coco_page_mapper.get_img_ids());
+        assert_eq!(
+            padded_mapper.categories().unwrap(),
+            coco_page_mapper.categories().unwrap()
+        );
+        for img_id in [5, 6] {
+            assert_eq!(
+                padded_mapper
+                    .get_item_dict(img_id, &mut padded_reader)
+                    .unwrap(),
+                coco_page_mapper.get_item_dict(img_id, &mut reader).unwrap()
+            );
+            assert_eq!(
+                padded_mapper
+                    .get_anns_dict(img_id, &mut padded_reader)
+                    .unwrap(),
+                coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        const OTHER: &str = r#"
+        {
+            "licenses":[],
+            "info":{"description":"other"},
+            "categories":[{"id":7,"name":"x","supercategory":""}],
+            "images":[{"id":1,"width":4,"height":4,"file_name":"1.jpg"}],
+            "annotations":[{"id":9,"image_id":1,"category_id":7,"bbox":[0,0,1,1]}]
+        }"#;
+        let (_, mut coco_page_mapper) = prepare(EXAMPLE);
+        let mut reader = Cursor::new(OTHER.as_bytes());
+        coco_page_mapper.reset(&mut reader).unwrap();
+        let fresh = CocoPageMapper::from_bytes(OTHER.as_bytes()).unwrap();
+
+        assert_eq!(
+            coco_page_mapper.info(&mut reader).unwrap(),
+            fresh.info(Cursor::new(OTHER.as_bytes())).unwrap()
+        );
+        assert_eq!(
+            coco_page_mapper.categories().unwrap(),
+            fresh.categories().unwrap()
+        );
+        assert_eq!(coco_page_mapper.get_img_ids(), fresh.get_img_ids());
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![1]);
+        assert_eq!(coco_page_mapper.annotation_count(), 1);
+        assert_eq!(
+            coco_page_mapper.get_item_dict(1, &mut reader).unwrap(),
+            fresh.get_item_dict(1, &mut reader).unwrap()
+        );
+        assert_eq!(
+            coco_page_mapper
+                .get_anns_dict(5, &mut reader)
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(
+            coco_page_mapper.get_anns_dict(1, &mut reader).unwrap(),
+            fresh.get_anns_dict(1, &mut reader).unwrap()
+        );
+
+        let err = coco_page_mapper.reset(Cursor::new(b"{")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(coco_page_mapper.get_img_ids().is_empty());
+        assert_eq!(coco_page_mapper.annotation_count(), 0);
+
+        // The options of the builder are kept.
+        let trailing = OTHER.replace(r#""bbox":[0,0,1,1]}"#, r#""bbox":[0,0,1,1],"area":1.0,}"#);
+        let builder = CocoPageMapper::builder()
+            .allow_trailing_commas(true)
+            .rich_index(true)
+            .compact_index(true);
+        let mut coco_page_mapper = builder.build(Cursor::new(EXAMPLE.as_bytes())).unwrap();
+        let mut reader = Cursor::new(trailing.as_bytes());
+        coco_page_mapper.reset(&mut reader).unwrap();
+        let mut fresh = builder.build(Cursor::new(trailing.as_bytes())).unwrap();
+        fresh.shrink_to_fit();
+
+        assert_eq!(coco_page_mapper.ann_entries(1), fresh.ann_entries(1));
+        assert_eq!(coco_page_mapper.ann_entries(1).len(), 1);
+        coco_page_mapper.shrink_to_fit();
+        assert_eq!(
+            coco_page_mapper.index_memory_bytes().ann_offsets,
+            fresh.index_memory_bytes().ann_offsets
+        );
+        assert_eq!(
+            coco_page_mapper.get_anns_dict(1, &mut reader).unwrap(),
+            fresh.get_anns_dict(1, &mut reader).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rich_index() {
+        let mut anns = vec![];
+        for ann_id in 0..200 {
+            anns.push(format!(
+                r#"{{"id":{},"image_id":{},"category_id":{},"area":{},"iscrowd":{},"bbox":[0,0,1,1]}}"#,
+                ann_id,
+                ann_id % 7,
+                ann_id % 3,
+                (ann_id * 37) % 101,
+                (ann_id % 5 == 0) as i32
+            ));
+        }
+        let images: Vec<String> = (0..8)
+            .map(|img_id| format!(r#"{{"id":{},"file_name":"{}.jpg"}}"#, img_id, img_id))
+            .collect();
+        let example = format!(
+            r#"{{"licenses":[],"info":{{}},"categories":[],"images":[{}],"annotations":[{}]}}"#,
+            images.join(","),
+            anns.join(",\n")
+        );
+
+        let mut reader = Cursor::new(example.as_bytes());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .rich_index(true)
+            .build(&mut reader)
+            .unwrap();
+        assert!(CocoPageMapper::from_bytes(example.as_bytes())
+            .unwrap()
+            .ann_entries(0)
+            .is_empty());
+
+        for img_id in 0..8 {
+            let entries = coco_page_mapper.ann_entries(img_id);
+            let anns = coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap();
+            assert_eq!(entries.len(), anns.len());
+
+            let from_entries = entries
+                .iter()
+                .filter(|v| v.category_id == 1 && v.area > 50.0 && !v.iscrowd)
+                .count();
+            let from_anns = anns
+                .iter()
+                .filter(|v| {
+                    v["category_id"] == 1 && v["area"].as_f64().unwrap() > 50.0 && v["iscrowd"] == 0
+                })
+                .count();
+            assert_eq!(from_entries, from_anns);
+            assert_eq!(
+                entries.iter().map(|v| v.ann_id).collect::<Vec<_>>(),
+                anns.iter()
+                    .map(|v| v["id"].as_i64().unwrap())
+                    .collect::<Vec<_>>()
+            );
+        }
+        assert!(coco_page_mapper.ann_entries(7).is_empty());
+
+        let example = EXAMPLE
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":true}"#, 1)
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":1.0}"#, 1);
+        let coco_page_mapper = CocoPageMapper::builder()
+            .rich_index(true)
+            .build(Cursor::new(example.as_bytes()))
+            .unwrap();
+        assert_eq!(
+            coco_page_mapper
+                .ann_entries(5)
+                .iter()
+                .map(|v| v.iscrowd)
+                .collect::<Vec<_>>(),
+            [true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_integer_bbox() {
+        let example = EXAMPLE.replacen(r#""bbox":[2.0,2.0,3.0,1.0]"#, r#""bbox":[2,2,3,1]"#, 1);
+        assert_ne!(example, EXAMPLE);
+        let (mut reader, coco_page_mapper) = prepare(&example);
+
+        let (boxes, labels) = coco_page_mapper.get_bbox_tensor(5, &mut reader).unwrap();
+        assert_eq!(labels, vec![2, 2, 2]);
+        assert_eq!(&boxes[..4], &[2.0, 2.0, 3.0, 1.0]);
+
+        let anns = coco_page_mapper.get_anns_dict(5, &mut reader).unwrap();
+        assert_eq!(
+            crate::duplicates::parse_bbox(&anns[0]),
+            Some([2.0, 2.0, 3.0, 1.0])
+        );
+
+        // The integer box is still a duplicate of the float boxes.
+        let groups = coco_page_mapper
+            .find_duplicate_anns(&mut reader, 0.9)
+            .unwrap();
+        assert_eq!(groups[0].ann_ids, vec![1, 2, 3]);
     }
 }
@@ -0,0 +1,476 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::{error::CocoError, utils::invalid_data};
+
+/// Default for `max_decompressed_bytes`.
+pub const DEFAULT_MAX_DECOMPRESSED_BYTES: u64 = 8 << 30;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression from the first bytes of `reader` and rewinds it.
+    pub fn sniff(mut reader: impl Read + Seek) -> Result<Self, io::Error> {
+        let start = reader.stream_position()?;
+        let mut magic = [0; 4];
+        let mut len = 0;
+        while len < magic.len() {
+            match reader.read(&mut magic[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        reader.seek(SeekFrom::Start(start))?;
+
+        let magic = &magic[..len];
+        if magic.starts_with(GZIP_MAGIC) {
+            Ok(Compression::Gzip)
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Ok(Compression::Zstd)
+        } else if magic.starts_with(UTF8_BOM)
+            || magic
+                .first()
+                .is_none_or(|c| *c == b'{' || c.is_ascii_whitespace())
+        {
+            Ok(Compression::Plain)
+        } else {
+            Err(invalid_data(
+                "Unknown file format, expected JSON, gzip or zstd.",
+            ))
+        }
+    }
+}
+
+/// Opens `path` for reading. Compressed files are decompressed into an anonymous
+/// temporary file, which is removed once the returned reader is dropped.
+/// Decompression stops with `CocoError::DecompressionLimitExceeded` once the
+/// output grows beyond `max_decompressed_bytes`.
+pub fn open_path(
+    path: &Path,
+    max_decompressed_bytes: Option<u64>,
+) -> Result<(BufReader<File>, Compression), io::Error> {
+    let mut file = File::open(path)?;
+    let compression = Compression::sniff(&mut file)?;
+
+    let mut decoder: Box<dyn Read> = match compression {
+        Compression::Plain => {
+            skip_bom(&mut file)?;
+            return Ok((BufReader::new(file), compression));
+        }
+        Compression::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(file))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(zstd::Decoder::new(file)?),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => {
+            return Err(invalid_data(
+                "Cannot read a zstd file, the crate is built without the zstd feature.",
+            ))
+        }
+    };
+
+    let mut spill = tempfile::tempfile()?;
+    match max_decompressed_bytes {
+        Some(limit) => {
+            let written = io::copy(&mut decoder.take(limit.saturating_add(1)), &mut spill)?;
+            if written > limit {
+                return Err(CocoError::DecompressionLimitExceeded { limit }.into());
+            }
+        }
+        None => {
+            io::copy(&mut decoder, &mut spill)?;
+        }
+    }
+    spill.rewind()?;
+    skip_bom(&mut spill)?;
+    Ok((BufReader::new(spill), compression))
+}
+
+/// Moves past a UTF-8 BOM at the start of `file`, which the JSON scanner does
+/// not accept. Offsets stay those of the file.
+fn skip_bom(file: &mut File) -> Result<(), io::Error> {
+    let mut head = [0; 3];
+    let mut len = 0;
+    while len < head.len() {
+        match file.read(&mut head[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    if &head[..len] != UTF8_BOM {
+        file.rewind()?;
+    }
+    Ok(())
+}
+
+/// Reads the `.gzi` index written by `bgzip -i`: the number of entries, then
+/// `(compressed, uncompressed)` offset pairs of the block starts, all as
+/// little-endian `u64`. The first block, at `(0, 0)`, is implicit.
+pub fn read_gzi(mut index: impl Read) -> Result<Vec<(u64, u64)>, io::Error> {
+    let mut read_u64 = || -> Result<u64, io::Error> {
+        let mut buf = [0; 8];
+        index.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    };
+
+    let n_entries = read_u64()?;
+    let mut blocks = vec![(0, 0)];
+    for _ in 0..n_entries {
+        let block = (read_u64()?, read_u64()?);
+        let last = blocks[blocks.len() - 1];
+        if block.0 <= last.0 || block.1 < last.1 {
+            return Err(invalid_data(
+                "The .gzi index offsets are not in increasing order.",
+            ));
+        }
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+/// Random access to a bgzip (blocked gzip) stream through its `.gzi` index.
+/// Offsets are those of the decompressed data; only the block holding the
+/// current position is decompressed and kept in memory.
+#[derive(Debug)]
+pub struct BgzfReader<R> {
+    inner: R,
+    /// `(compressed, uncompressed)` start offsets of the indexed blocks.
+    blocks: Vec<(u64, u64)>,
+    block: Vec<u8>,
+    block_idx: Option<usize>,
+    pos: u64,
+    len: Option<u64>,
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    pub fn new(inner: R, index: impl Read) -> Result<Self, io::Error> {
+        Ok(Self {
+            inner,
+            blocks: read_gzi(index)?,
+            block: vec![],
+            block_idx: None,
+            pos: 0,
+            len: None,
+        })
+    }
+
+    /// Decompresses the data from the start of the indexed block `idx` up to
+    /// the next indexed block, or to the end of the stream for the last one.
+    fn load(&mut self, idx: usize) -> Result<(), io::Error> {
+        if self.block_idx == Some(idx) {
+            return Ok(());
+        }
+        self.block_idx = None;
+        self.block.clear();
+
+        let start = self.blocks[idx].0;
+        self.inner.seek(SeekFrom::Start(start))?;
+        match self.blocks.get(idx + 1) {
+            Some((end, _)) => {
+                MultiGzDecoder::new((&mut self.inner).take(end - start))
+                    .read_to_end(&mut self.block)?;
+                if self.block.len() as u64 != self.blocks[idx + 1].1 - self.blocks[idx].1 {
+                    return Err(invalid_data("The .gzi index does not match the data."));
+                }
+            }
+            None => {
+                MultiGzDecoder::new(&mut self.inner).read_to_end(&mut self.block)?;
+                self.len = Some(self.blocks[idx].1 + self.block.len() as u64);
+            }
+        }
+        self.block_idx = Some(idx);
+        Ok(())
+    }
+
+    fn len(&mut self) -> Result<u64, io::Error> {
+        if let Some(len) = self.len {
+            return Ok(len);
+        }
+        self.load(self.blocks.len() - 1)?;
+        Ok(self.len.unwrap_or_default())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let idx = self.blocks.partition_point(|(_, start)| *start <= self.pos) - 1;
+        self.load(idx)?;
+
+        let from = (self.pos - self.blocks[idx].1) as usize;
+        let available = self.block.get(from..).unwrap_or_default();
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for BgzfReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::Current(v) => self.pos.checked_add_signed(v),
+            SeekFrom::End(v) => self.len()?.checked_add_signed(v),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// A file read with positioned reads, which leave the cursor of the handle
+/// alone, so that clones of it can be read on other threads at once. This is
+/// needed for the spill file of `open_path`, which cannot be opened again by
+/// path. On Windows the cursor still moves, but reads never depend on it.
+/// Elsewhere the cursor is sought before every read, so clones read at once
+/// on other threads may race.
+#[derive(Debug)]
+pub struct PositionedFile {
+    file: File,
+    pos: u64,
+}
+
+impl PositionedFile {
+    pub fn new(file: File) -> Self {
+        Self { file, pos: 0 }
+    }
+
+    /// Another reader of the same file, with its own position.
+    pub fn try_clone(&self) -> Result<Self, io::Error> {
+        Ok(Self::new(self.file.try_clone()?))
+    }
+
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Read for PositionedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(&self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(&self.file, buf, self.pos)?;
+        #[cfg(not(any(unix, windows)))]
+        let n = {
+            let mut file = &self.file;
+            file.seek(SeekFrom::Start(self.pos))?;
+            file.read(buf)?
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for PositionedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::Current(v) => self.pos.checked_add_signed(v),
+            SeekFrom::End(v) => self.file.metadata()?.len().checked_add_signed(v),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::{write::GzEncoder, Compression as GzLevel, GzBuilder};
+
+    use super::*;
+    use crate::{
+        coco_page_mapper::CocoPageMapper,
+        error::coco_error,
+        test_utils::{write_example, CountingReader, EXAMPLE},
+    };
+
+    /// Compresses `data` as bgzip blocks of `block_size` input bytes, followed
+    /// by the empty EOF block. Returns the file and its `.gzi` index.
+    fn bgzip(data: &[u8], block_size: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut blocks: Vec<&[u8]> = data.chunks(block_size).collect();
+        blocks.push(&[]);
+
+        let (mut file, mut entries) = (vec![], vec![]);
+        let mut uncompressed = 0;
+        for block in blocks {
+            if !file.is_empty() {
+                entries.push((file.len() as u64, uncompressed));
+            }
+            // The BC extra subfield holds the total block size minus 1.
+            let mut encoder = GzBuilder::new()
+                .extra(vec![b'B', b'C', 2, 0, 0, 0])
+                .write(vec![], GzLevel::default());
+            encoder.write_all(block).unwrap();
+            let mut compressed = encoder.finish().unwrap();
+            let bsize = (compressed.len() - 1) as u16;
+            compressed[16..18].copy_from_slice(&bsize.to_le_bytes());
+            file.extend(compressed);
+            uncompressed += block.len() as u64;
+        }
+
+        let mut index = (entries.len() as u64).to_le_bytes().to_vec();
+        for (compressed, uncompressed) in entries {
+            index.extend(compressed.to_le_bytes());
+            index.extend(uncompressed.to_le_bytes());
+        }
+        (file, index)
+    }
+
+    fn check_mapper(path: &Path, expected: Compression) {
+        let (mut reader, compression) = open_path(path, None).unwrap();
+        assert_eq!(compression, expected);
+
+        let (coco_page_mapper, _) = CocoPageMapper::from_path(path).unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 6]);
+        let anns = coco_page_mapper.get_anns_dict(6, &mut reader).unwrap();
+        assert_eq!(anns.len(), 2);
+    }
+
+    #[test]
+    fn test_sniff() {
+        let sniff = |v: &[u8]| Compression::sniff(Cursor::new(v.to_vec())).ok();
+        assert_eq!(sniff(b"{}"), Some(Compression::Plain));
+        assert_eq!(sniff(b"\n {}"), Some(Compression::Plain));
+        assert_eq!(sniff(b"\xef\xbb\xbf{}"), Some(Compression::Plain));
+        assert_eq!(sniff(b"\x1f\x8b\x08\x00"), Some(Compression::Gzip));
+        assert_eq!(sniff(b"\x28\xb5\x2f\xfd"), Some(Compression::Zstd));
+        assert_eq!(sniff(b"PK\x03\x04"), None);
+    }
+
+    #[test]
+    fn test_plain() {
+        check_mapper(&write_example(EXAMPLE), Compression::Plain);
+    }
+
+    #[test]
+    fn test_utf8_bom() {
+        let path = write_example(&format!("\u{feff}{}", EXAMPLE));
+        check_mapper(&path, Compression::Plain);
+
+        let (coco_page_mapper, mut reader) = CocoPageMapper::from_path(&path).unwrap();
+        let item = coco_page_mapper.get_item_dict(6, &mut reader).unwrap();
+        assert_eq!(item["file_name"], "b.jpg");
+
+        let mut encoder = GzEncoder::new(vec![], GzLevel::default());
+        encoder.write_all(UTF8_BOM).unwrap();
+        encoder.write_all(EXAMPLE.as_bytes()).unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+        check_mapper(&path, Compression::Gzip);
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut encoder = GzEncoder::new(vec![], GzLevel::default());
+        encoder.write_all(EXAMPLE.as_bytes()).unwrap();
+        let path = write_example(EXAMPLE);
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        check_mapper(&path, Compression::Gzip);
+    }
+
+    #[test]
+    fn test_max_decompressed_bytes() {
+        let mut encoder = GzEncoder::new(vec![], GzLevel::default());
+        encoder.write_all(&vec![b' '; 1 << 20]).unwrap();
+        encoder.write_all(EXAMPLE.as_bytes()).unwrap();
+        let path = write_example(EXAMPLE);
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let err = CocoPageMapper::builder()
+            .max_decompressed_bytes(Some(1 << 16))
+            .build_from_path(&path)
+            .unwrap_err();
+        assert!(matches!(
+            coco_error(&err),
+            Some(CocoError::DecompressionLimitExceeded { limit: 65536 })
+        ));
+
+        let (coco_page_mapper, _) = CocoPageMapper::builder()
+            .max_decompressed_bytes(None)
+            .build_from_path(&path)
+            .unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 6]);
+    }
+
+    #[test]
+    fn test_bgzip() {
+        let (file, index) = bgzip(EXAMPLE.as_bytes(), 256);
+        // The whole file is still readable as plain gzip.
+        let mut plain = String::new();
+        MultiGzDecoder::new(file.as_slice())
+            .read_to_string(&mut plain)
+            .unwrap();
+        assert_eq!(plain, EXAMPLE);
+
+        let (coco_page_mapper, mut reader) =
+            CocoPageMapper::from_bgzip(Cursor::new(file.clone()), index.as_slice()).unwrap();
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![5, 6]);
+        let expected = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+        let mut plain_reader = Cursor::new(EXAMPLE.as_bytes());
+        for img_id in [6, 5] {
+            assert_eq!(
+                coco_page_mapper.get_item_dict(img_id, &mut reader).unwrap(),
+                expected.get_item_dict(img_id, &mut plain_reader).unwrap()
+            );
+            assert_eq!(
+                coco_page_mapper.get_anns_dict(img_id, &mut reader).unwrap(),
+                expected.get_anns_dict(img_id, &mut plain_reader).unwrap()
+            );
+        }
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), EXAMPLE.len() as u64);
+
+        // A lookup only touches the compressed bytes of its own block.
+        let mut reader =
+            BgzfReader::new(CountingReader::new(Cursor::new(file)), index.as_slice()).unwrap();
+        coco_page_mapper.get_item_dict(6, &mut reader).unwrap();
+        let counter = reader.into_inner();
+        assert_eq!(counter.seeks, 1);
+        assert!(counter.reads <= 2);
+
+        let err =
+            BgzfReader::new(Cursor::new(vec![]), &[1u8, 0, 0, 0, 0, 0, 0, 0][..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd() {
+        let path = write_example(EXAMPLE);
+        std::fs::write(&path, zstd::encode_all(EXAMPLE.as_bytes(), 0).unwrap()).unwrap();
+
+        check_mapper(&path, Compression::Zstd);
+    }
+}
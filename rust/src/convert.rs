@@ -0,0 +1,164 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, Write},
+};
+
+use serde_json::json;
+
+use crate::{coco_page_mapper::CocoPageMapper, export::rewrite_dataset, page_maps::JsonDict};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SegmentKind {
+    #[default]
+    All,
+    Things,
+    Stuff,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PanopticToInstancesOptions {
+    pub segments: SegmentKind,
+}
+
+fn is_thing(category: &JsonDict) -> bool {
+    match category.get("isthing") {
+        Some(v) => v.as_i64().map(|v| v != 0).or(v.as_bool()).unwrap_or(true),
+        None => true,
+    }
+}
+
+/// Writes an instances file with one annotation per panoptic segment. Segment
+/// masks are not decoded, so `segmentation` is left empty. Segments of stuff
+/// categories are marked as `iscrowd`, and `isthing` is moved into the category
+/// `attributes`.
+pub fn panoptic_to_instances(
+    mapper: &CocoPageMapper,
+    reader: impl Read + Seek,
+    writer: impl Write,
+    options: &PanopticToInstancesOptions,
+) -> Result<(), io::Error> {
+    let mut isthing: HashMap<i64, bool> = HashMap::new();
+    let mut categories = vec![];
+    for category in mapper.categories()?.as_array().into_iter().flatten() {
+        let mut category = category.clone();
+        let thing = is_thing(&category);
+        if let Some(id) = category.get("id").and_then(|v| v.as_i64()) {
+            isthing.insert(id, thing);
+        }
+        if let Some(fields) = category.as_object_mut() {
+            fields.remove("isthing");
+            fields.insert("attributes".into(), json!({ "isthing": thing }));
+        }
+        categories.push(category);
+    }
+
+    let mut next_ann_id = 1;
+    rewrite_dataset(
+        mapper,
+        reader,
+        writer,
+        mapper.get_img_ids(),
+        &JsonDict::Array(categories),
+        |_, item| Ok(vec![item]),
+        |img_id, anns| {
+            let mut converted = vec![];
+            for ann in anns.iter() {
+                let segments = ann.get("segments_info").and_then(|v| v.as_array());
+                for segment in segments.into_iter().flatten() {
+                    let category_id = segment.get("category_id").and_then(|v| v.as_i64());
+                    let thing = category_id
+                        .and_then(|v| isthing.get(&v))
+                        .copied()
+                        .unwrap_or(true);
+                    let keep = match options.segments {
+                        SegmentKind::All => true,
+                        SegmentKind::Things => thing,
+                        SegmentKind::Stuff => !thing,
+                    };
+                    if !keep {
+                        continue;
+                    }
+
+                    let iscrowd = match thing {
+                        true => segment.get("iscrowd").and_then(|v| v.as_i64()).unwrap_or(0),
+                        false => 1,
+                    };
+                    converted.push(json!({
+                        "id": next_ann_id,
+                        "image_id": img_id,
+                        "category_id": category_id,
+                        "bbox": segment.get("bbox").cloned().unwrap_or(json!([])),
+                        "area": segment.get("area").cloned().unwrap_or(json!(0.0)),
+                        "iscrowd": iscrowd,
+                        "segmentation": [],
+                    }));
+                    next_ann_id += 1;
+                }
+            }
+            Ok(converted)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        page_maps::SegKind,
+        test_utils::{prepare, reopen},
+    };
+
+    const EXAMPLE: &str = r#"
+    {"licenses":[],"info":{},"categories":[{"id":1,"name":"person","supercategory":"","isthing":1},{"id":2,"name":"sky","supercategory":"","isthing":0}],"images":[{"id":1,"width":4,"height":4,"file_name":"1.jpg"}],"annotations":[{"image_id":1,"file_name":"1.png","segments_info":[{"id":3,"category_id":1,"area":5.0,"bbox":[1.0,0.0,2.0,2.0],"iscrowd":0},{"id":4,"category_id":2,"area":4.0,"bbox":[0.0,0.0,4.0,1.0],"iscrowd":0}]}]}
+    "#;
+
+    #[test]
+    fn test_panoptic_to_instances() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let mut buf = vec![];
+        panoptic_to_instances(
+            &coco_page_mapper,
+            &mut reader,
+            &mut buf,
+            &PanopticToInstancesOptions::default(),
+        )
+        .unwrap();
+
+        let (mut reader, instances) = reopen(buf);
+        let categories = instances.categories().unwrap().as_array().unwrap();
+        assert_eq!(categories[1]["attributes"]["isthing"], false);
+        assert!(categories[1].get("isthing").is_none());
+
+        let mut anns = instances.get_anns_dict(1, &mut reader).unwrap();
+        anns.sort_by_key(|v| v["id"].as_i64());
+        assert_eq!(anns.len(), 2);
+        assert_eq!(anns[0]["category_id"], 1);
+        assert_eq!(anns[0]["iscrowd"], 0);
+        assert_eq!(anns[0]["bbox"], json!([1.0, 0.0, 2.0, 2.0]));
+        assert_eq!(anns[1]["category_id"], 2);
+        assert_eq!(anns[1]["iscrowd"], 1);
+        assert_eq!(anns[1]["area"], 4.0);
+        assert_eq!(instances.ann_seg_kind(1), Some(SegKind::None));
+
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let mut buf = vec![];
+        panoptic_to_instances(
+            &coco_page_mapper,
+            &mut reader,
+            &mut buf,
+            &PanopticToInstancesOptions {
+                segments: SegmentKind::Things,
+            },
+        )
+        .unwrap();
+        let (mut reader, instances) = reopen(buf);
+        let anns = instances.get_anns_dict(1, &mut reader).unwrap();
+        assert_eq!(anns.len(), 1);
+        assert_eq!(anns[0]["category_id"], 1);
+    }
+}
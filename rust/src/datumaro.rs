@@ -0,0 +1,286 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, Write},
+    path::Path,
+};
+
+use serde_json::{json, Map};
+
+use crate::{
+    coco_page_mapper::{CocoPageMapper, BATCH_SIZE},
+    duplicates::parse_bbox,
+    page_maps::{parse_dimension, JsonDict},
+    rle::{is_rle, Rle},
+    utils::is_crowd,
+};
+const DM_FORMAT_VERSION: &str = "1.0";
+/// `MediaType.IMAGE` of datumaro.
+const MEDIA_TYPE_IMAGE: i64 = 2;
+
+fn label_categories(mapper: &CocoPageMapper) -> Result<JsonDict, io::Error> {
+    let labels: Vec<JsonDict> = mapper
+        .unique_categories()?
+        .into_iter()
+        .map(|category| {
+            json!({
+                "name": category.get("name").and_then(|v| v.as_str()).unwrap_or(""),
+                "parent": category.get("supercategory").and_then(|v| v.as_str()).unwrap_or(""),
+                "attributes": [],
+            })
+        })
+        .collect();
+    Ok(json!({ "labels": labels, "attributes": [] }))
+}
+
+fn points_categories(
+    mapper: &CocoPageMapper,
+    labels: &HashMap<i64, usize>,
+) -> Result<Vec<JsonDict>, io::Error> {
+    Ok(mapper
+        .unique_categories()?
+        .into_iter()
+        .filter_map(|category| {
+            let label_id = labels.get(&category.get("id")?.as_i64()?)?;
+            let keypoints = category.get("keypoints")?.as_array()?;
+            Some(json!({
+                "label_id": label_id,
+                "labels": keypoints,
+                "joints": category.get("skeleton").cloned().unwrap_or(json!([])),
+            }))
+        })
+        .collect())
+}
+
+/// Datumaro annotations of a COCO annotation, as made by the datumaro COCO
+/// importer: a polygon per polygon of the segmentation, a mask for RLE, or
+/// else the box. Keypoints and captions give points and caption annotations.
+fn convert_annotation(ann: &JsonDict, labels: &HashMap<i64, usize>) -> Vec<JsonDict> {
+    let id = ann.get("id").and_then(|v| v.as_i64()).unwrap_or(0);
+
+    if let Some(caption) = ann.get("caption").and_then(|v| v.as_str()) {
+        return vec![json!({
+            "id": id,
+            "type": "caption",
+            "attributes": {},
+            "group": 0,
+            "caption": caption,
+        })];
+    }
+
+    let mut attributes = Map::new();
+    attributes.insert(
+        "is_crowd".into(),
+        ann.get("iscrowd")
+            .and_then(is_crowd)
+            .unwrap_or(false)
+            .into(),
+    );
+    if let Some(score) = ann.get("score") {
+        attributes.insert("score".into(), score.clone());
+    }
+    if let Some(extra) = ann.get("attributes").and_then(|v| v.as_object()) {
+        attributes.extend(extra.clone());
+    }
+
+    let label_id = ann
+        .get("category_id")
+        .and_then(|v| v.as_i64())
+        .and_then(|v| labels.get(&v));
+    let base = |kind: &str| {
+        let mut dm_ann = Map::new();
+        dm_ann.insert("id".into(), id.into());
+        dm_ann.insert("type".into(), kind.into());
+        dm_ann.insert("attributes".into(), attributes.clone().into());
+        dm_ann.insert("group".into(), id.into());
+        dm_ann.insert("label_id".into(), json!(label_id));
+        dm_ann.insert("z_order".into(), 0.into());
+        dm_ann
+    };
+
+    let mut dm_anns = vec![];
+    if let Some(keypoints) = ann.get("keypoints").and_then(|v| v.as_array()) {
+        let mut points = vec![];
+        let mut visibility = vec![];
+        for triplet in keypoints.chunks_exact(3) {
+            points.extend([triplet[0].clone(), triplet[1].clone()]);
+            visibility.push(triplet[2].as_i64().unwrap_or(0));
+        }
+        let mut dm_ann = base("points");
+        dm_ann.insert("points".into(), points.into());
+        dm_ann.insert("visibility".into(), visibility.into());
+        dm_anns.push(dm_ann.into());
+    }
+
+    match ann.get("segmentation") {
+        Some(segmentation) if is_rle(segmentation) => {
+            if let Ok(rle) = Rle::from_json(segmentation) {
+                let counts = match segmentation.get("counts") {
+                    Some(JsonDict::String(counts)) => counts.clone(),
+                    _ => rle.to_compressed_string(),
+                };
+                let mut dm_ann = base("mask");
+                dm_ann.insert(
+                    "rle".into(),
+                    json!({ "counts": counts, "size": segmentation["size"] }),
+                );
+                dm_anns.push(dm_ann.into());
+            }
+        }
+        Some(JsonDict::Array(polygons))
+            if polygons
+                .iter()
+                .any(|v| v.as_array().is_some_and(|v| !v.is_empty())) =>
+        {
+            for polygon in polygons
+                .iter()
+                .filter_map(|v| v.as_array())
+                .filter(|v| !v.is_empty())
+            {
+                let mut dm_ann = base("polygon");
+                dm_ann.insert("points".into(), polygon.clone().into());
+                dm_anns.push(dm_ann.into());
+            }
+        }
+        _ => {
+            if let Some(bbox) = parse_bbox(ann) {
+                let mut dm_ann = base("bbox");
+                dm_ann.insert("bbox".into(), json!(bbox));
+                dm_anns.push(dm_ann.into());
+            }
+        }
+    }
+    dm_anns
+}
+
+/// A datumaro `DatasetItem`, identified by the file name without extension.
+fn convert_item(
+    img_id: i64,
+    item: &JsonDict,
+    anns: &[JsonDict],
+    labels: &HashMap<i64, usize>,
+) -> JsonDict {
+    let file_name = item.get("file_name").and_then(|v| v.as_str()).unwrap_or("");
+    let stem = Path::new(file_name)
+        .with_extension("")
+        .to_string_lossy()
+        .into_owned();
+    let mut image = json!({ "path": file_name });
+    if let (Some(width), Some(height)) = (
+        parse_dimension(item.get("width")).ok().flatten(),
+        parse_dimension(item.get("height")).ok().flatten(),
+    ) {
+        image["size"] = json!([height, width]);
+    }
+
+    let annotations: Vec<JsonDict> = anns
+        .iter()
+        .flat_map(|ann| convert_annotation(ann, labels))
+        .collect();
+    json!({
+        "id": stem,
+        "annotations": annotations,
+        "attr": { "id": img_id },
+        "image": image,
+    })
+}
+
+impl CocoPageMapper {
+    /// Writes the dataset in the datumaro JSON format, one item per image.
+    /// Label ids are the positions of the categories, see `contiguous_labels`,
+    /// and image sizes are given as `[height, width]`.
+    pub fn to_datumaro_json(
+        &self,
+        mut reader: impl Read + Seek,
+        mut writer: impl Write,
+    ) -> Result<(), io::Error> {
+        let labels = self.contiguous_labels()?;
+        let mut categories = json!({ "label": label_categories(self)? });
+        let points = points_categories(self, &labels)?;
+        if !points.is_empty() {
+            categories["points"] = json!({ "items": points });
+        }
+
+        write!(
+            writer,
+            r#"{{"dm_format_version":"{}","media_type":{},"infos":"#,
+            DM_FORMAT_VERSION, MEDIA_TYPE_IMAGE
+        )?;
+        serde_json::to_writer(&mut writer, self.info(&mut reader)?)?;
+        writer.write_all(br#","categories":"#)?;
+        serde_json::to_writer(&mut writer, &categories)?;
+        writer.write_all(br#","items":["#)?;
+
+        let mut first = true;
+        for batch in self.iter_items_batched(&mut reader, BATCH_SIZE) {
+            for (img_id, item, anns) in batch? {
+                if !first {
+                    writer.write_all(b",")?;
+                }
+                first = false;
+                serde_json::to_writer(&mut writer, &convert_item(img_id, &item, &anns, &labels))?;
+            }
+        }
+        writer.write_all(b"]}")?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{page_maps::JsonDict, test_utils::prepare, test_utils::EXAMPLE};
+
+    #[test]
+    fn test_to_datumaro_json() {
+        let example = EXAMPLE
+            .replacen(
+                r#""segmentation":[],"area":3.0"#,
+                r#""segmentation":[[0,0,4,0,4,4],[5,5,6,5,6,6]],"area":3.0"#,
+                1,
+            )
+            .replacen(r#""iscrowd":0}"#, r#""iscrowd":1.0}"#, 1);
+        let (mut reader, coco_page_mapper) = prepare(&example);
+        let mut output = vec![];
+        coco_page_mapper
+            .to_datumaro_json(&mut reader, &mut output)
+            .unwrap();
+        let dataset: JsonDict = serde_json::from_slice(&output).unwrap();
+
+        assert_eq!(dataset["dm_format_version"], "1.0");
+        assert_eq!(dataset["media_type"], 2);
+        assert_eq!(
+            dataset["categories"]["label"]["labels"][1],
+            json!({"name": "b", "parent": "", "attributes": []})
+        );
+
+        let items = dataset["items"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        let item = &items[0];
+        assert_eq!(item["id"], "a");
+        assert_eq!(item["attr"], json!({"id": 5}));
+        assert_eq!(item["image"], json!({"path": "a.jpg", "size": [5, 10]}));
+
+        let anns = item["annotations"].as_array().unwrap();
+        assert_eq!(anns.len(), 4);
+        assert_eq!(
+            anns[0],
+            json!({
+                "id": 1, "type": "polygon", "attributes": {"is_crowd": true},
+                "group": 1, "label_id": 1, "z_order": 0, "points": [0, 0, 4, 0, 4, 4],
+            })
+        );
+        assert_eq!(anns[1]["points"], json!([5, 5, 6, 5, 6, 6]));
+        assert_eq!(
+            anns[2],
+            json!({
+                "id": 2, "type": "bbox", "attributes": {"is_crowd": false},
+                "group": 2, "label_id": 1, "z_order": 0, "bbox": [2.0, 2.0, 3.0, 1.0],
+            })
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+
+use crate::{
+    page_maps::JsonDict,
+    rle::{is_rle, rle_iou, Rle},
+    utils::is_crowd,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    pub img_id: i64,
+    pub ann_ids: Vec<i64>,
+}
+
+pub fn parse_bbox(ann: &JsonDict) -> Option<[f64; 4]> {
+    let bbox = ann.get("bbox")?.as_array()?;
+    if bbox.len() != 4 {
+        return None;
+    }
+    let mut v = [0.0; 4];
+    for (dst, src) in v.iter_mut().zip(bbox.iter()) {
+        *dst = src.as_f64()?;
+    }
+    Some(v)
+}
+
+/// IoU of two boxes in COCO `[x, y, w, h]` form.
+pub fn bbox_iou(a: &[f64; 4], b: &[f64; 4]) -> f64 {
+    let iw = (a[0] + a[2]).min(b[0] + b[2]) - a[0].max(b[0]);
+    let ih = (a[1] + a[3]).min(b[1] + b[3]) - a[1].max(b[1]);
+    if iw <= 0.0 || ih <= 0.0 {
+        return 0.0;
+    }
+    let inter = iw * ih;
+    let union = a[2] * a[3] + b[2] * b[3] - inter;
+    if union <= 0.0 {
+        return 0.0;
+    }
+    inter / union
+}
+
+struct DisjointSet {
+    parents: Vec<usize>,
+}
+
+impl DisjointSet {
+    fn new(size: usize) -> Self {
+        Self {
+            parents: (0..size).collect(),
+        }
+    }
+
+    fn find(&mut self, mut idx: usize) -> usize {
+        while self.parents[idx] != idx {
+            self.parents[idx] = self.parents[self.parents[idx]];
+            idx = self.parents[idx];
+        }
+        idx
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        // Keep the earliest element as the root so that groups stay in input order.
+        if a < b {
+            self.parents[b] = a;
+        } else if b < a {
+            self.parents[a] = b;
+        }
+    }
+}
+
+/// RLE mask of a crowd annotation.
+fn crowd_mask(ann: &JsonDict) -> Option<Rle> {
+    if ann.get("iscrowd").and_then(is_crowd) != Some(true) {
+        return None;
+    }
+    let segmentation = ann.get("segmentation").filter(|v| is_rle(v))?;
+    Rle::from_json(segmentation).ok()
+}
+
+/// Groups the annotations of one image which share a category and whose boxes
+/// overlap by more than `iou_threshold`. Two crowd annotations with RLE masks
+/// are compared by the IoU of their masks instead. Annotations without an
+/// integer `id`, `category_id` or a valid `bbox` are never reported as duplicates.
+pub fn find_duplicates(img_id: i64, anns: &[JsonDict], iou_threshold: f64) -> Vec<DuplicateGroup> {
+    let entries: Vec<(i64, i64, [f64; 4], Option<Rle>)> = anns
+        .iter()
+        .filter_map(|ann| {
+            let ann_id = ann.get("id")?.as_i64()?;
+            let category_id = ann.get("category_id")?.as_i64()?;
+            let bbox = parse_bbox(ann)?;
+            Some((ann_id, category_id, bbox, crowd_mask(ann)))
+        })
+        .collect();
+
+    let mut sets = DisjointSet::new(entries.len());
+
+    // Exact matches are found by hashing, only the remaining representatives are compared pairwise.
+    let mut exact: HashMap<(i64, [u64; 4]), usize> = HashMap::new();
+    let mut representatives = vec![];
+    for (idx, (_, category_id, bbox, mask)) in entries.iter().enumerate() {
+        // Equal boxes say nothing about the masks of crowd regions.
+        if mask.is_some() {
+            representatives.push(idx);
+            continue;
+        }
+        let key = (*category_id, bbox.map(f64::to_bits));
+        match exact.get(&key) {
+            Some(first) => sets.union(*first, idx),
+            None => {
+                exact.insert(key, idx);
+                representatives.push(idx);
+            }
+        }
+    }
+
+    for (i, a) in representatives.iter().enumerate() {
+        for b in representatives.iter().skip(i + 1) {
+            let (_, cat_a, bbox_a, mask_a) = &entries[*a];
+            let (_, cat_b, bbox_b, mask_b) = &entries[*b];
+            if cat_a != cat_b {
+                continue;
+            }
+            let iou = match (mask_a, mask_b) {
+                (Some(mask_a), Some(mask_b)) => rle_iou(mask_a, mask_b).ok(),
+                _ => None,
+            };
+            if iou.unwrap_or_else(|| bbox_iou(bbox_a, bbox_b)) > iou_threshold {
+                sets.union(*a, *b);
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<i64>> = HashMap::new();
+    let mut roots = vec![];
+    for (idx, (ann_id, _, _, _)) in entries.iter().enumerate() {
+        let root = sets.find(idx);
+        let group = members.entry(root).or_default();
+        if group.is_empty() {
+            roots.push(root);
+        }
+        group.push(*ann_id);
+    }
+
+    roots
+        .into_iter()
+        .filter_map(|root| {
+            let ann_ids = members.remove(&root)?;
+            if ann_ids.len() < 2 {
+                return None;
+            }
+            Some(DuplicateGroup { img_id, ann_ids })
+        })
+        .collect()
+}
+
+/// Drops annotations whose `category_id` and `bbox` (within `eps`) equal an
+/// earlier annotation's. Returns the kept annotations and the number removed.
+pub fn dedup_anns(anns: Vec<JsonDict>, eps: f64) -> (Vec<JsonDict>, usize) {
+    let mut kept: Vec<JsonDict> = Vec::with_capacity(anns.len());
+    let mut kept_keys: Vec<(i64, [f64; 4])> = vec![];
+    let mut removed = 0;
+
+    for ann in anns {
+        let key = ann
+            .get("category_id")
+            .and_then(|v| v.as_i64())
+            .zip(parse_bbox(&ann));
+
+        if let Some((category_id, bbox)) = key {
+            let is_dup = kept_keys.iter().any(|(other_cat, other_bbox)| {
+                *other_cat == category_id
+                    && bbox
+                        .iter()
+                        .zip(other_bbox.iter())
+                        .all(|(a, b)| (a - b).abs() <= eps)
+            });
+            if is_dup {
+                removed += 1;
+                continue;
+            }
+            kept_keys.push((category_id, bbox));
+        }
+        kept.push(ann);
+    }
+
+    (kept, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ann(id: i64, category_id: i64, bbox: [f64; 4]) -> JsonDict {
+        json!({"id": id, "image_id": 1, "category_id": category_id, "bbox": bbox})
+    }
+
+    #[test]
+    fn test_exact_duplicates() {
+        let anns = vec![
+            ann(1, 1, [0.0, 0.0, 10.0, 10.0]),
+            ann(2, 1, [0.0, 0.0, 10.0, 10.0]),
+            ann(3, 1, [50.0, 50.0, 10.0, 10.0]),
+        ];
+        let groups = find_duplicates(1, &anns, 0.9);
+        assert_eq!(
+            groups,
+            vec![DuplicateGroup {
+                img_id: 1,
+                ann_ids: vec![1, 2]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_near_duplicates_threshold() {
+        // IoU = 90 / 110
+        let anns = vec![
+            ann(1, 1, [0.0, 0.0, 10.0, 10.0]),
+            ann(2, 1, [1.0, 0.0, 10.0, 10.0]),
+        ];
+        assert_eq!(find_duplicates(1, &anns, 0.8).len(), 1);
+        assert!(find_duplicates(1, &anns, 0.85).is_empty());
+    }
+
+    #[test]
+    fn test_different_categories_are_not_duplicates() {
+        let anns = vec![
+            ann(1, 1, [0.0, 0.0, 10.0, 10.0]),
+            ann(2, 2, [0.0, 0.0, 10.0, 10.0]),
+        ];
+        assert!(find_duplicates(1, &anns, 0.5).is_empty());
+    }
+
+    #[test]
+    fn test_crowd_masks() {
+        let crowd = |id: i64, counts: [u32; 3]| {
+            json!({"id": id, "image_id": 1, "category_id": 1, "iscrowd": 1,
+                "bbox": [0.0, 0.0, 4.0, 4.0], "segmentation": {"size": [4, 4], "counts": counts}})
+        };
+        let mut anns = vec![
+            crowd(1, [0, 8, 8]),
+            crowd(2, [8, 8, 0]),
+            crowd(3, [0, 7, 9]),
+        ];
+        anns[2]["iscrowd"] = json!(true);
+        assert_eq!(
+            find_duplicates(1, &anns, 0.5),
+            vec![DuplicateGroup {
+                img_id: 1,
+                ann_ids: vec![1, 3]
+            }]
+        );
+    }
+}
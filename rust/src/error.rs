@@ -0,0 +1,78 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io;
+
+use derive_more::Display;
+
+/// Structured errors carried inside the `io::Error`s returned by this crate.
+/// Use `io::Error::get_ref` and `downcast_ref::<CocoError>` to inspect them.
+#[derive(Debug, Display)]
+pub enum CocoError {
+    #[display(
+        fmt = "Cannot parse the record of image id: {}, annotation id: {:?} at offset: {}. {}",
+        img_id,
+        ann_id,
+        pos,
+        source
+    )]
+    FieldParse {
+        img_id: i64,
+        ann_id: Option<i64>,
+        pos: u64,
+        source: io::Error,
+    },
+    #[display(fmt = "The decompressed input exceeds the limit of {} bytes.", limit)]
+    DecompressionLimitExceeded { limit: u64 },
+    #[display(fmt = "Invalid compressed RLE counts at byte {}: {}", pos, reason)]
+    RleString { pos: usize, reason: &'static str },
+    /// A record or an eagerly parsed section starting at `offset` is longer
+    /// than `limit` bytes. The scan stops at the limit.
+    #[display(
+        fmt = "The value at offset {} is longer than the limit of {} bytes.",
+        offset,
+        limit
+    )]
+    RecordTooLarge { offset: u64, limit: u64 },
+    /// Image ids are integers in some records and strings in others.
+    #[display(
+        fmt = "Mixed image id types: {} integers and {} strings.",
+        integers,
+        strings
+    )]
+    MixedIdTypes { integers: usize, strings: usize },
+    /// `offset` is the absolute position in the file where `serde_json` gave up.
+    #[display(fmt = "[Parse error, offset={}] {}", offset, source)]
+    Json {
+        offset: u64,
+        source: serde_json::Error,
+    },
+}
+
+impl std::error::Error for CocoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CocoError::FieldParse { source, .. } => Some(source),
+            CocoError::Json { source, .. } => Some(source),
+            CocoError::DecompressionLimitExceeded { .. }
+            | CocoError::RleString { .. }
+            | CocoError::RecordTooLarge { .. }
+            | CocoError::MixedIdTypes { .. } => None,
+        }
+    }
+}
+
+impl From<CocoError> for io::Error {
+    fn from(value: CocoError) -> Self {
+        let kind = match value {
+            CocoError::DecompressionLimitExceeded { .. } => io::ErrorKind::OutOfMemory,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, value)
+    }
+}
+
+pub fn coco_error(err: &io::Error) -> Option<&CocoError> {
+    err.get_ref()?.downcast_ref::<CocoError>()
+}
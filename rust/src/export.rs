@@ -0,0 +1,473 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read, Seek, Write},
+};
+
+use crate::{
+    coco_page_mapper::CocoPageMapper, duplicates::find_duplicates, filter::AnnFilter,
+    page_maps::JsonDict, utils::invalid_data, writer::CocoWriter,
+};
+
+#[derive(Debug, Clone)]
+pub enum ExportOption {
+    /// Keep only the first annotation of every group found by `find_duplicate_anns`.
+    DropDuplicateAnns { iou_threshold: f64 },
+    /// Export only the given images and their annotations.
+    Subset(Vec<i64>),
+    /// Rewrite `annotations[].id` to 1..N in output order. Annotations without
+    /// an id (panoptic) are left as is. Ids referenced from other fields, such
+    /// as `attributes`, are not rewritten.
+    ReindexAnnotations,
+    /// Assign contiguous image ids starting from 1 and rewrite `annotations[].image_id`
+    /// accordingly. Images listed in the map get the given ids instead, the
+    /// other images skip the ids taken by them. Two images mapped to the same
+    /// id are an error.
+    ReindexImages(HashMap<i64, i64>),
+    /// Export only the annotations matching the filter.
+    Filter(AnnFilter),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CategorySubsetOptions {
+    /// Keep the images left without annotations, with no annotations.
+    pub keep_empty_images: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    pub num_images: usize,
+    pub num_annotations: usize,
+    pub dropped_ann_ids: Vec<i64>,
+    /// `(old, new)` annotation ids in output order, filled by `ReindexAnnotations`.
+    pub ann_id_mapping: Vec<(i64, i64)>,
+    /// `(old, new)` image ids in output order, filled by `ReindexImages`.
+    pub img_id_mapping: Vec<(i64, i64)>,
+}
+
+fn reindex_images(
+    img_ids: &[i64],
+    targets: &HashMap<i64, i64>,
+) -> Result<Vec<(i64, i64)>, io::Error> {
+    let mut taken = HashSet::new();
+    for (old, new) in targets.iter() {
+        if !taken.insert(*new) {
+            let msg = format!(
+                "Cannot reindex image id: {}, the target id: {} is already assigned",
+                old, new
+            );
+            return Err(invalid_data(msg.as_str()));
+        }
+    }
+
+    let mut next_id = 1;
+    let mut mapping = Vec::with_capacity(img_ids.len());
+    for img_id in img_ids {
+        let new_id = match targets.get(img_id) {
+            Some(new_id) => *new_id,
+            None => {
+                while taken.contains(&next_id) {
+                    next_id += 1;
+                }
+                next_id += 1;
+                next_id - 1
+            }
+        };
+        mapping.push((*img_id, new_id));
+    }
+    Ok(mapping)
+}
+
+/// Streams a COCO file built from `mapper`. `on_item` maps every image of
+/// `img_ids` to the image entries to emit, `on_anns` does the same for the
+/// annotations of each image.
+pub(crate) fn rewrite_dataset<R, W, FI, FA>(
+    mapper: &CocoPageMapper,
+    mut reader: R,
+    writer: W,
+    img_ids: &[i64],
+    categories: &JsonDict,
+    mut on_item: FI,
+    mut on_anns: FA,
+) -> Result<(), io::Error>
+where
+    R: Read + Seek,
+    W: Write,
+    FI: FnMut(i64, JsonDict) -> Result<Vec<JsonDict>, io::Error>,
+    FA: FnMut(i64, Vec<JsonDict>) -> Result<Vec<JsonDict>, io::Error>,
+{
+    let mut writer = CocoWriter::new(writer);
+    writer.write_licenses(mapper.licenses(&mut reader)?)?;
+    writer.write_info(mapper.info(&mut reader)?)?;
+    writer.write_categories(categories)?;
+
+    writer.begin_images()?;
+    for img_id in img_ids {
+        let item = mapper.get_item_dict(*img_id, &mut reader)?;
+        for item in on_item(*img_id, item)? {
+            writer.write_image(&item)?;
+        }
+    }
+    writer.end_images()?;
+
+    writer.begin_annotations()?;
+    for img_id in img_ids {
+        let anns = mapper.get_anns_dict(*img_id, &mut reader)?;
+        for ann in on_anns(*img_id, anns)? {
+            writer.write_annotation(&ann)?;
+        }
+    }
+    writer.end_annotations()?;
+    writer.finish()?;
+    Ok(())
+}
+
+impl CocoPageMapper {
+    pub fn export(
+        &self,
+        mut reader: impl Read + Seek,
+        mut writer: impl Write,
+        options: &[ExportOption],
+    ) -> Result<ExportReport, io::Error> {
+        let mut report = ExportReport::default();
+
+        let mut dup_iou_threshold = None;
+        let mut subset = None;
+        let mut reindex_anns = false;
+        let mut img_targets = None;
+        let mut filters = vec![];
+        for option in options {
+            match option {
+                ExportOption::DropDuplicateAnns { iou_threshold } => {
+                    dup_iou_threshold = Some(*iou_threshold)
+                }
+                ExportOption::Subset(img_ids) => {
+                    subset = Some(img_ids.iter().copied().collect::<HashSet<i64>>())
+                }
+                ExportOption::ReindexAnnotations => reindex_anns = true,
+                ExportOption::ReindexImages(targets) => img_targets = Some(targets),
+                ExportOption::Filter(filter) => filters.push(filter),
+            }
+        }
+
+        let img_ids: Vec<i64> = self
+            .get_img_ids()
+            .iter()
+            .copied()
+            .filter(|img_id| subset.as_ref().is_none_or(|v| v.contains(img_id)))
+            .collect();
+
+        let new_img_ids: HashMap<i64, i64> = match img_targets {
+            Some(targets) => {
+                report.img_id_mapping = reindex_images(&img_ids, targets)?;
+                report.img_id_mapping.iter().copied().collect()
+            }
+            None => HashMap::new(),
+        };
+
+        let mut next_ann_id = 1;
+        rewrite_dataset(
+            self,
+            &mut reader,
+            &mut writer,
+            &img_ids,
+            self.categories()?,
+            |img_id, mut item| {
+                if let Some(new_id) = new_img_ids.get(&img_id) {
+                    item["id"] = (*new_id).into();
+                }
+                report.num_images += 1;
+                Ok(vec![item])
+            },
+            |img_id, anns| {
+                let mut dropped = HashSet::new();
+                if let Some(iou_threshold) = dup_iou_threshold {
+                    for group in find_duplicates(img_id, &anns, iou_threshold) {
+                        dropped.extend(group.ann_ids.into_iter().skip(1));
+                    }
+                }
+
+                let mut kept = Vec::with_capacity(anns.len());
+                for mut ann in anns {
+                    if !filters.iter().all(|v| v.matches(&ann)) {
+                        continue;
+                    }
+                    let ann_id = ann.get("id").and_then(|v| v.as_i64());
+                    if let Some(ann_id) = ann_id.filter(|v| dropped.contains(v)) {
+                        report.dropped_ann_ids.push(ann_id);
+                        continue;
+                    }
+                    if let (true, Some(ann_id)) = (reindex_anns, ann_id) {
+                        ann["id"] = next_ann_id.into();
+                        report.ann_id_mapping.push((ann_id, next_ann_id));
+                        next_ann_id += 1;
+                    }
+                    if let Some(new_id) = new_img_ids.get(&img_id) {
+                        ann["image_id"] = (*new_id).into();
+                    }
+                    kept.push(ann);
+                }
+                report.num_annotations += kept.len();
+                Ok(kept)
+            },
+        )?;
+
+        Ok(report)
+    }
+
+    pub fn write_category_subset(
+        &self,
+        reader: impl Read + Seek,
+        keep_categories: &[i64],
+        writer: impl Write,
+    ) -> Result<(), io::Error> {
+        self.write_category_subset_with_options(
+            reader,
+            keep_categories,
+            writer,
+            &CategorySubsetOptions::default(),
+        )
+    }
+
+    /// Writes a COCO file with only the kept categories and the annotations
+    /// of them. Images without such annotations are dropped, unless
+    /// `keep_empty_images` is set. Panoptic annotations, which have no
+    /// `category_id`, are dropped.
+    pub fn write_category_subset_with_options(
+        &self,
+        reader: impl Read + Seek,
+        keep_categories: &[i64],
+        writer: impl Write,
+        options: &CategorySubsetOptions,
+    ) -> Result<(), io::Error> {
+        let keep: HashSet<i64> = keep_categories.iter().copied().collect();
+        let categories: Vec<JsonDict> = self
+            .categories()?
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|v| {
+                v.get("id")
+                    .and_then(|v| v.as_i64())
+                    .is_some_and(|v| keep.contains(&v))
+            })
+            .cloned()
+            .collect();
+
+        let img_ids: Vec<i64> = match options.keep_empty_images {
+            true => self.get_img_ids().clone(),
+            false => {
+                let kept_img_ids = self.img_ids_with_categories(&keep);
+                self.get_img_ids()
+                    .iter()
+                    .copied()
+                    .filter(|img_id| kept_img_ids.contains(img_id))
+                    .collect()
+            }
+        };
+
+        rewrite_dataset(
+            self,
+            reader,
+            writer,
+            &img_ids,
+            &JsonDict::Array(categories),
+            |_, item| Ok(vec![item]),
+            |_, anns| {
+                Ok(anns
+                    .into_iter()
+                    .filter(|ann| {
+                        ann.get("category_id")
+                            .and_then(|v| v.as_i64())
+                            .is_some_and(|v| keep.contains(&v))
+                    })
+                    .collect())
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_utils::{prepare, EXAMPLE};
+
+    fn export_to_mapper(
+        options: &[ExportOption],
+    ) -> (Cursor<Vec<u8>>, CocoPageMapper, ExportReport) {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let mut buf = vec![];
+        let report = coco_page_mapper
+            .export(&mut reader, &mut buf, options)
+            .unwrap();
+
+        let mut reader = Cursor::new(buf);
+        let exported = CocoPageMapper::new(&mut reader).unwrap();
+        (reader, exported, report)
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let (mut reader, exported, report) = export_to_mapper(&[]);
+
+        assert_eq!(report.num_images, 2);
+        assert_eq!(report.num_annotations, 5);
+        assert_eq!(exported.get_img_ids(), &vec![5, 6]);
+        assert_eq!(exported.get_anns_dict(5, &mut reader).unwrap().len(), 3);
+        assert_eq!(exported.get_anns_dict(6, &mut reader).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_export_drop_duplicate_anns() {
+        let (mut reader, exported, report) =
+            export_to_mapper(&[ExportOption::DropDuplicateAnns { iou_threshold: 0.9 }]);
+
+        assert_eq!(report.num_annotations, 2);
+        assert_eq!(report.dropped_ann_ids.len(), 3);
+        for img_id in [5, 6] {
+            assert_eq!(
+                exported.get_anns_dict(img_id, &mut reader).unwrap().len(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn test_export_reindex_annotations() {
+        let (mut reader, exported, report) = export_to_mapper(&[
+            ExportOption::Subset(vec![6]),
+            ExportOption::ReindexAnnotations,
+        ]);
+
+        assert_eq!(exported.get_img_ids(), &vec![6]);
+
+        let anns = exported.get_anns_dict(6, &mut reader).unwrap();
+        let mut new_ids: Vec<i64> = anns.iter().map(|v| v["id"].as_i64().unwrap()).collect();
+        new_ids.sort();
+        assert_eq!(new_ids, vec![1, 2]);
+
+        let mut mapping = report.ann_id_mapping.clone();
+        mapping.sort();
+        assert_eq!(
+            mapping.iter().map(|(old, _)| *old).collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+        for (old, new) in report.ann_id_mapping {
+            let ann = anns.iter().find(|v| v["id"].as_i64() == Some(new)).unwrap();
+            assert_eq!(ann["image_id"].as_i64(), Some(6));
+            assert!(old == 4 || old == 5);
+        }
+    }
+
+    #[test]
+    fn test_export_reindex_panoptic_is_noop() {
+        const EXAMPLE: &str = r#"
+        {"licenses":[],"info":{},"categories":[],"images":[{"id":1,"width":4,"height":4,"file_name":"1.jpg"}],"annotations":[{"image_id":1,"file_name":"1.png","segments_info":[{"id":3,"category_id":5}]}]}
+        "#;
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let mut buf = vec![];
+        let report = coco_page_mapper
+            .export(&mut reader, &mut buf, &[ExportOption::ReindexAnnotations])
+            .unwrap();
+
+        assert!(report.ann_id_mapping.is_empty());
+        let exported: JsonDict = serde_json::from_slice(&buf).unwrap();
+        assert!(exported["annotations"][0].get("id").is_none());
+        assert_eq!(exported["annotations"][0]["segments_info"][0]["id"], 3);
+    }
+
+    #[test]
+    fn test_export_reindex_images() {
+        let (mut reader, exported, report) =
+            export_to_mapper(&[ExportOption::ReindexImages(HashMap::new())]);
+
+        assert_eq!(report.img_id_mapping, vec![(5, 1), (6, 2)]);
+        assert_eq!(exported.get_img_ids(), &vec![1, 2]);
+
+        for (img_id, count) in [(1, 3), (2, 2)] {
+            let item = exported.get_item_dict(img_id, &mut reader).unwrap();
+            assert_eq!(item["id"].as_i64(), Some(img_id));
+
+            let anns = exported.get_anns_dict(img_id, &mut reader).unwrap();
+            assert_eq!(anns.len(), count);
+            for ann in anns {
+                assert_eq!(ann["image_id"].as_i64(), Some(img_id));
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_reindex_images_with_targets() {
+        let (_, _, report) =
+            export_to_mapper(&[ExportOption::ReindexImages(HashMap::from([(6, 1)]))]);
+        assert_eq!(report.img_id_mapping, vec![(5, 2), (6, 1)]);
+
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let err = coco_page_mapper
+            .export(
+                &mut reader,
+                io::sink(),
+                &[ExportOption::ReindexImages(HashMap::from([(5, 7), (6, 7)]))],
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_write_category_subset() {
+        let example = EXAMPLE
+            .replacen(
+                r#""id":4,"image_id":6,"category_id":2"#,
+                r#""id":4,"image_id":6,"category_id":1"#,
+                1,
+            )
+            .replacen(
+                r#""id":5,"image_id":6,"category_id":2"#,
+                r#""id":5,"image_id":6,"category_id":1"#,
+                1,
+            );
+        let (mut reader, coco_page_mapper) = prepare(&example);
+
+        let mut buf = vec![];
+        coco_page_mapper
+            .write_category_subset(&mut reader, &[2], &mut buf)
+            .unwrap();
+        let mut exported_reader = Cursor::new(buf);
+        let exported = CocoPageMapper::new(&mut exported_reader).unwrap();
+
+        assert_eq!(exported.get_img_ids(), &vec![5]);
+        assert_eq!(exported.annotation_count(), 3);
+        assert_eq!(exported.categories().unwrap().as_array().unwrap().len(), 1);
+        assert_eq!(exported.categories().unwrap()[0]["name"], "b");
+        assert_eq!(
+            exported.get_item_dict(5, &mut exported_reader).unwrap(),
+            coco_page_mapper.get_item_dict(5, &mut reader).unwrap()
+        );
+        assert_eq!(
+            exported.get_anns_dict(5, &mut exported_reader).unwrap(),
+            coco_page_mapper.get_anns_dict(5, &mut reader).unwrap()
+        );
+
+        let mut buf = vec![];
+        coco_page_mapper
+            .write_category_subset_with_options(
+                &mut reader,
+                &[2],
+                &mut buf,
+                &CategorySubsetOptions {
+                    keep_empty_images: true,
+                },
+            )
+            .unwrap();
+        let exported = CocoPageMapper::new(Cursor::new(buf)).unwrap();
+        assert_eq!(exported.get_img_ids(), &vec![5, 6]);
+        assert_eq!(exported.annotation_count(), 3);
+    }
+}
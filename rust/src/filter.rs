@@ -0,0 +1,94 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io::{self, Read, Seek};
+
+use crate::{coco_page_mapper::CocoPageMapper, page_maps::JsonDict};
+
+/// Conditions an annotation must satisfy, combined with a logical and.
+#[derive(Debug, Clone, Default)]
+pub struct AnnFilter {
+    min_keypoints: Option<usize>,
+}
+
+/// Number of keypoints with a non-zero visibility flag.
+pub fn num_labeled_keypoints(ann: &JsonDict) -> usize {
+    match ann.get("keypoints").and_then(|v| v.as_array()) {
+        Some(keypoints) => keypoints
+            .chunks(3)
+            .filter(|v| v.get(2).and_then(|v| v.as_f64()).unwrap_or(0.0) > 0.0)
+            .count(),
+        None => ann
+            .get("num_keypoints")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize,
+    }
+}
+
+impl AnnFilter {
+    /// Keep annotations with at least `n` labeled keypoints.
+    pub fn min_keypoints(n: usize) -> Self {
+        Self::default().with_min_keypoints(n)
+    }
+
+    pub fn with_min_keypoints(mut self, n: usize) -> Self {
+        self.min_keypoints = Some(n);
+        self
+    }
+
+    pub fn matches(&self, ann: &JsonDict) -> bool {
+        if let Some(n) = self.min_keypoints {
+            if num_labeled_keypoints(ann) < n {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl CocoPageMapper {
+    /// Ids of the annotations for which `pred` holds, in file order. Every
+    /// annotation is read and parsed, prefer the indexed lookups where they
+    /// suffice. Annotations without an `id` are never returned.
+    pub fn filter_anns(
+        &self,
+        reader: impl Read + Seek,
+        pred: impl Fn(&JsonDict) -> bool,
+    ) -> Result<Vec<i64>, io::Error> {
+        let mut ann_ids = vec![];
+        for ann in self.iter_all_anns(reader) {
+            let ann = ann?;
+            if let Some(ann_id) = ann.get("id").and_then(|v| v.as_i64()) {
+                if pred(&ann) {
+                    ann_ids.push(ann_id);
+                }
+            }
+        }
+        Ok(ann_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{prepare, EXAMPLE};
+
+    #[test]
+    fn test_filter_anns() {
+        let mut example = EXAMPLE.to_owned();
+        for ann_id in [2, 4] {
+            let ann = format!(r#"{{"id":{},"image_id""#, ann_id);
+            let pos = example.find(&ann).unwrap();
+            let area = pos + example[pos..].find(r#""area":3.0"#).unwrap();
+            example.replace_range(area..area + 10, r#""area":2.0"#);
+        }
+        let (mut reader, coco_page_mapper) = prepare(&example);
+
+        let ann_ids = coco_page_mapper
+            .filter_anns(&mut reader, |ann| {
+                ann.get("area").and_then(|v| v.as_f64()).unwrap_or(0.0) > 2.5
+            })
+            .unwrap();
+        assert_eq!(ann_ids, vec![1, 3, 5]);
+    }
+}
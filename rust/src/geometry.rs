@@ -0,0 +1,212 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use crate::page_maps::JsonDict;
+
+/// Area of a flat `[x0, y0, x1, y1, ...]` ring by the shoelace formula.
+pub fn polygon_area(points: &[f64]) -> f64 {
+    let n = points.len() / 2;
+    let mut area = 0.0;
+    for i in 0..n {
+        let j = (i + 1) % n;
+        area += points[2 * i] * points[2 * j + 1] - points[2 * j] * points[2 * i + 1];
+    }
+    area.abs() / 2.0
+}
+
+/// Polygon rings of a segmentation, `None` if it is not a polygon list.
+pub fn parse_polygons(segmentation: &JsonDict) -> Option<Vec<Vec<f64>>> {
+    segmentation
+        .as_array()?
+        .iter()
+        .map(|ring| ring.as_array()?.iter().map(|v| v.as_f64()).collect())
+        .collect()
+}
+
+/// Box in COCO `[x, y, w, h]` form.
+pub type BBox = [f64; 4];
+
+/// Bounding box of a flat `[x0, y0, x1, y1, ...]` ring.
+pub fn points_bbox(points: &[f64]) -> Option<BBox> {
+    if points.len() < 2 {
+        return None;
+    }
+    let xs = points.iter().step_by(2);
+    let ys = points.iter().skip(1).step_by(2);
+    let (x0, x1) = xs.fold((f64::MAX, f64::MIN), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+    let (y0, y1) = ys.fold((f64::MAX, f64::MIN), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+    Some([x0, y0, x1 - x0, y1 - y0])
+}
+
+pub fn bbox_union(a: &BBox, b: &BBox) -> BBox {
+    let x0 = a[0].min(b[0]);
+    let y0 = a[1].min(b[1]);
+    let x1 = (a[0] + a[2]).max(b[0] + b[2]);
+    let y1 = (a[1] + a[3]).max(b[1] + b[3]);
+    [x0, y0, x1 - x0, y1 - y0]
+}
+
+pub fn bbox_intersection(a: &BBox, b: &BBox) -> Option<BBox> {
+    let x0 = a[0].max(b[0]);
+    let y0 = a[1].max(b[1]);
+    let x1 = (a[0] + a[2]).min(b[0] + b[2]);
+    let y1 = (a[1] + a[3]).min(b[1] + b[3]);
+    if x1 <= x0 || y1 <= y0 {
+        return None;
+    }
+    Some([x0, y0, x1 - x0, y1 - y0])
+}
+
+/// Clips a flat ring to the window with the Sutherland-Hodgman algorithm.
+pub fn clip_polygon(points: &[f64], window: &BBox) -> Vec<f64> {
+    let (x0, y0) = (window[0], window[1]);
+    let (x1, y1) = (window[0] + window[2], window[1] + window[3]);
+
+    // Each edge is given by an inside test and the intersection with the clip line.
+    type Inside = fn(f64, f64, f64) -> bool;
+    let edges: [(Inside, f64, bool); 4] = [
+        (|x, _, b| x >= b, x0, true),
+        (|x, _, b| x <= b, x1, true),
+        (|_, y, b| y >= b, y0, false),
+        (|_, y, b| y <= b, y1, false),
+    ];
+
+    let mut output: Vec<(f64, f64)> = points.chunks_exact(2).map(|p| (p[0], p[1])).collect();
+
+    for (inside, bound, vertical) in edges {
+        let input = std::mem::take(&mut output);
+        let Some(mut prev) = input.last().copied() else {
+            break;
+        };
+        for curr in input {
+            let curr_in = inside(curr.0, curr.1, bound);
+            let prev_in = inside(prev.0, prev.1, bound);
+            if curr_in != prev_in {
+                let t = if vertical {
+                    (bound - prev.0) / (curr.0 - prev.0)
+                } else {
+                    (bound - prev.1) / (curr.1 - prev.1)
+                };
+                output.push((
+                    prev.0 + t * (curr.0 - prev.0),
+                    prev.1 + t * (curr.1 - prev.1),
+                ));
+            }
+            if curr_in {
+                output.push(curr);
+            }
+            prev = curr;
+        }
+    }
+
+    output.into_iter().flat_map(|(x, y)| [x, y]).collect()
+}
+
+fn segment_distance(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len2 = dx * dx + dy * dy;
+    if len2 == 0.0 {
+        return ((p.0 - a.0).powi(2) + (p.1 - a.1).powi(2)).sqrt();
+    }
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len2).clamp(0.0, 1.0);
+    ((p.0 - a.0 - t * dx).powi(2) + (p.1 - a.1 - t * dy).powi(2)).sqrt()
+}
+
+/// Marks the points of `points[first..=last]` kept by Douglas-Peucker.
+fn douglas_peucker(
+    points: &[(f64, f64)],
+    first: usize,
+    last: usize,
+    epsilon: f64,
+    keep: &mut [bool],
+) {
+    if last <= first + 1 {
+        return;
+    }
+    let (mut max_idx, mut max_dist) = (first, 0.0);
+    for idx in first + 1..last {
+        let dist = segment_distance(points[idx], points[first], points[last]);
+        if dist > max_dist {
+            (max_idx, max_dist) = (idx, dist);
+        }
+    }
+    if max_dist > epsilon {
+        keep[max_idx] = true;
+        douglas_peucker(points, first, max_idx, epsilon, keep);
+        douglas_peucker(points, max_idx, last, epsilon, keep);
+    }
+}
+
+/// Simplifies a flat closed ring with Douglas-Peucker. Rings are never reduced
+/// below 3 points.
+pub fn simplify_ring(points: &[f64], epsilon: f64) -> Vec<f64> {
+    let pts: Vec<(f64, f64)> = points.chunks_exact(2).map(|p| (p[0], p[1])).collect();
+    if pts.len() <= 3 {
+        return points.to_vec();
+    }
+
+    // The ring is split at the first point and the point farthest from it.
+    let dist = |i: usize| (pts[i].0 - pts[0].0).powi(2) + (pts[i].1 - pts[0].1).powi(2);
+    let far = (1..pts.len()).fold(1, |best, i| if dist(i) > dist(best) { i } else { best });
+
+    let mut closed = pts.clone();
+    closed.push(pts[0]);
+    let mut keep = vec![false; closed.len()];
+    keep[0] = true;
+    keep[far] = true;
+    douglas_peucker(&closed, 0, far, epsilon, &mut keep);
+    douglas_peucker(&closed, far, pts.len(), epsilon, &mut keep);
+
+    if keep.iter().filter(|v| **v).count() < 3 {
+        let third = (1..pts.len())
+            .filter(|i| *i != far)
+            .map(|i| (i, segment_distance(pts[i], pts[0], pts[far])))
+            .fold((1, -1.0), |best, v| if v.1 > best.1 { v } else { best });
+        keep[third.0] = true;
+    }
+
+    pts.iter()
+        .zip(keep.iter())
+        .filter(|(_, keep)| **keep)
+        .flat_map(|(p, _)| [p.0, p.1])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polygon_area() {
+        assert_eq!(polygon_area(&[0.0, 0.0, 4.0, 0.0, 4.0, 2.0, 0.0, 2.0]), 8.0);
+        assert_eq!(polygon_area(&[0.0, 0.0, 1.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn test_clip_polygon() {
+        let square = [0.0, 0.0, 4.0, 0.0, 4.0, 4.0, 0.0, 4.0];
+
+        let clipped = clip_polygon(&square, &[2.0, 2.0, 10.0, 10.0]);
+        assert_eq!(polygon_area(&clipped), 4.0);
+        assert_eq!(points_bbox(&clipped), Some([2.0, 2.0, 2.0, 2.0]));
+
+        let triangle = [0.0, 0.0, 4.0, 0.0, 0.0, 4.0];
+        let clipped = clip_polygon(&triangle, &[0.0, 0.0, 2.0, 10.0]);
+        assert_eq!(polygon_area(&clipped), 6.0);
+
+        assert!(clip_polygon(&square, &[5.0, 5.0, 1.0, 1.0]).is_empty());
+    }
+
+    #[test]
+    fn test_simplify_ring() {
+        let square = [0.0, 0.0, 2.0, 0.0, 4.0, 0.0, 4.0, 4.0, 0.0, 4.0];
+        assert_eq!(
+            simplify_ring(&square, 0.1),
+            vec![0.0, 0.0, 4.0, 0.0, 4.0, 4.0, 0.0, 4.0]
+        );
+
+        let flat = [0.0, 0.0, 1.0, 0.01, 2.0, 0.0, 3.0, 0.01];
+        assert_eq!(simplify_ring(&flat, 1.0).len(), 6);
+    }
+}
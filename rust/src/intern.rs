@@ -0,0 +1,253 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{collections::HashSet, fmt, mem::size_of, sync::Arc};
+
+use serde::de::{DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::Number;
+
+use crate::{memory::hash_set_bytes, page_maps::JsonDict};
+
+/// Hands out one shared `Arc<str>` per distinct string.
+#[derive(Debug, Default)]
+pub struct StringInterner {
+    strings: HashSet<Arc<str>>,
+}
+
+impl StringInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, value: &str) -> Arc<str> {
+        if let Some(v) = self.strings.get(value) {
+            return v.clone();
+        }
+        let v: Arc<str> = Arc::from(value);
+        self.strings.insert(v.clone());
+        v
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    /// Heap bytes of the set and of the strings, with the `Arc` counters.
+    pub fn heap_bytes(&self) -> usize {
+        hash_set_bytes(&self.strings)
+            + self
+                .strings
+                .iter()
+                .map(|v| v.len() + 2 * size_of::<usize>())
+                .sum::<usize>()
+    }
+}
+
+/// A JSON value whose object keys are shared through a `StringInterner`, so
+/// that keeping many records only stores every distinct key once. Objects are
+/// kept as lists in document order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InternedValue {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Array(Vec<InternedValue>),
+    Object(Vec<(Arc<str>, InternedValue)>),
+}
+
+impl InternedValue {
+    pub fn from_json(value: &JsonDict, interner: &mut StringInterner) -> Self {
+        match value {
+            JsonDict::Null => Self::Null,
+            JsonDict::Bool(v) => Self::Bool(*v),
+            JsonDict::Number(v) => Self::Number(v.clone()),
+            JsonDict::String(v) => Self::String(v.clone()),
+            JsonDict::Array(v) => {
+                Self::Array(v.iter().map(|v| Self::from_json(v, interner)).collect())
+            }
+            JsonDict::Object(v) => Self::Object(
+                v.iter()
+                    .map(|(key, value)| (interner.intern(key), Self::from_json(value, interner)))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn to_json(&self) -> JsonDict {
+        match self {
+            Self::Null => JsonDict::Null,
+            Self::Bool(v) => JsonDict::Bool(*v),
+            Self::Number(v) => JsonDict::Number(v.clone()),
+            Self::String(v) => JsonDict::String(v.clone()),
+            Self::Array(v) => JsonDict::Array(v.iter().map(Self::to_json).collect()),
+            Self::Object(v) => JsonDict::Object(
+                v.iter()
+                    .map(|(key, value)| (key.to_string(), value.to_json()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The value of `key` in an object. The last one wins for repeated keys,
+    /// as in `to_json`.
+    pub fn get(&self, key: &str) -> Option<&InternedValue> {
+        match self {
+            Self::Object(v) => v.iter().rev().find(|(k, _)| &**k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Heap bytes below the value, not counting the value itself nor the
+    /// shared keys, see `StringInterner::heap_bytes`.
+    pub fn heap_bytes(&self) -> usize {
+        match self {
+            Self::String(v) => v.capacity(),
+            Self::Array(v) => {
+                v.capacity() * size_of::<Self>() + v.iter().map(Self::heap_bytes).sum::<usize>()
+            }
+            Self::Object(v) => {
+                v.capacity() * size_of::<(Arc<str>, Self)>()
+                    + v.iter().map(|(_, v)| v.heap_bytes()).sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Deserializes an `InternedValue`, interning the object keys as they are
+/// read instead of allocating them.
+pub struct InternSeed<'a>(pub &'a mut StringInterner);
+
+impl<'de> DeserializeSeed<'de> for InternSeed<'_> {
+    type Value = InternedValue;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<InternedValue, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for InternSeed<'_> {
+    type Value = InternedValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<InternedValue, E> {
+        Ok(InternedValue::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<InternedValue, E> {
+        Ok(InternedValue::Number(v.into()))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<InternedValue, E> {
+        Ok(InternedValue::Number(v.into()))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<InternedValue, E> {
+        Ok(Number::from_f64(v).map_or(InternedValue::Null, InternedValue::Number))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<InternedValue, E> {
+        Ok(InternedValue::String(v.to_owned()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<InternedValue, E> {
+        Ok(InternedValue::String(v))
+    }
+
+    fn visit_unit<E>(self) -> Result<InternedValue, E> {
+        Ok(InternedValue::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<InternedValue, E> {
+        Ok(InternedValue::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<InternedValue, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<InternedValue, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element_seed(InternSeed(&mut *self.0))? {
+            values.push(value);
+        }
+        Ok(InternedValue::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<InternedValue, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(key) = map.next_key_seed(KeySeed(&mut *self.0))? {
+            let value = map.next_value_seed(InternSeed(&mut *self.0))?;
+            entries.push((key, value));
+        }
+        Ok(InternedValue::Object(entries))
+    }
+}
+
+struct KeySeed<'a>(&'a mut StringInterner);
+
+impl<'de> DeserializeSeed<'de> for KeySeed<'_> {
+    type Value = Arc<str>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Arc<str>, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de> Visitor<'de> for KeySeed<'_> {
+    type Value = Arc<str>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an object key")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Arc<str>, E> {
+        Ok(self.0.intern(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_intern_seed() {
+        let record =
+            br#"{"id": 1, "bbox": [1.5, -2, 3, 4], "a": {"id": null, "b": [true, "x"]}, "id": 2}"#;
+        let mut interner = StringInterner::new();
+        let mut de = serde_json::Deserializer::from_slice(record);
+        let value = InternSeed(&mut interner).deserialize(&mut de).unwrap();
+
+        let plain: JsonDict = serde_json::from_slice(record).unwrap();
+        assert_eq!(value.to_json(), plain);
+        assert_eq!(value.get("id"), Some(&InternedValue::Number(2.into())));
+        assert_eq!(
+            InternedValue::from_json(&plain, &mut interner).to_json(),
+            plain
+        );
+        // "id", "bbox", "a" and "b".
+        assert_eq!(interner.len(), 4);
+
+        let (InternedValue::Object(outer), Some(InternedValue::Object(inner))) =
+            (&value, value.get("a"))
+        else {
+            panic!("not objects");
+        };
+        assert!(Arc::ptr_eq(&outer[0].0, &inner[0].0));
+        assert_eq!(
+            value.get("a").unwrap().to_json(),
+            json!({"id": null, "b": [true, "x"]})
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek},
+};
+
+use crate::coco_page_mapper::{CocoPageMapper, BATCH_SIZE};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeypointStat {
+    pub category_id: i64,
+    pub index: usize,
+    pub name: String,
+    /// Keypoints with a visibility flag of 1 or 2.
+    pub num_labeled: usize,
+    /// Keypoints with a visibility flag of 2.
+    pub num_visible: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeypointReport {
+    pub stats: Vec<KeypointStat>,
+    /// Annotations whose `keypoints` length is not three times the number of
+    /// keypoints declared by their category.
+    pub mismatched_ann_ids: Vec<i64>,
+}
+
+impl CocoPageMapper {
+    pub fn keypoint_stats(
+        &self,
+        mut reader: impl Read + Seek,
+    ) -> Result<KeypointReport, io::Error> {
+        let mut report = KeypointReport::default();
+        let mut stat_idx: HashMap<i64, usize> = HashMap::new();
+
+        for category in self.categories()?.as_array().into_iter().flatten() {
+            let category_id = category.get("id").and_then(|v| v.as_i64());
+            let names = category.get("keypoints").and_then(|v| v.as_array());
+            if let (Some(category_id), Some(names)) = (category_id, names) {
+                stat_idx.insert(category_id, report.stats.len());
+                for (index, name) in names.iter().enumerate() {
+                    report.stats.push(KeypointStat {
+                        category_id,
+                        index,
+                        name: name.as_str().unwrap_or_default().to_string(),
+                        num_labeled: 0,
+                        num_visible: 0,
+                    });
+                }
+            }
+        }
+
+        for img_ids in self.get_img_ids().chunks(BATCH_SIZE) {
+            for anns in self.get_anns_batch(img_ids, &mut reader)? {
+                for ann in anns {
+                    let Some(keypoints) = ann.get("keypoints").and_then(|v| v.as_array()) else {
+                        continue;
+                    };
+                    let category_id = ann.get("category_id").and_then(|v| v.as_i64());
+                    let first = category_id.and_then(|v| stat_idx.get(&v)).copied();
+                    let num_declared = first.map_or(0, |first| {
+                        report.stats[first..]
+                            .iter()
+                            .take_while(|v| Some(v.category_id) == category_id)
+                            .count()
+                    });
+
+                    if keypoints.len() != 3 * num_declared {
+                        if let Some(ann_id) = ann.get("id").and_then(|v| v.as_i64()) {
+                            report.mismatched_ann_ids.push(ann_id);
+                        }
+                    }
+
+                    let Some(first) = first else {
+                        continue;
+                    };
+                    for (index, point) in keypoints.chunks(3).take(num_declared).enumerate() {
+                        let flag = point.get(2).and_then(|v| v.as_f64()).unwrap_or(0.0);
+                        let stat = &mut report.stats[first + index];
+                        if flag > 0.0 {
+                            stat.num_labeled += 1;
+                        }
+                        if flag >= 2.0 {
+                            stat.num_visible += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        report.mismatched_ann_ids.sort_unstable();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        export::ExportOption,
+        filter::AnnFilter,
+        test_utils::{prepare, reopen},
+    };
+
+    const EXAMPLE: &str = r#"
+    {
+        "licenses":[],
+        "info":{},
+        "categories":[{"id":1,"name":"person","supercategory":"","keypoints":["nose","left_eye","right_eye"],"skeleton":[[1,2],[1,3]]}],
+        "images":[{"id":1,"width":10,"height":10,"file_name":"a.jpg"}],
+        "annotations":[
+            {"id":1,"image_id":1,"category_id":1,"keypoints":[1,1,2,2,2,1,0,0,0],"num_keypoints":2},
+            {"id":2,"image_id":1,"category_id":1,"keypoints":[1,1,2,2,2,2,3,3,2],"num_keypoints":3},
+            {"id":3,"image_id":1,"category_id":1,"keypoints":[1,1,1],"num_keypoints":1}
+        ]
+    }"#;
+
+    #[test]
+    fn test_keypoint_stats() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let report = coco_page_mapper.keypoint_stats(&mut reader).unwrap();
+
+        let counts: Vec<(&str, usize, usize)> = report
+            .stats
+            .iter()
+            .map(|v| (v.name.as_str(), v.num_labeled, v.num_visible))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("nose", 3, 2), ("left_eye", 2, 1), ("right_eye", 1, 1)]
+        );
+        assert_eq!(report.mismatched_ann_ids, vec![3]);
+    }
+
+    #[test]
+    fn test_min_keypoints_filter() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let anns = coco_page_mapper
+            .get_anns_filtered(1, &mut reader, &AnnFilter::min_keypoints(2))
+            .unwrap();
+        let mut ids: Vec<i64> = anns.iter().map(|v| v["id"].as_i64().unwrap()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+
+        let mut buf = vec![];
+        coco_page_mapper
+            .export(
+                &mut reader,
+                &mut buf,
+                &[ExportOption::Filter(AnnFilter::min_keypoints(3))],
+            )
+            .unwrap();
+        let (mut reader, exported) = reopen(buf);
+        let anns = exported.get_anns_dict(1, &mut reader).unwrap();
+        assert_eq!(anns.len(), 1);
+        assert_eq!(anns[0]["id"].as_i64(), Some(2));
+    }
+}
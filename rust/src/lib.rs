@@ -2,24 +2,67 @@
 //
 //  SPDX-License-Identifier: MIT
 
-mod coco_page_mapper;
-mod page_maps;
-mod utils;
-
-use std::{fs::File, io::BufReader, path::Path};
+// pyo3 0.19 macros expand to impl blocks nested in generated functions.
+#![allow(non_local_definitions)]
+
+pub mod cache;
+pub mod coco_page_mapper;
+pub mod compression;
+pub mod convert;
+pub mod datumaro;
+pub mod duplicates;
+pub mod error;
+pub mod export;
+pub mod filter;
+pub mod geometry;
+pub mod intern;
+pub mod keypoints;
+pub mod memory;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+pub mod offset_index;
+pub mod page_maps;
+#[cfg(feature = "rayon")]
+mod parallel;
+pub mod profiling;
+pub mod progress;
+pub mod rle;
+pub mod selection;
+pub mod spatial;
+pub mod stats;
+#[cfg(test)]
+mod test_utils;
+#[cfg(any(test, feature = "testutils"))]
+pub mod testing;
+pub mod transform;
+#[cfg(feature = "uring")]
+pub mod uring;
+pub mod utils;
+pub mod validation;
+pub mod writer;
+pub mod yolo;
+
+use std::{
+    io::{self, BufReader},
+    path::PathBuf,
+};
 
-use crate::coco_page_mapper::CocoPageMapper as CocoPageMapperImpl;
+use crate::cache::{content_fingerprint, CacheBudget, CachedPageMapper};
+use crate::compression::PositionedFile;
+use crate::selection::FieldSelection;
 use pyo3::{
     exceptions::PyValueError,
     prelude::*,
     types::{PyBool, PyDict, PyFloat, PyList, PyUnicode},
 };
-use serde_json;
 
 #[pyclass]
 struct CocoPageMapper {
-    reader: BufReader<File>,
-    mapper: CocoPageMapperImpl,
+    inner: CachedPageMapper<BufReader<PositionedFile>>,
+    /// The mapped file, fingerprinted to notice changes under the cache.
+    path: PathBuf,
 }
 
 fn convert_to_py_object(value: &serde_json::Value, py: Python<'_>) -> PyResult<PyObject> {
@@ -30,7 +73,7 @@ fn convert_to_py_object(value: &serde_json::Value, py: Python<'_>) -> PyResult<P
             list.append(convert_to_py_object(child, py)?)?;
         }
 
-        return Ok(list.into());
+        Ok(list.into())
     } else if value.is_object() {
         let dict = PyDict::new(py);
 
@@ -39,69 +82,140 @@ fn convert_to_py_object(value: &serde_json::Value, py: Python<'_>) -> PyResult<P
             dict.set_item(key, child)?;
         }
 
-        return Ok(dict.into());
+        Ok(dict.into())
     } else if value.is_boolean() {
-        return Ok(PyBool::new(py, value.as_bool().unwrap()).into());
+        Ok(PyBool::new(py, value.as_bool().unwrap()).into())
     } else if value.is_f64() {
-        return Ok(PyFloat::new(py, value.as_f64().unwrap()).into());
+        Ok(PyFloat::new(py, value.as_f64().unwrap()).into())
     } else if value.is_i64() {
-        return Ok(value.as_i64().unwrap().to_object(py));
+        Ok(value.as_i64().unwrap().to_object(py))
     } else if value.is_u64() {
-        return Ok(value.as_u64().unwrap().to_object(py));
+        Ok(value.as_u64().unwrap().to_object(py))
     } else if value.is_string() {
-        return Ok(PyUnicode::new(py, value.as_str().unwrap()).into());
+        Ok(PyUnicode::new(py, value.as_str().unwrap()).into())
     } else if value.is_null() {
-        return Ok(PyUnicode::new(py, "null").into());
+        Ok(PyUnicode::new(py, "null").into())
     } else {
-        return Err(PyValueError::new_err("Unknown value type"));
+        Err(PyValueError::new_err("Unknown value type"))
     }
 }
 
 #[pymethods]
 impl CocoPageMapper {
     #[new]
-    fn py_new(path: String) -> PyResult<Self> {
-        let file = File::open(Path::new(&path))?;
-        let mut reader = BufReader::new(file);
-        let mapper = CocoPageMapperImpl::new(&mut reader)?;
+    #[pyo3(signature = (path, cache_entries = 0))]
+    fn py_new(path: String, cache_entries: usize) -> PyResult<Self> {
+        let path = PathBuf::from(path);
+        let mut inner = CachedPageMapper::open(&path, CacheBudget::Entries(cache_entries))?;
+        if inner.is_caching() {
+            inner.set_content_hash(content_fingerprint(&path)?);
+        }
 
-        Ok(CocoPageMapper { reader, mapper })
+        Ok(CocoPageMapper { inner, path })
     }
 
-    fn licenses(self_: PyRef<Self>) -> PyResult<PyObject> {
-        convert_to_py_object(self_.mapper.licenses(), self_.py())
+    fn licenses(&mut self, py: Python<'_>) -> PyResult<PyObject> {
+        let (mapper, reader) = self.inner.parts_mut();
+        convert_to_py_object(mapper.licenses(reader)?, py)
     }
 
-    fn info(self_: PyRef<Self>) -> PyResult<PyObject> {
-        convert_to_py_object(self_.mapper.info(), self_.py())
+    fn info(&mut self, py: Python<'_>) -> PyResult<PyObject> {
+        let (mapper, reader) = self.inner.parts_mut();
+        convert_to_py_object(mapper.info(reader)?, py)
     }
 
     fn categories(self_: PyRef<Self>) -> PyResult<PyObject> {
-        convert_to_py_object(self_.mapper.categories(), self_.py())
+        convert_to_py_object(self_.inner.mapper().categories()?, self_.py())
     }
 
     fn get_item_dict(&mut self, py: Python<'_>, img_id: i64) -> PyResult<PyObject> {
-        let item_dict = self.mapper.get_item_dict(img_id, &mut self.reader)?;
-        Ok(convert_to_py_object(&item_dict, py)?)
+        self.check_content_hash()?;
+        let item_dict = self.inner.get_item_dict(img_id)?;
+        convert_to_py_object(&item_dict, py)
     }
 
     fn get_anns_dict(&mut self, py: Python<'_>, img_id: i64) -> PyResult<PyObject> {
+        self.check_content_hash()?;
         let anns_list = PyList::new(
             py,
-            self.mapper
-                .get_anns_dict(img_id, &mut self.reader)?
+            self.inner
+                .get_anns_dict(img_id)?
                 .iter()
                 .map(|child| convert_to_py_object(child, py).unwrap()),
         );
         Ok(anns_list.into())
     }
 
+    /// Like `get_anns_dict`, with only the `include` keys or without the
+    /// `exclude` keys of every annotation. Not cached.
+    #[pyo3(signature = (img_id, include = None, exclude = None))]
+    fn get_anns_dict_selected(
+        &mut self,
+        py: Python<'_>,
+        img_id: i64,
+        include: Option<Vec<String>>,
+        exclude: Option<Vec<String>>,
+    ) -> PyResult<PyObject> {
+        let selection = match (include, exclude) {
+            (Some(_), Some(_)) => {
+                return Err(PyValueError::new_err("Pass either include or exclude"))
+            }
+            (Some(keys), None) => FieldSelection::Include(keys.into_iter().collect()),
+            (None, Some(keys)) => FieldSelection::Exclude(keys.into_iter().collect()),
+            (None, None) => FieldSelection::All,
+        };
+        let (mapper, reader) = self.inner.parts_mut();
+        let anns = mapper.get_anns_dict_selected(img_id, reader, &selection)?;
+        let anns_list = PyList::new(
+            py,
+            anns.iter()
+                .map(|child| convert_to_py_object(child, py).unwrap()),
+        );
+        Ok(anns_list.into())
+    }
+
+    /// Reads the items and annotations of `img_ids` into the cache on a
+    /// background thread, e.g. for the next batch of a data loader.
+    fn prefetch(&mut self, img_ids: Vec<i64>) -> PyResult<()> {
+        self.check_content_hash()?;
+        Ok(self.inner.prefetch(&img_ids)?)
+    }
+
+    fn prefetch_blocking(&mut self, img_ids: Vec<i64>) -> PyResult<()> {
+        self.check_content_hash()?;
+        Ok(self.inner.prefetch_blocking(&img_ids)?)
+    }
+
     fn get_img_ids(&self) -> Vec<i64> {
-        self.mapper.get_img_ids().to_owned()
+        self.inner.mapper().get_img_ids().to_owned()
+    }
+
+    /// `(hits, misses)` of the item and annotation cache.
+    fn cache_stats(&self) -> (u64, u64) {
+        let stats = self.inner.cache_stats();
+        (stats.hits, stats.misses)
+    }
+
+    fn clear_cache(&mut self) {
+        self.inner.clear_cache();
     }
 
     fn __len__(&self) -> PyResult<usize> {
-        Ok(self.mapper.get_img_ids().len())
+        Ok(self.inner.mapper().get_img_ids().len())
+    }
+}
+
+impl CocoPageMapper {
+    /// Drops the cache if the file was modified since the last access. The
+    /// file is not checked when nothing is cached. Only parts of large files
+    /// are hashed, see `content_fingerprint`, so an edit elsewhere keeping the
+    /// length of the file is missed.
+    fn check_content_hash(&mut self) -> io::Result<()> {
+        if self.inner.is_caching() {
+            let hash = content_fingerprint(&self.path)?;
+            self.inner.set_content_hash(hash);
+        }
+        Ok(())
     }
 }
 
@@ -0,0 +1,75 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{HashMap, HashSet},
+    mem::size_of,
+};
+
+use crate::page_maps::JsonDict;
+
+/// Heap bytes held by the index of a `CocoPageMapper`, estimated from the
+/// capacities of its containers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexMemory {
+    pub images: usize,
+    /// Annotation pages and the secondary indexes built over them.
+    pub annotations: usize,
+    /// The part of `annotations` spent on record offsets, see
+    /// `CocoPageMapperBuilder::compact_index`.
+    pub ann_offsets: usize,
+    /// `licenses`, `info` and `categories`.
+    pub sections: usize,
+    pub total: usize,
+}
+
+/// Heap bytes of all annotations once parsed, as `JsonDict`s and as
+/// `InternedValue`s, see `CocoPageMapper::anns_interning_memory`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterningMemory {
+    pub plain: usize,
+    pub interned: usize,
+    /// The part of `interned` spent on the shared keys.
+    pub keys: usize,
+}
+
+pub(crate) fn vec_bytes<T>(v: &Vec<T>) -> usize {
+    v.capacity() * size_of::<T>()
+}
+
+/// Counts a control byte per slot on top of the entries, as hashbrown does.
+pub(crate) fn hash_map_bytes<K, V>(m: &HashMap<K, V>) -> usize {
+    m.capacity() * (size_of::<(K, V)>() + 1)
+}
+
+pub(crate) fn hash_set_bytes<T>(s: &HashSet<T>) -> usize {
+    s.capacity() * (size_of::<T>() + 1)
+}
+
+/// Heap bytes below a JSON value, not counting the value itself.
+pub(crate) fn json_bytes(value: &JsonDict) -> usize {
+    match value {
+        JsonDict::String(v) => v.capacity(),
+        JsonDict::Array(v) => vec_bytes(v) + v.iter().map(json_bytes).sum::<usize>(),
+        JsonDict::Object(v) => v
+            .iter()
+            .map(|(key, value)| {
+                size_of::<String>() + size_of::<JsonDict>() + key.capacity() + json_bytes(value)
+            })
+            .sum(),
+        _ => 0,
+    }
+}
+
+pub(crate) fn shrink_json(value: &mut JsonDict) {
+    match value {
+        JsonDict::String(v) => v.shrink_to_fit(),
+        JsonDict::Array(v) => {
+            v.shrink_to_fit();
+            v.iter_mut().for_each(shrink_json);
+        }
+        JsonDict::Object(v) => v.values_mut().for_each(shrink_json),
+        _ => {}
+    }
+}
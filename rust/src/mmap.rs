@@ -0,0 +1,126 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    fs::File,
+    io::{self, Cursor},
+    path::Path,
+};
+
+use memmap2::Mmap;
+
+use crate::{
+    coco_page_mapper::{CocoPageMapper, CocoPageMapperBuilder},
+    page_maps::JsonDict,
+};
+
+/// A file mapped into memory, so that records are parsed from the mapping
+/// without read calls or copies.
+///
+/// The file must not be modified while it is mapped: the index would no
+/// longer match it, and truncating it makes the mapping fault on access.
+#[derive(Debug)]
+pub struct MmapSource {
+    /// `None` for an empty file, which cannot be mapped.
+    map: Option<Mmap>,
+}
+
+impl MmapSource {
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(Self { map: None });
+        }
+        // Safety: the file is expected not to change while mapped, see above.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self { map: Some(map) })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or_default()
+    }
+
+    /// A reader over the mapping, for the methods which take one.
+    pub fn reader(&self) -> Cursor<&[u8]> {
+        Cursor::new(self.as_bytes())
+    }
+
+    pub fn get_item_dict(
+        &self,
+        mapper: &CocoPageMapper,
+        img_id: i64,
+    ) -> Result<JsonDict, io::Error> {
+        mapper.get_item_dict_from_bytes(img_id, self.as_bytes())
+    }
+
+    pub fn get_anns_dict(
+        &self,
+        mapper: &CocoPageMapper,
+        img_id: i64,
+    ) -> Result<Vec<JsonDict>, io::Error> {
+        mapper.get_anns_dict_from_bytes(img_id, self.as_bytes())
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_bytes().is_empty()
+    }
+}
+
+impl CocoPageMapperBuilder {
+    /// Maps an uncompressed file into memory and scans the mapping.
+    pub fn build_mmap(&self, path: &Path) -> Result<(CocoPageMapper, MmapSource), io::Error> {
+        let source = MmapSource::open(path)?;
+        let mapper = self.build(source.reader())?;
+        Ok((mapper, source))
+    }
+}
+
+impl CocoPageMapper {
+    /// See `CocoPageMapperBuilder::build_mmap`.
+    pub fn open_mmap(path: &Path) -> Result<(Self, MmapSource), io::Error> {
+        CocoPageMapperBuilder::new().build_mmap(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{prepare, write_example, EXAMPLE};
+
+    #[test]
+    fn test_open_mmap() {
+        let filepath = write_example(EXAMPLE);
+        let (coco_page_mapper, source) = CocoPageMapper::open_mmap(&filepath).unwrap();
+        let (mut reader, expected) = prepare(EXAMPLE);
+
+        assert_eq!(coco_page_mapper.get_img_ids(), expected.get_img_ids());
+        for img_id in expected.get_img_ids() {
+            assert_eq!(
+                source.get_item_dict(&coco_page_mapper, *img_id).unwrap(),
+                expected.get_item_dict(*img_id, &mut reader).unwrap()
+            );
+            assert_eq!(
+                source.get_anns_dict(&coco_page_mapper, *img_id).unwrap(),
+                expected.get_anns_dict(*img_id, &mut reader).unwrap()
+            );
+        }
+        assert!(source.get_item_dict(&coco_page_mapper, 7).is_err());
+        assert!(source
+            .get_anns_dict(&coco_page_mapper, 7)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_open_mmap_empty_file() {
+        let filepath = write_example("");
+        let source = MmapSource::open(&filepath).unwrap();
+        assert!(source.is_empty());
+        assert!(CocoPageMapper::open_mmap(&filepath).is_err());
+    }
+}
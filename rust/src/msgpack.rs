@@ -0,0 +1,59 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io::{self, Read, Seek};
+
+use serde::Serialize;
+
+use crate::coco_page_mapper::CocoPageMapper;
+
+fn to_msgpack(value: &impl Serialize) -> Result<Vec<u8>, io::Error> {
+    rmp_serde::to_vec(value).map_err(io::Error::other)
+}
+
+impl CocoPageMapper {
+    /// Like `get_item_dict`, encoded as MessagePack, which is more compact
+    /// than JSON to send to another process.
+    pub fn get_item_msgpack(
+        &self,
+        img_id: i64,
+        reader: impl Read + Seek,
+    ) -> Result<Vec<u8>, io::Error> {
+        to_msgpack(&self.get_item_dict(img_id, reader)?)
+    }
+
+    /// Like `get_anns_dict`, encoded as a MessagePack array.
+    pub fn get_anns_msgpack(
+        &self,
+        img_id: i64,
+        reader: impl Read + Seek,
+    ) -> Result<Vec<u8>, io::Error> {
+        to_msgpack(&self.get_anns_dict(img_id, reader)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::test_utils::{prepare, EXAMPLE};
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let bytes = coco_page_mapper.get_item_msgpack(5, &mut reader).unwrap();
+        let item: Value = rmp_serde::from_slice(&bytes).unwrap();
+        let expected = coco_page_mapper.get_item_dict(5, &mut reader).unwrap();
+        assert_eq!(item, expected);
+        assert!(bytes.len() < serde_json::to_vec(&expected).unwrap().len());
+
+        let bytes = coco_page_mapper.get_anns_msgpack(6, &mut reader).unwrap();
+        let anns: Value = rmp_serde::from_slice(&bytes).unwrap();
+        let expected = coco_page_mapper.get_anns_dict(6, &mut reader).unwrap();
+        assert_eq!(anns, Value::Array(expected));
+
+        assert!(coco_page_mapper.get_item_msgpack(7, &mut reader).is_err());
+    }
+}
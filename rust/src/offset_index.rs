@@ -0,0 +1,943 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::{self, Read, Seek},
+};
+
+use serde::de::{DeserializeOwned, DeserializeSeed};
+
+use crate::{
+    memory::{hash_map_bytes, vec_bytes},
+    page_maps::ScanOptions,
+    utils::{
+        from_json_slice, json_error, stream_error, strip_trailing_commas, JsonTokenizer, Token,
+    },
+};
+
+/// Records closer than this are fetched with a single read, unless changed
+/// with `IoTuning::coalesce_gap_bytes`.
+pub const MAX_COALESCE_GAP: u64 = 4096;
+
+/// Largest read of the scan, unless changed with `IoTuning::scan_buffer_bytes`.
+pub const DEFAULT_SCAN_BUFFER_BYTES: usize = 64 << 10;
+
+/// Largest read of coalesced records, unless changed with
+/// `IoTuning::record_read_chunk`.
+pub const DEFAULT_RECORD_READ_CHUNK: usize = 1 << 20;
+
+/// Read sizes, to be matched to the storage: e.g. large reads for network
+/// file systems and object stores, small ones for local SSDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoTuning {
+    /// Largest read of the scan, which starts with smaller reads.
+    pub scan_buffer_bytes: usize,
+    /// Largest read of records fetched together. A longer record is still
+    /// read at once.
+    pub record_read_chunk: usize,
+    /// Records at most this many bytes apart are fetched with a single read,
+    /// e.g. the annotations of an image.
+    pub coalesce_gap_bytes: u64,
+}
+
+impl Default for IoTuning {
+    fn default() -> Self {
+        Self {
+            scan_buffer_bytes: DEFAULT_SCAN_BUFFER_BYTES,
+            record_read_chunk: DEFAULT_RECORD_READ_CHUNK,
+            coalesce_gap_bytes: MAX_COALESCE_GAP,
+        }
+    }
+}
+
+/// Longest image or annotation record, unless changed with
+/// `ScanOptions::max_record_bytes`.
+pub const DEFAULT_MAX_RECORD_BYTES: u64 = 64 << 20;
+
+/// The `max_record_bytes` of `options`, lowered to `u32::MAX`, the longest
+/// record a `RecordSpan` holds.
+pub(crate) fn max_record_bytes(options: &ScanOptions) -> u64 {
+    options
+        .max_record_bytes
+        .unwrap_or(DEFAULT_MAX_RECORD_BYTES)
+        .min(u32::MAX as u64)
+}
+
+/// Buffers kept between reads of records, so that repeated lookups do not
+/// allocate. They only grow, see `OffsetIndex::read_positions_with`.
+#[derive(Debug, Default)]
+pub struct ReadScratch {
+    buf: Vec<u8>,
+    requests: Vec<(usize, RecordSpan)>,
+    /// Byte ranges fetched with a single read, and where their requests start.
+    runs: Vec<(u64, usize)>,
+    run_starts: Vec<usize>,
+    pub(crate) positions: Vec<usize>,
+    pub(crate) order: Vec<usize>,
+}
+
+impl ReadScratch {
+    /// Room for reading `records` records at once without growing.
+    pub fn with_capacity(records: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            requests: Vec::with_capacity(records),
+            runs: Vec::new(),
+            run_starts: Vec::new(),
+            positions: Vec::with_capacity(records),
+            order: Vec::with_capacity(records),
+        }
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        vec_bytes(&self.buf)
+            + vec_bytes(&self.requests)
+            + vec_bytes(&self.runs)
+            + vec_bytes(&self.run_starts)
+            + vec_bytes(&self.positions)
+            + vec_bytes(&self.order)
+    }
+}
+
+/// Called with the index and the bytes of every run, see `RecordSource`.
+pub type OnRun<'a> = &'a mut dyn FnMut(usize, &[u8]) -> Result<(), io::Error>;
+
+/// Where `OffsetIndex::read_positions` fetches records from: any reader, or a
+/// backend reading many byte ranges at once, e.g. `UringSource`.
+pub trait RecordSource {
+    /// Reads the byte ranges `runs`, given as `(offset, len)` and sorted by
+    /// offset, and passes the bytes of each to `on_run` with its index, in
+    /// order. `buf` is scratch space, grown as needed.
+    fn read_runs(
+        &mut self,
+        runs: &[(u64, usize)],
+        buf: &mut Vec<u8>,
+        on_run: OnRun<'_>,
+    ) -> Result<(), io::Error>;
+}
+
+impl<R: Read + Seek + ?Sized> RecordSource for R {
+    fn read_runs(
+        &mut self,
+        runs: &[(u64, usize)],
+        buf: &mut Vec<u8>,
+        on_run: OnRun<'_>,
+    ) -> Result<(), io::Error> {
+        for (idx, (offset, len)) in runs.iter().enumerate() {
+            if buf.len() < *len {
+                buf.resize(*len, 0);
+            }
+            self.seek(io::SeekFrom::Start(*offset))?;
+            self.read_exact(&mut buf[..*len])?;
+            on_run(idx, &buf[..*len])?;
+        }
+        Ok(())
+    }
+}
+
+/// Location of a record in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordSpan {
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// Calls `on_record` with the parsed object, its span and its raw bytes for
+/// every element of the JSON array starting at the current reader position. The reader is left
+/// right after the array. With `malformed`, elements which cannot be read,
+/// parsed or are rejected by `on_record` are skipped and their offsets pushed
+/// there instead of failing the scan.
+pub(crate) fn scan_array<T, R, F>(
+    reader: R,
+    options: &ScanOptions,
+    mut malformed: Option<&mut Vec<u64>>,
+    mut on_record: F,
+) -> Result<(), io::Error>
+where
+    T: DeserializeOwned + Send,
+    R: Read + Seek,
+    F: FnMut(T, RecordSpan, &[u8]) -> Result<(), io::Error>,
+{
+    #[cfg(feature = "rayon")]
+    if let Some(threads) = options.parallel_threads {
+        return crate::parallel::scan_array(reader, threads, options, malformed, on_record);
+    }
+
+    let mut tokens = JsonTokenizer::with_buffer_size(reader, options.io_tuning.scan_buffer_bytes)?;
+    tokens.expect_token(Token::BeginArray)?;
+    let mut after_comma = false;
+    let mut buf = vec![];
+    let limit = max_record_bytes(options);
+
+    loop {
+        let start = match tokens.peek_byte()? {
+            Some((b']', pos)) => {
+                if after_comma && !options.allow_trailing_commas {
+                    return Err(stream_error("Trailing comma in the array", pos));
+                }
+                tokens.expect_token(Token::EndArray)?;
+                break;
+            }
+            Some((_, pos)) => pos,
+            None => return Err(stream_error("Unexpected end of input", tokens.position())),
+        };
+
+        let depth = tokens.depth();
+        let result = tokens.read_value_into(&mut buf, limit).and_then(|span| {
+            let record = parse_record(&buf, span.start, options.allow_trailing_commas)?;
+            on_record(
+                record,
+                RecordSpan {
+                    offset: span.start,
+                    // Longer records failed `read_value_into`.
+                    size: buf.len() as u32,
+                },
+                &buf,
+            )
+        });
+        if let Err(e) = result {
+            match malformed.as_mut() {
+                Some(malformed) => {
+                    tokens.skip_malformed_value(start, depth)?;
+                    malformed.push(start);
+                }
+                None => return Err(e),
+            }
+        }
+        if let Some(progress) = &options.progress {
+            progress.record(tokens.position());
+        }
+
+        match tokens.next_token()? {
+            Some((Token::Comma, _)) => after_comma = true,
+            Some((Token::EndArray, _)) => break,
+            Some((_, span)) => return Err(stream_error("Expected ',' or ']'", span.start)),
+            None => return Err(stream_error("Unexpected end of input", tokens.position())),
+        }
+    }
+
+    tokens.into_inner()?;
+    Ok(())
+}
+
+/// Parses a record read from the absolute `offset`.
+pub(crate) fn parse_record<T>(
+    buf: &[u8],
+    offset: u64,
+    allow_trailing_commas: bool,
+) -> Result<T, io::Error>
+where
+    T: DeserializeOwned,
+{
+    if allow_trailing_commas {
+        let stripped = strip_trailing_commas(buf);
+        from_json_slice(&stripped).map_err(|e| json_error(e, &stripped, offset))
+    } else {
+        from_json_slice(buf).map_err(|e| json_error(e, buf, offset))
+    }
+}
+
+/// Like `parse_record`, but deserializes with `seed`, through `serde_json`.
+pub(crate) fn parse_record_seed<S, T>(
+    buf: &[u8],
+    offset: u64,
+    allow_trailing_commas: bool,
+    seed: S,
+) -> Result<T, io::Error>
+where
+    S: for<'de> DeserializeSeed<'de, Value = T>,
+{
+    let parse = |buf: &[u8]| {
+        let mut deserializer = serde_json::Deserializer::from_slice(buf);
+        let value = seed.deserialize(&mut deserializer)?;
+        deserializer.end().map(|_| value)
+    };
+    if allow_trailing_commas {
+        let stripped = strip_trailing_commas(buf);
+        parse(&stripped).map_err(|e| json_error(e, &stripped, offset))
+    } else {
+        parse(buf).map_err(|e| json_error(e, buf, offset))
+    }
+}
+
+/// Records per checkpoint of `SpanStore::Compact`.
+const COMPACT_BLOCK_SIZE: usize = 32;
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Record spans, either as is or as varints of the gap to the end of the
+/// previous record and of the size. Compact spans are decoded from the last
+/// checkpoint, every `COMPACT_BLOCK_SIZE` records, on access.
+#[derive(Debug, Clone)]
+enum SpanStore {
+    Plain(Vec<RecordSpan>),
+    Compact {
+        bytes: Vec<u8>,
+        /// End of the previous record and position in `bytes` of every block.
+        checkpoints: Vec<(u64, usize)>,
+        len: usize,
+        last_end: u64,
+    },
+}
+
+impl SpanStore {
+    fn new(compact: bool) -> Self {
+        match compact {
+            true => SpanStore::Compact {
+                bytes: Vec::with_capacity(0),
+                checkpoints: Vec::with_capacity(0),
+                len: 0,
+                last_end: 0,
+            },
+            false => SpanStore::Plain(Vec::with_capacity(0)),
+        }
+    }
+
+    fn is_compact(&self) -> bool {
+        matches!(self, SpanStore::Compact { .. })
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            SpanStore::Plain(spans) => spans.len(),
+            SpanStore::Compact { len, .. } => *len,
+        }
+    }
+
+    fn push(&mut self, span: RecordSpan) {
+        match self {
+            SpanStore::Plain(spans) => spans.push(span),
+            SpanStore::Compact {
+                bytes,
+                checkpoints,
+                len,
+                last_end,
+            } => {
+                if *len % COMPACT_BLOCK_SIZE == 0 {
+                    checkpoints.push((*last_end, bytes.len()));
+                }
+                // Zigzag, records pushed out of file order have negative gaps.
+                let gap = span.offset.wrapping_sub(*last_end) as i64;
+                write_varint(bytes, ((gap << 1) ^ (gap >> 63)) as u64);
+                write_varint(bytes, span.size as u64);
+                *last_end = span.offset + span.size as u64;
+                *len += 1;
+            }
+        }
+    }
+
+    fn get(&self, position: usize) -> RecordSpan {
+        match self {
+            SpanStore::Plain(spans) => spans[position],
+            SpanStore::Compact {
+                bytes, checkpoints, ..
+            } => {
+                let (mut end, mut pos) = checkpoints[position / COMPACT_BLOCK_SIZE];
+                let mut span = RecordSpan { offset: 0, size: 0 };
+                for _ in 0..=position % COMPACT_BLOCK_SIZE {
+                    let gap = read_varint(bytes, &mut pos);
+                    let gap = ((gap >> 1) as i64) ^ -((gap & 1) as i64);
+                    span.offset = end.wrapping_add(gap as u64);
+                    span.size = read_varint(bytes, &mut pos) as u32;
+                    end = span.offset + span.size as u64;
+                }
+                span
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            SpanStore::Plain(spans) => spans.clear(),
+            SpanStore::Compact {
+                bytes,
+                checkpoints,
+                len,
+                last_end,
+            } => {
+                bytes.clear();
+                checkpoints.clear();
+                *len = 0;
+                *last_end = 0;
+            }
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        match self {
+            SpanStore::Plain(spans) => vec_bytes(spans),
+            SpanStore::Compact {
+                bytes, checkpoints, ..
+            } => vec_bytes(bytes) + vec_bytes(checkpoints),
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        match self {
+            SpanStore::Plain(spans) => spans.shrink_to_fit(),
+            SpanStore::Compact {
+                bytes, checkpoints, ..
+            } => {
+                bytes.shrink_to_fit();
+                checkpoints.shrink_to_fit();
+            }
+        }
+    }
+}
+
+/// Records of a JSON array in file order, looked up by a key. A key inserted
+/// more than once keeps its first record for lookups, or its last one with
+/// `keep_last`. Positions, the order of insertion, can be used to keep more
+/// data per record in parallel arrays.
+#[derive(Debug, Clone)]
+pub struct OffsetIndex<K> {
+    keys: Vec<K>,
+    spans: SpanStore,
+    positions: HashMap<K, usize>,
+    allow_trailing_commas: bool,
+    keep_last: bool,
+    io_tuning: IoTuning,
+}
+
+impl<K: Hash + Eq + Clone> OffsetIndex<K> {
+    pub fn new(allow_trailing_commas: bool) -> Self {
+        Self {
+            keys: Vec::with_capacity(0),
+            spans: SpanStore::new(false),
+            positions: HashMap::with_capacity(0),
+            allow_trailing_commas,
+            keep_last: false,
+            io_tuning: IoTuning::default(),
+        }
+    }
+
+    /// Resolve repeated keys to their last record.
+    pub fn keep_last(mut self, keep_last: bool) -> Self {
+        self.keep_last = keep_last;
+        self
+    }
+
+    /// Scans the JSON array at the reader position. `key_extractor` gets every
+    /// element deserialized as `T`, which may declare only the fields needed.
+    pub fn from_array_reader<T, R, F>(
+        reader: R,
+        options: &ScanOptions,
+        key_extractor: F,
+    ) -> Result<Self, io::Error>
+    where
+        T: DeserializeOwned + Send,
+        R: Read + Seek,
+        F: FnMut(T, RecordSpan) -> Result<K, io::Error>,
+    {
+        let mut index = Self::new(options.allow_trailing_commas);
+        index.rebuild(reader, options, key_extractor)?;
+        Ok(index)
+    }
+
+    /// Like `from_array_reader`, but replaces the contents keeping the allocations.
+    pub fn rebuild<T, R, F>(
+        &mut self,
+        reader: R,
+        options: &ScanOptions,
+        mut key_extractor: F,
+    ) -> Result<(), io::Error>
+    where
+        T: DeserializeOwned + Send,
+        R: Read + Seek,
+        F: FnMut(T, RecordSpan) -> Result<K, io::Error>,
+    {
+        self.rebuild_skipping(reader, options, None, |record, span, _| {
+            key_extractor(record, span).map(Some)
+        })
+    }
+
+    /// Like `rebuild`, but with `malformed` the elements which cannot be
+    /// parsed or are rejected by `key_extractor` are left out and their
+    /// offsets collected. `key_extractor` must not keep state for the records
+    /// it rejects. Records for which it returns `None` are left out as well,
+    /// without being reported. It is also passed the raw bytes of the record.
+    pub fn rebuild_skipping<T, R, F>(
+        &mut self,
+        reader: R,
+        options: &ScanOptions,
+        malformed: Option<&mut Vec<u64>>,
+        mut key_extractor: F,
+    ) -> Result<(), io::Error>
+    where
+        T: DeserializeOwned + Send,
+        R: Read + Seek,
+        F: FnMut(T, RecordSpan, &[u8]) -> Result<Option<K>, io::Error>,
+    {
+        self.clear();
+        self.allow_trailing_commas = options.allow_trailing_commas;
+        self.io_tuning = options.io_tuning;
+        scan_array(reader, options, malformed, |record, span, bytes| {
+            if let Some(key) = key_extractor(record, span, bytes)? {
+                self.insert(key, span.offset, span.size);
+            }
+            Ok(())
+        })
+    }
+
+    pub fn io_tuning(&self) -> IoTuning {
+        self.io_tuning
+    }
+
+    /// Changes how the records are read from now on.
+    pub fn set_io_tuning(&mut self, io_tuning: IoTuning) {
+        self.io_tuning = io_tuning;
+    }
+
+    /// Returns the position of the new record.
+    pub fn insert(&mut self, key: K, offset: u64, size: u32) -> usize {
+        let position = self.keys.len();
+        if self.keep_last {
+            self.positions.insert(key.clone(), position);
+        } else {
+            self.positions.entry(key.clone()).or_insert(position);
+        }
+        self.keys.push(key);
+        self.spans.push(RecordSpan { offset, size });
+        position
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.spans.clear();
+        self.positions.clear();
+    }
+
+    /// Heap bytes held by the index. Keys owning heap data are counted by
+    /// their inline size only.
+    pub fn memory_bytes(&self) -> usize {
+        vec_bytes(&self.keys) + self.spans.memory_bytes() + hash_map_bytes(&self.positions)
+    }
+
+    /// Heap bytes of the record spans alone.
+    pub fn span_bytes(&self) -> usize {
+        self.spans.memory_bytes()
+    }
+
+    /// Switches to storing the spans as delta encoded varints, several times
+    /// smaller than plain spans but decoded on every access.
+    pub fn set_compact(&mut self, compact: bool) {
+        if self.spans.is_compact() == compact {
+            return;
+        }
+        let mut spans = SpanStore::new(compact);
+        for position in 0..self.spans.len() {
+            spans.push(self.spans.get(position));
+        }
+        self.spans = spans;
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.keys.shrink_to_fit();
+        self.spans.shrink_to_fit();
+        self.positions.shrink_to_fit();
+    }
+
+    pub fn position(&self, key: &K) -> Option<usize> {
+        self.positions.get(key).copied()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    /// Keys in file order, repeated keys included.
+    pub fn keys(&self) -> &Vec<K> {
+        &self.keys
+    }
+
+    pub fn span_at(&self, position: usize) -> RecordSpan {
+        self.spans.get(position)
+    }
+
+    pub fn span(&self, key: &K) -> Option<RecordSpan> {
+        self.position(key).map(|position| self.spans.get(position))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The bytes of the record at `position` in `data`, which holds the whole
+    /// scanned input.
+    pub fn record_bytes<'a>(&self, data: &'a [u8], position: usize) -> Result<&'a [u8], io::Error> {
+        let span = self.span_at(position);
+        let start = span.offset as usize;
+        data.get(start..start + span.size as usize)
+            .ok_or(stream_error("The record is out of bounds", span.offset))
+    }
+
+    /// Parses the raw bytes of a record, as passed by `read_many`.
+    pub fn parse_bytes<T>(&self, buf: &[u8], span: RecordSpan) -> Result<T, io::Error>
+    where
+        T: DeserializeOwned,
+    {
+        parse_record(buf, span.offset, self.allow_trailing_commas)
+    }
+
+    /// Like `parse_bytes`, but deserializes with `seed`.
+    pub fn parse_bytes_seed<S, T>(
+        &self,
+        buf: &[u8],
+        span: RecordSpan,
+        seed: S,
+    ) -> Result<T, io::Error>
+    where
+        S: for<'de> DeserializeSeed<'de, Value = T>,
+    {
+        parse_record_seed(buf, span.offset, self.allow_trailing_commas, seed)
+    }
+
+    pub fn read_at<T, R>(&self, reader: &mut R, position: usize) -> Result<T, io::Error>
+    where
+        T: DeserializeOwned,
+        R: Read + Seek,
+    {
+        let buf = self.read_bytes_at(reader, position)?;
+        self.parse_bytes(&buf, self.spans.get(position))
+    }
+
+    /// The raw bytes of a record, read with a single seek.
+    pub fn read_bytes_at<R>(&self, reader: &mut R, position: usize) -> Result<Vec<u8>, io::Error>
+    where
+        R: Read + Seek,
+    {
+        let span = self.spans.get(position);
+        let mut buf = vec![0u8; span.size as usize];
+        reader.seek(io::SeekFrom::Start(span.offset))?;
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// `None` if the key is not indexed.
+    pub fn get_one<T, R>(&self, reader: &mut R, key: &K) -> Result<Option<T>, io::Error>
+    where
+        T: DeserializeOwned,
+        R: Read + Seek,
+    {
+        match self.position(key) {
+            Some(position) => self.read_at(reader, position).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches many records at once, see `read_many`. Returns them in the
+    /// order of `keys` and the keys which are not indexed.
+    #[allow(clippy::type_complexity)]
+    pub fn get_many<T, R>(
+        &self,
+        reader: &mut R,
+        keys: &[K],
+    ) -> Result<(Vec<(K, T)>, Vec<K>), io::Error>
+    where
+        T: DeserializeOwned,
+        R: Read + Seek,
+    {
+        let mut records: Vec<Option<(K, T)>> = (0..keys.len()).map(|_| None).collect();
+        let missing = self.read_many(reader, keys, |idx, span, buf| {
+            let record = self.parse_bytes(buf, span)?;
+            records[idx] = Some((keys[idx].clone(), record));
+            Ok(())
+        })?;
+        Ok((records.into_iter().flatten().collect(), missing))
+    }
+
+    /// Calls `on_record` with the index into `keys`, the span and the raw bytes
+    /// of every indexed record, see `read_positions`. Returns the keys which
+    /// are not indexed.
+    pub fn read_many<R, F>(
+        &self,
+        reader: &mut R,
+        keys: &[K],
+        mut on_record: F,
+    ) -> Result<Vec<K>, io::Error>
+    where
+        R: RecordSource,
+        F: FnMut(usize, RecordSpan, &[u8]) -> Result<(), io::Error>,
+    {
+        let mut missing = vec![];
+        let mut positions = vec![];
+        let mut idxs = vec![];
+        for (idx, key) in keys.iter().enumerate() {
+            match self.position(key) {
+                Some(position) => {
+                    positions.push(position);
+                    idxs.push(idx);
+                }
+                None => missing.push(key.clone()),
+            }
+        }
+        self.read_positions(reader, &positions, |idx, span, buf| {
+            on_record(idxs[idx], span, buf)
+        })?;
+        Ok(missing)
+    }
+
+    /// Calls `on_record` with the index into `positions`, the span and the raw
+    /// bytes of every record, in file order. Records closer to each other than
+    /// the coalesce gap are fetched with a single read, of at most
+    /// `IoTuning::record_read_chunk` bytes.
+    pub fn read_positions<R, F>(
+        &self,
+        reader: &mut R,
+        positions: &[usize],
+        on_record: F,
+    ) -> Result<(), io::Error>
+    where
+        R: RecordSource,
+        F: FnMut(usize, RecordSpan, &[u8]) -> Result<(), io::Error>,
+    {
+        self.read_positions_with(reader, positions, &mut ReadScratch::default(), on_record)
+    }
+
+    /// Like `read_positions`, but reads into the buffers of `scratch`.
+    pub fn read_positions_with<R, F>(
+        &self,
+        reader: &mut R,
+        positions: &[usize],
+        scratch: &mut ReadScratch,
+        mut on_record: F,
+    ) -> Result<(), io::Error>
+    where
+        R: RecordSource,
+        F: FnMut(usize, RecordSpan, &[u8]) -> Result<(), io::Error>,
+    {
+        let ReadScratch {
+            buf,
+            requests,
+            runs,
+            run_starts,
+            ..
+        } = scratch;
+        requests.clear();
+        requests.extend(
+            positions
+                .iter()
+                .map(|position| self.span_at(*position))
+                .enumerate(),
+        );
+        requests.sort_unstable_by_key(|(idx, span)| (span.offset, *idx));
+
+        runs.clear();
+        run_starts.clear();
+        let mut run_start = 0;
+        while run_start < requests.len() {
+            let start = requests[run_start].1.offset;
+            let mut end = start + requests[run_start].1.size as u64;
+            let mut run_end = run_start + 1;
+            while let Some((_, span)) = requests.get(run_end) {
+                let gap = self.io_tuning.coalesce_gap_bytes;
+                let len = span.offset + span.size as u64 - start;
+                if span.offset > end + gap || len > self.io_tuning.record_read_chunk as u64 {
+                    break;
+                }
+                end = end.max(span.offset + span.size as u64);
+                run_end += 1;
+            }
+            runs.push((start, (end - start) as usize));
+            run_starts.push(run_start);
+            run_start = run_end;
+        }
+
+        reader.read_runs(runs, buf, &mut |run, bytes| {
+            let start = runs[run].0;
+            let end = run_starts.get(run + 1).copied().unwrap_or(requests.len());
+            for (idx, span) in &requests[run_starts[run]..end] {
+                let from = (span.offset - start) as usize;
+                on_record(*idx, *span, &bytes[from..from + span.size as usize])?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for OffsetIndex<K> {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::test_utils::CountingReader;
+
+    #[derive(Deserialize)]
+    struct FileName {
+        file_name: String,
+    }
+
+    const RECORDS: &str = r#"[
+        {"id": 1, "file_name": "a.jpg", "width": 4},
+        {"id": 2, "file_name": "b.jpg", "width": 5},
+        {"id": 3, "file_name": "a.jpg", "width": 6}
+    ]"#;
+
+    #[test]
+    fn test_max_record_bytes() {
+        let mut options = ScanOptions::default();
+        assert_eq!(max_record_bytes(&options), DEFAULT_MAX_RECORD_BYTES);
+        options.max_record_bytes = Some(10);
+        assert_eq!(max_record_bytes(&options), 10);
+        options.max_record_bytes = Some(u64::MAX);
+        assert_eq!(max_record_bytes(&options), u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_custom_key_extractor() {
+        let mut reader = Cursor::new(RECORDS.as_bytes());
+        let index = OffsetIndex::from_array_reader(
+            &mut reader,
+            &ScanOptions::default(),
+            |record: FileName, _| Ok(record.file_name),
+        )
+        .unwrap();
+        assert_eq!(reader.position(), RECORDS.len() as u64);
+
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.keys(), &vec!["a.jpg", "b.jpg", "a.jpg"]);
+        assert_eq!(index.position(&"b.jpg".to_owned()), Some(1));
+
+        // Repeated keys resolve to their first record.
+        let record: Option<serde_json::Value> =
+            index.get_one(&mut reader, &"a.jpg".to_owned()).unwrap();
+        assert_eq!(record.unwrap()["id"], 1);
+        let record: serde_json::Value = index.read_at(&mut reader, 2).unwrap();
+        assert_eq!(record["id"], 3);
+
+        let none: Option<serde_json::Value> =
+            index.get_one(&mut reader, &"c.jpg".to_owned()).unwrap();
+        assert!(none.is_none());
+
+        let mut index = OffsetIndex::default().keep_last(true);
+        reader.set_position(0);
+        index
+            .rebuild(
+                &mut reader,
+                &ScanOptions::default(),
+                |record: FileName, _| Ok(record.file_name),
+            )
+            .unwrap();
+        assert_eq!(index.position(&"a.jpg".to_owned()), Some(2));
+    }
+
+    #[test]
+    fn test_get_many() {
+        let mut reader = CountingReader::new(Cursor::new(RECORDS.as_bytes()));
+        let options = ScanOptions::default();
+        let index = OffsetIndex::from_array_reader(
+            &mut reader,
+            &options,
+            |record: serde_json::Value, _| {
+                record["id"]
+                    .as_i64()
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+            },
+        )
+        .unwrap();
+
+        (reader.reads, reader.seeks) = (0, 0);
+        let (records, missing): (Vec<(i64, serde_json::Value)>, _) =
+            index.get_many(&mut reader, &[3, 9, 1]).unwrap();
+        assert_eq!((reader.reads, reader.seeks), (1, 1));
+        assert_eq!(missing, vec![9]);
+        assert_eq!(
+            records
+                .iter()
+                .map(|(id, record)| (*id, record["width"].as_i64().unwrap()))
+                .collect::<Vec<_>>(),
+            vec![(3, 6), (1, 4)]
+        );
+    }
+
+    #[test]
+    fn test_trailing_commas() {
+        let input = r#"[{"id": 1, "tags": [1, 2,],}, {"id": 2},]"#;
+        let options = ScanOptions::default();
+        assert!(OffsetIndex::<i64>::from_array_reader(
+            Cursor::new(input.as_bytes()),
+            &options,
+            |_: serde_json::Value, _| Ok(0)
+        )
+        .is_err());
+
+        let options = ScanOptions {
+            allow_trailing_commas: true,
+            ..Default::default()
+        };
+        let mut reader = Cursor::new(input.as_bytes());
+        let index = OffsetIndex::from_array_reader(
+            &mut reader,
+            &options,
+            |record: serde_json::Value, span| {
+                assert_eq!(&input[span.offset as usize..][..1], "{");
+                Ok(record["id"].as_i64().unwrap())
+            },
+        )
+        .unwrap();
+        let record: serde_json::Value = index.get_one(&mut reader, &1).unwrap().unwrap();
+        assert_eq!(record["tags"], serde_json::json!([1, 2]));
+    }
+
+    #[test]
+    fn test_compact_spans() {
+        let spans = [
+            (10, 5),
+            (16, 300),
+            (400, 1),
+            (100, 20),
+            (u32::MAX as u64 * 4, u32::MAX),
+        ];
+        let mut plain = OffsetIndex::new(false);
+        let mut compact = OffsetIndex::new(false);
+        compact.set_compact(true);
+        for key in 0..100 {
+            let (offset, size) = spans[key % spans.len()];
+            let offset = offset + key as u64 * 1000;
+            plain.insert(key, offset, size);
+            compact.insert(key, offset, size);
+        }
+        for position in 0..100 {
+            assert_eq!(compact.span_at(position), plain.span_at(position));
+        }
+        assert!(compact.span_bytes() < plain.span_bytes());
+
+        compact.set_compact(false);
+        assert_eq!(compact.span(&99), plain.span(&99));
+    }
+}
@@ -2,255 +2,2085 @@
 //
 //  SPDX-License-Identifier: MIT
 
+use crate::error::CocoError;
+use crate::intern::{InternSeed, InternedValue, StringInterner};
+use crate::memory::{hash_map_bytes, hash_set_bytes, json_bytes, shrink_json, vec_bytes};
+use crate::offset_index::{
+    parse_record, IoTuning, OffsetIndex, ReadScratch, RecordSource, RecordSpan,
+};
+use crate::progress::ProgressHook;
+use crate::selection::FieldSelection;
 use crate::utils::{
-    invalid_data, parse_serde_json_value_from_page, read_skipping_ws, stream_error,
+    array_element_spans, check_json_value, count_array_elements, invalid_data, is_crowd,
+    is_json_ws, parse_lenient_json_value, parse_serde_json_value, stream_error, JsonTokenizer,
+};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor,
 };
+use serde::{Deserialize, Deserializer};
 use std::{
-    collections::HashMap,
-    io::{self},
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
 };
 
-fn is_empty_list(mut reader: impl io::Read + io::Seek) -> Result<(bool, u64), io::Error> {
-    let curr_pos = reader.stream_position()?;
-    let mut empty_list_str = [0u8; 2];
-    for i in 0..2 {
-        if let Ok(c) = read_skipping_ws(&mut reader) {
-            empty_list_str[i] = c;
+pub type JsonDict = serde_json::Value;
+
+type RecordMap = HashMap<String, serde_json::Value>;
+
+/// Deserializes a field which may be `null` as `Some`, so that it is told
+/// apart from a missing one.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+fn is_present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    IgnoredAny::deserialize(deserializer).map(|_| true)
+}
+
+/// A record scanned by `ImgPageMap`.
+trait ImgRecord: DeserializeOwned + Send {
+    fn id(&self) -> Option<&serde_json::Value>;
+    fn width(&self) -> Option<&serde_json::Value>;
+    fn height(&self) -> Option<&serde_json::Value>;
+}
+
+impl ImgRecord for RecordMap {
+    fn id(&self) -> Option<&serde_json::Value> {
+        self.get("id")
+    }
+    fn width(&self) -> Option<&serde_json::Value> {
+        self.get("width")
+    }
+    fn height(&self) -> Option<&serde_json::Value> {
+        self.get("height")
+    }
+}
+
+/// The fields of an image read by `ImgPageMap::rebuild`. Field names are
+/// matched in place and other fields are skipped without building values.
+#[derive(Deserialize)]
+struct ImgFields {
+    #[serde(default, deserialize_with = "present")]
+    id: Option<serde_json::Value>,
+    width: Option<serde_json::Value>,
+    height: Option<serde_json::Value>,
+}
+
+impl ImgRecord for ImgFields {
+    fn id(&self) -> Option<&serde_json::Value> {
+        self.id.as_ref()
+    }
+    fn width(&self) -> Option<&serde_json::Value> {
+        self.width.as_ref()
+    }
+    fn height(&self) -> Option<&serde_json::Value> {
+        self.height.as_ref()
+    }
+}
+
+/// How many integer and string values an id field took over a scan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdTypeCounts {
+    pub integers: usize,
+    pub strings: usize,
+}
+
+impl IdTypeCounts {
+    fn count(&mut self, value: Option<&serde_json::Value>) {
+        match value {
+            Some(serde_json::Value::Number(v)) if v.is_i64() || v.is_u64() => self.integers += 1,
+            Some(serde_json::Value::String(_)) => self.strings += 1,
+            _ => {}
         }
     }
 
-    if empty_list_str == "[]".as_bytes() {
-        Ok((true, curr_pos))
-    } else {
-        Ok((false, curr_pos))
+    /// Whether both integer and string ids appeared, which never match each
+    /// other when ids are looked up.
+    pub fn is_mixed(&self) -> bool {
+        self.integers > 0 && self.strings > 0
     }
 }
 
-pub type JsonDict = serde_json::Value;
+impl std::ops::Add for IdTypeCounts {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            integers: self.integers + other.integers,
+            strings: self.strings + other.strings,
+        }
+    }
+}
 
-#[derive(Debug)]
+/// Fetched `(img_id, dict)` pairs and the ids which were not found.
+pub type ImgDicts<K = i64> = (Vec<(K, JsonDict)>, Vec<K>);
+
+/// Reads an image `width` or `height`, which some exporters write as numeric
+/// strings. Other strings are an error, other values are taken as absent.
+pub fn parse_dimension(value: Option<&serde_json::Value>) -> Result<Option<i64>, io::Error> {
+    match value {
+        Some(serde_json::Value::String(v)) => {
+            v.trim().parse().map(Some).map_err(|_| {
+                invalid_data(format!("The image size is not a number: {:?}", v).as_str())
+            })
+        }
+        value => Ok(value.and_then(|v| v.as_i64())),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    pub allow_trailing_commas: bool,
+    /// First id assigned to image objects without an `id`, if any should be synthesized.
+    pub synthesize_missing_ids: Option<i64>,
+    /// Keep an `AnnEntry` per annotation, see `AnnPageMap::entries`.
+    pub rich_index: bool,
+    /// Skip annotation objects which cannot be indexed instead of failing,
+    /// see `AnnPageMap::malformed_positions`.
+    pub skip_malformed_anns: bool,
+    /// Skip image objects which cannot be indexed instead of failing, see
+    /// `ImgPageMap::skipped_count`.
+    pub skip_malformed_images: bool,
+    /// Only check that the annotations are well formed, see `AnnPageMap::is_indexed`.
+    pub skip_annotations: bool,
+    /// Check the annotation offsets after the scan, see `AnnPageMap::verify_offsets`.
+    pub verify_offsets: bool,
+    /// Fail if an annotation refers to an image which was not indexed, see
+    /// `CocoPageMapper::orphan_ann_img_ids`.
+    pub reject_orphan_anns: bool,
+    /// Fail if the image ids are integers in some records and strings in
+    /// others, see `CocoPageMapper::mixed_id_types`.
+    pub reject_mixed_id_types: bool,
+    /// Read sizes of the scan and of the later lookups.
+    pub io_tuning: IoTuning,
+    /// Longest image, annotation or category record, defaults to
+    /// `DEFAULT_MAX_RECORD_BYTES` and is at most `u32::MAX`. Longer records
+    /// fail the scan or are skipped like other malformed records.
+    pub max_record_bytes: Option<u64>,
+    /// Longest `licenses`, `info` or `categories` section, which are kept in
+    /// memory, defaults to `DEFAULT_MAX_SECTION_BYTES`.
+    pub max_section_bytes: Option<u64>,
+    /// Index only the images with these ids and their annotations. Other
+    /// records are still parsed to read their ids, but take no memory.
+    pub restrict_to_img_ids: Option<HashSet<i64>>,
+    /// Store the annotation offsets delta encoded, see `OffsetIndex::set_compact`.
+    pub compact_index: bool,
+    /// Parse the records on this many threads, all cores for 0, see
+    /// `CocoPageMapperBuilder::parallel`.
+    #[cfg(feature = "rayon")]
+    pub parallel_threads: Option<usize>,
+    /// Set by `CocoPageMapperBuilder::on_progress`.
+    pub(crate) progress: Option<ProgressHook>,
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct ImgPage {
     pub offset: u64,
     pub size: u32,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
 }
 
-#[derive(Debug)]
-pub struct ImgPageMap {
-    ids: Vec<i64>,
-    pages: HashMap<i64, ImgPage>,
+/// Key of the image records of an `ImgPageMap`.
+pub trait ImgKey: Ord + Hash + Clone + fmt::Display {
+    /// How the accepted `id` values are described in errors.
+    const EXPECTED: &'static str;
+
+    /// `None` if the `id` value has an unexpected type.
+    fn from_id(value: &serde_json::Value) -> Option<Self>;
+
+    /// The value written into the records which lack an `id`.
+    fn to_id(&self) -> serde_json::Value;
+
+    /// Key assigned to the `n`-th record without an `id`.
+    fn synthesized(n: i64) -> Self;
+
+    /// Error for a record of this image which cannot be parsed at `pos`.
+    fn parse_error(&self, pos: u64, source: io::Error) -> io::Error;
+}
+
+impl ImgKey for i64 {
+    const EXPECTED: &'static str = "an integer";
+
+    fn from_id(value: &serde_json::Value) -> Option<Self> {
+        value.as_i64()
+    }
+
+    fn to_id(&self) -> serde_json::Value {
+        (*self).into()
+    }
+
+    fn synthesized(n: i64) -> Self {
+        n
+    }
+
+    fn parse_error(&self, pos: u64, source: io::Error) -> io::Error {
+        CocoError::FieldParse {
+            img_id: *self,
+            ann_id: None,
+            pos,
+            source,
+        }
+        .into()
+    }
 }
 
-impl ImgPageMap {
-    pub fn get_dict<R>(&self, reader: &mut R, img_id: i64) -> Result<JsonDict, io::Error>
+/// String ids, integer ids are taken by their decimal text.
+impl ImgKey for String {
+    const EXPECTED: &'static str = "a string or an integer";
+
+    fn from_id(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::String(v) => Some(v.clone()),
+            serde_json::Value::Number(v) if v.is_i64() || v.is_u64() => Some(v.to_string()),
+            _ => None,
+        }
+    }
+
+    fn to_id(&self) -> serde_json::Value {
+        self.as_str().into()
+    }
+
+    fn synthesized(n: i64) -> Self {
+        n.to_string()
+    }
+
+    fn parse_error(&self, pos: u64, source: io::Error) -> io::Error {
+        io::Error::new(
+            source.kind(),
+            format!(
+                "Cannot parse the record of image id: {} at offset: {}. {}",
+                self, pos, source
+            ),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImgPageMap<K = i64> {
+    index: OffsetIndex<K>,
+    /// `(width, height)` of every record, parallel to `index`.
+    sizes: Vec<(Option<i64>, Option<i64>)>,
+    synthesized_ids: Vec<K>,
+    /// Offsets of the objects skipped with `ScanOptions::skip_malformed_images`.
+    malformed_positions: Vec<u64>,
+    /// Elements of the scanned array whose key was read.
+    keyed_count: usize,
+    id_types: IdTypeCounts,
+}
+
+impl<K: ImgKey> ImgPageMap<K> {
+    fn with_id(mut dict: JsonDict, img_id: &K) -> JsonDict {
+        if let Some(dict) = dict.as_object_mut() {
+            dict.entry("id").or_insert_with(|| img_id.to_id());
+        }
+        dict
+    }
+
+    pub fn get_dict<R>(&self, reader: &mut R, img_id: K) -> Result<JsonDict, io::Error>
     where
         R: io::Read + io::Seek,
     {
-        match self.pages.get(&img_id) {
-            Some(page) => parse_serde_json_value_from_page(reader, page.offset, page.size as u64),
+        match self.index.position(&img_id) {
+            Some(position) => {
+                let dict: JsonDict = self.index.read_at(reader, position).map_err(|source| {
+                    img_id.parse_error(self.index.span_at(position).offset, source)
+                })?;
+                Ok(Self::with_id(dict, &img_id))
+            }
             None => Err(invalid_data(
                 format!("Image id: {} is not on the page map", img_id).as_str(),
             )),
         }
     }
 
-    pub fn push(&mut self, img_id: i64, page: ImgPage) {
-        self.ids.push(img_id);
-        self.pages.insert(img_id, page);
+    /// Like `get_dict`, but also returns the raw bytes the dict was parsed from.
+    pub fn get_raw_and_dict<R>(
+        &self,
+        reader: &mut R,
+        img_id: K,
+    ) -> Result<(Vec<u8>, JsonDict), io::Error>
+    where
+        R: io::Read + io::Seek,
+    {
+        match self.index.position(&img_id) {
+            Some(position) => {
+                let span = self.index.span_at(position);
+                let buf = self.index.read_bytes_at(reader, position)?;
+                let dict: JsonDict = self
+                    .index
+                    .parse_bytes(&buf, span)
+                    .map_err(|source| img_id.parse_error(span.offset, source))?;
+                Ok((buf, Self::with_id(dict, &img_id)))
+            }
+            None => Err(invalid_data(
+                format!("Image id: {} is not on the page map", img_id).as_str(),
+            )),
+        }
     }
 
-    pub fn from_reader(mut reader: impl io::Read + io::Seek) -> Result<ImgPageMap, io::Error> {
-        let mut page_map = ImgPageMap::default();
+    /// Like `get_dict`, but parses the record right from `data`, the whole
+    /// scanned input, e.g. a memory mapped file.
+    pub fn get_dict_from_bytes(&self, data: &[u8], img_id: K) -> Result<JsonDict, io::Error> {
+        match self.index.position(&img_id) {
+            Some(position) => {
+                let span = self.index.span_at(position);
+                let dict: JsonDict = self
+                    .index
+                    .record_bytes(data, position)
+                    .and_then(|buf| self.index.parse_bytes(buf, span))
+                    .map_err(|source| img_id.parse_error(span.offset, source))?;
+                Ok(Self::with_id(dict, &img_id))
+            }
+            None => Err(invalid_data(
+                format!("Image id: {} is not on the page map", img_id).as_str(),
+            )),
+        }
+    }
 
-        let (empty, rewind_pos) = is_empty_list(&mut reader)?;
+    /// Fetches many images at once, reading records which are close to each
+    /// other in the file with a single read. Returns the dicts in the order of
+    /// `img_ids` and the ids which are not on the page map.
+    pub fn get_dicts<R>(&self, reader: &mut R, img_ids: &[K]) -> Result<ImgDicts<K>, io::Error>
+    where
+        R: RecordSource,
+    {
+        let mut dicts: Vec<Option<(K, JsonDict)>> = vec![None; img_ids.len()];
+        let missing = self.index.read_many(reader, img_ids, |idx, span, buf| {
+            let img_id = &img_ids[idx];
+            let dict: JsonDict = self
+                .index
+                .parse_bytes(buf, span)
+                .map_err(|source| img_id.parse_error(span.offset, source))?;
+            dicts[idx] = Some((img_id.clone(), Self::with_id(dict, img_id)));
+            Ok(())
+        })?;
 
-        if empty {
-            return Ok(page_map);
-        } else {
-            reader.seek(io::SeekFrom::Start(rewind_pos))?;
-        }
-
-        while let Ok(c) = read_skipping_ws(&mut reader) {
-            match c {
-                b'[' | b',' => {
-                    let curr_pos = reader.stream_position()?;
-                    let de = serde_json::Deserializer::from_reader(&mut reader);
-                    let mut stream = de.into_iter::<HashMap<String, serde_json::Value>>();
-                    let offset = curr_pos + stream.byte_offset() as u64;
-
-                    match stream.next().unwrap() {
-                        Ok(parsed_map) => {
-                            let id = parsed_map
-                                .get("id")
-                                .ok_or(stream_error("Cannot find an image id", offset))?
-                                .as_i64()
-                                .ok_or(stream_error("The image id is not an integer.", offset))?;
-
-                            let size = (curr_pos + stream.byte_offset() as u64 - offset) as u32;
-                            page_map.push(id, ImgPage { offset, size });
-                        }
-                        Err(e) => {
-                            return Err(stream_error(e.to_string().as_str(), offset));
-                        }
-                    }
+        Ok((dicts.into_iter().flatten().collect(), missing))
+    }
+
+    pub fn push(&mut self, img_id: K, page: ImgPage) {
+        self.index.insert(img_id, page.offset, page.size);
+        self.sizes.push((page.width, page.height));
+    }
+
+    pub fn from_reader(reader: impl io::Read + io::Seek) -> Result<Self, io::Error> {
+        Self::from_reader_with_options(reader, &ScanOptions::default())
+    }
+
+    pub fn from_reader_with_options(
+        reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+    ) -> Result<Self, io::Error> {
+        let mut page_map = Self::default();
+        page_map.rebuild(reader, options)?;
+        Ok(page_map)
+    }
+
+    /// Replaces the contents with the scan of `reader`, keeping the allocations.
+    pub fn rebuild(
+        &mut self,
+        reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+    ) -> Result<(), io::Error> {
+        self.rebuild_records(reader, options, |record: &ImgFields, span| {
+            record
+                .id
+                .as_ref()
+                .map(|v| {
+                    K::from_id(v).ok_or(stream_error(
+                        format!("The image id is not {}.", K::EXPECTED).as_str(),
+                        span.offset,
+                    ))
+                })
+                .transpose()
+        })
+    }
+
+    /// Like `rebuild`, with the key of every record taken by `key_extractor`.
+    /// Records for which it returns `None` get a synthesized key, if enabled.
+    pub fn rebuild_with<F>(
+        &mut self,
+        reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+        key_extractor: F,
+    ) -> Result<(), io::Error>
+    where
+        F: FnMut(&RecordMap, RecordSpan) -> Result<Option<K>, io::Error>,
+    {
+        self.rebuild_records(reader, options, key_extractor)
+    }
+
+    fn rebuild_records<T, F>(
+        &mut self,
+        reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+        mut key_extractor: F,
+    ) -> Result<(), io::Error>
+    where
+        T: ImgRecord,
+        F: FnMut(&T, RecordSpan) -> Result<Option<K>, io::Error>,
+    {
+        self.sizes.clear();
+        self.synthesized_ids.clear();
+        self.malformed_positions.clear();
+        self.keyed_count = 0;
+        self.id_types = IdTypeCounts::default();
+
+        let malformed = options
+            .skip_malformed_images
+            .then_some(&mut self.malformed_positions);
+        let sizes = &mut self.sizes;
+        let synthesized_ids = &mut self.synthesized_ids;
+        let keyed_count = &mut self.keyed_count;
+        let id_types = &mut self.id_types;
+        let mut seen = HashSet::new();
+        let mut position = 0;
+        let restrict_to: Option<HashSet<K>> = options.restrict_to_img_ids.as_ref().map(|ids| {
+            ids.iter()
+                .filter_map(|id| K::from_id(&(*id).into()))
+                .collect()
+        });
+
+        self.index
+            .rebuild_skipping(reader, options, malformed, |record: T, span, _| {
+                let offset = span.offset;
+                position += 1;
+                id_types.count(record.id());
+                let (id, synthesized) = match (
+                    key_extractor(&record, span)?,
+                    options.synthesize_missing_ids,
+                ) {
+                    (Some(id), _) => (id, false),
+                    (None, Some(start)) => (K::synthesized(start + position - 1), true),
+                    (None, None) => return Err(stream_error("Cannot find an image id", offset)),
+                };
+
+                let size = |value| {
+                    parse_dimension(value).map_err(|e| stream_error(e.to_string().as_str(), offset))
+                };
+                let (width, height) = (size(record.width())?, size(record.height())?);
+
+                if !seen.insert(id.clone()) && (synthesized || synthesized_ids.contains(&id)) {
+                    return Err(stream_error(
+                        format!("The synthesized image id: {} is already taken.", id).as_str(),
+                        offset,
+                    ));
                 }
-                b']' => break,
-                _ => {}
-            }
+                if synthesized {
+                    synthesized_ids.push(id.clone());
+                }
+                *keyed_count += 1;
+
+                if restrict_to.as_ref().is_some_and(|ids| !ids.contains(&id)) {
+                    return Ok(None);
+                }
+
+                sizes.push((width, height));
+                Ok(Some(id))
+            })
+    }
+
+    fn page_at(&self, position: usize) -> ImgPage {
+        let span = self.index.span_at(position);
+        let (width, height) = self.sizes[position];
+        ImgPage {
+            offset: span.offset,
+            size: span.size,
+            width,
+            height,
         }
-        Ok(page_map)
     }
 
-    pub fn ids(&self) -> &Vec<i64> {
-        return &self.ids;
+    pub fn get_page(&self, img_id: &K) -> Option<ImgPage> {
+        self.index
+            .position(img_id)
+            .map(|position| self.page_at(position))
+    }
+
+    pub fn ids(&self) -> &Vec<K> {
+        self.index.keys()
+    }
+
+    pub fn synthesized_ids(&self) -> &Vec<K> {
+        &self.synthesized_ids
+    }
+
+    /// Number of elements in the scanned array, including those left out.
+    pub fn scanned_count(&self) -> usize {
+        self.keyed_count + self.malformed_positions.len()
+    }
+
+    /// Number of elements which could not be keyed, skipped with
+    /// `ScanOptions::skip_malformed_images`.
+    pub fn skipped_count(&self) -> usize {
+        self.scanned_count() - self.keyed_count
+    }
+
+    pub fn malformed_positions(&self) -> &Vec<u64> {
+        &self.malformed_positions
+    }
+
+    /// Types of the `id` values of the scanned records, skipped ones included.
+    pub fn id_types(&self) -> IdTypeCounts {
+        self.id_types
+    }
+
+    pub fn io_tuning(&self) -> IoTuning {
+        self.index.io_tuning()
+    }
+
+    pub fn set_io_tuning(&mut self, io_tuning: IoTuning) {
+        self.index.set_io_tuning(io_tuning);
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.index.memory_bytes()
+            + vec_bytes(&self.sizes)
+            + vec_bytes(&self.synthesized_ids)
+            + vec_bytes(&self.malformed_positions)
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.index.shrink_to_fit();
+        self.sizes.shrink_to_fit();
+        self.synthesized_ids.shrink_to_fit();
+        self.malformed_positions.shrink_to_fit();
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
     }
 }
 
-impl IntoIterator for ImgPageMap {
-    type Item = (i64, ImgPage);
+impl<K: ImgKey> IntoIterator for ImgPageMap<K> {
+    type Item = (K, ImgPage);
 
-    type IntoIter = <HashMap<i64, ImgPage> as IntoIterator>::IntoIter;
+    type IntoIter = std::vec::IntoIter<(K, ImgPage)>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.pages.into_iter()
+        let index = &self.index;
+        (0..self.len())
+            .filter(|position| index.position(&index.keys()[*position]) == Some(*position))
+            .map(|position| (index.keys()[position].clone(), self.page_at(position)))
+            .collect::<Vec<_>>()
+            .into_iter()
     }
 }
 
-impl Default for ImgPageMap {
+impl<K: ImgKey> Default for ImgPageMap<K> {
     fn default() -> Self {
         Self {
-            ids: Vec::with_capacity(0),
-            pages: HashMap::with_capacity(0),
+            index: OffsetIndex::default().keep_last(true),
+            sizes: Vec::with_capacity(0),
+            synthesized_ids: Vec::with_capacity(0),
+            malformed_positions: Vec::with_capacity(0),
+            keyed_count: 0,
+            id_types: IdTypeCounts::default(),
+        }
+    }
+}
+
+/// A section parsed on first access, for the `licenses` and `info` sections,
+/// which are rarely read. The scan checks the grammar and only keeps the span,
+/// the section is read again from the file when it is first needed.
+#[derive(Debug, Default)]
+pub struct LazySection {
+    offset: u64,
+    size: u64,
+    allow_trailing_commas: bool,
+    parsed: OnceLock<JsonDict>,
+}
+
+impl LazySection {
+    pub fn from_reader(
+        reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+    ) -> Result<Self, io::Error> {
+        let span = check_json_value(reader, options.allow_trailing_commas)?;
+        Ok(Self {
+            offset: span.start,
+            size: span.end - span.start,
+            allow_trailing_commas: options.allow_trailing_commas,
+            parsed: OnceLock::new(),
+        })
+    }
+
+    pub fn from_json(value: JsonDict) -> Self {
+        Self {
+            parsed: OnceLock::from(value),
+            ..Default::default()
+        }
+    }
+
+    /// The section, read from `reader` and parsed on the first call.
+    pub fn get<R: io::Read + io::Seek>(&self, mut reader: R) -> Result<&JsonDict, io::Error> {
+        if let Some(parsed) = self.parsed.get() {
+            return Ok(parsed);
+        }
+        let mut buf = vec![0; self.size as usize];
+        reader.seek(io::SeekFrom::Start(self.offset))?;
+        reader.read_exact(&mut buf)?;
+        let parsed = parse_record(&buf, self.offset, self.allow_trailing_commas)?;
+        Ok(self.parsed.get_or_init(|| parsed))
+    }
+
+    pub fn is_parsed(&self) -> bool {
+        self.parsed.get().is_some()
+    }
+
+    pub fn into_json<R: io::Read + io::Seek>(mut self, reader: R) -> Result<JsonDict, io::Error> {
+        self.get(reader)?;
+        Ok(self.parsed.take().unwrap_or_default())
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.parsed.get().map_or(0, json_bytes)
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        if let Some(parsed) = self.parsed.get_mut() {
+            shrink_json(parsed);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CategoryId {
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+}
+
+/// The `categories` section, indexed by category id. Single categories are
+/// read on demand with `get_category`, while `all` parses the whole section
+/// once from the bytes kept by the scan, which are dropped then. A section
+/// which is not an array is parsed right away.
+#[derive(Debug, Default)]
+pub struct CategoriesPageMap {
+    /// Categories without an integer `id` are indexed under `None`.
+    index: OffsetIndex<Option<i64>>,
+    /// The section as scanned, until it is parsed.
+    raw: Mutex<Vec<u8>>,
+    raw_offset: u64,
+    allow_trailing_commas: bool,
+    parsed: OnceLock<JsonDict>,
+    parsed_count: AtomicUsize,
+}
+
+impl CategoriesPageMap {
+    pub fn from_reader(
+        reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+    ) -> Result<Self, io::Error> {
+        let mut page_map = Self::default();
+        page_map.rebuild(reader, options)?;
+        Ok(page_map)
+    }
+
+    pub fn rebuild(
+        &mut self,
+        mut reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+    ) -> Result<(), io::Error> {
+        self.parsed = OnceLock::new();
+        self.allow_trailing_commas = options.allow_trailing_commas;
+
+        let mut tokens = JsonTokenizer::new(&mut reader)?;
+        let first = tokens.peek_byte()?;
+        tokens.into_inner()?;
+
+        if let Some((b'[', start)) = first {
+            let scanned = self
+                .index
+                .rebuild(&mut reader, options, |record: CategoryId, _| {
+                    Ok(record.id.and_then(|id| id.as_i64()))
+                });
+            if scanned.is_ok() {
+                let end = reader.stream_position()?;
+                reader.seek(io::SeekFrom::Start(start))?;
+                let raw = self.raw.get_mut().unwrap();
+                raw.resize((end - start) as usize, 0);
+                reader.read_exact(raw)?;
+                self.raw_offset = start;
+                return Ok(());
+            }
+            // A full parse reports where the section is malformed, as before.
+            reader.seek(io::SeekFrom::Start(start))?;
+            return self.parse_whole(reader, options).and(scanned);
+        }
+        self.parse_whole(reader, options)
+    }
+
+    fn parse_whole(
+        &mut self,
+        reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+    ) -> Result<(), io::Error> {
+        self.index.clear();
+        self.raw.get_mut().unwrap().clear();
+        let parsed = if options.allow_trailing_commas {
+            parse_lenient_json_value(reader)?
+        } else {
+            parse_serde_json_value(reader)?
+        };
+        self.parsed = OnceLock::from(parsed);
+        Ok(())
+    }
+
+    /// The whole section, parsed on the first call. The scan only parses the
+    /// ids, so this still fails on e.g. numbers out of range in other fields.
+    pub fn all(&self) -> Result<&JsonDict, io::Error> {
+        if let Some(parsed) = self.parsed.get() {
+            return Ok(parsed);
+        }
+        let mut raw = self.raw.lock().unwrap();
+        // Another thread may have parsed the section meanwhile.
+        if let Some(parsed) = self.parsed.get() {
+            return Ok(parsed);
+        }
+        let parsed: JsonDict = parse_record(&raw, self.raw_offset, self.allow_trailing_commas)?;
+        self.parsed_count
+            .fetch_add(parsed.as_array().map_or(0, |v| v.len()), Ordering::Relaxed);
+        *raw = vec![];
+        Ok(self.parsed.get_or_init(|| parsed))
+    }
+
+    pub fn into_json(mut self) -> Result<JsonDict, io::Error> {
+        self.all()?;
+        Ok(self.parsed.take().unwrap_or_default())
+    }
+
+    /// Reads the first category with `id == cat_id`.
+    pub fn get_category<R: io::Read + io::Seek>(
+        &self,
+        cat_id: i64,
+        reader: &mut R,
+    ) -> Result<Option<JsonDict>, io::Error> {
+        let category = self.index.get_one(reader, &Some(cat_id))?;
+        if category.is_some() {
+            self.parsed_count.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(category)
+    }
+
+    /// Number of category objects parsed so far, by `all` and `get_category`.
+    pub fn parsed_count(&self) -> usize {
+        self.parsed_count.load(Ordering::Relaxed)
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.index.memory_bytes()
+            + vec_bytes(&self.raw.lock().unwrap())
+            + self.parsed.get().map_or(0, json_bytes)
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.index.shrink_to_fit();
+        self.raw.get_mut().unwrap().shrink_to_fit();
+        if let Some(parsed) = self.parsed.get_mut() {
+            shrink_json(parsed);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.parsed.get() {
+            Some(parsed) => parsed.as_array().map_or(0, |v| v.len()),
+            None => self.index.len(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SegKind {
+    None,
+    Polygon,
+    Rle,
+    CompressedRle,
+}
+
+impl SegKind {
+    pub fn from_segmentation(segmentation: Option<&serde_json::Value>) -> Self {
+        match segmentation {
+            Some(serde_json::Value::Array(v)) if !v.is_empty() => SegKind::Polygon,
+            Some(serde_json::Value::Object(v)) => match v.get("counts") {
+                Some(serde_json::Value::String(_)) => SegKind::CompressedRle,
+                Some(serde_json::Value::Array(_)) => SegKind::Rle,
+                _ => SegKind::None,
+            },
+            _ => SegKind::None,
+        }
+    }
+}
+
+/// The `SegKind` of a `segmentation` value, told without building the value.
+struct SegShape(SegKind);
+
+impl Default for SegShape {
+    fn default() -> Self {
+        SegShape(SegKind::None)
+    }
+}
+
+impl<'de> Deserialize<'de> for SegShape {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_any(ShapeVisitor { counts: false })
+            .map(SegShape)
+    }
+}
+
+/// Classifies a `segmentation` value, or its `counts` field if `counts`.
+struct ShapeVisitor {
+    counts: bool,
 }
 
-#[derive(Debug)]
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum SegField {
+    Counts,
+    #[serde(other)]
+    Other,
+}
+
+impl<'de> Visitor<'de> for ShapeVisitor {
+    type Value = SegKind;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a segmentation")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<SegKind, A::Error> {
+        let mut len = 0;
+        while seq.next_element::<IgnoredAny>()?.is_some() {
+            len += 1;
+        }
+        Ok(match (self.counts, len) {
+            (true, _) => SegKind::Rle,
+            (false, 0) => SegKind::None,
+            (false, _) => SegKind::Polygon,
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SegKind, A::Error> {
+        let mut kind = SegKind::None;
+        while let Some(field) = map.next_key::<SegField>()? {
+            match field {
+                SegField::Counts if !self.counts => {
+                    kind = map.next_value_seed(ShapeVisitor { counts: true })?;
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(if self.counts { SegKind::None } else { kind })
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<SegKind, E> {
+        Ok(match self.counts {
+            true => SegKind::CompressedRle,
+            false => SegKind::None,
+        })
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<SegKind, E> {
+        Ok(SegKind::None)
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<SegKind, E> {
+        Ok(SegKind::None)
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<SegKind, E> {
+        Ok(SegKind::None)
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<SegKind, E> {
+        Ok(SegKind::None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<SegKind, E> {
+        Ok(SegKind::None)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ShapeVisitor {
+    type Value = SegKind;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<SegKind, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+/// The fields of an annotation read by `AnnPageMap::rebuild`, see `ImgFields`.
+#[derive(Deserialize)]
+struct AnnFields {
+    #[serde(default, deserialize_with = "present")]
+    id: Option<serde_json::Value>,
+    #[serde(default, deserialize_with = "present")]
+    image_id: Option<serde_json::Value>,
+    category_id: Option<serde_json::Value>,
+    area: Option<serde_json::Value>,
+    iscrowd: Option<serde_json::Value>,
+    #[serde(default)]
+    segmentation: SegShape,
+    #[serde(default, deserialize_with = "present")]
+    segments_info: Option<serde_json::Value>,
+    #[serde(default, deserialize_with = "is_present")]
+    caption: bool,
+    #[serde(default, deserialize_with = "is_present")]
+    keypoints: bool,
+}
+
+/// Kind of COCO annotation file, told by the fields of its first annotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnnotationFlavor {
+    Instances,
+    PersonKeypoints,
+    Captions,
+    Panoptic,
+}
+
+impl AnnotationFlavor {
+    fn from_record(record: &AnnFields) -> Self {
+        if record.segments_info.is_some() {
+            AnnotationFlavor::Panoptic
+        } else if record.caption {
+            AnnotationFlavor::Captions
+        } else if record.keypoints {
+            AnnotationFlavor::PersonKeypoints
+        } else {
+            AnnotationFlavor::Instances
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct AnnPage {
     pub id: i64,
-    pub offset: u64,
-    pub size: u32,
+    pub img_id: i64,
     pub ptr: usize,
+    pub seg_kind: SegKind,
+    pub category_id: Option<i64>,
+}
+
+/// Scalars of an annotation kept by the rich index. A missing `category_id` is
+/// stored as -1, a missing `area` as NaN.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnnEntry {
+    pub ann_id: i64,
+    pub category_id: i64,
+    pub area: f32,
+    pub iscrowd: bool,
+}
+
+impl AnnEntry {
+    fn from_record(ann_id: i64, record: &AnnFields) -> Self {
+        Self {
+            ann_id,
+            category_id: record
+                .category_id
+                .as_ref()
+                .and_then(|v| v.as_i64())
+                .unwrap_or(-1),
+            area: record
+                .area
+                .as_ref()
+                .and_then(|v| v.as_f64())
+                .map_or(f32::NAN, |v| v as f32),
+            iscrowd: record.iscrowd.as_ref().and_then(is_crowd).unwrap_or(false),
+        }
+    }
+}
+
+/// An entry of the `segments_info` of a panoptic annotation, see
+/// `AnnPageMap::get_segment`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SegmentInfo {
+    pub id: i64,
+    pub category_id: i64,
+    #[serde(default)]
+    pub area: Option<f64>,
+    #[serde(default)]
+    pub bbox: Option<[f64; 4]>,
+    #[serde(default)]
+    pub iscrowd: Option<i64>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AnnPageMap {
+    /// Records keyed by annotation id. `pages` is parallel to it.
+    index: OffsetIndex<i64>,
     pages: Vec<AnnPage>,
-    head_pointers: HashMap<i64, usize>,
+    /// First and last page of the annotation list of every image, in file order.
+    head_pointers: HashMap<i64, (usize, usize)>,
+    /// Categories of panoptic `segments_info` entries, which have no page of their own.
+    segment_category_ids: HashSet<i64>,
+    /// Spans of the `segments_info` entries by image and segment id, only
+    /// built for panoptic files.
+    segment_spans: HashMap<(i64, i64), RecordSpan>,
+    flavor: Option<AnnotationFlavor>,
+    /// Rich index entries grouped by image, in file order within an image.
+    entries: Vec<AnnEntry>,
+    entry_ranges: HashMap<i64, (usize, usize)>,
+    malformed_positions: Vec<u64>,
+    img_id_types: IdTypeCounts,
+    indexed: bool,
+}
+
+/// Appends `page` to the list of `img_id`.
+fn link(
+    pages: &mut Vec<AnnPage>,
+    head_pointers: &mut HashMap<i64, (usize, usize)>,
+    img_id: i64,
+    mut page: AnnPage,
+) {
+    let new_idx = pages.len();
+    page.ptr = usize::MAX;
+    pages.push(page);
+
+    match head_pointers.get_mut(&img_id) {
+        Some((_, tail)) => {
+            pages[*tail].ptr = new_idx;
+            *tail = new_idx;
+        }
+        None => {
+            head_pointers.insert(img_id, (new_idx, new_idx));
+        }
+    }
 }
 
 impl AnnPageMap {
+    /// The rich index entries of an image, empty unless the map was built
+    /// with `ScanOptions::rich_index`.
+    pub fn entries(&self, img_id: i64) -> &[AnnEntry] {
+        match self.entry_ranges.get(&img_id) {
+            Some((start, end)) => &self.entries[*start..*end],
+            None => &[],
+        }
+    }
+
+    /// Regroups `entries`, parallel to `pages`, by image.
+    fn group_entries(&mut self) {
+        let mut grouped = Vec::with_capacity(self.entries.len());
+        for (img_id, (head, _)) in self.head_pointers.iter() {
+            let start = grouped.len();
+            let mut ptr = *head;
+            while ptr != usize::MAX {
+                grouped.push(self.entries[ptr]);
+                ptr = self.pages[ptr].ptr;
+            }
+            self.entry_ranges.insert(*img_id, (start, grouped.len()));
+        }
+        self.entries = grouped;
+    }
+
+    /// `None` if there are no annotations.
+    pub fn flavor(&self) -> Option<AnnotationFlavor> {
+        self.flavor
+    }
+
+    /// `false` if the map was built with `ScanOptions::skip_annotations`,
+    /// reading annotations then fails.
+    pub fn is_indexed(&self) -> bool {
+        self.indexed
+    }
+
+    fn check_indexed(&self) -> Result<(), io::Error> {
+        match self.indexed {
+            true => Ok(()),
+            false => Err(invalid_data("The annotation index is disabled.")),
+        }
+    }
+
     pub fn get_anns<R>(&self, reader: &mut R, img_id: i64) -> Result<Vec<JsonDict>, io::Error>
     where
         R: io::Read + io::Seek,
     {
-        let curr_ptr = self.head_pointers.get(&img_id);
+        self.get_anns_as(reader, img_id)
+    }
 
-        match curr_ptr {
-            Some(head) => {
-                let mut anns = vec![];
-                let mut ptr = *head;
+    /// Like `get_anns`, but deserializes every annotation into `T` so that
+    /// fields not declared by `T` are skipped without being materialized.
+    /// Records of the image lying close to each other are fetched with one
+    /// read, see `OffsetIndex::read_positions`.
+    pub fn get_anns_as<T, R>(&self, reader: &mut R, img_id: i64) -> Result<Vec<T>, io::Error>
+    where
+        T: DeserializeOwned,
+        R: io::Read + io::Seek,
+    {
+        self.get_anns_as_with(reader, img_id, &mut ReadScratch::default())
+    }
 
-                loop {
-                    if ptr == usize::MAX {
-                        break;
-                    }
-                    let page = &self.pages[ptr];
-                    ptr = page.ptr;
+    /// Like `get_anns_as`, but reads through the buffers of `scratch`, so
+    /// that repeated calls only allocate the returned values.
+    pub fn get_anns_as_with<T, R>(
+        &self,
+        reader: &mut R,
+        img_id: i64,
+        scratch: &mut ReadScratch,
+    ) -> Result<Vec<T>, io::Error>
+    where
+        T: DeserializeOwned,
+        R: io::Read + io::Seek,
+    {
+        self.read_anns(reader, img_id, scratch, |buf, span| {
+            self.index.parse_bytes(buf, span)
+        })
+    }
 
-                    match parse_serde_json_value_from_page(reader, page.offset, page.size as u64) {
-                        Ok(v) => {
-                            anns.push(v);
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    }
+    /// Like `get_anns`, but only parses the top-level keys picked by
+    /// `selection`, the other values are skipped.
+    pub fn get_anns_selected<R>(
+        &self,
+        reader: &mut R,
+        img_id: i64,
+        selection: &FieldSelection,
+    ) -> Result<Vec<JsonDict>, io::Error>
+    where
+        R: io::Read + io::Seek,
+    {
+        self.read_anns(reader, img_id, &mut ReadScratch::default(), |buf, span| {
+            self.index.parse_bytes_seed(buf, span, selection)
+        })
+    }
+
+    /// Like `get_anns`, with the object keys shared through `interner`.
+    pub fn get_anns_interned<R>(
+        &self,
+        reader: &mut R,
+        img_id: i64,
+        interner: &mut StringInterner,
+    ) -> Result<Vec<InternedValue>, io::Error>
+    where
+        R: io::Read + io::Seek,
+    {
+        self.read_anns(reader, img_id, &mut ReadScratch::default(), |buf, span| {
+            self.index
+                .parse_bytes_seed(buf, span, InternSeed(&mut *interner))
+        })
+    }
+
+    /// Like `iter_all`, see `get_anns_interned`.
+    pub fn iter_all_interned<'a, R>(
+        &'a self,
+        reader: R,
+        interner: &'a mut StringInterner,
+    ) -> impl Iterator<Item = Result<InternedValue, io::Error>> + 'a
+    where
+        R: io::Read + io::Seek + 'a,
+    {
+        self.iter_all_with(reader, move |buf, span| {
+            self.index
+                .parse_bytes_seed(buf, span, InternSeed(&mut *interner))
+        })
+    }
+
+    /// Like `get_anns`, with the offset of every record in the file.
+    pub fn get_anns_with_offsets<R>(
+        &self,
+        reader: &mut R,
+        img_id: i64,
+    ) -> Result<Vec<(u64, JsonDict)>, io::Error>
+    where
+        R: io::Read + io::Seek,
+    {
+        self.read_anns(reader, img_id, &mut ReadScratch::default(), |buf, span| {
+            Ok((span.offset, self.index.parse_bytes(buf, span)?))
+        })
+    }
+
+    fn read_anns<T, R>(
+        &self,
+        reader: &mut R,
+        img_id: i64,
+        scratch: &mut ReadScratch,
+        mut parse: impl FnMut(&[u8], RecordSpan) -> Result<T, io::Error>,
+    ) -> Result<Vec<T>, io::Error>
+    where
+        R: io::Read + io::Seek,
+    {
+        self.check_indexed()?;
+        let mut positions = std::mem::take(&mut scratch.positions);
+        positions.clear();
+        let mut ptr = self.head(img_id);
+        while ptr != usize::MAX {
+            positions.push(ptr);
+            ptr = self.pages[ptr].ptr;
+        }
+
+        // Records arrive in file order, `order` holds their index in the list.
+        let mut order = std::mem::take(&mut scratch.order);
+        order.clear();
+        let mut anns = Vec::with_capacity(positions.len());
+        let result =
+            self.index
+                .read_positions_with(reader, &positions, scratch, |idx, span, buf| {
+                    let ann = parse(buf, span).map_err(|source| CocoError::FieldParse {
+                        img_id,
+                        ann_id: Some(self.pages[positions[idx]].id),
+                        pos: span.offset,
+                        source,
+                    })?;
+                    anns.push(ann);
+                    order.push(idx);
+                    Ok(())
+                });
+
+        if result.is_ok() {
+            for k in 0..order.len() {
+                while order[k] != k {
+                    let j = order[k];
+                    anns.swap(k, j);
+                    order.swap(k, j);
+                }
+            }
+        }
+        (scratch.positions, scratch.order) = (positions, order);
+        result.map(|_| anns)
+    }
+
+    /// Like `get_anns`, but parses the records right from `data`, the whole
+    /// scanned input, e.g. a memory mapped file.
+    pub fn get_anns_from_bytes(
+        &self,
+        data: &[u8],
+        img_id: i64,
+    ) -> Result<Vec<JsonDict>, io::Error> {
+        self.check_indexed()?;
+        let mut anns = vec![];
+        let mut ptr = self.head(img_id);
+        while ptr != usize::MAX {
+            let span = self.index.span_at(ptr);
+            let ann = self
+                .index
+                .record_bytes(data, ptr)
+                .and_then(|buf| self.index.parse_bytes(buf, span))
+                .map_err(|source| CocoError::FieldParse {
+                    img_id,
+                    ann_id: Some(self.pages[ptr].id),
+                    pos: span.offset,
+                    source,
+                })?;
+            anns.push(ann);
+            ptr = self.pages[ptr].ptr;
+        }
+        Ok(anns)
+    }
+
+    /// Annotations looked up by id, in the order of `ann_ids`, `None` for the
+    /// ids which are not on the page map. Nearby records share a read.
+    pub fn get_by_ids_as<T, R>(
+        &self,
+        reader: &mut R,
+        ann_ids: &[i64],
+    ) -> Result<Vec<Option<T>>, io::Error>
+    where
+        T: DeserializeOwned,
+        R: io::Read + io::Seek,
+    {
+        self.check_indexed()?;
+        let mut anns: Vec<Option<T>> = (0..ann_ids.len()).map(|_| None).collect();
+        self.index.read_many(reader, ann_ids, |idx, span, buf| {
+            let ann = self.index.parse_bytes(buf, span).map_err(|source| {
+                let page = self.get_page(ann_ids[idx]);
+                CocoError::FieldParse {
+                    img_id: page.map_or(-1, |page| page.img_id),
+                    ann_id: Some(ann_ids[idx]),
+                    pos: span.offset,
+                    source,
                 }
+            })?;
+            anns[idx] = Some(ann);
+            Ok(())
+        })?;
+        Ok(anns)
+    }
+
+    pub fn count(&self, img_id: i64) -> usize {
+        let mut count = 0;
+        let mut ptr = self.head(img_id);
+        while ptr != usize::MAX {
+            ptr = self.pages[ptr].ptr;
+            count += 1;
+        }
+        count
+    }
+
+    pub fn get_anns_batch<R>(
+        &self,
+        reader: &mut R,
+        img_ids: &[i64],
+    ) -> Result<Vec<Vec<JsonDict>>, io::Error>
+    where
+        R: RecordSource,
+    {
+        self.read_batch(reader, img_ids, |buf, span| {
+            self.index.parse_bytes(buf, span)
+        })
+    }
+
+    /// Like `get_anns_batch`, see `get_anns_selected`.
+    pub fn get_anns_batch_selected<R>(
+        &self,
+        reader: &mut R,
+        img_ids: &[i64],
+        selection: &FieldSelection,
+    ) -> Result<Vec<Vec<JsonDict>>, io::Error>
+    where
+        R: RecordSource,
+    {
+        self.read_batch(reader, img_ids, |buf, span| {
+            self.index.parse_bytes_seed(buf, span, selection)
+        })
+    }
+
+    fn read_batch<R>(
+        &self,
+        reader: &mut R,
+        img_ids: &[i64],
+        parse: impl Fn(&[u8], RecordSpan) -> Result<JsonDict, io::Error>,
+    ) -> Result<Vec<Vec<JsonDict>>, io::Error>
+    where
+        R: RecordSource,
+    {
+        self.check_indexed()?;
+        let mut anns = Vec::with_capacity(img_ids.len());
+        let mut positions = vec![];
+        let mut slots = vec![];
+
+        for (img_idx, img_id) in img_ids.iter().enumerate() {
+            let mut ptr = self.head(*img_id);
+            let mut ann_idx = 0;
+
+            while ptr != usize::MAX {
+                positions.push(ptr);
+                slots.push((img_idx, ann_idx));
+                ptr = self.pages[ptr].ptr;
+                ann_idx += 1;
+            }
+            anns.push(vec![JsonDict::Null; ann_idx]);
+        }
+
+        // Records are read in file order, so every annotation is visited once with forward seeks only.
+        self.index
+            .read_positions(reader, &positions, |idx, span, buf| {
+                let (img_idx, ann_idx) = slots[idx];
+                anns[img_idx][ann_idx] =
+                    parse(buf, span).map_err(|source| CocoError::FieldParse {
+                        img_id: img_ids[img_idx],
+                        ann_id: Some(self.pages[positions[idx]].id),
+                        pos: span.offset,
+                        source,
+                    })?;
+                Ok(())
+            })?;
+
+        Ok(anns)
+    }
+
+    fn head(&self, img_id: i64) -> usize {
+        self.head_pointers
+            .get(&img_id)
+            .map_or(usize::MAX, |(head, _)| *head)
+    }
+
+    /// Appends `page`, found at `span`, to the annotations of `img_id`, which
+    /// are returned in push order.
+    pub fn push(&mut self, img_id: i64, page: AnnPage, span: RecordSpan) {
+        self.index.insert(page.id, span.offset, span.size);
+        link(&mut self.pages, &mut self.head_pointers, img_id, page);
+    }
+
+    /// `(img_id, offset, size)` of every annotation record, from its `{` to
+    /// its `}`, in file order.
+    pub fn iter_offsets(&self) -> impl Iterator<Item = (i64, u64, u32)> + '_ {
+        self.pages.iter().enumerate().map(|(ptr, page)| {
+            let span = self.index.span_at(ptr);
+            (page.img_id, span.offset, span.size)
+        })
+    }
 
-                Ok(anns)
+    /// Checks that every record offset points at the `{` of an object, with
+    /// only whitespace in front of it. Fails with the first bad offset.
+    pub fn verify_offsets<R>(&self, reader: &mut R) -> Result<(), io::Error>
+    where
+        R: io::Read + io::Seek,
+    {
+        for (_, offset, size) in self.iter_offsets() {
+            reader.seek(io::SeekFrom::Start(offset))?;
+            let mut record = reader.by_ref().take(size as u64);
+            let mut buf = [0; 64];
+            let first = loop {
+                let n = record.read(&mut buf)?;
+                if n == 0 {
+                    break None;
+                }
+                if let Some(c) = buf[..n].iter().find(|c| !is_json_ws(**c)) {
+                    break Some(*c);
+                }
+            };
+            if first != Some(b'{') {
+                return Err(stream_error(
+                    "The annotation offset does not point at an object",
+                    offset,
+                ));
             }
-            None => Ok(vec![]),
         }
+        Ok(())
+    }
+
+    /// `(offset, size)` of the annotation records of an image, in file order.
+    pub fn offsets_for(&self, img_id: i64) -> impl Iterator<Item = (u64, u32)> + '_ {
+        let mut ptr = self.head(img_id);
+        std::iter::from_fn(move || {
+            if ptr == usize::MAX {
+                return None;
+            }
+            let span = self.index.span_at(ptr);
+            ptr = self.pages[ptr].ptr;
+            Some((span.offset, span.size))
+        })
     }
 
-    pub fn push(&mut self, ann_id: i64, img_id: i64, offset: u64, size: u32) {
-        let lookup = self.head_pointers.get(&img_id);
+    /// All annotations in file order.
+    pub fn iter_all<'a, R>(
+        &'a self,
+        reader: R,
+    ) -> impl Iterator<Item = Result<JsonDict, io::Error>> + 'a
+    where
+        R: io::Read + io::Seek + 'a,
+    {
+        self.iter_all_with(reader, |buf, span| self.index.parse_bytes(buf, span))
+    }
+
+    /// Like `iter_all`, parsing the raw bytes of every record with `parse`.
+    pub fn iter_all_with<'a, R, T: 'a>(
+        &'a self,
+        mut reader: R,
+        mut parse: impl FnMut(&[u8], RecordSpan) -> Result<T, io::Error> + 'a,
+    ) -> impl Iterator<Item = Result<T, io::Error>> + 'a
+    where
+        R: io::Read + io::Seek + 'a,
+    {
+        let disabled = self.check_indexed().err().map(Err);
+        disabled
+            .into_iter()
+            .chain(self.pages.iter().enumerate().map(move |(ptr, page)| {
+                let span = self.index.span_at(ptr);
+                let parsed = self
+                    .index
+                    .read_bytes_at(&mut reader, ptr)
+                    .and_then(|buf| parse(&buf, span));
+                parsed.map_err(|source| {
+                    CocoError::FieldParse {
+                        img_id: page.img_id,
+                        ann_id: Some(page.id),
+                        pos: span.offset,
+                        source,
+                    }
+                    .into()
+                })
+            }))
+    }
 
-        let mut ptr = usize::MAX;
-        if let Some(idx) = lookup {
-            ptr = *idx;
+    pub fn get_page(&self, ann_id: i64) -> Option<&AnnPage> {
+        self.index.position(&ann_id).map(|idx| &self.pages[idx])
+    }
+
+    /// Reads a single `segments_info` entry of a panoptic annotation, without
+    /// the rest of the annotation.
+    pub fn get_segment<R: io::Read + io::Seek>(
+        &self,
+        reader: &mut R,
+        img_id: i64,
+        segment_id: i64,
+    ) -> Result<SegmentInfo, io::Error> {
+        let span = self
+            .segment_spans
+            .get(&(img_id, segment_id))
+            .copied()
+            .ok_or_else(|| {
+                let msg = format!(
+                    "Segment id: {} of image id: {} is not on the page map",
+                    segment_id, img_id
+                );
+                invalid_data(msg.as_str())
+            })?;
+        let mut buf = vec![0u8; span.size as usize];
+        reader.seek(io::SeekFrom::Start(span.offset))?;
+        reader.read_exact(&mut buf)?;
+        self.index.parse_bytes(&buf, span)
+    }
+
+    pub fn seg_kind_counts(&self) -> HashMap<SegKind, usize> {
+        let mut counts = HashMap::new();
+        for page in self.pages.iter() {
+            *counts.entry(page.seg_kind).or_default() += 1;
         }
-        let new_head_idx = self.pages.len();
-        self.pages.push(AnnPage {
-            id: ann_id,
-            offset,
-            size,
-            ptr,
-        });
-        self.head_pointers.insert(img_id, new_head_idx);
+        counts
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.index.memory_bytes()
+            + vec_bytes(&self.pages)
+            + hash_map_bytes(&self.head_pointers)
+            + hash_set_bytes(&self.segment_category_ids)
+            + hash_map_bytes(&self.segment_spans)
+            + vec_bytes(&self.entries)
+            + hash_map_bytes(&self.entry_ranges)
+            + vec_bytes(&self.malformed_positions)
     }
 
-    pub fn from_reader(mut reader: impl io::Read + io::Seek) -> Result<AnnPageMap, io::Error> {
+    pub fn shrink_to_fit(&mut self) {
+        self.index.shrink_to_fit();
+        self.pages.shrink_to_fit();
+        self.head_pointers.shrink_to_fit();
+        self.segment_category_ids.shrink_to_fit();
+        self.segment_spans.shrink_to_fit();
+        self.entries.shrink_to_fit();
+        self.entry_ranges.shrink_to_fit();
+        self.malformed_positions.shrink_to_fit();
+    }
+
+    /// Heap bytes of the annotation offsets, part of `memory_bytes`.
+    pub fn offset_bytes(&self) -> usize {
+        self.index.span_bytes()
+    }
+
+    /// Offsets of the annotation objects skipped by the scan, see
+    /// `ScanOptions::skip_malformed_anns`.
+    pub fn malformed_positions(&self) -> &Vec<u64> {
+        &self.malformed_positions
+    }
+
+    /// Types of the `image_id` values of the scanned annotations.
+    pub fn img_id_types(&self) -> IdTypeCounts {
+        self.img_id_types
+    }
+
+    pub fn set_io_tuning(&mut self, io_tuning: IoTuning) {
+        self.index.set_io_tuning(io_tuning);
+    }
+
+    /// Images with at least one annotation of the given categories.
+    pub fn img_ids_with_categories(&self, category_ids: &HashSet<i64>) -> HashSet<i64> {
+        self.pages
+            .iter()
+            .filter(|page| page.category_id.is_some_and(|v| category_ids.contains(&v)))
+            .map(|page| page.img_id)
+            .collect()
+    }
+
+    /// Ids of the images with at least one annotation, in no particular order.
+    pub fn img_ids(&self) -> impl Iterator<Item = i64> + '_ {
+        self.head_pointers.keys().copied()
+    }
+
+    pub fn category_ids(&self) -> HashSet<i64> {
+        let mut ids = self.segment_category_ids.clone();
+        ids.extend(self.pages.iter().filter_map(|page| page.category_id));
+        ids
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub fn from_reader(reader: impl io::Read + io::Seek) -> Result<AnnPageMap, io::Error> {
+        Self::from_reader_with_options(reader, &ScanOptions::default())
+    }
+
+    pub fn from_reader_with_options(
+        reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+    ) -> Result<AnnPageMap, io::Error> {
         let mut page_map = AnnPageMap::default();
+        page_map.rebuild(reader, options)?;
+        Ok(page_map)
+    }
 
-        let (empty, rewind_pos) = is_empty_list(&mut reader)?;
+    /// Replaces the contents with the scan of `reader`, keeping the allocations.
+    pub fn rebuild(
+        &mut self,
+        mut reader: impl io::Read + io::Seek,
+        options: &ScanOptions,
+    ) -> Result<(), io::Error> {
+        self.pages.clear();
+        self.head_pointers.clear();
+        self.segment_category_ids.clear();
+        self.segment_spans.clear();
+        self.flavor = None;
+        self.entries.clear();
+        self.entry_ranges.clear();
+        self.malformed_positions.clear();
+        self.img_id_types = IdTypeCounts::default();
+        self.index.clear();
 
-        if empty {
-            return Ok(page_map);
-        } else {
-            reader.seek(io::SeekFrom::Start(rewind_pos))?;
+        self.indexed = !options.skip_annotations;
+        if !self.indexed {
+            let (_, size) = count_array_elements(&mut reader)?;
+            reader.seek(io::SeekFrom::Current(size as i64))?;
+            return Ok(());
         }
 
+        self.index.set_compact(options.compact_index);
+
+        let malformed = options
+            .skip_malformed_anns
+            .then_some(&mut self.malformed_positions);
+        let pages = &mut self.pages;
+        let head_pointers = &mut self.head_pointers;
+        let segment_category_ids = &mut self.segment_category_ids;
+        let segment_spans = &mut self.segment_spans;
+        let flavor = &mut self.flavor;
+        let entries = &mut self.entries;
+        let img_id_types = &mut self.img_id_types;
         let mut missing_ann_id = 0;
 
-        while let Ok(c) = read_skipping_ws(&mut reader) {
-            match c {
-                b'[' | b',' => {
-                    let curr_pos = reader.stream_position()?;
-                    let de = serde_json::Deserializer::from_reader(&mut reader);
-                    let mut stream = de.into_iter::<HashMap<String, serde_json::Value>>();
-                    let offset = curr_pos + stream.byte_offset() as u64;
-
-                    match stream.next().unwrap() {
-                        Ok(parsed_map) => {
-                            let ann_id = if let Some(v) = parsed_map.get("id") {
-                                v.as_i64().ok_or(stream_error(
-                                    "The annotation id is not an integer.",
-                                    offset,
-                                ))?
-                            } else {
-                                let new_id = missing_ann_id;
-                                missing_ann_id += 1;
-                                new_id
-                            };
+        self.index.rebuild_skipping(
+            &mut reader,
+            options,
+            malformed,
+            |record: AnnFields, span, bytes| {
+                let offset = span.offset;
+                img_id_types.count(record.image_id.as_ref());
+                let ann_id = match &record.id {
+                    Some(v) => Some(
+                        v.as_i64()
+                            .ok_or(stream_error("The annotation id is not an integer.", offset))?,
+                    ),
+                    None => None,
+                };
 
-                            let img_id = parsed_map
-                                .get("image_id")
-                                .ok_or(stream_error("Cannot find an image id", offset))?
-                                .as_i64()
-                                .ok_or(stream_error("The image id is not an integer.", offset))?;
+                let img_id = record
+                    .image_id
+                    .as_ref()
+                    .ok_or(stream_error("Cannot find an image id", offset))?
+                    .as_i64()
+                    .ok_or(stream_error("The image id is not an integer.", offset))?;
 
-                            let size = (curr_pos + stream.byte_offset() as u64 - offset) as u32;
-                            page_map.push(ann_id, img_id, offset, size);
-                        }
-                        Err(e) => {
-                            return Err(stream_error(e.to_string().as_str(), offset));
+                // Assigned once the record is accepted, so skipped records take no id.
+                let ann_id = ann_id.unwrap_or_else(|| {
+                    missing_ann_id += 1;
+                    missing_ann_id - 1
+                });
+
+                if flavor.is_none() {
+                    *flavor = Some(AnnotationFlavor::from_record(&record));
+                }
+
+                if let Some(img_ids) = &options.restrict_to_img_ids {
+                    if !img_ids.contains(&img_id) {
+                        return Ok(None);
+                    }
+                }
+
+                let seg_kind = record.segmentation.0;
+                let category_id = record.category_id.as_ref().and_then(|v| v.as_i64());
+
+                let segments = record.segments_info.as_ref().and_then(|v| v.as_array());
+                if let (Some(segments), Some(AnnotationFlavor::Panoptic)) = (segments, *flavor) {
+                    let spans = array_element_spans(bytes, b"segments_info")?;
+                    for (segment, segment_span) in segments.iter().zip(spans) {
+                        if let Some(segment_id) = segment.get("id").and_then(|v| v.as_i64()) {
+                            // Segments are shorter than their record.
+                            let span = RecordSpan {
+                                offset: offset + segment_span.start,
+                                size: (segment_span.end - segment_span.start) as u32,
+                            };
+                            segment_spans.insert((img_id, segment_id), span);
                         }
                     }
                 }
-                b']' => break,
-                _ => {}
-            }
+                if let Some(segments) = segments {
+                    segment_category_ids.extend(
+                        segments
+                            .iter()
+                            .filter_map(|v| v.get("category_id").and_then(|v| v.as_i64())),
+                    );
+                }
+
+                if options.rich_index {
+                    entries.push(AnnEntry::from_record(ann_id, &record));
+                }
+
+                link(
+                    pages,
+                    head_pointers,
+                    img_id,
+                    AnnPage {
+                        id: ann_id,
+                        img_id,
+                        ptr: usize::MAX,
+                        seg_kind,
+                        category_id,
+                    },
+                );
+                Ok(Some(ann_id))
+            },
+        )?;
+
+        if options.rich_index {
+            self.group_entries();
         }
-        Ok(page_map)
+        Ok(())
     }
 }
 
 impl Default for AnnPageMap {
     fn default() -> Self {
         Self {
+            index: OffsetIndex::default(),
             pages: Vec::with_capacity(0),
             head_pointers: HashMap::with_capacity(0),
+            segment_category_ids: HashSet::with_capacity(0),
+            segment_spans: HashMap::with_capacity(0),
+            flavor: None,
+            entries: Vec::with_capacity(0),
+            entry_ranges: HashMap::with_capacity(0),
+            malformed_positions: Vec::with_capacity(0),
+            img_id_types: IdTypeCounts::default(),
+            indexed: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coco_page_mapper::CocoPageMapper;
+    use crate::offset_index::MAX_COALESCE_GAP;
+    use crate::test_utils::{allocations, CountingReader, EXAMPLE};
+
+    #[test]
+    fn test_get_dicts() {
+        let mut images = String::from("[");
+        for img_id in 0..10 {
+            if img_id > 0 {
+                images.push_str(",\n");
+            }
+            if img_id == 5 {
+                images.push_str(&" ".repeat(2 * MAX_COALESCE_GAP as usize));
+            }
+            images.push_str(&format!(
+                r#"{{"id":{},"file_name":"{}.jpg"}}"#,
+                img_id, img_id
+            ));
+        }
+        images.push(']');
+
+        let mut reader = CountingReader::new(io::Cursor::new(images.into_bytes()));
+        let page_map = ImgPageMap::from_reader(&mut reader).unwrap();
+
+        (reader.reads, reader.seeks) = (0, 0);
+        let (dicts, missing) = page_map
+            .get_dicts(&mut reader, &[3, 1, 42, 0, 2, 7, 8])
+            .unwrap();
+        // One read for the images 0 to 3 and one for 7 and 8, past the padding.
+        assert_eq!((reader.reads, reader.seeks), (2, 2));
+
+        assert_eq!(
+            dicts.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![3, 1, 0, 2, 7, 8]
+        );
+        for (img_id, dict) in dicts {
+            assert_eq!(dict["file_name"], format!("{}.jpg", img_id));
+            assert_eq!(dict, page_map.get_dict(&mut reader, img_id).unwrap());
+        }
+        assert_eq!(missing, vec![42]);
+
+        let mut reader = io::Cursor::new(EXAMPLE.as_bytes());
+        let (dicts, missing) = ImgPageMap::default().get_dicts(&mut reader, &[5]).unwrap();
+        assert!(dicts.is_empty());
+        assert_eq!(missing, vec![5]);
+    }
+
+    #[test]
+    fn test_string_keys() {
+        let images = r#"[
+            {"id": "a", "file_name": "a.jpg", "width": 4, "height": 3},
+            {"id": 7, "file_name": "7.jpg"},
+            {"file_name": "c.jpg"}
+        ]"#;
+        let options = ScanOptions {
+            synthesize_missing_ids: Some(100),
+            ..Default::default()
+        };
+        let mut reader = io::Cursor::new(images.as_bytes());
+        let page_map: ImgPageMap<String> =
+            ImgPageMap::from_reader_with_options(&mut reader, &options).unwrap();
+
+        assert_eq!(page_map.ids(), &vec!["a", "7", "102"]);
+        assert_eq!(page_map.synthesized_ids(), &vec!["102"]);
+        let page = page_map.get_page(&"a".to_owned()).unwrap();
+        assert_eq!((page.width, page.height), (Some(4), Some(3)));
+
+        let dict = page_map.get_dict(&mut reader, "102".to_owned()).unwrap();
+        assert_eq!(dict["id"], "102");
+        assert_eq!(dict["file_name"], "c.jpg");
+        let (dicts, missing) = page_map
+            .get_dicts(&mut reader, &["7".to_owned(), "b".to_owned()])
+            .unwrap();
+        assert_eq!(dicts[0].1["file_name"], "7.jpg");
+        assert_eq!(missing, vec!["b"]);
+
+        // Keys taken from another field.
+        let mut page_map = ImgPageMap::<String>::default();
+        reader.set_position(0);
+        page_map
+            .rebuild_with(&mut reader, &ScanOptions::default(), |record, _| {
+                Ok(record
+                    .get("file_name")
+                    .and_then(|v| v.as_str())
+                    .map(|v| v.to_owned()))
+            })
+            .unwrap();
+        assert_eq!(page_map.ids(), &vec!["a.jpg", "7.jpg", "c.jpg"]);
+
+        let err =
+            ImgPageMap::<String>::from_reader(io::Cursor::new(r#"[{"id": 1.5}]"#)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("The image id is not a string or an integer."));
+    }
+
+    #[test]
+    fn test_seg_shape() {
+        for segmentation in [
+            r#"[]"#,
+            r#"[[0, 0, 1, 1, 2, 2]]"#,
+            r#"{"size": [5, 10], "counts": [5, 45]}"#,
+            r#"{"counts": "52203", "size": [5, 10]}"#,
+            r#"{"counts": {"a": 1}}"#,
+            r#"{"counts": "a", "counts": [1]}"#,
+            r#"{"size": [5, 10]}"#,
+            r#"null"#,
+            r#""abc""#,
+            r#"1.5"#,
+        ] {
+            let value: serde_json::Value = serde_json::from_str(segmentation).unwrap();
+            let shape: SegShape = serde_json::from_str(segmentation).unwrap();
+            assert_eq!(
+                shape.0,
+                SegKind::from_segmentation(Some(&value)),
+                "{}",
+                segmentation
+            );
+        }
+
+        let record: AnnFields = serde_json::from_str(
+            r#"{"image_id": 1, "id": null, "caption": null, "extra": {"id": 2}}"#,
+        )
+        .unwrap();
+        assert_eq!(record.id, Some(serde_json::Value::Null));
+        assert_eq!(record.image_id, Some(1.into()));
+        assert!(record.caption && !record.keypoints);
+        assert!(record.segments_info.is_none());
+    }
+
+    #[test]
+    fn test_iter_offsets() {
+        let input = EXAMPLE.replace(",\n", " ,\n\t");
+        let mut reader = io::Cursor::new(input.as_bytes());
+        let start = input.find(r#""annotations":"#).unwrap() + r#""annotations":"#.len();
+        reader.set_position(start as u64);
+        let page_map = AnnPageMap::from_reader(&mut reader).unwrap();
+
+        let offsets: Vec<(i64, u64, u32)> = page_map.iter_offsets().collect();
+        assert_eq!(offsets.len(), 5);
+        assert!(offsets.windows(2).all(|v| v[0].1 < v[1].1));
+        for (img_id, offset, size) in offsets.iter() {
+            let record = &input.as_bytes()[*offset as usize..(*offset + *size as u64) as usize];
+            assert_eq!((record[0], record[record.len() - 1]), (b'{', b'}'));
+            let ann: JsonDict = serde_json::from_slice(record).unwrap();
+            assert_eq!(ann["image_id"], *img_id);
+        }
+
+        let for_image: Vec<(u64, u32)> = page_map.offsets_for(6).collect();
+        let expected: Vec<(u64, u32)> = offsets
+            .iter()
+            .filter(|(img_id, _, _)| *img_id == 6)
+            .map(|(_, offset, size)| (*offset, *size))
+            .collect();
+        assert_eq!(for_image, expected);
+        assert_eq!(page_map.offsets_for(42).count(), 0);
+    }
+
+    #[test]
+    fn test_get_anns_scratch_allocations() {
+        use serde::de::IgnoredAny;
+
+        let mut reader = io::Cursor::new(EXAMPLE.as_bytes());
+        let parts = CocoPageMapper::from_bytes(EXAMPLE.as_bytes())
+            .unwrap()
+            .into_parts(&mut reader)
+            .unwrap();
+        let anns = &parts.annotations;
+
+        // Zero sized values, so that only the read path allocates.
+        let start = allocations();
+        for _ in 0..100 {
+            let values: Vec<IgnoredAny> = anns.get_anns_as(&mut reader, 5).unwrap();
+            assert_eq!(values.len(), 3);
         }
+        let fresh = allocations() - start;
+
+        let mut scratch = ReadScratch::default();
+        let start = allocations();
+        for _ in 0..100 {
+            let values: Vec<IgnoredAny> =
+                anns.get_anns_as_with(&mut reader, 5, &mut scratch).unwrap();
+            assert_eq!(values.len(), 3);
+        }
+        let reused = allocations() - start;
+        // The positions, the spans and the bytes read are allocated once
+        // instead of per call. The parser allocates the same in both loops.
+        assert!(fresh >= reused + 3 * 99, "{} vs {}", fresh, reused);
+
+        assert_eq!(
+            anns.get_anns_as_with::<JsonDict, _>(&mut reader, 6, &mut scratch)
+                .unwrap(),
+            anns.get_anns(&mut reader, 6).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_verify_offsets() {
+        let input = EXAMPLE.replace(",\n", " ,\n\t");
+        let mut reader = io::Cursor::new(input.as_bytes());
+        let start = input.find(r#""annotations":"#).unwrap() + r#""annotations":"#.len();
+        reader.set_position(start as u64);
+        let mut page_map = AnnPageMap::from_reader(&mut reader).unwrap();
+        page_map.verify_offsets(&mut reader).unwrap();
+
+        let page = AnnPage {
+            id: 42,
+            img_id: 5,
+            ptr: usize::MAX,
+            seg_kind: SegKind::None,
+            category_id: None,
+        };
+        let (_, offset, size) = page_map.iter_offsets().last().unwrap();
+        page_map.push(
+            5,
+            page,
+            RecordSpan {
+                offset: offset + 1,
+                size,
+            },
+        );
+        let err = page_map.verify_offsets(&mut reader).unwrap_err();
+        assert!(err.to_string().contains(&format!("offset={}", offset + 1)));
+    }
+
+    #[test]
+    fn test_get_anns_read_ahead() {
+        let ann = |id: i64, img_id: i64| {
+            format!(
+                r#"{{"id":{},"image_id":{},"bbox":[0,0,1,1],"pad":"{}"}}"#,
+                id,
+                img_id,
+                "x".repeat(100)
+            )
+        };
+        let input = format!(
+            "[{}]",
+            [
+                ann(1, 5),
+                ann(2, 5),
+                ann(3, 5),
+                ann(4, 6),
+                ann(5, 5),
+                ann(6, 6)
+            ]
+            .join(",\n")
+        );
+        let ids = |anns: Vec<JsonDict>| anns.iter().map(|v| v["id"].clone()).collect::<Vec<_>>();
+
+        let page_map = AnnPageMap::from_reader(io::Cursor::new(input.as_bytes())).unwrap();
+        let mut reader = CountingReader::new(io::Cursor::new(input.as_bytes()));
+        assert_eq!(
+            ids(page_map.get_anns(&mut reader, 5).unwrap()),
+            [1, 2, 3, 5]
+        );
+        assert_eq!((reader.reads, reader.seeks), (1, 1));
+
+        // Only the separators between neighbours are read over.
+        let options = ScanOptions {
+            io_tuning: IoTuning {
+                coalesce_gap_bytes: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let page_map =
+            AnnPageMap::from_reader_with_options(io::Cursor::new(input.as_bytes()), &options)
+                .unwrap();
+        let mut reader = CountingReader::new(io::Cursor::new(input.as_bytes()));
+        assert_eq!(
+            ids(page_map.get_anns(&mut reader, 5).unwrap()),
+            [1, 2, 3, 5]
+        );
+        assert_eq!(reader.reads, 2);
+        assert_eq!(ids(page_map.get_anns(&mut reader, 6).unwrap()), [4, 6]);
+        assert_eq!(reader.reads, 4);
+
+        let batch = page_map.get_anns_batch(&mut reader, &[6, 5]).unwrap();
+        let batch: Vec<_> = batch.into_iter().map(ids).collect();
+        assert_eq!(batch, [vec![4, 6], vec![1, 2, 3, 5]]);
+    }
+
+    #[test]
+    fn test_segment_spans() {
+        let input = r#"[
+            {"image_id": 1, "file_name": "1.png", "segments_info": [
+                {"id": 3, "category_id": 5},
+                {"id": 4, "category_id": 6, "bbox": [0, 0, 2, 2], "note": "]"},
+            ], "extra": {}},
+            {"image_id": 2, "segments_info": [{"id": 3, "category_id": 7}]}
+        ]"#;
+        let options = ScanOptions {
+            allow_trailing_commas: true,
+            ..Default::default()
+        };
+        let mut reader = io::Cursor::new(input.as_bytes());
+        let page_map = AnnPageMap::from_reader_with_options(&mut reader, &options).unwrap();
+
+        let segment = |img_id, segment_id| {
+            let mut reader = io::Cursor::new(input.as_bytes());
+            page_map.get_segment(&mut reader, img_id, segment_id)
+        };
+        assert_eq!(segment(1, 3).unwrap().category_id, 5);
+        assert_eq!(segment(1, 4).unwrap().bbox, Some([0.0, 0.0, 2.0, 2.0]));
+        assert_eq!(segment(2, 3).unwrap().category_id, 7);
+        assert!(segment(2, 4).is_err());
+
+        // Only panoptic files have segments.
+        let mapper = CocoPageMapper::from_bytes(EXAMPLE.as_bytes()).unwrap();
+        let reader = io::Cursor::new(EXAMPLE.as_bytes());
+        assert!(mapper.get_segment(5, 1, reader).is_err());
     }
 }
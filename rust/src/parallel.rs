@@ -0,0 +1,286 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::CocoError,
+    offset_index::{max_record_bytes, parse_record, RecordSpan},
+    page_maps::ScanOptions,
+    utils::{is_json_ws, stream_error},
+};
+
+/// Bytes of elements collected before they are parsed.
+const CHUNK_BYTES: usize = 8 << 20;
+
+fn unexpected_end(pos: u64) -> io::Error {
+    stream_error("Unexpected end of input", pos)
+}
+
+/// Finds the elements of an array by bracket counting, which is much cheaper
+/// than tokenizing them.
+struct ElementScanner<R> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> ElementScanner<R> {
+    fn peek_byte(&mut self) -> Result<Option<u8>, io::Error> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn consume(&mut self) {
+        self.reader.consume(1);
+        self.pos += 1;
+    }
+
+    /// The next byte after whitespace and its position, not consumed.
+    fn skip_ws(&mut self) -> Result<(u8, u64), io::Error> {
+        loop {
+            match self.peek_byte()? {
+                Some(c) if is_json_ws(c) => self.consume(),
+                Some(c) => return Ok((c, self.pos)),
+                None => return Err(unexpected_end(self.pos)),
+            }
+        }
+    }
+
+    /// Appends the element at the reader position to `buf`, up to the `,` or
+    /// `]` following it. Malformed elements end as `skip_malformed_value` ends them.
+    /// Elements longer than `limit` fail with `CocoError::RecordTooLarge`.
+    fn read_element(&mut self, buf: &mut Vec<u8>, limit: u64) -> Result<(), io::Error> {
+        let (mut level, mut in_string, mut escaped) = (0usize, false, false);
+        let (begin, start) = (buf.len(), self.pos);
+        loop {
+            if (buf.len() - begin) as u64 > limit {
+                return Err(CocoError::RecordTooLarge {
+                    offset: start,
+                    limit,
+                }
+                .into());
+            }
+            let c = self.peek_byte()?.ok_or_else(|| unexpected_end(self.pos))?;
+            if in_string {
+                self.consume();
+                buf.push(c);
+                match c {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            if level == 0 && matches!(c, b',' | b']' | b'}') {
+                break;
+            }
+            self.consume();
+            buf.push(c);
+            match c {
+                b'"' => in_string = true,
+                b'{' | b'[' => level += 1,
+                b'}' | b']' => {
+                    level -= 1;
+                    if level == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        while buf.last().is_some_and(|c| is_json_ws(*c)) {
+            buf.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Elements found by the scanner: their offsets and ranges in `bytes`.
+#[derive(Default)]
+struct Chunk {
+    bytes: Vec<u8>,
+    elements: Vec<(u64, Range<usize>)>,
+}
+
+impl Chunk {
+    /// Parses the elements on the pool, then passes them to `on_record` in
+    /// file order.
+    fn flush<T, F>(
+        &mut self,
+        pool: &ThreadPool,
+        options: &ScanOptions,
+        malformed: &mut Option<&mut Vec<u64>>,
+        on_record: &mut F,
+    ) -> Result<(), io::Error>
+    where
+        T: DeserializeOwned + Send,
+        F: FnMut(T, RecordSpan, &[u8]) -> Result<(), io::Error>,
+    {
+        let allow_trailing_commas = options.allow_trailing_commas;
+        let records: Vec<Result<T, io::Error>> = pool.install(|| {
+            self.elements
+                .par_iter()
+                .map(|(offset, range)| {
+                    parse_record(&self.bytes[range.clone()], *offset, allow_trailing_commas)
+                })
+                .collect()
+        });
+
+        for ((offset, range), record) in self.elements.drain(..).zip(records) {
+            // Longer elements failed `read_element`.
+            let span = RecordSpan {
+                offset,
+                size: range.len() as u32,
+            };
+            let bytes = &self.bytes[range];
+            if let Err(e) = record.and_then(|record| on_record(record, span, bytes)) {
+                match malformed.as_mut() {
+                    Some(malformed) => malformed.push(offset),
+                    None => return Err(e),
+                }
+            }
+            if let Some(progress) = &options.progress {
+                progress.record(offset + span.size as u64);
+            }
+        }
+        self.bytes.clear();
+        Ok(())
+    }
+}
+
+/// Like `offset_index::scan_array`, but the elements are parsed on `threads`
+/// workers, all available cores for 0. `on_record` is still called in file order.
+pub(crate) fn scan_array<T, R, F>(
+    mut reader: R,
+    threads: usize,
+    options: &ScanOptions,
+    mut malformed: Option<&mut Vec<u64>>,
+    mut on_record: F,
+) -> Result<(), io::Error>
+where
+    T: DeserializeOwned + Send,
+    R: Read + Seek,
+    F: FnMut(T, RecordSpan, &[u8]) -> Result<(), io::Error>,
+{
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(io::Error::other)?;
+    let pos = reader.stream_position()?;
+    let mut scanner = ElementScanner {
+        reader: BufReader::with_capacity(options.io_tuning.scan_buffer_bytes.max(1), reader),
+        pos,
+    };
+
+    match scanner.skip_ws()? {
+        (b'[', _) => scanner.consume(),
+        (_, pos) => return Err(stream_error("Expected an array", pos)),
+    }
+
+    let mut chunk = Chunk::default();
+    let mut after_comma = false;
+    let limit = max_record_bytes(options);
+    loop {
+        let (c, pos) = scanner.skip_ws()?;
+        if c == b']' {
+            if after_comma && !options.allow_trailing_commas {
+                return Err(stream_error("Trailing comma in the array", pos));
+            }
+            scanner.consume();
+            break;
+        }
+
+        let begin = chunk.bytes.len();
+        scanner.read_element(&mut chunk.bytes, limit)?;
+        chunk.elements.push((pos, begin..chunk.bytes.len()));
+        if chunk.bytes.len() >= CHUNK_BYTES {
+            chunk.flush(&pool, options, &mut malformed, &mut on_record)?;
+        }
+
+        match scanner.skip_ws()? {
+            (b',', _) => after_comma = true,
+            (b']', _) => {
+                scanner.consume();
+                break;
+            }
+            (_, pos) => return Err(stream_error("Expected ',' or ']'", pos)),
+        }
+        scanner.consume();
+    }
+    chunk.flush(&pool, options, &mut malformed, &mut on_record)?;
+
+    let end = scanner.pos;
+    scanner.reader.into_inner().seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn scan(data: &str, options: &ScanOptions) -> Result<Vec<(Value, RecordSpan)>, io::Error> {
+        let mut records = vec![];
+        let mut reader = Cursor::new(format!("{} tail", data).into_bytes());
+        scan_array(&mut reader, 2, options, None, |record, span, _| {
+            records.push((record, span));
+            Ok(())
+        })?;
+        assert_eq!(reader.position(), data.len() as u64);
+        Ok(records)
+    }
+
+    #[test]
+    fn test_scan_array() {
+        let data = r#" [ {"a": "}\"]"}, 1 ,[2,{}], "x"]"#;
+        let records = scan(data, &ScanOptions::default()).unwrap();
+        let values: Vec<&Value> = records.iter().map(|(v, _)| v).collect();
+        assert_eq!(
+            values,
+            [
+                &json!({"a": "}\"]"}),
+                &1.into(),
+                &json!([2, {}]),
+                &"x".into()
+            ]
+        );
+        for (value, span) in &records {
+            let end = (span.offset + span.size as u64) as usize;
+            let bytes = &data.as_bytes()[span.offset as usize..end];
+            assert_eq!(&serde_json::from_slice::<Value>(bytes).unwrap(), value);
+        }
+
+        assert!(scan("[1,]", &ScanOptions::default()).is_err());
+        let options = ScanOptions {
+            allow_trailing_commas: true,
+            ..Default::default()
+        };
+        assert_eq!(scan("[1,]", &options).unwrap().len(), 1);
+        assert!(scan("[1 2]", &options).is_err());
+        assert!(scan("[{\"a\": 1}", &options).is_err());
+
+        let options = ScanOptions {
+            max_record_bytes: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(scan("[1, \"ab\"]", &options).unwrap().len(), 2);
+        let err = scan("[1, \"abcd\"]", &options).unwrap_err();
+        assert!(matches!(
+            crate::error::coco_error(&err),
+            Some(CocoError::RecordTooLarge {
+                offset: 4,
+                limit: 4
+            })
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::coco_page_mapper::{CocoSectionKind, OpenProfile};
+
+/// Wall time and bytes read of a phase of the construction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct PhaseStats {
+    pub bytes: u64,
+    pub time: Duration,
+}
+
+/// Totals over all the calls of a lookup method.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct CallStats {
+    pub count: u64,
+    pub bytes: u64,
+    pub time: Duration,
+}
+
+/// See `CocoPageMapper::profile`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProfileReport {
+    /// The whole construction, from the builder call to the returned mapper.
+    pub construction: PhaseStats,
+    /// Scanning the licenses, info and categories sections.
+    pub sections: PhaseStats,
+    pub images: PhaseStats,
+    pub annotations: PhaseStats,
+    pub get_item_dict: CallStats,
+    pub get_anns_dict: CallStats,
+}
+
+impl ProfileReport {
+    pub(crate) fn from_open_profile(profile: &OpenProfile, time: Duration) -> Self {
+        let mut report = ProfileReport {
+            construction: PhaseStats {
+                bytes: profile.bytes_scanned_total,
+                time,
+            },
+            ..Default::default()
+        };
+        for (kind, (bytes, time)) in &profile.per_section {
+            let phase = match kind {
+                CocoSectionKind::Images => &mut report.images,
+                CocoSectionKind::Annotations => &mut report.annotations,
+                _ => &mut report.sections,
+            };
+            phase.bytes += bytes;
+            phase.time += *time;
+        }
+        report
+    }
+}
+
+/// Collects a `ProfileReport`, enabled by `CocoPageMapperBuilder::profiling`.
+/// Disabled mappers hold no collector, so their lookups skip it entirely.
+#[derive(Debug)]
+pub(crate) struct Profiling(Mutex<ProfileReport>);
+
+#[derive(Clone, Copy)]
+pub(crate) enum Call {
+    GetItemDict,
+    GetAnnsDict,
+}
+
+impl Profiling {
+    pub fn new(report: ProfileReport) -> Self {
+        Self(Mutex::new(report))
+    }
+
+    pub fn report(&self) -> ProfileReport {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Runs `f` on a reader counting the bytes it reads, and adds the call to
+    /// the stats of `call`.
+    pub fn time_call<R: Read + Seek, T>(
+        &self,
+        call: Call,
+        reader: R,
+        f: impl FnOnce(&mut CountingReader<R>) -> Result<T, io::Error>,
+    ) -> Result<T, io::Error> {
+        let started = Instant::now();
+        let mut reader = CountingReader {
+            inner: reader,
+            bytes: 0,
+        };
+        let result = f(&mut reader);
+
+        let mut report = self.0.lock().unwrap();
+        let stats = match call {
+            Call::GetItemDict => &mut report.get_item_dict,
+            Call::GetAnnsDict => &mut report.get_anns_dict,
+        };
+        stats.count += 1;
+        stats.bytes += reader.bytes;
+        stats.time += started.elapsed();
+        result
+    }
+}
+
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    bytes: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
@@ -0,0 +1,114 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{cell::RefCell, fmt, rc::Rc};
+
+/// Default for `CocoPageMapperBuilder::progress_interval`.
+pub const DEFAULT_PROGRESS_INTERVAL: u64 = 1 << 20;
+
+/// State of a scan, passed to the callback of `CocoPageMapperBuilder::on_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes scanned from the start of the document.
+    pub bytes_read: u64,
+    /// Size of the document, if the reader can seek to its end.
+    pub total_bytes: Option<u64>,
+    pub images_indexed: usize,
+    pub annotations_indexed: usize,
+    /// Key of the section being scanned, `"done"` for the last call.
+    pub section: &'static str,
+}
+
+pub type ProgressCallback = Box<dyn FnMut(Progress)>;
+
+/// A callback shared by a builder and the scans it starts.
+#[derive(Clone)]
+pub(crate) struct SharedCallback(pub Rc<RefCell<ProgressCallback>>);
+
+impl fmt::Debug for SharedCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedCallback")
+    }
+}
+
+struct ProgressTracker {
+    callback: SharedCallback,
+    interval: u64,
+    /// Absolute offset of the document.
+    start: u64,
+    reported: u64,
+    progress: Progress,
+}
+
+/// Reports the progress of a scan to a callback, at most every `interval`
+/// bytes. Shared by the scans of all sections through `ScanOptions`.
+#[derive(Clone)]
+pub(crate) struct ProgressHook(Rc<RefCell<ProgressTracker>>);
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ProgressHook")
+            .field(&self.0.borrow().progress)
+            .finish()
+    }
+}
+
+impl ProgressHook {
+    pub fn new(
+        callback: SharedCallback,
+        interval: u64,
+        start: u64,
+        total_bytes: Option<u64>,
+    ) -> Self {
+        Self(Rc::new(RefCell::new(ProgressTracker {
+            callback,
+            interval,
+            start,
+            reported: 0,
+            progress: Progress {
+                bytes_read: 0,
+                total_bytes,
+                images_indexed: 0,
+                annotations_indexed: 0,
+                section: "",
+            },
+        })))
+    }
+
+    fn advance(tracker: &mut ProgressTracker, pos: u64) {
+        let bytes_read = pos.saturating_sub(tracker.start);
+        tracker.progress.bytes_read = tracker.progress.bytes_read.max(bytes_read);
+    }
+
+    fn report(tracker: &mut ProgressTracker) {
+        tracker.reported = tracker.progress.bytes_read;
+        let progress = tracker.progress;
+        (tracker.callback.0.borrow_mut())(progress);
+    }
+
+    pub fn start_section(&self, section: &'static str, pos: u64) {
+        let mut tracker = self.0.borrow_mut();
+        tracker.progress.section = section;
+        Self::advance(&mut tracker, pos);
+        Self::report(&mut tracker);
+    }
+
+    /// Called after every record of an array, which ends at `pos`.
+    pub fn record(&self, pos: u64) {
+        let mut tracker = self.0.borrow_mut();
+        match tracker.progress.section {
+            "images" => tracker.progress.images_indexed += 1,
+            "annotations" => tracker.progress.annotations_indexed += 1,
+            _ => {}
+        }
+        Self::advance(&mut tracker, pos);
+        if tracker.progress.bytes_read - tracker.reported >= tracker.interval {
+            Self::report(&mut tracker);
+        }
+    }
+
+    pub fn finish(&self, pos: u64) {
+        self.start_section("done", pos);
+    }
+}
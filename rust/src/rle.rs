@@ -0,0 +1,529 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io;
+
+use crate::{error::CocoError, geometry::BBox, page_maps::JsonDict, utils::invalid_data};
+
+/// COCO run-length encoded mask. Runs are column-major and start with background.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rle {
+    pub height: usize,
+    pub width: usize,
+    pub counts: Vec<u32>,
+}
+
+pub fn is_rle(segmentation: &JsonDict) -> bool {
+    segmentation.get("counts").is_some() && segmentation.get("size").is_some()
+}
+
+fn decode_counts_string(s: &str) -> Result<Vec<u32>, io::Error> {
+    let bytes = s.as_bytes();
+    let mut counts: Vec<u32> = vec![];
+    let mut p = 0;
+
+    while p < bytes.len() {
+        let start = p;
+        let mut x: i64 = 0;
+        let mut k = 0;
+        loop {
+            let c = match bytes.get(p) {
+                Some(c @ b'0'..=b'o') => (c - b'0') as i64,
+                Some(_) => {
+                    return Err(CocoError::RleString {
+                        pos: p,
+                        reason: "unexpected character",
+                    }
+                    .into())
+                }
+                None => {
+                    return Err(CocoError::RleString {
+                        pos: p,
+                        reason: "truncated count",
+                    }
+                    .into())
+                }
+            };
+            if k == 7 {
+                return Err(CocoError::RleString {
+                    pos: start,
+                    reason: "count out of range",
+                }
+                .into());
+            }
+            x |= (c & 0x1f) << (5 * k);
+            p += 1;
+            k += 1;
+            if c & 0x20 == 0 {
+                if c & 0x10 != 0 {
+                    x |= -1i64 << (5 * k);
+                }
+                break;
+            }
+        }
+        if counts.len() > 2 {
+            x += counts[counts.len() - 2] as i64;
+        }
+        let count = u32::try_from(x).map_err(|_| CocoError::RleString {
+            pos: start,
+            reason: "count out of range",
+        })?;
+        counts.push(count);
+    }
+
+    Ok(counts)
+}
+
+fn encode_counts_string(counts: &[u32]) -> String {
+    let mut s = String::new();
+    for (idx, count) in counts.iter().enumerate() {
+        let mut x = *count as i64;
+        if idx > 2 {
+            x -= counts[idx - 2] as i64;
+        }
+        loop {
+            let mut c = x & 0x1f;
+            x >>= 5;
+            let more = if c & 0x10 != 0 { x != -1 } else { x != 0 };
+            if more {
+                c |= 0x20;
+            }
+            s.push((c as u8 + b'0') as char);
+            if !more {
+                break;
+            }
+        }
+    }
+    s
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeOp {
+    Intersection,
+    Union,
+}
+
+/// Area of the intersection or the union of two masks, computed by walking
+/// both run streams in lockstep. The masks should have the same size.
+pub fn rle_merge_area(a: &Rle, b: &Rle, op: MergeOp) -> Result<u64, io::Error> {
+    if (a.height, a.width) != (b.height, b.width) {
+        return Err(invalid_data(
+            format!(
+                "Cannot merge RLE masks of different sizes: {}x{} and {}x{}",
+                a.height, a.width, b.height, b.width
+            )
+            .as_str(),
+        ));
+    }
+
+    let (mut runs_a, mut runs_b) = (a.counts.iter(), b.counts.iter());
+    // Remaining length and value of the current run of each mask. A mask whose
+    // runs are exhausted is background from there on.
+    let (mut left_a, mut value_a) = (Some(0u64), true);
+    let (mut left_b, mut value_b) = (Some(0u64), true);
+    let mut area = 0;
+
+    loop {
+        while left_a == Some(0) {
+            left_a = runs_a.next().map(|v| *v as u64);
+            value_a = left_a.is_some() && !value_a;
+        }
+        while left_b == Some(0) {
+            left_b = runs_b.next().map(|v| *v as u64);
+            value_b = left_b.is_some() && !value_b;
+        }
+        let step = match (left_a, left_b) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(v), None) | (None, Some(v)) => v,
+            (None, None) => break,
+        };
+
+        let covered = match op {
+            MergeOp::Intersection => value_a && value_b,
+            MergeOp::Union => value_a || value_b,
+        };
+        if covered {
+            area += step;
+        }
+        left_a = left_a.map(|v| v - step);
+        left_b = left_b.map(|v| v - step);
+    }
+
+    Ok(area)
+}
+
+pub fn rle_iou(a: &Rle, b: &Rle) -> Result<f64, io::Error> {
+    let union = rle_merge_area(a, b, MergeOp::Union)?;
+    if union == 0 {
+        return Ok(0.0);
+    }
+    Ok(rle_merge_area(a, b, MergeOp::Intersection)? as f64 / union as f64)
+}
+
+impl Rle {
+    pub fn from_json(segmentation: &JsonDict) -> Result<Self, io::Error> {
+        let size = segmentation
+            .get("size")
+            .and_then(|v| v.as_array())
+            .filter(|v| v.len() == 2)
+            .ok_or(invalid_data("RLE size should be [height, width]"))?;
+        let height = size[0]
+            .as_u64()
+            .ok_or(invalid_data("RLE height is not an integer"))? as usize;
+        let width = size[1]
+            .as_u64()
+            .ok_or(invalid_data("RLE width is not an integer"))? as usize;
+
+        let counts = match segmentation.get("counts") {
+            Some(JsonDict::String(s)) => decode_counts_string(s)?,
+            Some(JsonDict::Array(values)) => values
+                .iter()
+                .map(|v| {
+                    v.as_u64()
+                        .and_then(|v| u32::try_from(v).ok())
+                        .ok_or(invalid_data("RLE count is not an unsigned integer"))
+                })
+                .collect::<Result<Vec<u32>, io::Error>>()?,
+            _ => return Err(invalid_data("RLE counts should be a string or a list")),
+        };
+
+        let total: u64 = counts.iter().map(|v| *v as u64).sum();
+        if total != height as u64 * width as u64 {
+            let msg = format!(
+                "RLE counts add up to {} pixels instead of {}x{}",
+                total, height, width
+            );
+            return Err(invalid_data(msg.as_str()));
+        }
+
+        Ok(Self {
+            height,
+            width,
+            counts,
+        })
+    }
+
+    /// Uncompressed COCO representation, `{"size": [h, w], "counts": [...]}`.
+    pub fn to_json(&self) -> JsonDict {
+        serde_json::json!({
+            "size": [self.height, self.width],
+            "counts": self.counts,
+        })
+    }
+
+    /// COCO compressed representation of the counts, as written by pycocotools.
+    pub fn to_compressed_string(&self) -> String {
+        encode_counts_string(&self.counts)
+    }
+
+    /// Number of foreground pixels.
+    pub fn area(&self) -> u64 {
+        self.counts
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .map(|v| *v as u64)
+            .sum()
+    }
+
+    /// Tight `[x, y, w, h]` box of the foreground, computed from the runs without
+    /// decoding the mask. `None` if the mask is empty.
+    pub fn to_bbox(&self) -> Option<BBox> {
+        let height = self.height.max(1) as u64;
+        let (mut x0, mut y0, mut x1, mut y1) = (u64::MAX, u64::MAX, 0, 0);
+        let mut pos = 0u64;
+
+        for (idx, count) in self.counts.iter().enumerate() {
+            let (start, end) = (pos, pos + *count as u64);
+            pos = end;
+            if idx % 2 == 0 || start == end {
+                continue;
+            }
+            let (first_col, last_col) = (start / height, (end - 1) / height);
+            x0 = x0.min(first_col);
+            x1 = x1.max(last_col);
+            if first_col == last_col {
+                y0 = y0.min(start % height);
+                y1 = y1.max((end - 1) % height);
+            } else {
+                // The run wraps to the next column, so it covers the top and the bottom row.
+                y0 = 0;
+                y1 = height - 1;
+            }
+        }
+
+        if x0 == u64::MAX {
+            return None;
+        }
+        Some([
+            x0 as f64,
+            y0 as f64,
+            (x1 - x0 + 1) as f64,
+            (y1 - y0 + 1) as f64,
+        ])
+    }
+
+    /// Column-major mask with one byte per pixel.
+    pub fn decode(&self) -> Vec<u8> {
+        let mut mask = Vec::with_capacity(self.height * self.width);
+        let mut value = 0u8;
+        for count in self.counts.iter() {
+            mask.extend(std::iter::repeat_n(value, *count as usize));
+            value = 1 - value;
+        }
+        mask.resize(self.height * self.width, 0);
+        mask
+    }
+
+    pub fn encode(mask: &[u8], height: usize, width: usize) -> Self {
+        let mut counts = vec![];
+        let mut value = 0u8;
+        let mut run = 0u32;
+        for pixel in mask.iter().take(height * width) {
+            let pixel = (*pixel != 0) as u8;
+            if pixel != value {
+                counts.push(run);
+                run = 0;
+                value = pixel;
+            }
+            run += 1;
+        }
+        counts.push(run);
+
+        Self {
+            height,
+            width,
+            counts,
+        }
+    }
+
+    /// Mirrors the mask along the vertical axis.
+    pub fn hflip(&self) -> Self {
+        let mask = self.decode();
+        let mut flipped = Vec::with_capacity(mask.len());
+        for col in mask.chunks(self.height.max(1)).rev() {
+            flipped.extend_from_slice(col);
+        }
+        Self::encode(&flipped, self.height, self.width)
+    }
+
+    /// Cuts the `width` x `height` window at `(x, y)`, pixels outside of the mask are background.
+    pub fn crop(&self, x: i64, y: i64, width: usize, height: usize) -> Self {
+        let mask = self.decode();
+        let mut cropped = vec![0u8; width * height];
+        for col in 0..width {
+            let src_col = x + col as i64;
+            if src_col < 0 || src_col >= self.width as i64 {
+                continue;
+            }
+            for row in 0..height {
+                let src_row = y + row as i64;
+                if src_row < 0 || src_row >= self.height as i64 {
+                    continue;
+                }
+                cropped[col * height + row] =
+                    mask[src_col as usize * self.height + src_row as usize];
+            }
+        }
+        Self::encode(&cropped, height, width)
+    }
+
+    /// Nearest-neighbor resampling to `height` x `width`.
+    pub fn resize(&self, height: usize, width: usize) -> Self {
+        let mask = self.decode();
+        let mut resized = vec![0u8; height * width];
+
+        for col in 0..width {
+            let src_col = ((col as f64 + 0.5) * self.width as f64 / width as f64) as usize;
+            let src_col = src_col.min(self.width.saturating_sub(1));
+            for row in 0..height {
+                let src_row = ((row as f64 + 0.5) * self.height as f64 / height as f64) as usize;
+                let src_row = src_row.min(self.height.saturating_sub(1));
+                resized[col * height + row] = mask[src_col * self.height + src_row];
+            }
+        }
+
+        Self::encode(&resized, height, width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::coco_error, geometry::points_bbox};
+    use serde_json::json;
+
+    #[test]
+    fn test_decode_encode() {
+        let rle = Rle::from_json(&json!({"size": [2, 3], "counts": [1, 2, 3]})).unwrap();
+        let mask = rle.decode();
+        assert_eq!(mask, vec![0, 1, 1, 0, 0, 0]);
+        assert_eq!(Rle::encode(&mask, 2, 3), rle);
+    }
+
+    #[test]
+    fn test_decode_compressed() {
+        // pycocotools: encode of a 4x4 mask with a 2x2 square in the middle
+        let rle = Rle::from_json(&json!({"size": [4, 4], "counts": "52203"})).unwrap();
+        assert_eq!(rle.counts, vec![5, 2, 2, 2, 5]);
+    }
+
+    fn decoded_bbox(mask: &[u8], height: usize) -> Option<BBox> {
+        let pixels: Vec<f64> = mask
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| **v != 0)
+            .flat_map(|(idx, _)| [(idx / height) as f64, (idx % height) as f64])
+            .collect();
+        points_bbox(&pixels).map(|v| [v[0], v[1], v[2] + 1.0, v[3] + 1.0])
+    }
+
+    #[test]
+    fn test_area_and_bbox_from_counts() {
+        let mut seed = 12345u64;
+        let mut next = move || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize
+        };
+
+        for _ in 0..50 {
+            let (height, width) = (1 + next() % 8, 1 + next() % 8);
+            let density = next() % 4;
+            let mask: Vec<u8> = (0..height * width)
+                .map(|_| (next() % 4 < density) as u8)
+                .collect();
+            let rle = Rle::encode(&mask, height, width);
+
+            let area = mask.iter().filter(|v| **v != 0).count() as u64;
+            assert_eq!(rle.area(), area);
+            assert_eq!(rle.to_bbox(), decoded_bbox(&mask, height), "{:?}", rle);
+        }
+
+        let rle = Rle::from_json(&json!({"size": [4, 4], "counts": "52203"})).unwrap();
+        assert_eq!(rle.to_bbox(), decoded_bbox(&rle.decode(), 4));
+    }
+
+    #[test]
+    fn test_compressed_string() {
+        let pairs: [(&str, &[u32]); 4] = [
+            ("52203", &[5, 2, 2, 2, 5]),
+            ("0T3", &[0, 100]),
+            ("3:2Gb0", &[3, 10, 2, 1, 20]),
+            ("PeQ31b12eNQl0", &[100000, 1, 50, 3, 7, 900]),
+        ];
+        for (string, counts) in pairs {
+            assert_eq!(decode_counts_string(string).unwrap(), counts);
+            assert_eq!(encode_counts_string(counts), string);
+        }
+
+        let mut seed = 42u64;
+        for _ in 0..100 {
+            let counts: Vec<u32> = (0..1 + seed % 20)
+                .map(|_| {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    ((seed >> 33) % 5000) as u32
+                })
+                .collect();
+            let string = encode_counts_string(&counts);
+            assert_eq!(decode_counts_string(&string).unwrap(), counts);
+        }
+    }
+
+    #[test]
+    fn test_compressed_string_errors() {
+        let error_pos = |s: &str| match decode_counts_string(s) {
+            Err(err) => match coco_error(&err) {
+                Some(CocoError::RleString { pos, .. }) => Some(*pos),
+                _ => None,
+            },
+            Ok(_) => None,
+        };
+        assert_eq!(error_pos("52 03"), Some(2));
+        assert_eq!(error_pos("52Q"), Some(3));
+        assert_eq!(error_pos("5\u{7f}"), Some(1));
+        assert_eq!(error_pos("52203"), None);
+    }
+
+    #[test]
+    fn test_rle_merge_area() {
+        let count = |a: &[u8], b: &[u8], op: fn(bool, bool) -> bool| {
+            a.iter()
+                .zip(b)
+                .filter(|(a, b)| op(**a != 0, **b != 0))
+                .count() as u64
+        };
+        let masks: [&[u8]; 4] = [
+            &[0, 1, 1, 0, 1, 1, 0, 0, 1],
+            &[0, 0, 1, 1, 1, 0, 0, 0, 1],
+            &[1, 1, 0, 0, 0, 0, 1, 1, 0],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+
+        for a in masks.iter() {
+            for b in masks.iter() {
+                let (rle_a, rle_b) = (Rle::encode(a, 3, 3), Rle::encode(b, 3, 3));
+                assert_eq!(
+                    rle_merge_area(&rle_a, &rle_b, MergeOp::Intersection).unwrap(),
+                    count(a, b, |a, b| a && b)
+                );
+                assert_eq!(
+                    rle_merge_area(&rle_a, &rle_b, MergeOp::Union).unwrap(),
+                    count(a, b, |a, b| a || b)
+                );
+            }
+        }
+
+        let a = Rle::encode(masks[0], 3, 3);
+        assert_eq!(rle_iou(&a, &a).unwrap(), 1.0);
+        assert_eq!(
+            rle_iou(&a, &Rle::encode(masks[2], 3, 3)).unwrap(),
+            1.0 / 8.0
+        );
+        assert!(rle_iou(&a, &Rle::encode(masks[0], 9, 1)).is_err());
+    }
+
+    #[test]
+    fn test_short_counts() {
+        assert!(Rle::from_json(&json!({"size": [4, 4], "counts": [5, 2]})).is_err());
+        assert!(Rle::from_json(&json!({"size": [4, 4], "counts": [5, 2, 10]})).is_err());
+        assert!(Rle::from_json(&json!({"size": [2, 2], "counts": "52203"})).is_err());
+
+        // Built by hand, the missing tail is background.
+        let short = Rle {
+            height: 4,
+            width: 4,
+            counts: vec![5, 2],
+        };
+        let full = Rle {
+            height: 4,
+            width: 4,
+            counts: vec![0, 16],
+        };
+        assert_eq!(
+            rle_merge_area(&short, &full, MergeOp::Intersection).unwrap(),
+            2
+        );
+        assert_eq!(rle_merge_area(&short, &full, MergeOp::Union).unwrap(), 16);
+        assert_eq!(rle_merge_area(&short, &short, MergeOp::Union).unwrap(), 2);
+        assert_eq!(rle_iou(&short, &full).unwrap(), 2.0 / 16.0);
+    }
+
+    #[test]
+    fn test_resize() {
+        let rle = Rle::encode(&[0, 0, 1, 1], 2, 2);
+        let resized = rle.resize(4, 4);
+        assert_eq!(
+            resized.decode(),
+            vec![0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_hflip() {
+        let rle = Rle::encode(&[1, 1, 0, 0, 0, 1], 2, 3);
+        assert_eq!(rle.hflip().decode(), vec![0, 1, 0, 0, 1, 1]);
+    }
+}
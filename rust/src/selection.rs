@@ -0,0 +1,101 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{collections::HashSet, fmt};
+
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+
+use crate::page_maps::JsonDict;
+
+/// Top-level annotation keys to parse. The values of the other keys are
+/// skipped without being built, e.g. the large `segmentation` arrays when only
+/// the boxes are needed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FieldSelection {
+    #[default]
+    All,
+    Include(HashSet<String>),
+    Exclude(HashSet<String>),
+}
+
+impl FieldSelection {
+    pub fn include(keys: &[&str]) -> Self {
+        Self::Include(keys.iter().map(|v| v.to_string()).collect())
+    }
+
+    pub fn exclude(keys: &[&str]) -> Self {
+        Self::Exclude(keys.iter().map(|v| v.to_string()).collect())
+    }
+
+    pub fn selects(&self, key: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Include(keys) => keys.contains(key),
+            Self::Exclude(keys) => !keys.contains(key),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for &FieldSelection {
+    type Value = JsonDict;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<JsonDict, D::Error> {
+        deserializer.deserialize_map(SelectionVisitor(self))
+    }
+}
+
+struct SelectionVisitor<'a>(&'a FieldSelection);
+
+impl<'de> Visitor<'de> for SelectionVisitor<'_> {
+    type Value = JsonDict;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an annotation object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JsonDict, A::Error> {
+        let mut selected = serde_json::Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if self.0.selects(&key) {
+                let value = map.next_value()?;
+                selected.insert(key, value);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(JsonDict::Object(selected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_selection_seed() {
+        let record =
+            br#"{"id": 1, "bbox": [1, 2, 3, 4], "segmentation": [[1, 2, 3]], "x": {"y": 2}}"#;
+        let parse = |selection: &FieldSelection| {
+            let mut de = serde_json::Deserializer::from_slice(record);
+            selection.deserialize(&mut de).unwrap()
+        };
+        assert_eq!(
+            parse(&FieldSelection::All),
+            serde_json::from_slice::<JsonDict>(record).unwrap()
+        );
+        assert_eq!(
+            parse(&FieldSelection::exclude(&["segmentation", "x"])),
+            json!({"id": 1, "bbox": [1, 2, 3, 4]})
+        );
+        assert_eq!(
+            parse(&FieldSelection::include(&["x", "missing"])),
+            json!({"x": {"y": 2}})
+        );
+
+        let mut de = serde_json::Deserializer::from_slice(b"[1, 2]");
+        assert!(FieldSelection::All.deserialize(&mut de).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io::{self, Read, Seek};
+
+use crate::{
+    coco_page_mapper::CocoPageMapper,
+    duplicates::parse_bbox,
+    geometry::{bbox_intersection, points_bbox, BBox},
+};
+
+const MAX_GRID_SIZE: usize = 64;
+
+/// Uniform grid over the boxes of one image. Boxes reaching out of the grid
+/// are kept in its border cells.
+#[derive(Debug, Clone)]
+pub struct SpatialIndex {
+    /// `(ann_id, bbox)` in file order.
+    boxes: Vec<(i64, BBox)>,
+    origin: (f64, f64),
+    cell_size: (f64, f64),
+    cols: usize,
+    rows: usize,
+    /// Positions in `boxes`, row-major.
+    cells: Vec<Vec<usize>>,
+}
+
+impl SpatialIndex {
+    /// Lays the grid over `extent`, about one box per cell.
+    pub fn new(boxes: Vec<(i64, BBox)>, extent: BBox) -> Self {
+        let side = ((boxes.len() as f64).sqrt().ceil() as usize).clamp(1, MAX_GRID_SIZE);
+        let (cols, rows) = (side, side);
+        let cell_size = (
+            (extent[2] / cols as f64).max(f64::MIN_POSITIVE),
+            (extent[3] / rows as f64).max(f64::MIN_POSITIVE),
+        );
+        let mut index = Self {
+            boxes,
+            origin: (extent[0], extent[1]),
+            cell_size,
+            cols,
+            rows,
+            cells: vec![vec![]; cols * rows],
+        };
+
+        for (pos, (_, bbox)) in index.boxes.iter().enumerate() {
+            let (c0, r0, c1, r1) = index.cell_range(bbox);
+            for row in r0..=r1 {
+                for col in c0..=c1 {
+                    index.cells[row * cols + col].push(pos);
+                }
+            }
+        }
+        index
+    }
+
+    fn cell_range(&self, bbox: &BBox) -> (usize, usize, usize, usize) {
+        let cell = |v: f64, origin: f64, size: f64, n: usize| {
+            (((v - origin) / size).floor().max(0.0) as usize).min(n - 1)
+        };
+        (
+            cell(bbox[0], self.origin.0, self.cell_size.0, self.cols),
+            cell(bbox[1], self.origin.1, self.cell_size.1, self.rows),
+            cell(
+                bbox[0] + bbox[2],
+                self.origin.0,
+                self.cell_size.0,
+                self.cols,
+            ),
+            cell(
+                bbox[1] + bbox[3],
+                self.origin.1,
+                self.cell_size.1,
+                self.rows,
+            ),
+        )
+    }
+
+    /// Ids of the boxes overlapping `rect`, given as `[x, y, w, h]`, with a
+    /// positive area, in file order. Boxes only touching `rect` are left out.
+    pub fn query(&self, rect: &BBox) -> Vec<i64> {
+        let (c0, r0, c1, r1) = self.cell_range(rect);
+        let mut found: Vec<usize> = vec![];
+        for row in r0..=r1 {
+            for col in c0..=c1 {
+                found.extend(
+                    self.cells[row * self.cols + col]
+                        .iter()
+                        .filter(|pos| bbox_intersection(&self.boxes[**pos].1, rect).is_some()),
+                );
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found.into_iter().map(|pos| self.boxes[pos].0).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.boxes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boxes.is_empty()
+    }
+}
+
+impl CocoPageMapper {
+    /// Reads the boxes of an image into a `SpatialIndex` sized by the image
+    /// dimensions, or by the extent of the boxes if they are unknown.
+    /// Annotations without an `id` or a valid `bbox` are left out.
+    pub fn build_spatial_index(
+        &self,
+        img_id: i64,
+        reader: impl Read + Seek,
+    ) -> Result<SpatialIndex, io::Error> {
+        let boxes: Vec<(i64, BBox)> = self
+            .get_anns_dict(img_id, reader)?
+            .iter()
+            .filter_map(|ann| Some((ann.get("id")?.as_i64()?, parse_bbox(ann)?)))
+            .collect();
+
+        let extent = match self.image_size(img_id) {
+            Some((width, height)) if width > 0 && height > 0 => {
+                [0.0, 0.0, width as f64, height as f64]
+            }
+            _ => {
+                let corners: Vec<f64> = boxes
+                    .iter()
+                    .flat_map(|(_, b)| [b[0], b[1], b[0] + b[2], b[1] + b[3]])
+                    .collect();
+                points_bbox(&corners).unwrap_or([0.0, 0.0, 1.0, 1.0])
+            }
+        };
+        Ok(SpatialIndex::new(boxes, extent))
+    }
+
+    /// Ids of the annotations of an image whose boxes overlap `rect`, see
+    /// `SpatialIndex::query`. Keep the index of `build_spatial_index` instead
+    /// for repeated queries on the same image.
+    pub fn anns_in_region(
+        &self,
+        img_id: i64,
+        rect: [f64; 4],
+        reader: impl Read + Seek,
+    ) -> Result<Vec<i64>, io::Error> {
+        Ok(self.build_spatial_index(img_id, reader)?.query(&rect))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::reopen;
+
+    const EXAMPLE: &str = r#"
+    {
+        "licenses":[],
+        "info":{},
+        "categories":[{"id":1,"name":"a","supercategory":""}],
+        "images":[
+            {"id":1,"width":100,"height":100,"file_name":"a.jpg"},
+            {"id":2,"file_name":"b.jpg"}
+        ],
+        "annotations":[
+            {"id":1,"image_id":1,"category_id":1,"bbox":[0,0,10,10]},
+            {"id":2,"image_id":1,"category_id":1,"bbox":[20,20,10,10]},
+            {"id":3,"image_id":1,"category_id":1,"bbox":[80,80,30,30]},
+            {"id":4,"image_id":1,"category_id":1,"bbox":[5,40,90,5]},
+            {"id":5,"image_id":1,"category_id":1,"bbox":[30,0,10,20]},
+            {"id":6,"image_id":1,"category_id":1},
+            {"id":7,"image_id":2,"category_id":1,"bbox":[-50,-50,10,10]},
+            {"id":8,"image_id":2,"category_id":1,"bbox":[500,500,10,10]}
+        ]
+    }"#;
+
+    #[test]
+    fn test_anns_in_region() {
+        let (mut reader, coco_page_mapper) = reopen(EXAMPLE.as_bytes().to_vec());
+
+        let index = coco_page_mapper
+            .build_spatial_index(1, &mut reader)
+            .unwrap();
+        assert_eq!(index.len(), 5);
+        assert_eq!(index.query(&[0.0, 0.0, 25.0, 25.0]), vec![1, 2]);
+        // Touching the edge of box 5 only.
+        assert_eq!(index.query(&[15.0, 15.0, 15.0, 30.0]), vec![2, 4]);
+        // Box 3 extends past the image.
+        assert_eq!(index.query(&[100.0, 100.0, 5.0, 5.0]), vec![3]);
+        assert_eq!(index.query(&[0.0, 0.0, 100.0, 100.0]), vec![1, 2, 3, 4, 5]);
+        assert!(index.query(&[50.0, 0.0, 10.0, 10.0]).is_empty());
+
+        assert_eq!(
+            coco_page_mapper
+                .anns_in_region(1, [85.0, 38.0, 10.0, 50.0], &mut reader)
+                .unwrap(),
+            vec![3, 4]
+        );
+
+        // Without a known size the grid spans the boxes.
+        assert_eq!(
+            coco_page_mapper
+                .anns_in_region(2, [400.0, 400.0, 200.0, 200.0], &mut reader)
+                .unwrap(),
+            vec![8]
+        );
+        assert!(coco_page_mapper
+            .anns_in_region(3, [0.0, 0.0, 1.0, 1.0], &mut reader)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read, Seek},
+};
+
+use crate::{
+    coco_page_mapper::{CocoPageMapper, BATCH_SIZE},
+    page_maps::JsonDict,
+    rle::{is_rle, Rle},
+    utils::is_crowd,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CategoryStats {
+    pub num_crowd: usize,
+    pub num_non_crowd: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DatasetStats {
+    pub num_images: usize,
+    pub num_annotations: usize,
+    pub categories: HashMap<i64, CategoryStats>,
+    /// Total foreground pixels of the crowd annotations.
+    pub crowd_area: u64,
+    /// Images with annotations, all of which are crowd.
+    pub crowd_only_img_ids: Vec<i64>,
+}
+
+/// Summary of the numeric values of an annotation field, see
+/// `CocoPageMapper::ann_field_stats`. `min`, `max` and `mean` are `None`
+/// without any value.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FieldStats {
+    pub count: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    pub sum: f64,
+}
+
+impl FieldStats {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |v| v.min(value)));
+        self.max = Some(self.max.map_or(value, |v| v.max(value)));
+    }
+}
+
+/// Pixel area of an annotation, read from its RLE mask when there is one.
+fn pixel_area(ann: &JsonDict) -> u64 {
+    let rle = ann
+        .get("segmentation")
+        .filter(|v| is_rle(v))
+        .and_then(|v| Rle::from_json(v).ok());
+    match rle {
+        Some(rle) => rle.area(),
+        None => ann
+            .get("area")
+            .and_then(|v| v.as_f64())
+            .map_or(0, |v| v.round() as u64),
+    }
+}
+
+impl CocoPageMapper {
+    /// Counts annotations per category, separating crowd from non-crowd ones.
+    /// Panoptic segments are counted as crowd if they are marked so or if their
+    /// category is stuff (`isthing: 0`), matching `panoptic_to_instances`.
+    pub fn dataset_stats(&self, mut reader: impl Read + Seek) -> Result<DatasetStats, io::Error> {
+        let stuff_ids: HashSet<i64> = self
+            .categories()?
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|v| v.get("isthing").and_then(|v| v.as_i64()) == Some(0))
+            .filter_map(|v| v.get("id").and_then(|v| v.as_i64()))
+            .collect();
+
+        let mut stats = DatasetStats {
+            num_images: self.get_img_ids().len(),
+            ..Default::default()
+        };
+
+        for img_ids in self.get_img_ids().chunks(BATCH_SIZE) {
+            let batch = self.get_anns_batch(img_ids, &mut reader)?;
+            for (img_id, anns) in img_ids.iter().zip(batch) {
+                let mut num_crowd = 0;
+                let mut num_instances = 0;
+
+                for ann in anns.iter() {
+                    let segments = ann.get("segments_info").and_then(|v| v.as_array());
+                    let instances = match segments {
+                        Some(segments) => segments.iter().collect(),
+                        None => vec![ann],
+                    };
+                    for instance in instances {
+                        let category_id = instance.get("category_id").and_then(|v| v.as_i64());
+                        let crowd = instance.get("iscrowd").and_then(is_crowd) == Some(true)
+                            || category_id.is_some_and(|v| stuff_ids.contains(&v));
+
+                        num_instances += 1;
+                        let category = stats.categories.entry(category_id.unwrap_or(-1));
+                        let category = category.or_default();
+                        if crowd {
+                            num_crowd += 1;
+                            category.num_crowd += 1;
+                            stats.crowd_area += pixel_area(instance);
+                        } else {
+                            category.num_non_crowd += 1;
+                        }
+                    }
+                }
+
+                stats.num_annotations += num_instances;
+                if num_instances > 0 && num_crowd == num_instances {
+                    stats.crowd_only_img_ids.push(*img_id);
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Stats of the top-level `field` of every annotation, in one pass over
+    /// them. Annotations without the field or with a non-numeric value are
+    /// skipped.
+    pub fn ann_field_stats(
+        &self,
+        reader: impl Read + Seek,
+        field: &str,
+    ) -> Result<FieldStats, io::Error> {
+        let mut stats = FieldStats::default();
+        for ann in self.iter_all_anns(reader) {
+            if let Some(value) = ann?.get(field).and_then(|v| v.as_f64()) {
+                stats.add(value);
+            }
+        }
+        if stats.count > 0 {
+            stats.mean = Some(stats.sum / stats.count as f64);
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::prepare;
+
+    #[test]
+    fn test_crowd_stats() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[
+                {"id":1,"width":4,"height":4,"file_name":"a.jpg"},
+                {"id":2,"width":4,"height":4,"file_name":"b.jpg"},
+                {"id":3,"width":4,"height":4,"file_name":"c.jpg"}
+            ],
+            "annotations":[
+                {"id":1,"image_id":1,"category_id":1,"iscrowd":0,"area":4.0,"bbox":[0,0,2,2]},
+                {"id":2,"image_id":1,"category_id":1,"iscrowd":0,"area":4.0,"bbox":[2,2,2,2]},
+                {"id":3,"image_id":2,"category_id":1,"iscrowd":1,"area":16.0,"bbox":[0,0,4,4],"segmentation":{"size":[4,4],"counts":"52203"}},
+                {"id":4,"image_id":3,"category_id":1,"iscrowd":true,"area":3.0,"bbox":[0,0,1,3]}
+            ]
+        }"#;
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let stats = coco_page_mapper.dataset_stats(&mut reader).unwrap();
+        assert_eq!(stats.num_images, 3);
+        assert_eq!(stats.num_annotations, 4);
+        assert_eq!(
+            stats.categories[&1],
+            CategoryStats {
+                num_crowd: 2,
+                num_non_crowd: 2
+            }
+        );
+        assert_eq!(stats.crowd_area, 7);
+        assert_eq!(stats.crowd_only_img_ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_ann_field_stats() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[{"id":1,"width":4,"height":4,"file_name":"a.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":1,"category_id":1,"area":4.0,"score":"high"},
+                {"id":2,"image_id":1,"category_id":1,"area":2},
+                {"id":3,"image_id":1,"category_id":1,"area":12.5,"score":null},
+                {"id":4,"image_id":1,"category_id":1,"area":"none"},
+                {"id":5,"image_id":1,"category_id":1}
+            ]
+        }"#;
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let stats = coco_page_mapper
+            .ann_field_stats(&mut reader, "area")
+            .unwrap();
+        assert_eq!(
+            stats,
+            FieldStats {
+                count: 3,
+                min: Some(2.0),
+                max: Some(12.5),
+                mean: Some(18.5 / 3.0),
+                sum: 18.5,
+            }
+        );
+        assert_eq!(
+            coco_page_mapper
+                .ann_field_stats(&mut reader, "score")
+                .unwrap(),
+            FieldStats::default()
+        );
+    }
+
+    #[test]
+    fn test_panoptic_stuff_is_crowd() {
+        const EXAMPLE: &str = r#"
+        {"licenses":[],"info":{},"categories":[{"id":1,"name":"person","supercategory":"","isthing":1},{"id":2,"name":"sky","supercategory":"","isthing":0}],"images":[{"id":1,"width":4,"height":4,"file_name":"1.jpg"}],"annotations":[{"image_id":1,"file_name":"1.png","segments_info":[{"id":3,"category_id":1,"area":5.0,"bbox":[1.0,0.0,2.0,2.0],"iscrowd":0},{"id":4,"category_id":2,"area":4.0,"bbox":[0.0,0.0,4.0,1.0],"iscrowd":0}]}]}
+        "#;
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let stats = coco_page_mapper.dataset_stats(&mut reader).unwrap();
+        assert_eq!(stats.num_annotations, 2);
+        assert_eq!(stats.categories[&1].num_non_crowd, 1);
+        assert_eq!(stats.categories[&2].num_crowd, 1);
+        assert_eq!(stats.crowd_area, 4);
+        assert!(stats.crowd_only_img_ids.is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    env::temp_dir,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Cursor, Read, Seek, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::coco_page_mapper::CocoPageMapper;
+
+pub const EXAMPLE: &str = r#"
+        {
+            "licenses":[{"name":"","id":0,"url":""}],
+            "info":{"contributor":"","date_created":"","description":"","url":"","version":"","year":""},
+            "categories":[
+                {"id":1,"name":"a","supercategory":""},
+                {"id":2,"name":"b","supercategory":""},
+                {"id":4,"name":"c","supercategory":""}
+            ],
+            "images":[
+                {"id":5,"width":10,"height":5,"file_name":"a.jpg","license":0,"flickr_url":"","coco_url":"","date_captured":0},
+                {"id":6,"width":10,"height":5,"file_name":"b.jpg","license":0,"flickr_url":"","coco_url":"","date_captured":0}
+            ],
+            "annotations":[
+                {"id":1,"image_id":5,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0},
+                {"id":2,"image_id":5,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0},
+                {"id":3,"image_id":5,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0},
+                {"id":4,"image_id":6,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0},
+                {"id":5,"image_id":6,"category_id":2,"segmentation":[],"area":3.0,"bbox":[2.0,2.0,3.0,1.0],"iscrowd":0}
+            ]
+        }"#;
+
+pub fn write_example(example: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let filepath = temp_dir().join(format!(
+        "tmp-{}-{}.json",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    let mut f = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&filepath)
+        .expect("cannot open file");
+    let _ = f.write_all(example.as_bytes());
+
+    filepath
+}
+
+pub fn prepare(example: &str) -> (BufReader<File>, CocoPageMapper) {
+    let filepath = write_example(example);
+    let f = File::open(&filepath).expect("cannot open file");
+    let mut reader = BufReader::new(f);
+    let coco_page_mapper = CocoPageMapper::new(&mut reader).unwrap();
+
+    (reader, coco_page_mapper)
+}
+
+pub fn reopen(buf: Vec<u8>) -> (Cursor<Vec<u8>>, CocoPageMapper) {
+    let mut reader = Cursor::new(buf);
+    let coco_page_mapper = CocoPageMapper::new(&mut reader).unwrap();
+
+    (reader, coco_page_mapper)
+}
+
+/// Counts the calls made to the wrapped reader.
+pub struct CountingReader<R> {
+    inner: R,
+    pub reads: usize,
+    pub seeks: usize,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            reads: 0,
+            seeks: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.seeks += 1;
+        self.inner.seek(pos)
+    }
+}
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts the allocations of every thread, see `allocations`.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Allocations and reallocations made so far by the current thread.
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(|n| n.get())
+}
@@ -0,0 +1,362 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io::{self, Write};
+
+use serde_json::json;
+
+use crate::{geometry::polygon_area, page_maps::JsonDict, rle::Rle, writer::CocoWriter};
+
+/// Number of annotations drawn for every image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnsPerImage {
+    Fixed(usize),
+    /// Uniformly distributed in `min..=max`.
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentationStyle {
+    /// An empty `segmentation` list.
+    None,
+    /// A convex polygon inscribed in the box.
+    Polygon,
+    /// The box as a compressed RLE mask, with `iscrowd` set.
+    Rle,
+}
+
+/// Counts of the records written by `CocoGenerator::write`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GeneratedCounts {
+    pub images: usize,
+    pub annotations: usize,
+}
+
+/// SplitMix64, so that the output only depends on the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Self(seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        rng.next_u64();
+        rng
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `min..=max`.
+    fn range(&mut self, min: usize, max: usize) -> usize {
+        min + (self.next_u64() % (max - min + 1) as u64) as usize
+    }
+}
+
+/// Writes valid COCO files of any size for benchmarks and tests. The output
+/// is the same for the same seed and settings. Every image is drawn from its
+/// own random stream, so changing the image count keeps the other images.
+#[derive(Debug, Clone)]
+pub struct CocoGenerator {
+    seed: u64,
+    images: usize,
+    anns_per_image: AnnsPerImage,
+    categories: usize,
+    segmentation: SegmentationStyle,
+    target_bytes: Option<u64>,
+}
+
+impl CocoGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            images: 100,
+            anns_per_image: AnnsPerImage::Fixed(3),
+            categories: 10,
+            segmentation: SegmentationStyle::None,
+            target_bytes: None,
+        }
+    }
+
+    pub fn images(mut self, value: usize) -> Self {
+        self.images = value;
+        self
+    }
+
+    pub fn anns_per_image(mut self, value: AnnsPerImage) -> Self {
+        self.anns_per_image = value;
+        self
+    }
+
+    /// At least 1.
+    pub fn categories(mut self, value: usize) -> Self {
+        self.categories = value.max(1);
+        self
+    }
+
+    pub fn segmentation(mut self, value: SegmentationStyle) -> Self {
+        self.segmentation = value;
+        self
+    }
+
+    /// Write as many images as needed for the file to reach `value` bytes,
+    /// instead of the `images` count.
+    pub fn target_bytes(mut self, value: u64) -> Self {
+        self.target_bytes = Some(value);
+        self
+    }
+
+    pub fn write(&self, writer: impl Write) -> Result<GeneratedCounts, io::Error> {
+        let mut writer = CocoWriter::new(writer);
+        let header = self.header();
+        writer.write_licenses(&header[0])?;
+        writer.write_info(&header[1])?;
+        writer.write_categories(&header[2])?;
+
+        let n_images = match self.target_bytes {
+            Some(target) => self.images_for_target(target, &header),
+            None => self.images,
+        };
+        writer.begin_images()?;
+        for idx in 0..n_images {
+            writer.write_image(&self.draw_image(idx).1)?;
+        }
+        writer.end_images()?;
+
+        writer.begin_annotations()?;
+        let mut ann_id = 1;
+        for idx in 0..n_images {
+            let (mut rng, image) = self.draw_image(idx);
+            for ann in self.draw_anns(&mut rng, &image, &mut ann_id) {
+                writer.write_annotation(&ann)?;
+            }
+        }
+        writer.end_annotations()?;
+        writer.finish()?;
+
+        Ok(GeneratedCounts {
+            images: n_images,
+            annotations: ann_id as usize - 1,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        // Writing to memory does not fail.
+        self.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// `licenses`, `info` and `categories`.
+    fn header(&self) -> [JsonDict; 3] {
+        let categories: Vec<JsonDict> = (1..=self.categories)
+            .map(|id| {
+                json!({
+                    "id": id,
+                    "name": format!("category_{}", id),
+                    "supercategory": format!("group_{}", id % 5),
+                })
+            })
+            .collect();
+        [
+            json!([{"id": 1, "name": "generated", "url": ""}]),
+            json!({"description": "generated", "version": "1.0", "year": 2023}),
+            categories.into(),
+        ]
+    }
+
+    fn images_for_target(&self, target: u64, header: &[JsonDict; 3]) -> usize {
+        let size = |value: &JsonDict| serde_json::to_vec(value).map_or(0, |v| v.len() as u64 + 1);
+        let mut bytes: u64 = header.iter().map(size).sum::<u64>() + 64;
+        let mut ann_id = 1;
+        let mut n_images = 0;
+        while bytes < target {
+            let (mut rng, image) = self.draw_image(n_images);
+            bytes += size(&image);
+            bytes += self
+                .draw_anns(&mut rng, &image, &mut ann_id)
+                .iter()
+                .map(size)
+                .sum::<u64>();
+            n_images += 1;
+        }
+        n_images
+    }
+
+    /// The image at `idx` and the random stream of its annotations.
+    fn draw_image(&self, idx: usize) -> (Rng, JsonDict) {
+        let mut rng = Rng::new(self.seed, idx as u64);
+        let id = idx + 1;
+        let image = json!({
+            "id": id,
+            "width": rng.range(64, 1024),
+            "height": rng.range(64, 1024),
+            "file_name": format!("{:012}.jpg", id),
+            "license": 1,
+            "date_captured": "2023-01-01 00:00:00",
+        });
+        (rng, image)
+    }
+
+    fn draw_anns(&self, rng: &mut Rng, image: &JsonDict, ann_id: &mut i64) -> Vec<JsonDict> {
+        let count = match self.anns_per_image {
+            AnnsPerImage::Fixed(n) => n,
+            AnnsPerImage::Uniform { min, max } => rng.range(min, max.max(min)),
+        };
+        let dim = |key| image[key].as_u64().unwrap_or(1) as usize;
+        let (width, height) = (dim("width"), dim("height"));
+
+        (0..count)
+            .map(|_| {
+                let (x, y) = (rng.range(0, width - 1), rng.range(0, height - 1));
+                let (w, h) = (rng.range(1, width - x), rng.range(1, height - y));
+                let (segmentation, area, iscrowd) = match self.segmentation {
+                    SegmentationStyle::None => (json!([]), (w * h) as f64, 0),
+                    SegmentationStyle::Polygon => {
+                        let points = ellipse(rng, [x, y, w, h]);
+                        let area = polygon_area(&points);
+                        (json!([points]), area, 0)
+                    }
+                    SegmentationStyle::Rle => {
+                        let rle = box_rle([x, y, w, h], width, height);
+                        let counts = rle.to_compressed_string();
+                        (
+                            json!({"size": [height, width], "counts": counts}),
+                            (w * h) as f64,
+                            1,
+                        )
+                    }
+                };
+                let ann = json!({
+                    "id": *ann_id,
+                    "image_id": image["id"],
+                    "category_id": rng.range(1, self.categories),
+                    "segmentation": segmentation,
+                    "area": area,
+                    "bbox": [x as f64, y as f64, w as f64, h as f64],
+                    "iscrowd": iscrowd,
+                });
+                *ann_id += 1;
+                ann
+            })
+            .collect()
+    }
+}
+
+/// 4 to 8 points on the ellipse inscribed in `bbox`, counterclockwise.
+fn ellipse(rng: &mut Rng, bbox: [usize; 4]) -> Vec<f64> {
+    let [x, y, w, h] = bbox.map(|v| v as f64);
+    let n = rng.range(4, 8);
+    (0..n)
+        .flat_map(|i| {
+            let angle = 2.0 * std::f64::consts::PI * i as f64 / n as f64;
+            let px = x + w / 2.0 * (1.0 + angle.cos());
+            let py = y + h / 2.0 * (1.0 + angle.sin());
+            [(px * 10.0).round() / 10.0, (py * 10.0).round() / 10.0]
+        })
+        .collect()
+}
+
+/// The mask of `bbox` in an image of `width` by `height`.
+fn box_rle(bbox: [usize; 4], width: usize, height: usize) -> Rle {
+    let [x, y, w, h] = bbox;
+    let mut counts = vec![(x * height + y) as u32];
+    for col in 0..w {
+        counts.push(h as u32);
+        if col + 1 < w {
+            counts.push((height - h) as u32);
+        }
+    }
+    counts.push(((height - y - h) + (width - x - w) * height) as u32);
+    Rle {
+        height,
+        width,
+        counts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{coco_page_mapper::CocoPageMapper, validation::ValidationOptions};
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let generator = CocoGenerator::new(7)
+            .images(20)
+            .anns_per_image(AnnsPerImage::Uniform { min: 0, max: 5 });
+        let bytes = generator.to_bytes();
+        assert_eq!(bytes, generator.to_bytes());
+        assert_ne!(bytes, generator.clone().images(21).to_bytes());
+        assert_ne!(bytes, CocoGenerator::new(8).images(20).to_bytes());
+
+        // More images only append to the sections.
+        let more = generator.clone().images(21).to_bytes();
+        let images = |bytes: &[u8]| {
+            let mapper = CocoPageMapper::from_bytes(bytes).unwrap();
+            mapper
+                .get_item_dict(20, Cursor::new(bytes))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(images(&bytes), images(&more));
+    }
+
+    #[test]
+    fn test_generated_files_are_valid() {
+        for segmentation in [
+            SegmentationStyle::None,
+            SegmentationStyle::Polygon,
+            SegmentationStyle::Rle,
+        ] {
+            let mut bytes = vec![];
+            let counts = CocoGenerator::new(1)
+                .images(50)
+                .anns_per_image(AnnsPerImage::Uniform { min: 1, max: 6 })
+                .categories(3)
+                .segmentation(segmentation)
+                .write(&mut bytes)
+                .unwrap();
+            assert_eq!(counts.images, 50);
+
+            let mapper = CocoPageMapper::from_bytes(&bytes).unwrap();
+            assert_eq!(mapper.get_img_ids().len(), 50);
+            assert_eq!(mapper.categories().unwrap().as_array().unwrap().len(), 3);
+            let n_anns: usize = mapper
+                .get_img_ids()
+                .iter()
+                .map(|img_id| {
+                    mapper
+                        .get_anns_dict(*img_id, Cursor::new(&bytes))
+                        .unwrap()
+                        .len()
+                })
+                .sum();
+            assert_eq!(n_anns, counts.annotations);
+
+            let options = ValidationOptions {
+                check_self_intersections: true,
+            };
+            let report = mapper.validate(Cursor::new(&bytes), &options).unwrap();
+            assert!(report.is_ok(), "{:?}: {:?}", segmentation, report.issues);
+        }
+    }
+
+    #[test]
+    fn test_target_bytes() {
+        let target = 1 << 20;
+        let bytes = CocoGenerator::new(3).target_bytes(target).to_bytes();
+        assert!(bytes.len() as u64 >= target);
+        assert!((bytes.len() as u64) < target + 4096);
+        assert!(CocoPageMapper::from_bytes(&bytes).is_ok());
+    }
+}
@@ -0,0 +1,905 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, Write},
+};
+
+use crate::{
+    coco_page_mapper::CocoPageMapper,
+    duplicates::parse_bbox,
+    export::rewrite_dataset,
+    geometry::{
+        bbox_intersection, bbox_union, clip_polygon, parse_polygons, points_bbox, polygon_area,
+        simplify_ring, BBox,
+    },
+    page_maps::JsonDict,
+    rle::{is_rle, Rle},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BboxRepairPolicy {
+    /// Intersect boxes with the image rectangle, dropping the annotation if nothing is left.
+    Clamp,
+    /// Drop annotations whose box is not fully inside the image.
+    Drop,
+    /// Keep the file unchanged and only report the boxes `Clamp` would fix.
+    ReportOnly,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BboxRepair {
+    pub img_id: i64,
+    pub ann_id: Option<i64>,
+    pub before: [f64; 4],
+    /// `None` if the annotation was (or would be) dropped.
+    pub after: Option<[f64; 4]>,
+}
+
+fn clamp_bbox(bbox: &[f64; 4], width: f64, height: f64) -> Option<[f64; 4]> {
+    let x0 = bbox[0].max(0.0);
+    let y0 = bbox[1].max(0.0);
+    let x1 = (bbox[0] + bbox[2]).min(width);
+    let y1 = (bbox[1] + bbox[3]).min(height);
+    if x1 <= x0 || y1 <= y0 {
+        return None;
+    }
+    Some([x0, y0, x1 - x0, y1 - y0])
+}
+
+/// Tight box and pixel count of an RLE mask, computed without decoding it.
+fn rle_extent(ann: &JsonDict) -> Option<(BBox, u64)> {
+    let segmentation = ann.get("segmentation").filter(|v| is_rle(v))?;
+    let rle = Rle::from_json(segmentation).ok()?;
+    Some((rle.to_bbox()?, rle.area()))
+}
+
+/// Streams a copy of the dataset with the boxes lying outside of their image
+/// repaired according to `policy`. The `area` of a clamped annotation is set
+/// to the area of its new box. RLE annotations are clamped to the extent of
+/// their mask instead and keep the mask area. Images without a size are left
+/// untouched.
+pub fn repair_bboxes(
+    mapper: &CocoPageMapper,
+    reader: impl Read + Seek,
+    writer: impl Write,
+    policy: BboxRepairPolicy,
+) -> Result<Vec<BboxRepair>, io::Error> {
+    let mut repairs = vec![];
+
+    rewrite_dataset(
+        mapper,
+        reader,
+        writer,
+        mapper.get_img_ids(),
+        mapper.categories()?,
+        |_, item| Ok(vec![item]),
+        |img_id, anns| {
+            let Some((width, height)) = mapper.image_size(img_id) else {
+                return Ok(anns);
+            };
+            let (width, height) = (width as f64, height as f64);
+
+            let mut kept = Vec::with_capacity(anns.len());
+            for mut ann in anns {
+                let Some(bbox) = parse_bbox(&ann) else {
+                    kept.push(ann);
+                    continue;
+                };
+                let inside = bbox[0] >= 0.0
+                    && bbox[1] >= 0.0
+                    && bbox[0] + bbox[2] <= width
+                    && bbox[1] + bbox[3] <= height;
+                if inside {
+                    kept.push(ann);
+                    continue;
+                }
+
+                let extent = rle_extent(&ann);
+                let after = match policy {
+                    BboxRepairPolicy::Drop => None,
+                    _ => clamp_bbox(&extent.map_or(bbox, |(v, _)| v), width, height),
+                };
+                repairs.push(BboxRepair {
+                    img_id,
+                    ann_id: ann.get("id").and_then(|v| v.as_i64()),
+                    before: bbox,
+                    after,
+                });
+
+                match (policy, after) {
+                    (BboxRepairPolicy::ReportOnly, _) => kept.push(ann),
+                    (BboxRepairPolicy::Clamp, Some(after)) => {
+                        ann["bbox"] = JsonDict::from(after.to_vec());
+                        ann["area"] = match extent {
+                            Some((_, area)) => JsonDict::from(area),
+                            None => JsonDict::from(after[2] * after[3]),
+                        };
+                        kept.push(ann);
+                    }
+                    _ => {}
+                }
+            }
+            Ok(kept)
+        },
+    )?;
+
+    Ok(repairs)
+}
+
+#[derive(Debug, Clone)]
+pub enum ResizeSpec {
+    /// Scale every image by the same per-axis factors.
+    Scale { x: f64, y: f64 },
+    /// Resize the listed images to `(width, height)`, the other images are kept as is.
+    Targets(HashMap<i64, (i64, i64)>),
+}
+
+/// Multiplies every `stride`-th value starting at index 0 by `sx` and the ones
+/// right after them by `sy`.
+fn scale_coords(values: &mut JsonDict, stride: usize, sx: f64, sy: f64) {
+    if let Some(values) = values.as_array_mut() {
+        for (idx, v) in values.iter_mut().enumerate() {
+            let scale = match idx % stride {
+                0 => sx,
+                1 => sy,
+                _ => continue,
+            };
+            if let Some(f) = v.as_f64() {
+                *v = JsonDict::from(f * scale);
+            }
+        }
+    }
+}
+
+fn resize_ann(ann: &mut JsonDict, sx: f64, sy: f64) -> Result<(), io::Error> {
+    if let Some(bbox) = ann.get_mut("bbox") {
+        scale_coords(bbox, 2, sx, sy);
+    }
+    if let Some(keypoints) = ann.get_mut("keypoints") {
+        scale_coords(keypoints, 3, sx, sy);
+    }
+
+    let mut area = None;
+    if let Some(segmentation) = ann.get_mut("segmentation") {
+        if is_rle(segmentation) {
+            let rle = Rle::from_json(segmentation)?;
+            let height = (rle.height as f64 * sy).round() as usize;
+            let width = (rle.width as f64 * sx).round() as usize;
+            let resized = rle.resize(height, width);
+            area = Some(resized.area() as f64);
+            *segmentation = resized.to_json();
+        } else if let Some(rings) = segmentation.as_array_mut().filter(|v| !v.is_empty()) {
+            for ring in rings.iter_mut() {
+                scale_coords(ring, 2, sx, sy);
+            }
+            area = parse_polygons(segmentation)
+                .map(|rings| rings.iter().map(|v| polygon_area(v)).sum());
+        }
+    }
+
+    if let Some(old_area) = ann.get("area").and_then(|v| v.as_f64()) {
+        ann["area"] = JsonDict::from(area.unwrap_or(old_area * sx * sy));
+    }
+    Ok(())
+}
+
+/// Streams a copy of the dataset with images resized according to `spec`.
+/// Boxes, polygons and keypoints are scaled, RLE masks are resampled with the
+/// nearest neighbor and areas are recomputed from the scaled geometry.
+pub fn resize(
+    mapper: &CocoPageMapper,
+    reader: impl Read + Seek,
+    writer: impl Write,
+    spec: &ResizeSpec,
+) -> Result<(), io::Error> {
+    let scales: HashMap<i64, (f64, f64)> = mapper
+        .get_img_ids()
+        .iter()
+        .filter_map(|img_id| {
+            let scale = match spec {
+                ResizeSpec::Scale { x, y } => (*x, *y),
+                ResizeSpec::Targets(targets) => {
+                    let (width, height) = targets.get(img_id)?;
+                    let (old_width, old_height) = mapper.image_size(*img_id)?;
+                    (
+                        *width as f64 / old_width as f64,
+                        *height as f64 / old_height as f64,
+                    )
+                }
+            };
+            Some((*img_id, scale))
+        })
+        .collect();
+
+    rewrite_dataset(
+        mapper,
+        reader,
+        writer,
+        mapper.get_img_ids(),
+        mapper.categories()?,
+        |img_id, mut item| {
+            if let (Some((sx, sy)), Some((width, height))) =
+                (scales.get(&img_id), mapper.image_size(img_id))
+            {
+                let new_size = match spec {
+                    ResizeSpec::Targets(targets) => targets[&img_id],
+                    ResizeSpec::Scale { .. } => (
+                        (width as f64 * sx).round() as i64,
+                        (height as f64 * sy).round() as i64,
+                    ),
+                };
+                item["width"] = new_size.0.into();
+                item["height"] = new_size.1.into();
+            }
+            Ok(vec![item])
+        },
+        |img_id, mut anns| {
+            if let Some((sx, sy)) = scales.get(&img_id) {
+                for ann in anns.iter_mut() {
+                    resize_ann(ann, *sx, *sy)?;
+                }
+            }
+            Ok(anns)
+        },
+    )
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HflipOptions {
+    /// Pairs of keypoint indices (e.g. left/right eye) swapped after mirroring.
+    pub keypoint_flip_map: Vec<(usize, usize)>,
+    /// Appended to the stem of `file_name`, e.g. `a.jpg` becomes `a_flip.jpg` for `_flip`.
+    pub file_name_suffix: Option<String>,
+}
+
+fn add_file_name_suffix(file_name: &str, suffix: &str) -> String {
+    match file_name
+        .rfind('.')
+        .filter(|pos| !file_name[*pos..].contains('/'))
+    {
+        Some(pos) => format!("{}{}{}", &file_name[..pos], suffix, &file_name[pos..]),
+        None => format!("{}{}", file_name, suffix),
+    }
+}
+
+fn hflip_ann(
+    ann: &mut JsonDict,
+    width: f64,
+    keypoint_flip_map: &[(usize, usize)],
+) -> Result<(), io::Error> {
+    if let Some(bbox) = parse_bbox(ann) {
+        ann["bbox"] = JsonDict::from(vec![width - bbox[0] - bbox[2], bbox[1], bbox[2], bbox[3]]);
+    }
+
+    if let Some(segmentation) = ann.get_mut("segmentation") {
+        if is_rle(segmentation) {
+            *segmentation = Rle::from_json(segmentation)?.hflip().to_json();
+        } else if let Some(rings) = segmentation.as_array_mut() {
+            for ring in rings.iter_mut().filter_map(|v| v.as_array_mut()) {
+                for x in ring.iter_mut().step_by(2) {
+                    if let Some(v) = x.as_f64() {
+                        *x = JsonDict::from(width - v);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(keypoints) = ann.get_mut("keypoints").and_then(|v| v.as_array_mut()) {
+        for point in keypoints.chunks_mut(3) {
+            // Unlabeled keypoints are stored as (0, 0, 0) and stay so.
+            let labeled = point
+                .get(2)
+                .and_then(|v| v.as_f64())
+                .is_none_or(|v| v > 0.0);
+            if let (true, Some(x)) = (labeled, point[0].as_f64()) {
+                point[0] = JsonDict::from(width - x);
+            }
+        }
+        for (a, b) in keypoint_flip_map {
+            if 3 * a.max(b) + 2 < keypoints.len() {
+                for k in 0..3 {
+                    keypoints.swap(3 * a + k, 3 * b + k);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Streams a horizontally mirrored copy of the dataset. Image sizes are kept.
+pub fn hflip(
+    mapper: &CocoPageMapper,
+    reader: impl Read + Seek,
+    writer: impl Write,
+    keypoint_flip_map: Option<&[(usize, usize)]>,
+) -> Result<(), io::Error> {
+    let options = HflipOptions {
+        keypoint_flip_map: keypoint_flip_map.map(|v| v.to_vec()).unwrap_or_default(),
+        file_name_suffix: None,
+    };
+    hflip_with_options(mapper, reader, writer, &options)
+}
+
+pub fn hflip_with_options(
+    mapper: &CocoPageMapper,
+    reader: impl Read + Seek,
+    writer: impl Write,
+    options: &HflipOptions,
+) -> Result<(), io::Error> {
+    rewrite_dataset(
+        mapper,
+        reader,
+        writer,
+        mapper.get_img_ids(),
+        mapper.categories()?,
+        |_, mut item| {
+            let file_name = item.get("file_name").and_then(|v| v.as_str());
+            if let (Some(file_name), Some(suffix)) = (file_name, &options.file_name_suffix) {
+                item["file_name"] = add_file_name_suffix(file_name, suffix).into();
+            }
+            Ok(vec![item])
+        },
+        |img_id, mut anns| {
+            if let Some((width, _)) = mapper.image_size(img_id) {
+                for ann in anns.iter_mut() {
+                    hflip_ann(ann, width as f64, &options.keypoint_flip_map)?;
+                }
+            }
+            Ok(anns)
+        },
+    )
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CropOptions {
+    /// Drop annotations keeping less than this fraction of their area inside the crop.
+    pub min_area_fraction: f64,
+}
+
+/// Cuts `ann` to `window` and moves it to the window's coordinates. Returns
+/// `None` when the annotation should be dropped.
+fn crop_ann(
+    mut ann: JsonDict,
+    window: &BBox,
+    min_area_fraction: f64,
+) -> Result<Option<JsonDict>, io::Error> {
+    let (wx, wy) = (window[0], window[1]);
+
+    let mut orig_area = parse_bbox(&ann).map(|v| v[2] * v[3]);
+    let mut new_area = None;
+    let mut new_bbox = parse_bbox(&ann).and_then(|v| bbox_intersection(&v, window));
+
+    if let Some(segmentation) = ann.get_mut("segmentation") {
+        if is_rle(segmentation) {
+            let rle = Rle::from_json(segmentation)?;
+            let cropped = rle.crop(
+                wx.round() as i64,
+                wy.round() as i64,
+                window[2].round() as usize,
+                window[3].round() as usize,
+            );
+            orig_area = Some(rle.area() as f64);
+            new_area = Some(cropped.area() as f64);
+            *segmentation = cropped.to_json();
+        } else if let Some(rings) = parse_polygons(segmentation).filter(|v| !v.is_empty()) {
+            orig_area = Some(rings.iter().map(|v| polygon_area(v)).sum());
+
+            let clipped: Vec<Vec<f64>> = rings
+                .iter()
+                .map(|ring| clip_polygon(ring, window))
+                .filter(|ring| ring.len() >= 6 && polygon_area(ring) > 0.0)
+                .collect();
+
+            new_area = Some(clipped.iter().map(|v| polygon_area(v)).sum());
+            new_bbox = clipped
+                .iter()
+                .filter_map(|v| points_bbox(v))
+                .reduce(|a, b| bbox_union(&a, &b));
+
+            let clipped: Vec<Vec<f64>> = clipped
+                .into_iter()
+                .map(|ring| {
+                    ring.chunks_exact(2)
+                        .flat_map(|p| [p[0] - wx, p[1] - wy])
+                        .collect()
+                })
+                .collect();
+            *segmentation = JsonDict::from(clipped);
+        }
+    }
+
+    let Some(new_bbox) = new_bbox else {
+        return Ok(None);
+    };
+    let new_area = new_area.unwrap_or(new_bbox[2] * new_bbox[3]);
+    if new_area <= 0.0 {
+        return Ok(None);
+    }
+    if let Some(orig_area) = orig_area.filter(|v| *v > 0.0) {
+        if new_area / orig_area < min_area_fraction {
+            return Ok(None);
+        }
+    }
+
+    ann["bbox"] = JsonDict::from(vec![
+        new_bbox[0] - wx,
+        new_bbox[1] - wy,
+        new_bbox[2],
+        new_bbox[3],
+    ]);
+    ann["area"] = JsonDict::from(new_area);
+
+    if let Some(keypoints) = ann.get_mut("keypoints").and_then(|v| v.as_array_mut()) {
+        for point in keypoints.chunks_mut(3) {
+            if point.len() < 3 {
+                continue;
+            }
+            let (x, y) = (
+                point[0].as_f64().unwrap_or(0.0),
+                point[1].as_f64().unwrap_or(0.0),
+            );
+            let labeled = point[2].as_f64().unwrap_or(0.0) > 0.0;
+            let inside = x >= wx && y >= wy && x <= wx + window[2] && y <= wy + window[3];
+            if labeled && inside {
+                point[0] = JsonDict::from(x - wx);
+                point[1] = JsonDict::from(y - wy);
+            } else {
+                point.fill(JsonDict::from(0));
+            }
+        }
+    }
+
+    Ok(Some(ann))
+}
+
+/// Streams a dataset made of the given `(img_id, window, new_file_name)` crops.
+/// Every crop becomes a new image, images and annotations get fresh ids
+/// starting from 1. Polygons are clipped to the window, annotations left
+/// without area are dropped.
+pub fn crop(
+    mapper: &CocoPageMapper,
+    reader: impl Read + Seek,
+    writer: impl Write,
+    crops: &[(i64, BBox, String)],
+) -> Result<(), io::Error> {
+    crop_with_options(mapper, reader, writer, crops, &CropOptions::default())
+}
+
+pub fn crop_with_options(
+    mapper: &CocoPageMapper,
+    reader: impl Read + Seek,
+    writer: impl Write,
+    crops: &[(i64, BBox, String)],
+    options: &CropOptions,
+) -> Result<(), io::Error> {
+    let mut img_ids = vec![];
+    let mut crops_per_img: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (idx, (img_id, _, _)) in crops.iter().enumerate() {
+        crops_per_img
+            .entry(*img_id)
+            .or_insert_with(|| {
+                img_ids.push(*img_id);
+                vec![]
+            })
+            .push(idx);
+    }
+
+    let mut next_ann_id = 1;
+    rewrite_dataset(
+        mapper,
+        reader,
+        writer,
+        &img_ids,
+        mapper.categories()?,
+        |img_id, item| {
+            Ok(crops_per_img[&img_id]
+                .iter()
+                .map(|idx| {
+                    let (_, window, file_name) = &crops[*idx];
+                    let mut item = item.clone();
+                    item["id"] = (*idx as i64 + 1).into();
+                    item["width"] = (window[2].round() as i64).into();
+                    item["height"] = (window[3].round() as i64).into();
+                    item["file_name"] = file_name.as_str().into();
+                    item
+                })
+                .collect())
+        },
+        |img_id, anns| {
+            let mut cropped = vec![];
+            for idx in crops_per_img[&img_id].iter() {
+                let (_, window, _) = &crops[*idx];
+                for ann in anns.iter() {
+                    if let Some(mut ann) = crop_ann(ann.clone(), window, options.min_area_fraction)?
+                    {
+                        ann["id"] = next_ann_id.into();
+                        ann["image_id"] = (*idx as i64 + 1).into();
+                        next_ann_id += 1;
+                        cropped.push(ann);
+                    }
+                }
+            }
+            Ok(cropped)
+        },
+    )
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimplifyReport {
+    pub points_before: usize,
+    pub points_after: usize,
+}
+
+/// Streams a copy of the dataset with every polygon ring simplified by
+/// Douglas-Peucker with the given tolerance in pixels. The `area` of polygon
+/// annotations is recomputed, RLE masks and boxes are left as is.
+pub fn simplify_polygons(
+    mapper: &CocoPageMapper,
+    reader: impl Read + Seek,
+    writer: impl Write,
+    epsilon: f64,
+) -> Result<SimplifyReport, io::Error> {
+    let mut report = SimplifyReport::default();
+    rewrite_dataset(
+        mapper,
+        reader,
+        writer,
+        mapper.get_img_ids(),
+        mapper.categories()?,
+        |_, item| Ok(vec![item]),
+        |_, mut anns| {
+            for ann in anns.iter_mut() {
+                let Some(rings) = ann.get("segmentation").and_then(parse_polygons) else {
+                    continue;
+                };
+                if rings.is_empty() {
+                    continue;
+                }
+                let simplified: Vec<Vec<f64>> =
+                    rings.iter().map(|v| simplify_ring(v, epsilon)).collect();
+                report.points_before += rings.iter().map(|v| v.len() / 2).sum::<usize>();
+                report.points_after += simplified.iter().map(|v| v.len() / 2).sum::<usize>();
+
+                if ann.get("area").is_some() {
+                    ann["area"] = simplified
+                        .iter()
+                        .map(|v| polygon_area(v))
+                        .sum::<f64>()
+                        .into();
+                }
+                ann["segmentation"] = simplified.into();
+            }
+            Ok(anns)
+        },
+    )?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{prepare, reopen};
+    use serde_json::json;
+
+    const EXAMPLE: &str = r#"
+    {
+        "licenses":[],
+        "info":{},
+        "categories":[{"id":1,"name":"a","supercategory":""}],
+        "images":[{"id":1,"width":10,"height":5,"file_name":"a.jpg"}],
+        "annotations":[
+            {"id":1,"image_id":1,"category_id":1,"area":4.0,"bbox":[20.0,20.0,2.0,2.0]},
+            {"id":2,"image_id":1,"category_id":1,"area":16.0,"bbox":[8.0,3.0,4.0,4.0]},
+            {"id":3,"image_id":1,"category_id":1,"area":4.0,"bbox":[1.0,1.0,2.0,2.0]}
+        ]
+    }"#;
+
+    fn repair(policy: BboxRepairPolicy) -> (Vec<BboxRepair>, Vec<JsonDict>) {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let mut buf = vec![];
+        let mut repairs = repair_bboxes(&coco_page_mapper, &mut reader, &mut buf, policy).unwrap();
+        repairs.sort_by_key(|v| v.ann_id);
+
+        let (mut reader, repaired) = reopen(buf);
+        let mut anns = repaired.get_anns_dict(1, &mut reader).unwrap();
+        anns.sort_by_key(|v| v["id"].as_i64());
+        (repairs, anns)
+    }
+
+    #[test]
+    fn test_repair_bboxes_clamp() {
+        let (repairs, anns) = repair(BboxRepairPolicy::Clamp);
+
+        assert_eq!(
+            repairs,
+            vec![
+                BboxRepair {
+                    img_id: 1,
+                    ann_id: Some(1),
+                    before: [20.0, 20.0, 2.0, 2.0],
+                    after: None
+                },
+                BboxRepair {
+                    img_id: 1,
+                    ann_id: Some(2),
+                    before: [8.0, 3.0, 4.0, 4.0],
+                    after: Some([8.0, 3.0, 2.0, 2.0])
+                },
+            ]
+        );
+        assert_eq!(anns.len(), 2);
+        assert_eq!(parse_bbox(&anns[0]), Some([8.0, 3.0, 2.0, 2.0]));
+        assert_eq!(anns[0]["area"].as_f64(), Some(4.0));
+        assert_eq!(parse_bbox(&anns[1]), Some([1.0, 1.0, 2.0, 2.0]));
+    }
+
+    #[test]
+    fn test_repair_bboxes_rle() {
+        let example = json!({
+            "licenses": [],
+            "info": {},
+            "categories": [{"id": 1, "name": "a", "supercategory": ""}],
+            "images": [{"id": 1, "width": 4, "height": 4, "file_name": "a.jpg"}],
+            "annotations": [
+                {"id": 1, "image_id": 1, "category_id": 1, "iscrowd": 1, "area": 16.0, "bbox": [-1.0, -1.0, 8.0, 8.0], "segmentation": {"size": [4, 4], "counts": "52203"}}
+            ]
+        });
+        let (mut reader, coco_page_mapper) = prepare(&example.to_string());
+
+        let mut buf = vec![];
+        let repairs = repair_bboxes(
+            &coco_page_mapper,
+            &mut reader,
+            &mut buf,
+            BboxRepairPolicy::Clamp,
+        )
+        .unwrap();
+        assert_eq!(repairs[0].after, Some([1.0, 1.0, 2.0, 2.0]));
+
+        let (mut reader, repaired) = reopen(buf);
+        let anns = repaired.get_anns_dict(1, &mut reader).unwrap();
+        assert_eq!(parse_bbox(&anns[0]), Some([1.0, 1.0, 2.0, 2.0]));
+        assert_eq!(anns[0]["area"], 4);
+    }
+
+    #[test]
+    fn test_repair_bboxes_drop() {
+        let (repairs, anns) = repair(BboxRepairPolicy::Drop);
+
+        assert_eq!(repairs.len(), 2);
+        assert!(repairs.iter().all(|v| v.after.is_none()));
+        assert_eq!(anns.len(), 1);
+        assert_eq!(anns[0]["id"].as_i64(), Some(3));
+    }
+
+    #[test]
+    fn test_repair_bboxes_report_only() {
+        let (repairs, anns) = repair(BboxRepairPolicy::ReportOnly);
+
+        assert_eq!(repairs.len(), 2);
+        assert_eq!(repairs[1].after, Some([8.0, 3.0, 2.0, 2.0]));
+        assert_eq!(anns.len(), 3);
+        assert_eq!(parse_bbox(&anns[1]), Some([8.0, 3.0, 4.0, 4.0]));
+    }
+
+    #[test]
+    fn test_resize_scale() {
+        let (mut reader, coco_page_mapper) = prepare(crate::test_utils::EXAMPLE);
+        let mut buf = vec![];
+        resize(
+            &coco_page_mapper,
+            &mut reader,
+            &mut buf,
+            &ResizeSpec::Scale { x: 0.5, y: 0.5 },
+        )
+        .unwrap();
+
+        let (mut reader, resized) = reopen(buf);
+        for img_id in [5, 6] {
+            assert_eq!(resized.image_size(img_id), Some((5, 3)));
+            for ann in resized.get_anns_dict(img_id, &mut reader).unwrap() {
+                assert_eq!(parse_bbox(&ann), Some([1.0, 1.0, 1.5, 0.5]));
+                assert_eq!(ann["area"].as_f64(), Some(0.75));
+            }
+        }
+    }
+
+    #[test]
+    fn test_resize_targets() {
+        let mut ann = json!({
+            "id": 1, "image_id": 1, "category_id": 1, "area": 8.0, "bbox": [0.0, 0.0, 4.0, 2.0],
+            "segmentation": [[0.0, 0.0, 4.0, 0.0, 4.0, 2.0, 0.0, 2.0]],
+            "keypoints": [2.0, 1.0, 2, 0.0, 0.0, 0]
+        });
+        resize_ann(&mut ann, 2.0, 0.5).unwrap();
+        assert_eq!(parse_bbox(&ann), Some([0.0, 0.0, 8.0, 1.0]));
+        assert_eq!(
+            ann["segmentation"],
+            json!([[0.0, 0.0, 8.0, 0.0, 8.0, 1.0, 0.0, 1.0]])
+        );
+        assert_eq!(ann["keypoints"], json!([4.0, 0.5, 2, 0.0, 0.0, 0]));
+        assert_eq!(ann["area"].as_f64(), Some(8.0));
+
+        let mut crowd = json!({"area": 2.0, "segmentation": {"size": [2, 2], "counts": [2, 2]}});
+        resize_ann(&mut crowd, 2.0, 2.0).unwrap();
+        assert_eq!(
+            crowd["segmentation"],
+            json!({"size": [4, 4], "counts": [8, 8]})
+        );
+        assert_eq!(crowd["area"].as_f64(), Some(8.0));
+
+        let (mut reader, coco_page_mapper) = prepare(crate::test_utils::EXAMPLE);
+        let mut buf = vec![];
+        resize(
+            &coco_page_mapper,
+            &mut reader,
+            &mut buf,
+            &ResizeSpec::Targets(HashMap::from([(6, (20, 20))])),
+        )
+        .unwrap();
+
+        let (mut reader, resized) = reopen(buf);
+        assert_eq!(resized.image_size(5), Some((10, 5)));
+        assert_eq!(resized.image_size(6), Some((20, 20)));
+        let ann = &resized.get_anns_dict(6, &mut reader).unwrap()[0];
+        assert_eq!(parse_bbox(ann), Some([4.0, 8.0, 6.0, 4.0]));
+    }
+
+    #[test]
+    fn test_hflip() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"person","supercategory":"","keypoints":["nose","left_eye","right_eye"]}],
+            "images":[{"id":1,"width":10,"height":5,"file_name":"dir.v2/a.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":1,"category_id":1,"bbox":[0.0,1.0,3.0,2.0],
+                 "segmentation":[[0.0,1.0,3.0,1.0,3.0,3.0]],
+                 "keypoints":[5.0,1.0,2,2.0,1.0,2,4.0,1.0,1]},
+                {"id":2,"image_id":1,"category_id":1,"bbox":[0.0,0.0,1.0,1.0],
+                 "keypoints":[1.0,1.0,2,0,0,0,0,0,0]}
+            ]
+        }"#;
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let mut buf = vec![];
+        hflip_with_options(
+            &coco_page_mapper,
+            &mut reader,
+            &mut buf,
+            &HflipOptions {
+                keypoint_flip_map: vec![(1, 2)],
+                file_name_suffix: Some("_flip".to_string()),
+            },
+        )
+        .unwrap();
+
+        let (mut reader, flipped) = reopen(buf);
+        assert_eq!(flipped.image_size(1), Some((10, 5)));
+        let item = flipped.get_item_dict(1, &mut reader).unwrap();
+        assert_eq!(item["file_name"], "dir.v2/a_flip.jpg");
+
+        let mut anns = flipped.get_anns_dict(1, &mut reader).unwrap();
+        anns.sort_by_key(|v| v["id"].as_i64());
+
+        assert_eq!(parse_bbox(&anns[0]), Some([7.0, 1.0, 3.0, 2.0]));
+        assert_eq!(
+            anns[0]["segmentation"],
+            json!([[10.0, 1.0, 7.0, 1.0, 7.0, 3.0]])
+        );
+        assert_eq!(
+            anns[0]["keypoints"],
+            json!([5.0, 1.0, 2, 6.0, 1.0, 1, 8.0, 1.0, 2])
+        );
+        assert_eq!(anns[1]["keypoints"], json!([9.0, 1.0, 2, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_crop() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[{"id":1,"name":"a","supercategory":""}],
+            "images":[{"id":7,"width":100,"height":100,"file_name":"big.jpg"}],
+            "annotations":[
+                {"id":1,"image_id":7,"category_id":1,"area":4.0,"bbox":[12.0,12.0,2.0,2.0]},
+                {"id":2,"image_id":7,"category_id":1,"area":16.0,"bbox":[18.0,18.0,4.0,4.0],
+                 "segmentation":[[18.0,18.0,22.0,18.0,22.0,22.0,18.0,22.0]]},
+                {"id":3,"image_id":7,"category_id":1,"area":4.0,"bbox":[50.0,50.0,2.0,2.0]}
+            ]
+        }"#;
+
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let mut buf = vec![];
+        crop(
+            &coco_page_mapper,
+            &mut reader,
+            &mut buf,
+            &[(7, [10.0, 10.0, 10.0, 10.0], "big_0.jpg".to_string())],
+        )
+        .unwrap();
+
+        let (mut reader, cropped) = reopen(buf);
+        assert_eq!(cropped.get_img_ids(), &vec![1]);
+        assert_eq!(cropped.image_size(1), Some((10, 10)));
+        assert_eq!(
+            cropped.get_item_dict(1, &mut reader).unwrap()["file_name"],
+            "big_0.jpg"
+        );
+
+        let mut anns = cropped.get_anns_dict(1, &mut reader).unwrap();
+        anns.sort_by(|a, b| {
+            a["bbox"][0]
+                .as_f64()
+                .partial_cmp(&b["bbox"][0].as_f64())
+                .unwrap()
+        });
+        assert_eq!(anns.len(), 2);
+
+        assert_eq!(parse_bbox(&anns[0]), Some([2.0, 2.0, 2.0, 2.0]));
+        assert_eq!(anns[0]["area"].as_f64(), Some(4.0));
+
+        assert_eq!(parse_bbox(&anns[1]), Some([8.0, 8.0, 2.0, 2.0]));
+        assert_eq!(anns[1]["area"].as_f64(), Some(4.0));
+        assert_eq!(
+            anns[1]["segmentation"],
+            json!([[8.0, 10.0, 8.0, 8.0, 10.0, 8.0, 10.0, 10.0]])
+        );
+
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let mut buf = vec![];
+        crop_with_options(
+            &coco_page_mapper,
+            &mut reader,
+            &mut buf,
+            &[(7, [10.0, 10.0, 10.0, 10.0], "big_0.jpg".to_string())],
+            &CropOptions {
+                min_area_fraction: 0.5,
+            },
+        )
+        .unwrap();
+        let (mut reader, cropped) = reopen(buf);
+        assert_eq!(cropped.get_anns_dict(1, &mut reader).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_simplify_polygons() {
+        let circle: Vec<f64> = (0..1000)
+            .flat_map(|i| {
+                let t = i as f64 / 1000.0 * std::f64::consts::TAU;
+                [100.0 + 50.0 * t.cos(), 100.0 + 50.0 * t.sin()]
+            })
+            .collect();
+        let area = polygon_area(&circle);
+        let example = json!({
+            "licenses": [],
+            "info": {},
+            "categories": [{"id": 1, "name": "a", "supercategory": ""}],
+            "images": [{"id": 1, "width": 200, "height": 200, "file_name": "a.jpg"}],
+            "annotations": [
+                {"id": 1, "image_id": 1, "category_id": 1, "area": area, "bbox": [50.0, 50.0, 100.0, 100.0], "segmentation": [circle]},
+                {"id": 2, "image_id": 1, "category_id": 1, "area": 2.0, "bbox": [0.0, 0.0, 1.0, 2.0], "segmentation": {"size": [200, 200], "counts": [0, 2, 39998]}}
+            ]
+        });
+        let (mut reader, coco_page_mapper) = prepare(&example.to_string());
+
+        let mut buf = vec![];
+        let report = simplify_polygons(&coco_page_mapper, &mut reader, &mut buf, 0.25).unwrap();
+        assert_eq!(report.points_before, 1000);
+        assert!(report.points_after < 100);
+
+        let (mut reader, simplified) = reopen(buf);
+        let mut anns = simplified.get_anns_dict(1, &mut reader).unwrap();
+        anns.sort_by_key(|v| v["id"].as_i64());
+        let new_area = anns[0]["area"].as_f64().unwrap();
+        assert!((new_area - area).abs() / area < 0.01);
+        assert_eq!(anns[0]["bbox"], json!([50.0, 50.0, 100.0, 100.0]));
+        assert_eq!(anns[1]["segmentation"]["counts"], json!([0, 2, 39998]));
+        assert_eq!(anns[1]["area"], 2.0);
+    }
+}
@@ -0,0 +1,281 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{fmt, fs::File, io, path::Path};
+
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+
+#[cfg(target_os = "linux")]
+use io_uring::{opcode, types, IoUring};
+
+use crate::offset_index::{OnRun, RecordSource};
+
+/// Reads in flight at once.
+const QUEUE_DEPTH: usize = 64;
+
+/// Reads the records of a file as batches of io_uring requests, without a
+/// seek and a read call per record. Pass it to `get_anns_batch` or return it
+/// from the factory of `fetch_parallel`.
+///
+/// On other platforms, or if the kernel does not support io_uring, records
+/// are read with seeks and reads like any other reader.
+pub struct UringSource {
+    file: File,
+    #[cfg(target_os = "linux")]
+    ring: Option<IoUring>,
+}
+
+impl fmt::Debug for UringSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UringSource")
+            .field("file", &self.file)
+            .field("is_uring", &self.is_uring())
+            .finish()
+    }
+}
+
+impl UringSource {
+    pub fn new(file: File) -> Self {
+        Self {
+            #[cfg(target_os = "linux")]
+            ring: IoUring::new(QUEUE_DEPTH as u32).ok(),
+            file,
+        }
+    }
+
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        Ok(Self::new(File::open(path)?))
+    }
+
+    /// Always reads with seeks and reads.
+    pub fn portable(file: File) -> Self {
+        Self {
+            #[cfg(target_os = "linux")]
+            ring: None,
+            file,
+        }
+    }
+
+    /// Whether the records are read through io_uring.
+    pub fn is_uring(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.ring.is_some();
+        #[cfg(not(target_os = "linux"))]
+        false
+    }
+
+    /// The file, e.g. to read single records with `get_item_dict`.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl RecordSource for UringSource {
+    fn read_runs(
+        &mut self,
+        runs: &[(u64, usize)],
+        buf: &mut Vec<u8>,
+        on_run: OnRun<'_>,
+    ) -> Result<(), io::Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(ring) = &mut self.ring {
+            for (batch, batch_runs) in runs.chunks(QUEUE_DEPTH).enumerate() {
+                let first = batch * QUEUE_DEPTH;
+                read_batch(ring, &self.file, batch_runs, buf, &mut |idx, bytes| {
+                    on_run(first + idx, bytes)
+                })?;
+            }
+            return Ok(());
+        }
+        self.file.read_runs(runs, buf, on_run)
+    }
+}
+
+impl RecordSource for &mut UringSource {
+    fn read_runs(
+        &mut self,
+        runs: &[(u64, usize)],
+        buf: &mut Vec<u8>,
+        on_run: OnRun<'_>,
+    ) -> Result<(), io::Error> {
+        (**self).read_runs(runs, buf, on_run)
+    }
+}
+
+/// Submits a read per run, at most `QUEUE_DEPTH`, and passes the runs to
+/// `on_run` in order as soon as they and the runs before them are complete.
+#[cfg(target_os = "linux")]
+fn read_batch(
+    ring: &mut IoUring,
+    file: &File,
+    runs: &[(u64, usize)],
+    buf: &mut Vec<u8>,
+    on_run: OnRun<'_>,
+) -> Result<(), io::Error> {
+    let mut starts = Vec::with_capacity(runs.len());
+    let mut total = 0;
+    for (_, len) in runs {
+        starts.push(total);
+        total += len;
+    }
+    if buf.len() < total {
+        buf.resize(total, 0);
+    }
+    let base = buf.as_mut_ptr();
+    let fd = types::Fd(file.as_raw_fd());
+
+    // Bytes read so far of every run, a short read is resubmitted for the rest.
+    let mut filled = vec![0usize; runs.len()];
+    let submit = |ring: &mut IoUring, idx: usize, filled: usize| {
+        let (offset, len) = runs[idx];
+        let remaining = (len - filled).min(u32::MAX as usize) as u32;
+        // Safety: the range is within `buf`, which is not touched by anything
+        // else until the read completes, as every read is waited for below.
+        let entry = opcode::Read::new(fd, unsafe { base.add(starts[idx] + filled) }, remaining)
+            .offset(offset + filled as u64)
+            .build()
+            .user_data(idx as u64);
+        unsafe { ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("The io_uring submission queue is full"))
+    };
+
+    let mut result = Ok(());
+    let mut in_flight = 0;
+    for idx in 0..runs.len() {
+        result = submit(ring, idx, 0);
+        if result.is_err() {
+            break;
+        }
+        in_flight += 1;
+    }
+
+    let mut completions = Vec::with_capacity(runs.len());
+    let mut next = 0;
+    while in_flight > 0 {
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // The kernel may still write into the buffer, leak it instead.
+                std::mem::forget(std::mem::take(buf));
+                return Err(e);
+            }
+        }
+        completions.extend(ring.completion().map(|cqe| (cqe.user_data(), cqe.result())));
+        for (idx, res) in completions.drain(..) {
+            let idx = idx as usize;
+            in_flight -= 1;
+            if result.is_err() {
+                continue;
+            }
+            result = match res {
+                ..=-1 => Err(io::Error::from_raw_os_error(-res)),
+                0 => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                )),
+                _ => {
+                    filled[idx] += res as usize;
+                    if filled[idx] < runs[idx].1 {
+                        submit(ring, idx, filled[idx]).map(|_| in_flight += 1)
+                    } else {
+                        Ok(())
+                    }
+                }
+            };
+        }
+
+        while result.is_ok() && next < runs.len() && filled[next] == runs[next].1 {
+            // Safety: the run is complete and no read targets it anymore.
+            let bytes = unsafe { std::slice::from_raw_parts(base.add(starts[next]), runs[next].1) };
+            result = on_run(next, bytes);
+            next += 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+    use crate::test_utils::{prepare, write_example, EXAMPLE};
+
+    #[test]
+    fn test_read_runs() {
+        let data: Vec<u8> = (0..100_000u32).map(|v| (v % 251) as u8).collect();
+        let filepath = write_example("");
+        std::fs::write(&filepath, &data).unwrap();
+
+        let runs: Vec<(u64, usize)> = (0..200).map(|v| (v * 490, 7 + v as usize)).collect();
+        let read = |mut source: UringSource| {
+            let mut read = vec![];
+            let mut buf = vec![];
+            source
+                .read_runs(&runs, &mut buf, &mut |idx, bytes| {
+                    read.push((idx, bytes.to_vec()));
+                    Ok(())
+                })
+                .unwrap();
+            read
+        };
+        let expected: Vec<(usize, Vec<u8>)> = runs
+            .iter()
+            .enumerate()
+            .map(|(idx, (offset, len))| {
+                let offset = *offset as usize;
+                (idx, data[offset..offset + len].to_vec())
+            })
+            .collect();
+        assert_eq!(read(UringSource::open(&filepath).unwrap()), expected);
+        let file = File::open(&filepath).unwrap();
+        assert_eq!(read(UringSource::portable(file)), expected);
+
+        let mut source = UringSource::open(&filepath).unwrap();
+        let err = source
+            .read_runs(&[(99_990, 20)], &mut vec![], &mut |_, _| Ok(()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_uring_matches_portable() {
+        let filepath = write_example(EXAMPLE);
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let img_ids = [6, 7, 5];
+
+        let expected = coco_page_mapper
+            .get_anns_batch(&img_ids, &mut reader)
+            .unwrap();
+        let mut source = UringSource::open(&filepath).unwrap();
+        assert_eq!(
+            coco_page_mapper
+                .get_anns_batch(&img_ids, &mut source)
+                .unwrap(),
+            expected
+        );
+
+        let fetch = |threads| {
+            coco_page_mapper
+                .fetch_parallel(&img_ids, || UringSource::open(&filepath).unwrap(), threads)
+                .into_iter()
+                .map(|(img_id, item)| (img_id, item.map_err(|e| e.to_string())))
+                .collect::<Vec<_>>()
+        };
+        let portable: Vec<_> = coco_page_mapper
+            .fetch_parallel(
+                &img_ids,
+                || BufReader::new(File::open(&filepath).unwrap()),
+                1,
+            )
+            .into_iter()
+            .map(|(img_id, item)| (img_id, item.map_err(|e| e.to_string())))
+            .collect();
+        assert_eq!(fetch(1), portable);
+        assert_eq!(fetch(3), portable);
+    }
+}
@@ -2,17 +2,11 @@
 //
 //  SPDX-License-Identifier: MIT
 
+use serde::de::DeserializeOwned;
 use std::io::{self};
 
-pub fn read_skipping_ws(mut reader: impl io::Read) -> io::Result<u8> {
-    loop {
-        let mut byte = 0u8;
-        reader.read_exact(std::slice::from_mut(&mut byte))?;
-        if !byte.is_ascii_whitespace() {
-            return Ok(byte);
-        }
-    }
-}
+use crate::error::CocoError;
+use crate::offset_index::DEFAULT_SCAN_BUFFER_BYTES;
 
 pub fn invalid_data(msg: &str) -> io::Error {
     io::Error::new(io::ErrorKind::InvalidData, msg)
@@ -23,41 +17,1285 @@ pub fn stream_error(error: &str, offset: u64) -> io::Error {
     invalid_data(msg.as_str())
 }
 
-pub fn parse_serde_json_value_from_page<R>(
-    reader: &mut R,
-    offset: u64,
-    size: u64,
-) -> Result<serde_json::Value, io::Error>
+/// Wraps a `serde_json` error raised while parsing `buf`, which was read from
+/// the absolute offset `start`, with the absolute offset of the failure.
+pub fn json_error(e: serde_json::Error, buf: &[u8], start: u64) -> io::Error {
+    let mut line_start = 0;
+    if e.line() > 1 {
+        line_start = buf
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c == b'\n')
+            .nth(e.line() - 2)
+            .map_or(buf.len(), |(idx, _)| idx + 1);
+    }
+    let pos = (line_start + e.column().saturating_sub(1)).min(buf.len());
+    CocoError::Json {
+        offset: start + pos as u64,
+        source: e,
+    }
+    .into()
+}
+
+/// Parses a whole JSON document, with simd-json under the `simd` feature. The
+/// records which simd-json rejects are parsed again by `serde_json`, so that
+/// errors are reported as without the feature.
+#[cfg(feature = "simd")]
+pub(crate) fn from_json_slice<T: DeserializeOwned>(buf: &[u8]) -> Result<T, serde_json::Error> {
+    let mut owned = buf.to_vec();
+    simd_json::serde::from_slice(&mut owned).or_else(|_| serde_json::from_slice(buf))
+}
+
+#[cfg(not(feature = "simd"))]
+pub(crate) fn from_json_slice<T: DeserializeOwned>(buf: &[u8]) -> Result<T, serde_json::Error> {
+    serde_json::from_slice(buf)
+}
+
+pub fn parse_from_page<T, R>(reader: &mut R, offset: u64, size: u64) -> Result<T, io::Error>
 where
+    T: DeserializeOwned,
     R: io::Read + io::Seek,
 {
     reader.seek(io::SeekFrom::Start(offset))?;
 
     let mut buf = vec![0u8; size as usize];
-    let _ = reader.read(buf.as_mut_slice())?;
-
-    let img_dict_str = String::from_utf8(buf).ok().ok_or(invalid_data(
-        format!("Cannot read offset: {} and size: {}", offset, size).as_str(),
-    ))?;
+    reader.read_exact(buf.as_mut_slice())?;
 
-    serde_json::from_str(img_dict_str.as_str())
-        .ok()
-        .ok_or(invalid_data(
-            format!("Cannot parse to dict offset: {} and size: {}", offset, size).as_str(),
-        ))
+    from_json_slice(&buf).map_err(|e| json_error(e, &buf, offset))
 }
 
+/// Parses the value at the current reader position. Failures carry the
+/// absolute offset at which parsing stopped.
 pub fn parse_serde_json_value(
-    reader: impl io::Read + io::Seek,
+    mut reader: impl io::Read + io::Seek,
 ) -> Result<serde_json::Value, io::Error> {
-    let de = serde_json::Deserializer::from_reader(reader);
+    #[cfg(feature = "simd")]
+    {
+        let start = reader.stream_position()?;
+        if let Some(value) = parse_simd_json_value(&mut reader)? {
+            return Ok(value);
+        }
+        reader.seek(io::SeekFrom::Start(start))?;
+    }
+
+    let de = serde_json::Deserializer::from_reader(&mut reader);
     let mut stream = de.into_iter::<serde_json::Value>();
-    match stream.next().unwrap() {
-        Ok(x) => Ok(x),
-        Err(e) => {
-            let cur_pos = stream.byte_offset();
-            let msg = format!("Parse error: {} at pos: {}", e, cur_pos);
-            Err(invalid_data(msg.as_str()))
+    match stream.next() {
+        Some(Ok(x)) => Ok(x),
+        Some(Err(e)) => {
+            drop(stream);
+            // The failing byte has been consumed already.
+            let offset = reader.stream_position()?.saturating_sub(1);
+            Err(CocoError::Json { offset, source: e }.into())
+        }
+        None => Err(stream_error(
+            "Unexpected end of input",
+            reader.stream_position()?,
+        )),
+    }
+}
+
+/// The value at the reader position, or `None` if it cannot be read by
+/// simd-json, which leaves the reader anywhere.
+#[cfg(feature = "simd")]
+fn parse_simd_json_value(
+    reader: impl io::Read + io::Seek,
+) -> Result<Option<serde_json::Value>, io::Error> {
+    let mut tokens = JsonTokenizer::new(reader)?;
+    let Ok((mut buf, _)) = tokens.read_value_bytes() else {
+        return Ok(None);
+    };
+    tokens.into_inner()?;
+    Ok(simd_json::serde::from_slice(&mut buf).ok())
+}
+
+pub fn parse_lenient_from_page<T, R>(reader: &mut R, offset: u64, size: u64) -> Result<T, io::Error>
+where
+    T: DeserializeOwned,
+    R: io::Read + io::Seek,
+{
+    reader.seek(io::SeekFrom::Start(offset))?;
+
+    let mut buf = vec![0u8; size as usize];
+    reader.read_exact(buf.as_mut_slice())?;
+
+    // Offsets within the stripped copy may be off by the commas removed.
+    let stripped = strip_trailing_commas(&buf);
+    from_json_slice(&stripped).map_err(|e| json_error(e, &stripped, offset))
+}
+
+/// Deepest nesting of arrays and objects accepted by `JsonTokenizer`, the
+/// same as the recursion limit of `serde_json`.
+pub const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    BeginObject,
+    EndObject,
+    BeginArray,
+    EndArray,
+    Key(String),
+    String(String),
+    /// The number as written.
+    Number(String),
+    Bool(bool),
+    Null,
+    Comma,
+    Colon,
+}
+
+/// Byte range `[start, end)` of a token in the underlying reader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: u64,
+    pub end: u64,
+}
+
+const MIN_CHUNK_SIZE: usize = 512;
+
+pub(crate) fn is_json_ws(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+/// Reads an `iscrowd` value, which is `0`/`1`, also written as `0.0`/`1.0`,
+/// or a boolean. `null` is `false`, other values are `None`.
+pub fn is_crowd(iscrowd: &serde_json::Value) -> Option<bool> {
+    match iscrowd {
+        serde_json::Value::Null => Some(false),
+        serde_json::Value::Bool(value) => Some(*value),
+        serde_json::Value::Number(value) => match value.as_f64() {
+            Some(0.0) => Some(false),
+            Some(1.0) => Some(true),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A read-ahead window over a reader, which knows the absolute offset of every
+/// buffered byte. The window grows from `MIN_CHUNK_SIZE` up to `max_chunk`,
+/// 64 KiB by default, so that short scans do not read much more than they need.
+///
+/// The reader is ahead of `position()`. As a `Read + Seek` itself, the buffer
+/// reports the logical position and seeks within the window without touching
+/// the reader, so nested scanners and `serde_json` can read through it.
+pub(crate) struct ScanBuffer<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// Absolute offset of `buf[0]`, the reader is at `start + len`.
+    start: u64,
+    pos: usize,
+    len: usize,
+    capture: Option<Vec<u8>>,
+    /// Offset of the first captured byte.
+    capture_start: u64,
+    capture_limit: u64,
+    max_chunk: usize,
+}
+
+impl<R: io::Read + io::Seek> ScanBuffer<R> {
+    /// Reads at most `max_chunk` bytes at once, see `IoTuning::scan_buffer_bytes`.
+    pub fn with_max_chunk(mut reader: R, max_chunk: usize) -> Result<Self, io::Error> {
+        let start = reader.stream_position()?;
+        Ok(Self::at(reader, start, max_chunk))
+    }
+
+    /// Like `with_max_chunk`, for a reader known to be at `start`, e.g. right
+    /// after a seek. Positions are counted from there without asking the reader.
+    pub fn at(reader: R, start: u64, max_chunk: usize) -> Self {
+        Self {
+            reader,
+            buf: vec![],
+            start,
+            pos: 0,
+            len: 0,
+            capture: None,
+            capture_start: 0,
+            capture_limit: u64::MAX,
+            max_chunk: max_chunk.max(1),
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.start + self.pos as u64
+    }
+
+    /// Refills the window, returns `false` at the end of input.
+    fn fill(&mut self) -> Result<bool, io::Error> {
+        self.check_capture()?;
+        let chunk_size =
+            (2 * self.buf.len()).clamp(MIN_CHUNK_SIZE.min(self.max_chunk), self.max_chunk);
+        self.buf.resize(chunk_size, 0);
+        self.start += self.len as u64;
+        (self.pos, self.len) = (0, 0);
+        loop {
+            match self.reader.read(&mut self.buf) {
+                Ok(n) => {
+                    self.len = n;
+                    return Ok(n > 0);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The next byte, without consuming it.
+    pub fn peek(&mut self) -> Result<Option<u8>, io::Error> {
+        if self.pos == self.len && !self.fill()? {
+            return Ok(None);
+        }
+        Ok(Some(self.buf[self.pos]))
+    }
+
+    /// Consumes `n` bytes, which must have been buffered.
+    pub fn advance(&mut self, n: usize) {
+        if let Some(capture) = self.capture.as_mut() {
+            capture.extend_from_slice(&self.buf[self.pos..self.pos + n]);
+        }
+        self.pos += n;
+    }
+
+    /// Consumes the bytes up to the first one matching `pred`, which is
+    /// returned without being consumed. `None` at the end of input.
+    pub fn find_byte(&mut self, pred: impl Fn(u8) -> bool) -> Result<Option<u8>, io::Error> {
+        loop {
+            let rest = &self.buf[self.pos..self.len];
+            match rest.iter().position(|c| pred(*c)) {
+                Some(n) => {
+                    self.advance(n);
+                    return Ok(Some(self.buf[self.pos]));
+                }
+                None => {
+                    self.advance(rest.len());
+                    if !self.fill()? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Starts collecting the consumed bytes into the cleared `buf`, replacing
+    /// the previous capture. Once more than `limit` bytes are captured, the
+    /// next refill of the window fails, so the capture stays bounded.
+    pub fn start_capture_in(&mut self, mut buf: Vec<u8>, limit: u64) {
+        buf.clear();
+        self.capture = Some(buf);
+        self.capture_start = self.position();
+        self.capture_limit = limit;
+    }
+
+    /// Fails with `CocoError::RecordTooLarge` if the capture is over its limit.
+    pub fn check_capture(&self) -> Result<(), io::Error> {
+        match &self.capture {
+            Some(capture) if capture.len() as u64 > self.capture_limit => {
+                Err(CocoError::RecordTooLarge {
+                    offset: self.capture_start,
+                    limit: self.capture_limit,
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn take_capture(&mut self) -> Option<Vec<u8>> {
+        self.capture.take()
+    }
+
+    /// The reader at `position()`, the window is dropped.
+    pub fn get_mut(&mut self) -> Result<&mut R, io::Error> {
+        self.start = self.reader.seek(io::SeekFrom::Start(self.position()))?;
+        (self.pos, self.len) = (0, 0);
+        Ok(&mut self.reader)
+    }
+
+    pub fn into_inner(mut self) -> Result<R, io::Error> {
+        self.get_mut()?;
+        Ok(self.reader)
+    }
+
+    /// Continues from the reader position after it was used through `get_mut`.
+    pub fn resync(&mut self) -> Result<(), io::Error> {
+        self.start = self.reader.stream_position()?;
+        (self.pos, self.len) = (0, 0);
+        Ok(())
+    }
+}
+
+impl<R: io::Read + io::Seek> io::Read for ScanBuffer<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let rest = io::BufRead::fill_buf(self)?;
+        let n = rest.len().min(out.len());
+        out[..n].copy_from_slice(&rest[..n]);
+        self.advance(n);
+        Ok(n)
+    }
+}
+
+impl<R: io::Read + io::Seek> io::BufRead for ScanBuffer<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.len {
+            self.fill()?;
+        }
+        Ok(&self.buf[self.pos..self.len])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.advance(n);
+    }
+}
+
+impl<R: io::Read + io::Seek> io::Seek for ScanBuffer<R> {
+    fn seek(&mut self, target: io::SeekFrom) -> io::Result<u64> {
+        let target = match target {
+            io::SeekFrom::Start(offset) => offset,
+            io::SeekFrom::Current(delta) => self
+                .position()
+                .checked_add_signed(delta)
+                .ok_or_else(|| invalid_data("Seek before the start of the input"))?,
+            io::SeekFrom::End(_) => {
+                self.start = self.reader.seek(target)?;
+                (self.pos, self.len) = (0, 0);
+                return Ok(self.start);
+            }
+        };
+        if (self.start..=self.start + self.len as u64).contains(&target) {
+            self.pos = (target - self.start) as usize;
+        } else {
+            self.start = self.reader.seek(io::SeekFrom::Start(target))?;
+            (self.pos, self.len) = (0, 0);
+        }
+        Ok(target)
+    }
+}
+
+/// Splits JSON into tokens, tracking their absolute position in the reader.
+/// Grammar is not checked beyond matching brackets, so it also serves the
+/// lenient parsing of files with trailing commas. Strings in key position,
+/// right after `{` or after `,` in an object, are returned as `Token::Key`.
+///
+/// The reader is read ahead through a `ScanBuffer`, so its position is past
+/// `position()`. `get_mut` and `into_inner` seek it back.
+pub struct JsonTokenizer<R> {
+    buffer: ScanBuffer<R>,
+    /// Whether each open container is an object.
+    stack: Vec<bool>,
+    expect_key: bool,
+    /// Raw text of the last string or number, reused between tokens.
+    text_buf: Vec<u8>,
+    /// Check strings and numbers without decoding them, as their tokens are
+    /// dropped by `read_value_bytes`.
+    skip_text: bool,
+}
+
+impl<R: io::Read + io::Seek> JsonTokenizer<R> {
+    pub fn new(reader: R) -> Result<Self, io::Error> {
+        Self::with_buffer_size(reader, DEFAULT_SCAN_BUFFER_BYTES)
+    }
+
+    /// Reads at most `size` bytes at once, see `IoTuning::scan_buffer_bytes`.
+    pub fn with_buffer_size(reader: R, size: usize) -> Result<Self, io::Error> {
+        Ok(Self {
+            buffer: ScanBuffer::with_max_chunk(reader, size)?,
+            stack: vec![],
+            expect_key: false,
+            text_buf: vec![],
+            skip_text: false,
+        })
+    }
+
+    pub fn position(&self) -> u64 {
+        self.buffer.position()
+    }
+
+    /// Number of open arrays and objects.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// The underlying reader at `position()`, e.g. to hand a value over to
+    /// `serde_json`. Call `resync` once the value has been read.
+    pub fn get_mut(&mut self) -> Result<&mut R, io::Error> {
+        self.buffer.get_mut()
+    }
+
+    /// The underlying reader at `position()`.
+    pub fn into_inner(self) -> Result<R, io::Error> {
+        self.buffer.into_inner()
+    }
+
+    /// Picks up the reader position after a complete value was read through `get_mut`.
+    pub fn resync(&mut self) -> Result<(), io::Error> {
+        self.buffer.resync()?;
+        self.expect_key = false;
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, io::Error> {
+        let c = self.buffer.peek()?;
+        if c.is_some() {
+            self.buffer.advance(1);
+        }
+        Ok(c)
+    }
+
+    fn expect_byte(&mut self) -> Result<u8, io::Error> {
+        self.read_byte()?
+            .ok_or_else(|| stream_error("Unexpected end of input", self.position()))
+    }
+
+    fn skip_ws(&mut self) -> Result<Option<u8>, io::Error> {
+        self.buffer.find_byte(|c| !is_json_ws(c))?;
+        self.read_byte()
+    }
+
+    /// The next non-whitespace byte and its offset, without consuming it.
+    pub fn peek_byte(&mut self) -> Result<Option<(u8, u64)>, io::Error> {
+        let c = self.buffer.find_byte(|c| !is_json_ws(c))?;
+        Ok(c.map(|c| (c, self.position())))
+    }
+
+    pub fn next_token(&mut self) -> Result<Option<(Token, Span)>, io::Error> {
+        let Some(c) = self.skip_ws()? else {
+            return Ok(None);
+        };
+        let start = self.position() - 1;
+        let expect_key = std::mem::take(&mut self.expect_key);
+
+        let token = match c {
+            b'{' | b'[' => {
+                if self.stack.len() == MAX_DEPTH {
+                    return Err(stream_error("Maximum nesting depth exceeded", start));
+                }
+                self.stack.push(c == b'{');
+                self.expect_key = c == b'{';
+                match c {
+                    b'{' => Token::BeginObject,
+                    _ => Token::BeginArray,
+                }
+            }
+            b'}' | b']' => {
+                if self.stack.pop() != Some(c == b'}') {
+                    return Err(stream_error("Mismatched closing bracket", start));
+                }
+                match c {
+                    b'}' => Token::EndObject,
+                    _ => Token::EndArray,
+                }
+            }
+            b',' => {
+                self.expect_key = self.stack.last() == Some(&true);
+                Token::Comma
+            }
+            b':' => Token::Colon,
+            b'"' => {
+                let value = self.read_string(start)?;
+                match expect_key {
+                    true => Token::Key(value),
+                    false => Token::String(value),
+                }
+            }
+            b't' => self.read_literal(b"rue", Token::Bool(true))?,
+            b'f' => self.read_literal(b"alse", Token::Bool(false))?,
+            b'n' => self.read_literal(b"ull", Token::Null)?,
+            b'-' | b'0'..=b'9' => self.read_number(c, start)?,
+            _ => return Err(stream_error("Unexpected character", start)),
+        };
+
+        Ok(Some((
+            token,
+            Span {
+                start,
+                end: self.position(),
+            },
+        )))
+    }
+
+    /// Reads the next token and fails unless it equals `expected`.
+    pub fn expect_token(&mut self, expected: Token) -> Result<Span, io::Error> {
+        match self.next_token()? {
+            Some((token, span)) if token == expected => Ok(span),
+            Some((token, span)) => {
+                let msg = format!("Expected {:?}, found {:?}", expected, token);
+                Err(stream_error(msg.as_str(), span.start))
+            }
+            None => Err(stream_error("Unexpected end of input", self.position())),
+        }
+    }
+
+    /// Reads the next token, which has to be a key, and passes its text to
+    /// `f`. Keys without escapes are passed from a reused buffer, so matching
+    /// them against known names does not allocate.
+    pub fn next_key_with<T>(&mut self, f: impl FnOnce(&[u8]) -> T) -> Result<(T, Span), io::Error> {
+        let start = match self.skip_ws()? {
+            Some(b'"') if std::mem::take(&mut self.expect_key) => self.position() - 1,
+            Some(_) => return Err(stream_error("Expected a key", self.position() - 1)),
+            None => return Err(stream_error("Unexpected end of input", self.position())),
+        };
+
+        let mut raw = std::mem::take(&mut self.text_buf);
+        raw.clear();
+        let value = match self.read_raw_string(&mut raw)? {
+            true => {
+                let key: String = serde_json::from_slice(&raw)
+                    .map_err(|e| stream_error(e.to_string().as_str(), start))?;
+                f(key.as_bytes())
+            }
+            false => f(&raw[1..raw.len() - 1]),
+        };
+        self.text_buf = raw;
+        let span = Span {
+            start,
+            end: self.position(),
+        };
+        Ok((value, span))
+    }
+
+    /// Reads the rest of a string whose opening quote at `start` was consumed.
+    fn read_string(&mut self, start: u64) -> Result<String, io::Error> {
+        let mut raw = std::mem::take(&mut self.text_buf);
+        raw.clear();
+        let result = self
+            .read_raw_string(&mut raw)
+            .and_then(|_| match self.skip_text {
+                true => Ok(String::new()),
+                false => serde_json::from_slice(&raw)
+                    .map_err(|e| stream_error(e.to_string().as_str(), start)),
+            });
+        self.text_buf = raw;
+        result
+    }
+
+    /// Appends the string whose opening quote was consumed to `raw`, quotes
+    /// included. Returns whether it has escapes.
+    fn read_raw_string(&mut self, raw: &mut Vec<u8>) -> Result<bool, io::Error> {
+        raw.push(b'"');
+        let mut escaped = false;
+        loop {
+            let c = self.expect_byte()?;
+            raw.push(c);
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    escaped = true;
+                    let escape = self.expect_byte()?;
+                    raw.push(escape);
+                    match escape {
+                        b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => {}
+                        b'u' => {
+                            for _ in 0..4 {
+                                let c = self.expect_byte()?;
+                                if !c.is_ascii_hexdigit() {
+                                    return Err(stream_error(
+                                        "Invalid unicode escape",
+                                        self.position() - 1,
+                                    ));
+                                }
+                                raw.push(c);
+                            }
+                        }
+                        _ => return Err(stream_error("Invalid escape", self.position() - 1)),
+                    }
+                }
+                c if c < 0x20 => {
+                    return Err(stream_error(
+                        "Control character in string",
+                        self.position() - 1,
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(escaped)
+    }
+
+    fn read_literal(&mut self, rest: &[u8], token: Token) -> Result<Token, io::Error> {
+        for expected in rest {
+            if self.expect_byte()? != *expected {
+                return Err(stream_error("Invalid literal", self.position() - 1));
+            }
+        }
+        Ok(token)
+    }
+
+    /// Reads `-?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)?` whose first byte was consumed.
+    fn read_number(&mut self, first: u8, start: u64) -> Result<Token, io::Error> {
+        let mut number = std::mem::take(&mut self.text_buf);
+        number.clear();
+        number.push(first);
+        let result = self.read_digits(&mut number, start);
+        self.text_buf = number;
+        result
+    }
+
+    fn read_digits(&mut self, number: &mut Vec<u8>, start: u64) -> Result<Token, io::Error> {
+        while let Some(c) = self.buffer.peek()? {
+            if !matches!(c, b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E') {
+                break;
+            }
+            self.buffer.advance(1);
+            number.push(c);
+        }
+
+        let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+        let mut rest = number.strip_prefix(b"-").unwrap_or(number);
+        let n = digits(rest);
+        let mut valid = n == 1 || (n > 1 && rest[0] != b'0');
+        rest = &rest[n..];
+        if let Some(frac) = rest.strip_prefix(b".") {
+            let n = digits(frac);
+            valid &= n > 0;
+            rest = &frac[n..];
+        }
+        if let Some(exp) = rest.strip_prefix(b"e").or(rest.strip_prefix(b"E")) {
+            let exp = exp
+                .strip_prefix(b"+")
+                .or(exp.strip_prefix(b"-"))
+                .unwrap_or(exp);
+            let n = digits(exp);
+            valid &= n > 0;
+            rest = &exp[n..];
+        }
+        if !valid || !rest.is_empty() {
+            return Err(stream_error("Invalid number", start));
+        }
+        if self.skip_text {
+            return Ok(Token::Number(String::new()));
+        }
+        // Only ASCII bytes were accepted above.
+        Ok(Token::Number(String::from_utf8_lossy(number).into_owned()))
+    }
+
+    /// Reads the raw bytes of the next value without checking its grammar, so
+    /// that trailing commas inside of it are kept. The reader is left right
+    /// after the value.
+    pub fn read_value_bytes(&mut self) -> Result<(Vec<u8>, Span), io::Error> {
+        let mut buf = vec![];
+        let span = self.read_value_into(&mut buf, u64::MAX)?;
+        Ok((buf, span))
+    }
+
+    /// Like `read_value_bytes`, reading into the cleared `buf`, so that its
+    /// allocation is reused for every value of an array. Values longer than
+    /// `limit` bytes fail with `CocoError::RecordTooLarge`, which is raised
+    /// before much more than `limit` bytes are buffered.
+    pub fn read_value_into(&mut self, buf: &mut Vec<u8>, limit: u64) -> Result<Span, io::Error> {
+        let start = match self.peek_byte()? {
+            Some((_, start)) => start,
+            None => return Err(stream_error("Unexpected end of input", self.position())),
+        };
+        let depth = self.depth();
+        self.buffer.start_capture_in(std::mem::take(buf), limit);
+
+        self.skip_text = true;
+        let result = loop {
+            match self.next_token() {
+                Ok(Some((Token::Comma | Token::Colon | Token::Key(_), _))) => {}
+                Ok(Some(_)) if self.depth() == depth => break Ok(()),
+                Ok(Some(_)) => {}
+                Ok(None) => break Err(stream_error("Unexpected end of input", self.position())),
+                Err(e) => break Err(e),
+            }
+        };
+        self.skip_text = false;
+
+        let result = result.and_then(|_| self.buffer.check_capture());
+        *buf = self.buffer.take_capture().unwrap_or_default();
+        result?;
+        Ok(Span {
+            start,
+            end: self.position(),
+        })
+    }
+
+    /// Recovers from a malformed value starting at `start` in a container of
+    /// `depth`, by skipping it up to its matching bracket, or up to the next
+    /// `,` or closing bracket if it is not a container. Only strings and
+    /// brackets are followed, so the value may hold any other garbage.
+    pub fn skip_malformed_value(&mut self, start: u64, depth: usize) -> Result<Span, io::Error> {
+        io::Seek::seek(&mut self.buffer, io::SeekFrom::Start(start))?;
+        self.buffer.take_capture();
+        self.stack.truncate(depth);
+        self.expect_key = false;
+
+        let (mut level, mut in_string) = (0usize, false);
+        loop {
+            if !in_string && level == 0 && matches!(self.buffer.peek()?, Some(b'}' | b']' | b',')) {
+                break;
+            }
+            let c = self.expect_byte()?;
+            if in_string {
+                match c {
+                    b'\\' => {
+                        self.expect_byte()?;
+                    }
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                b'"' => in_string = true,
+                b'{' | b'[' => level += 1,
+                b'}' | b']' => {
+                    level -= 1;
+                    if level == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Span {
+            start,
+            end: self.position(),
+        })
+    }
+}
+
+/// A reader which ends `limit` bytes past the position it was created at.
+pub(crate) struct LimitedReader<R> {
+    inner: R,
+    pos: u64,
+    end: u64,
+    /// Whether a read was made at the limit.
+    reached: bool,
+}
+
+impl<R: io::Read + io::Seek> io::Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.end.saturating_sub(self.pos);
+        if left == 0 && !buf.is_empty() {
+            self.reached = true;
+        }
+        let len = left.min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: io::Seek> io::Seek for LimitedReader<R> {
+    fn seek(&mut self, target: io::SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(target)?;
+        Ok(self.pos)
+    }
+}
+
+/// Runs `f` on `reader` cut off `limit` bytes past its position. If `f`
+/// fails after a read was cut, it fails with `CocoError::RecordTooLarge`.
+pub(crate) fn read_limited<R, T>(
+    mut reader: R,
+    limit: u64,
+    f: impl FnOnce(&mut LimitedReader<R>) -> Result<T, io::Error>,
+) -> Result<T, io::Error>
+where
+    R: io::Read + io::Seek,
+{
+    let start = reader.stream_position()?;
+    let mut reader = LimitedReader {
+        inner: reader,
+        pos: start,
+        end: start.saturating_add(limit),
+        reached: false,
+    };
+    f(&mut reader).map_err(|e| match reader.reached {
+        true => CocoError::RecordTooLarge {
+            offset: start,
+            limit,
         }
+        .into(),
+        false => e,
+    })
+}
+
+/// Removes commas directly followed (up to whitespace) by `]` or `}` outside of strings.
+pub fn strip_trailing_commas(buf: &[u8]) -> Vec<u8> {
+    let mut tokens = match JsonTokenizer::new(io::Cursor::new(buf)) {
+        Ok(tokens) => tokens,
+        Err(_) => return buf.to_vec(),
+    };
+
+    let mut trailing = vec![];
+    let mut last_comma = None;
+    // Malformed input is passed on as is for the parser to report.
+    while let Ok(Some((token, span))) = tokens.next_token() {
+        match token {
+            Token::Comma => last_comma = Some(span.start as usize),
+            Token::EndArray | Token::EndObject => trailing.extend(last_comma.take()),
+            _ => last_comma = None,
+        }
+    }
+
+    buf.iter()
+        .enumerate()
+        .filter(|(idx, _)| !trailing.contains(idx))
+        .map(|(_, c)| *c)
+        .collect()
+}
+
+pub fn parse_lenient_json_value(
+    reader: impl io::Read + io::Seek,
+) -> Result<serde_json::Value, io::Error> {
+    let mut tokens = JsonTokenizer::new(reader)?;
+    let (buf, span) = tokens.read_value_bytes()?;
+    tokens.into_inner()?;
+
+    let stripped = strip_trailing_commas(&buf);
+    from_json_slice(&stripped).map_err(|e| json_error(e, &stripped, span.start))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    Value,
+    ValueOrEnd,
+    Key,
+    KeyOrEnd,
+    Colon,
+    CommaOrEnd,
+}
+
+/// Advances `reader` past one complete JSON value, including the whitespace
+/// in front of it, without building it. Returns the number of bytes skipped
+/// and leaves the reader right after the value.
+pub fn skip_json_value<R>(reader: R) -> Result<u64, io::Error>
+where
+    R: io::Read + io::Seek,
+{
+    let mut tokens = JsonTokenizer::new(reader)?;
+    let start = tokens.position();
+    skip_value(&mut tokens)?;
+    let size = tokens.position() - start;
+    tokens.into_inner()?;
+    Ok(size)
+}
+
+/// Like `skip_json_value`, but returns the span of the value, without the
+/// whitespace in front of it. Trailing commas are accepted if
+/// `allow_trailing_commas` is set.
+pub fn check_json_value<R>(reader: R, allow_trailing_commas: bool) -> Result<Span, io::Error>
+where
+    R: io::Read + io::Seek,
+{
+    let mut tokens = JsonTokenizer::new(reader)?;
+    let start = match tokens.peek_byte()? {
+        Some((_, start)) => start,
+        None => return Err(stream_error("Unexpected end of input", tokens.position())),
+    };
+    tokens.skip_text = true;
+    let result = skip_value_with(&mut tokens, allow_trailing_commas);
+    tokens.skip_text = false;
+    result?;
+
+    let end = tokens.position();
+    tokens.into_inner()?;
+    Ok(Span { start, end })
+}
+
+/// Spans of the elements of the array under `key` in the JSON object `record`,
+/// relative to its start. Empty if there is no such key.
+pub(crate) fn array_element_spans(record: &[u8], key: &[u8]) -> Result<Vec<Span>, io::Error> {
+    let mut tokens = JsonTokenizer::new(io::Cursor::new(record))?;
+    let mut buf = vec![];
+    let mut spans = vec![];
+    let skip_comma = |tokens: &mut JsonTokenizer<io::Cursor<&[u8]>>| match tokens.peek_byte()? {
+        Some((b',', _)) => tokens.next_token().map(|_| ()),
+        _ => Ok(()),
+    };
+
+    tokens.expect_token(Token::BeginObject)?;
+    while !matches!(tokens.peek_byte()?, Some((b'}', _)) | None) {
+        let (found, _) = tokens.next_key_with(|k| k == key)?;
+        tokens.expect_token(Token::Colon)?;
+        if found {
+            tokens.expect_token(Token::BeginArray)?;
+            while !matches!(tokens.peek_byte()?, Some((b']', _)) | None) {
+                spans.push(tokens.read_value_into(&mut buf, u64::MAX)?);
+                skip_comma(&mut tokens)?;
+            }
+            break;
+        }
+        tokens.read_value_into(&mut buf, u64::MAX)?;
+        skip_comma(&mut tokens)?;
+    }
+    Ok(spans)
+}
+
+/// Reads the tokens of one complete value, checking its grammar.
+fn skip_value<R>(tokens: &mut JsonTokenizer<R>) -> Result<(), io::Error>
+where
+    R: io::Read + io::Seek,
+{
+    skip_value_with(tokens, false)
+}
+
+fn skip_value_with<R>(
+    tokens: &mut JsonTokenizer<R>,
+    allow_trailing_commas: bool,
+) -> Result<(), io::Error>
+where
+    R: io::Read + io::Seek,
+{
+    let depth = tokens.depth();
+    let mut expect = Expect::Value;
+
+    loop {
+        let Some((token, span)) = tokens.next_token()? else {
+            return Err(stream_error("Unexpected end of input", tokens.position()));
+        };
+
+        let value_done = match (expect, token) {
+            (Expect::ValueOrEnd, Token::EndArray) | (Expect::KeyOrEnd, Token::EndObject) => true,
+            (Expect::Value | Expect::ValueOrEnd, Token::BeginObject) => {
+                expect = Expect::KeyOrEnd;
+                false
+            }
+            (Expect::Value | Expect::ValueOrEnd, Token::BeginArray) => {
+                expect = Expect::ValueOrEnd;
+                false
+            }
+            (
+                Expect::Value | Expect::ValueOrEnd,
+                Token::String(_) | Token::Number(_) | Token::Bool(_) | Token::Null,
+            ) => true,
+            (Expect::Key | Expect::KeyOrEnd, Token::Key(_)) => {
+                expect = Expect::Colon;
+                false
+            }
+            (Expect::Colon, Token::Colon) => {
+                expect = Expect::Value;
+                false
+            }
+            (Expect::CommaOrEnd, Token::Comma) => {
+                expect = match (tokens.expect_key, allow_trailing_commas) {
+                    (true, false) => Expect::Key,
+                    (true, true) => Expect::KeyOrEnd,
+                    (false, false) => Expect::Value,
+                    (false, true) => Expect::ValueOrEnd,
+                };
+                false
+            }
+            (Expect::CommaOrEnd, Token::EndArray | Token::EndObject) => true,
+            _ => return Err(stream_error("Unexpected token", span.start)),
+        };
+
+        if value_done {
+            if tokens.depth() == depth {
+                return Ok(());
+            }
+            expect = Expect::CommaOrEnd;
+        }
+    }
+}
+
+/// Counts the elements of the JSON array starting at the current reader
+/// position without building them. Returns the count and the byte length of
+/// the array, including the whitespace in front of it. The reader position is
+/// restored afterwards.
+pub fn count_array_elements<R>(mut reader: R) -> Result<(usize, u64), io::Error>
+where
+    R: io::Read + io::Seek,
+{
+    let start = reader.stream_position()?;
+    let mut tokens = JsonTokenizer::new(&mut reader)?;
+    tokens.expect_token(Token::BeginArray)?;
+
+    let mut count = 0;
+    if let Some((b']', _)) = tokens.peek_byte()? {
+        tokens.expect_token(Token::EndArray)?;
+    } else {
+        loop {
+            skip_value(&mut tokens)?;
+            count += 1;
+
+            match tokens.next_token()? {
+                Some((Token::Comma, _)) => continue,
+                Some((Token::EndArray, _)) => break,
+                Some((_, span)) => return Err(stream_error("Expected ',' or ']'", span.start)),
+                None => return Err(stream_error("Unexpected end of input", tokens.position())),
+            }
+        }
+    }
+
+    let size = tokens.position() - start;
+    reader.seek(io::SeekFrom::Start(start))?;
+    Ok((count, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_trailing_commas() {
+        assert_eq!(
+            strip_trailing_commas(br#"[{"a": [1, 2, ], "b": "x,]",} , ]"#),
+            br#"[{"a": [1, 2 ], "b": "x,]"}  ]"#.to_vec()
+        );
+    }
+
+    fn tokenize(input: &str) -> Vec<(Token, Span)> {
+        let mut tokens = JsonTokenizer::new(io::Cursor::new(input.as_bytes())).unwrap();
+        std::iter::from_fn(|| tokens.next_token().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_tokenizer_nesting() {
+        let span = |start, end| Span { start, end };
+        assert_eq!(
+            tokenize(r#" {"a": [1, {"b": null}], "c": true}"#),
+            vec![
+                (Token::BeginObject, span(1, 2)),
+                (Token::Key("a".to_owned()), span(2, 5)),
+                (Token::Colon, span(5, 6)),
+                (Token::BeginArray, span(7, 8)),
+                (Token::Number("1".to_owned()), span(8, 9)),
+                (Token::Comma, span(9, 10)),
+                (Token::BeginObject, span(11, 12)),
+                (Token::Key("b".to_owned()), span(12, 15)),
+                (Token::Colon, span(15, 16)),
+                (Token::Null, span(17, 21)),
+                (Token::EndObject, span(21, 22)),
+                (Token::EndArray, span(22, 23)),
+                (Token::Comma, span(23, 24)),
+                (Token::Key("c".to_owned()), span(25, 28)),
+                (Token::Colon, span(28, 29)),
+                (Token::Bool(true), span(30, 34)),
+                (Token::EndObject, span(34, 35)),
+            ]
+        );
+        assert_eq!(
+            tokenize(r#"["a", "b"]"#)
+                .into_iter()
+                .map(|(token, _)| token)
+                .collect::<Vec<_>>(),
+            vec![
+                Token::BeginArray,
+                Token::String("a".to_owned()),
+                Token::Comma,
+                Token::String("b".to_owned()),
+                Token::EndArray,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_escapes() {
+        assert_eq!(
+            tokenize(r#""a\"b\\c\/\n\u00e9😀""#),
+            vec![(
+                Token::String("a\"b\\c/\n\u{e9}\u{1f600}".to_owned()),
+                Span { start: 0, end: 23 }
+            )]
+        );
+
+        for input in [r#""\x""#, r#""\u12g4""#, "\"a\nb\""] {
+            let mut tokens = JsonTokenizer::new(io::Cursor::new(input.as_bytes())).unwrap();
+            assert!(tokens.next_token().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_tokenizer_read_value_bytes() {
+        let mut tokens = JsonTokenizer::new(io::Cursor::new(br#"[ {"a": [1,],}, 2]"#)).unwrap();
+        tokens.expect_token(Token::BeginArray).unwrap();
+
+        let (buf, span) = tokens.read_value_bytes().unwrap();
+        assert_eq!(buf, br#"{"a": [1,],}"#.to_vec());
+        assert_eq!(span, Span { start: 2, end: 14 });
+        assert_eq!(tokens.depth(), 1);
+        assert_eq!(tokens.next_token().unwrap().unwrap().0, Token::Comma);
+    }
+
+    fn skip(input: &str) -> Result<(u64, u64), String> {
+        let mut reader = io::Cursor::new(input.as_bytes());
+        match skip_json_value(&mut reader) {
+            Ok(n) => Ok((n, reader.position())),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_skip_json_value() {
+        assert_eq!(skip(r#""a\"b\u00e9" ,"#), Ok((12, 12)));
+        assert_eq!(skip("  true]"), Ok((6, 6)));
+        assert_eq!(skip("false"), Ok((5, 5)));
+        assert_eq!(skip("null,"), Ok((4, 4)));
+        assert_eq!(skip("-12.5e+3,"), Ok((8, 8)));
+        assert_eq!(skip("0"), Ok((1, 1)));
+        assert_eq!(skip("[]"), Ok((2, 2)));
+        assert_eq!(skip(r#"{"a": [1, {"b": "}]"}], "c": {}} 1"#), Ok((32, 32)));
+
+        let nested = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(skip(&nested).is_ok());
+    }
+
+    #[test]
+    fn test_skip_json_value_errors() {
+        let offset = |input: &str| {
+            let err = skip(input).unwrap_err();
+            let start = err.find("offset=").unwrap() + 7;
+            let end = err[start..].find(']').unwrap() + start;
+            err[start..end].parse::<u64>().unwrap()
+        };
+        assert_eq!(offset("[1,]"), 3);
+        assert_eq!(offset(r#"{"a" 1}"#), 5);
+        assert_eq!(offset(r#"{"a": 1,}"#), 8);
+        assert_eq!(offset("[1}"), 2);
+        assert_eq!(offset("tru"), 3);
+        assert_eq!(offset("trux"), 3);
+        assert_eq!(offset(r#""ab"#), 3);
+        assert_eq!(offset(r#""a\x""#), 3);
+        assert_eq!(offset("[01]"), 1);
+        assert_eq!(offset("[1.]"), 1);
+        assert_eq!(offset("x"), 0);
+
+        let nested = "[".repeat(MAX_DEPTH + 1);
+        assert_eq!(offset(&nested), MAX_DEPTH as u64);
+    }
+
+    #[test]
+    fn test_count_array_elements() {
+        let count = |input: &str| {
+            let mut reader = io::Cursor::new(input.as_bytes());
+            reader.set_position(1);
+            let result = count_array_elements(&mut reader).unwrap();
+            assert_eq!(reader.position(), 1);
+            result
+        };
+
+        assert_eq!(count(": [ ] ,"), (0, 4));
+        assert_eq!(count(":[[1, [2]], [], [[]]]"), (3, 20));
+        assert_eq!(
+            count(r#":[1, "a,]\"[", {"b": [1, 2]}, null, true, -0.5e3, []]"#),
+            (7, 52)
+        );
+
+        let mut reader = io::Cursor::new(b"[1, 2".to_vec());
+        assert!(count_array_elements(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_tokenizer_whitespace_runs() {
+        let pad = " \t\r\n".repeat(50_000);
+        let input = format!("{pad}[{pad}1{pad},\r\n\"a\"{pad}]{pad}");
+        let tokens = tokenize(&input);
+        let starts: Vec<u64> = tokens.iter().map(|(_, span)| span.start).collect();
+
+        let n = pad.len() as u64;
+        assert_eq!(
+            tokens.iter().map(|(token, _)| token).collect::<Vec<_>>(),
+            vec![
+                &Token::BeginArray,
+                &Token::Number("1".to_owned()),
+                &Token::Comma,
+                &Token::String("a".to_owned()),
+                &Token::EndArray,
+            ]
+        );
+        assert_eq!(starts, vec![n, 2 * n + 1, 3 * n + 2, 3 * n + 5, 4 * n + 8]);
+
+        let mut reader = io::Cursor::new(input.as_bytes());
+        assert_eq!(skip_json_value(&mut reader).unwrap(), 4 * n + 9);
+        assert_eq!(reader.position(), 4 * n + 9);
+
+        // Form feed is not JSON whitespace.
+        let mut tokens = JsonTokenizer::new(io::Cursor::new(b"\x0c1")).unwrap();
+        assert!(tokens.next_token().is_err());
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn test_simd_matches_serde_json() {
+        let records = [
+            crate::test_utils::EXAMPLE,
+            r#"{"a": [1, -2.5e3, 0.1, 18446744073709551615, 1e400], "b": "\u00e9\n😀", "c": null}"#,
+            r#"{"a": 1, "a": 2}"#,
+            r#"{"a": 1,}"#,
+            r#"{"a" 1}"#,
+            "[1, 2] 3",
+        ];
+        for record in records {
+            let simd = from_json_slice::<serde_json::Value>(record.as_bytes());
+            let serde = serde_json::from_slice::<serde_json::Value>(record.as_bytes());
+            match (simd, serde) {
+                (Ok(simd), Ok(serde)) => assert_eq!(simd, serde, "{}", record),
+                (Err(simd), Err(serde)) => assert_eq!(simd.to_string(), serde.to_string()),
+                (simd, serde) => panic!("{}: {:?} != {:?}", record, simd, serde),
+            }
+
+            let simd = parse_serde_json_value(io::Cursor::new(record.as_bytes()));
+            let serde = serde_json::Deserializer::from_slice(record.as_bytes())
+                .into_iter::<serde_json::Value>()
+                .next()
+                .unwrap();
+            match (simd, serde) {
+                (Ok(simd), Ok(serde)) => assert_eq!(simd, serde, "{}", record),
+                (simd, serde) => assert!(simd.is_err() && serde.is_err(), "{}", record),
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_buffer() {
+        use io::{BufRead, Read, Seek};
+
+        let input = format!("ab  {}c", " ".repeat(3 * DEFAULT_SCAN_BUFFER_BYTES));
+        let mut reader = io::Cursor::new(input.as_bytes());
+        reader.set_position(1);
+        let mut buffer =
+            ScanBuffer::with_max_chunk(&mut reader, DEFAULT_SCAN_BUFFER_BYTES).unwrap();
+        assert_eq!(buffer.position(), 1);
+        assert_eq!(buffer.peek().unwrap(), Some(b'b'));
+        buffer.advance(1);
+        assert_eq!(buffer.find_byte(|c| c != b' ').unwrap(), Some(b'c'));
+        assert_eq!(buffer.position(), input.len() as u64 - 1);
+        assert_eq!(buffer.stream_position().unwrap(), buffer.position());
+
+        // Within the window and before it.
+        buffer.seek(io::SeekFrom::Current(-2)).unwrap();
+        assert_eq!(buffer.fill_buf().unwrap(), b"  c");
+        buffer.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut head = [0; 3];
+        buffer.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"ab ");
+
+        buffer.seek(io::SeekFrom::End(-1)).unwrap();
+        buffer.start_capture_in(vec![], u64::MAX);
+        let mut rest = vec![];
+        buffer.read_to_end(&mut rest).unwrap();
+        assert_eq!(
+            (rest.as_slice(), buffer.take_capture().unwrap().as_slice()),
+            (&b"c"[..], &b"c"[..])
+        );
+        assert_eq!(buffer.peek().unwrap(), None);
+        assert_eq!(buffer.find_byte(|_| true).unwrap(), None);
+
+        buffer.seek(io::SeekFrom::Start(1)).unwrap();
+        buffer.into_inner().unwrap();
+        assert_eq!(reader.position(), 1);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_whitespace_skipping() {
+        let record = format!("{{\"id\": 1,{}\"name\": \"a\"}}", " ".repeat(1000));
+        let input = format!("[{}]", vec![record; 20_000].join(",\r\n"));
+
+        let started = std::time::Instant::now();
+        let (count, size) = count_array_elements(io::Cursor::new(input.as_bytes())).unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!((count, size), (20_000, input.len() as u64));
+        println!(
+            "{} MiB in {:?}, {:.0} MiB/s",
+            size >> 20,
+            elapsed,
+            size as f64 / (1 << 20) as f64 / elapsed.as_secs_f64()
+        );
     }
 }
@@ -0,0 +1,374 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io::{self, Read, Seek};
+
+use crate::{
+    coco_page_mapper::{CocoPageMapper, BATCH_SIZE},
+    geometry::polygon_area,
+    page_maps::JsonDict,
+    rle::{is_rle, Rle},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolygonIssue {
+    /// Less than 3 points.
+    TooFewPoints,
+    /// An odd number of coordinates.
+    OddLength,
+    /// A coordinate is not a finite number.
+    NonFinite,
+    ZeroArea,
+    SelfIntersection,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationIssue {
+    InvalidPolygon {
+        img_id: i64,
+        ann_id: Option<i64>,
+        ring: usize,
+        issue: PolygonIssue,
+    },
+    /// The declared `area` differs from the pixel count of the RLE mask.
+    AreaMismatch {
+        img_id: i64,
+        ann_id: Option<i64>,
+        declared: f64,
+        computed: u64,
+    },
+    /// The image declares a non-positive `width` or `height`.
+    InvalidImageSize {
+        img_id: i64,
+        width: i64,
+        height: i64,
+    },
+    /// The image has no `id` and was given one by its position, see
+    /// `CocoPageMapperBuilder::synthesize_missing_ids`.
+    SynthesizedImageId { img_id: i64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl ValidationIssue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            ValidationIssue::InvalidPolygon { .. } => Severity::Error,
+            ValidationIssue::AreaMismatch { .. }
+            | ValidationIssue::InvalidImageSize { .. }
+            | ValidationIssue::SynthesizedImageId { .. } => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationOptions {
+    /// Also look for self-intersecting polygon rings. This is quadratic in the
+    /// number of points per ring.
+    pub check_self_intersections: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+fn cross(o: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn segments_intersect(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> bool {
+    let on_segment = |p: (f64, f64), q: (f64, f64), r: (f64, f64)| {
+        r.0 >= p.0.min(q.0) && r.0 <= p.0.max(q.0) && r.1 >= p.1.min(q.1) && r.1 <= p.1.max(q.1)
+    };
+    let (d1, d2) = (cross(c, d, a), cross(c, d, b));
+    let (d3, d4) = (cross(a, b, c), cross(a, b, d));
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+    {
+        return true;
+    }
+    (d1 == 0.0 && on_segment(c, d, a))
+        || (d2 == 0.0 && on_segment(c, d, b))
+        || (d3 == 0.0 && on_segment(a, b, c))
+        || (d4 == 0.0 && on_segment(a, b, d))
+}
+
+/// Whether two non-adjacent edges of a closed flat ring cross or touch.
+pub fn is_self_intersecting(points: &[f64]) -> bool {
+    let pts: Vec<(f64, f64)> = points.chunks_exact(2).map(|p| (p[0], p[1])).collect();
+    let n = pts.len();
+    for i in 0..n {
+        for j in i + 2..n {
+            if i == 0 && j == n - 1 {
+                continue;
+            }
+            if segments_intersect(pts[i], pts[(i + 1) % n], pts[j], pts[(j + 1) % n]) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Problems found in the polygon rings of one annotation as `(ring, issue)`.
+/// RLE and empty segmentations are not checked.
+pub fn check_polygons(ann: &JsonDict, options: &ValidationOptions) -> Vec<(usize, PolygonIssue)> {
+    let mut issues = vec![];
+    let Some(rings) = ann.get("segmentation").and_then(|v| v.as_array()) else {
+        return issues;
+    };
+
+    for (idx, ring) in rings.iter().enumerate() {
+        let Some(values) = ring.as_array() else {
+            continue;
+        };
+        let coords: Option<Vec<f64>> = values.iter().map(|v| v.as_f64()).collect();
+        let Some(coords) = coords.filter(|v| v.iter().all(|v| v.is_finite())) else {
+            issues.push((idx, PolygonIssue::NonFinite));
+            continue;
+        };
+        if coords.len() % 2 != 0 {
+            issues.push((idx, PolygonIssue::OddLength));
+            continue;
+        }
+        if coords.len() < 6 {
+            issues.push((idx, PolygonIssue::TooFewPoints));
+            continue;
+        }
+        if polygon_area(&coords) == 0.0 {
+            issues.push((idx, PolygonIssue::ZeroArea));
+        }
+        if options.check_self_intersections && is_self_intersecting(&coords) {
+            issues.push((idx, PolygonIssue::SelfIntersection));
+        }
+    }
+    issues
+}
+
+/// Declared and computed area of an RLE annotation whose `area` is off by more
+/// than half a pixel. Unparsable masks are not checked.
+pub fn check_rle_area(ann: &JsonDict) -> Option<(f64, u64)> {
+    let segmentation = ann.get("segmentation").filter(|v| is_rle(v))?;
+    let declared = ann.get("area")?.as_f64()?;
+    let computed = Rle::from_json(segmentation).ok()?.area();
+    ((declared - computed as f64).abs() > 0.5).then_some((declared, computed))
+}
+
+impl CocoPageMapper {
+    /// Images with a non-positive size, found from the sizes cached by the scan.
+    pub fn check_image_sizes(&self) -> Vec<ValidationIssue> {
+        self.get_img_ids()
+            .iter()
+            .filter_map(|img_id| {
+                let (width, height) = self.image_size(*img_id)?;
+                (width <= 0 || height <= 0).then_some(ValidationIssue::InvalidImageSize {
+                    img_id: *img_id,
+                    width,
+                    height,
+                })
+            })
+            .collect()
+    }
+
+    pub fn validate(
+        &self,
+        mut reader: impl Read + Seek,
+        options: &ValidationOptions,
+    ) -> Result<ValidationReport, io::Error> {
+        let mut report = ValidationReport {
+            issues: self.check_image_sizes(),
+        };
+        report.issues.extend(
+            self.synthesized_img_ids()
+                .iter()
+                .map(|img_id| ValidationIssue::SynthesizedImageId { img_id: *img_id }),
+        );
+
+        for img_ids in self.get_img_ids().chunks(BATCH_SIZE) {
+            let batch = self.get_anns_batch(img_ids, &mut reader)?;
+            for (img_id, anns) in img_ids.iter().zip(batch) {
+                for ann in anns.iter() {
+                    let ann_id = ann.get("id").and_then(|v| v.as_i64());
+                    report
+                        .issues
+                        .extend(
+                            check_polygons(ann, options)
+                                .into_iter()
+                                .map(|(ring, issue)| ValidationIssue::InvalidPolygon {
+                                    img_id: *img_id,
+                                    ann_id,
+                                    ring,
+                                    issue,
+                                }),
+                        );
+                    if let Some((declared, computed)) = check_rle_area(ann) {
+                        report.issues.push(ValidationIssue::AreaMismatch {
+                            img_id: *img_id,
+                            ann_id,
+                            declared,
+                            computed,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::prepare;
+
+    const EXAMPLE: &str = r#"
+    {
+        "licenses":[],
+        "info":{},
+        "categories":[{"id":1,"name":"a","supercategory":""}],
+        "images":[{"id":1,"width":10,"height":10,"file_name":"a.jpg"}],
+        "annotations":[
+            {"id":1,"image_id":1,"category_id":1,"segmentation":[[0,0,4,0,4,4,0,4]]},
+            {"id":2,"image_id":1,"category_id":1,"segmentation":[[0,0,4,4]]},
+            {"id":3,"image_id":1,"category_id":1,"segmentation":[[0,0,4,0,4,4,0,4],[0,0,4,4,4,0,0,2]]},
+            {"id":4,"image_id":1,"category_id":1,"segmentation":[[0,0,1,1,2,2]]},
+            {"id":5,"image_id":1,"category_id":1,"area":100,"segmentation":{"size":[10,10],"counts":[0,100]}},
+            {"id":6,"image_id":1,"category_id":1,"area":10,"segmentation":{"size":[10,10],"counts":"0T3"}}
+        ]
+    }"#;
+
+    #[test]
+    fn test_check_polygons() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let report = coco_page_mapper
+            .validate(&mut reader, &ValidationOptions::default())
+            .unwrap();
+        let mut issues: Vec<(Option<i64>, usize, PolygonIssue)> = report
+            .issues
+            .iter()
+            .filter_map(|v| match v {
+                ValidationIssue::InvalidPolygon {
+                    ann_id,
+                    ring,
+                    issue,
+                    ..
+                } => Some((*ann_id, *ring, *issue)),
+                _ => None,
+            })
+            .collect();
+        issues.sort_by_key(|v| v.0);
+        assert_eq!(
+            issues,
+            vec![
+                (Some(2), 0, PolygonIssue::TooFewPoints),
+                (Some(4), 0, PolygonIssue::ZeroArea),
+            ]
+        );
+
+        let report = coco_page_mapper
+            .validate(
+                &mut reader,
+                &ValidationOptions {
+                    check_self_intersections: true,
+                },
+            )
+            .unwrap();
+        assert!(report.issues.contains(&ValidationIssue::InvalidPolygon {
+            img_id: 1,
+            ann_id: Some(3),
+            ring: 1,
+            issue: PolygonIssue::SelfIntersection,
+        }));
+        assert!(!report.issues.iter().any(|v| matches!(
+            v,
+            ValidationIssue::InvalidPolygon {
+                ann_id: Some(1),
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn test_rle_area_mismatch() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let report = coco_page_mapper
+            .validate(&mut reader, &ValidationOptions::default())
+            .unwrap();
+        let mismatches: Vec<&ValidationIssue> = report
+            .issues
+            .iter()
+            .filter(|v| matches!(v, ValidationIssue::AreaMismatch { .. }))
+            .collect();
+        assert_eq!(
+            mismatches,
+            vec![&ValidationIssue::AreaMismatch {
+                img_id: 1,
+                ann_id: Some(6),
+                declared: 10.0,
+                computed: 100,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_invalid_image_size() {
+        const EXAMPLE: &str = r#"
+        {
+            "licenses":[],
+            "info":{},
+            "categories":[],
+            "images":[
+                {"id":1,"width":0,"height":10,"file_name":"a.jpg"},
+                {"id":2,"width":10,"height":10,"file_name":"b.jpg"}
+            ],
+            "annotations":[]
+        }"#;
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+
+        let expected = vec![ValidationIssue::InvalidImageSize {
+            img_id: 1,
+            width: 0,
+            height: 10,
+        }];
+        assert_eq!(coco_page_mapper.check_image_sizes(), expected);
+
+        let report = coco_page_mapper
+            .validate(&mut reader, &ValidationOptions::default())
+            .unwrap();
+        assert_eq!(report.issues, expected);
+        assert_eq!(report.issues[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn test_synthesized_image_ids() {
+        let example = EXAMPLE.replace(r#"{"id":1,"width":10"#, r#"{"width":10"#);
+        let mut reader = std::io::Cursor::new(example.as_bytes());
+        let coco_page_mapper = CocoPageMapper::builder()
+            .synthesize_missing_ids(true)
+            .synthesized_id_start(1)
+            .build(&mut reader)
+            .unwrap();
+
+        let report = coco_page_mapper
+            .validate(&mut reader, &ValidationOptions::default())
+            .unwrap();
+        let issue = ValidationIssue::SynthesizedImageId { img_id: 1 };
+        assert_eq!(report.issues.first(), Some(&issue));
+        assert_eq!(issue.severity(), Severity::Warning);
+    }
+}
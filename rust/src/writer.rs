@@ -0,0 +1,220 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::io::{self, Write};
+
+use crate::{page_maps::JsonDict, utils::invalid_data};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriterState {
+    Header,
+    Images,
+    ImagesDone,
+    Annotations,
+    AnnotationsDone,
+}
+
+/// Streams a COCO file section by section. `licenses`, `info` and `categories`
+/// come first in any order, then the images and then the annotations. Calls
+/// out of this order fail with `InvalidInput`.
+pub struct CocoWriter<W: Write> {
+    writer: W,
+    state: WriterState,
+    header: Vec<&'static str>,
+    empty_array: bool,
+}
+
+impl<W: Write> CocoWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            state: WriterState::Header,
+            header: Vec::with_capacity(3),
+            empty_array: true,
+        }
+    }
+
+    fn expect_state(&self, state: WriterState, call: &str) -> Result<(), io::Error> {
+        if self.state != state {
+            let msg = format!(
+                "CocoWriter::{} is not allowed in the {:?} state",
+                call, self.state
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        Ok(())
+    }
+
+    fn write_key(&mut self, key: &str) -> Result<(), io::Error> {
+        let sep = match self.header.is_empty() && self.state == WriterState::Header {
+            true => "{",
+            false => ",",
+        };
+        write!(self.writer, "{}\"{}\":", sep, key)
+    }
+
+    fn write_header(&mut self, key: &'static str, value: &JsonDict) -> Result<(), io::Error> {
+        self.expect_state(WriterState::Header, key)?;
+        if self.header.contains(&key) {
+            let msg = format!("The {} section is already written", key);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.write_key(key)?;
+        self.header.push(key);
+        serde_json::to_writer(&mut self.writer, value)?;
+        Ok(())
+    }
+
+    pub fn write_licenses(&mut self, licenses: &JsonDict) -> Result<(), io::Error> {
+        self.write_header("licenses", licenses)
+    }
+
+    pub fn write_info(&mut self, info: &JsonDict) -> Result<(), io::Error> {
+        self.write_header("info", info)
+    }
+
+    pub fn write_categories(&mut self, categories: &JsonDict) -> Result<(), io::Error> {
+        self.write_header("categories", categories)
+    }
+
+    fn begin_array(&mut self, key: &str, next: WriterState) -> Result<(), io::Error> {
+        self.write_key(key)?;
+        self.writer.write_all(b"[")?;
+        self.state = next;
+        self.empty_array = true;
+        Ok(())
+    }
+
+    fn push(&mut self, value: &JsonDict) -> Result<(), io::Error> {
+        if !self.empty_array {
+            self.writer.write_all(b",")?;
+        }
+        self.empty_array = false;
+        serde_json::to_writer(&mut self.writer, value)?;
+        Ok(())
+    }
+
+    fn end_array(&mut self, next: WriterState) -> Result<(), io::Error> {
+        self.writer.write_all(b"]")?;
+        self.state = next;
+        Ok(())
+    }
+
+    pub fn begin_images(&mut self) -> Result<(), io::Error> {
+        self.expect_state(WriterState::Header, "begin_images")?;
+        for key in ["licenses", "info", "categories"] {
+            if !self.header.contains(&key) {
+                let msg = format!("The {} section should be written before the images", key);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+        self.begin_array("images", WriterState::Images)
+    }
+
+    pub fn write_image(&mut self, image: &JsonDict) -> Result<(), io::Error> {
+        self.expect_state(WriterState::Images, "write_image")?;
+        if !image.is_object() {
+            return Err(invalid_data("An image should be a JSON object"));
+        }
+        self.push(image)
+    }
+
+    pub fn end_images(&mut self) -> Result<(), io::Error> {
+        self.expect_state(WriterState::Images, "end_images")?;
+        self.end_array(WriterState::ImagesDone)
+    }
+
+    pub fn begin_annotations(&mut self) -> Result<(), io::Error> {
+        self.expect_state(WriterState::ImagesDone, "begin_annotations")?;
+        self.begin_array("annotations", WriterState::Annotations)
+    }
+
+    pub fn write_annotation(&mut self, annotation: &JsonDict) -> Result<(), io::Error> {
+        self.expect_state(WriterState::Annotations, "write_annotation")?;
+        if !annotation.is_object() {
+            return Err(invalid_data("An annotation should be a JSON object"));
+        }
+        self.push(annotation)
+    }
+
+    pub fn end_annotations(&mut self) -> Result<(), io::Error> {
+        self.expect_state(WriterState::Annotations, "end_annotations")?;
+        self.end_array(WriterState::AnnotationsDone)
+    }
+
+    /// Closes the top-level object and returns the inner writer.
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        self.expect_state(WriterState::AnnotationsDone, "finish")?;
+        self.writer.write_all(b"}")?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::reopen;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = CocoWriter::new(vec![]);
+        writer
+            .write_info(&json!({"description": "generated"}))
+            .unwrap();
+        writer.write_licenses(&json!([])).unwrap();
+        writer
+            .write_categories(&json!([{"id": 1, "name": "a", "supercategory": ""}]))
+            .unwrap();
+        assert!(writer.begin_annotations().is_err());
+
+        writer.begin_images().unwrap();
+        for img_id in 1..=3 {
+            writer
+                .write_image(&json!({"id": img_id, "width": 4, "height": 4, "file_name": format!("{}.jpg", img_id)}))
+                .unwrap();
+        }
+        writer.end_images().unwrap();
+        writer.begin_annotations().unwrap();
+        for ann_id in 1..=2 {
+            writer
+                .write_annotation(
+                    &json!({"id": ann_id, "image_id": 2, "category_id": 1, "bbox": [0, 0, 1, 1]}),
+                )
+                .unwrap();
+        }
+        assert!(writer.write_image(&json!({"id": 4})).is_err());
+        writer.end_annotations().unwrap();
+        let buf = writer.finish().unwrap();
+
+        let (mut reader, coco_page_mapper) = reopen(buf);
+        assert_eq!(coco_page_mapper.get_img_ids(), &vec![1, 2, 3]);
+        assert_eq!(
+            coco_page_mapper.info(&mut reader).unwrap()["description"],
+            "generated"
+        );
+        assert_eq!(
+            coco_page_mapper
+                .get_anns_dict(2, &mut reader)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(coco_page_mapper
+            .get_anns_dict(3, &mut reader)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_call_order() {
+        let mut writer = CocoWriter::new(vec![]);
+        assert!(writer.begin_images().is_err());
+        writer.write_info(&json!({})).unwrap();
+        assert!(writer.write_info(&json!({})).is_err());
+        assert!(writer.end_images().is_err());
+        assert!(CocoWriter::new(vec![]).finish().is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//  Copyright (C) 2023 Intel Corporation
+//
+//  SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Read, Seek, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    coco_page_mapper::{CocoPageMapper, BATCH_SIZE},
+    duplicates::parse_bbox,
+    utils::invalid_data,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct YoloExportOptions {
+    /// Write an empty label file for images without annotations instead of skipping them.
+    pub write_empty: bool,
+}
+
+/// The label file of an image under `out_dir`, keeping the directories of its
+/// `file_name`. `None` for a path leaving `out_dir`.
+fn label_path(out_dir: &Path, file_name: &Path) -> Option<PathBuf> {
+    let relative = file_name
+        .components()
+        .all(|v| matches!(v, Component::Normal(_) | Component::CurDir));
+    relative.then(|| out_dir.join(file_name.with_extension("txt")))
+}
+
+fn yaml_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+impl CocoPageMapper {
+    pub fn export_yolo(&self, reader: impl Read + Seek, out_dir: &Path) -> Result<(), io::Error> {
+        self.export_yolo_with_options(reader, out_dir, &YoloExportOptions::default())
+    }
+
+    pub fn export_yolo_with_options(
+        &self,
+        mut reader: impl Read + Seek,
+        out_dir: &Path,
+        options: &YoloExportOptions,
+    ) -> Result<(), io::Error> {
+        std::fs::create_dir_all(out_dir)?;
+
+        let labels = self.contiguous_labels()?;
+        let mut written = HashSet::new();
+
+        for img_ids in self.get_img_ids().chunks(BATCH_SIZE) {
+            let anns_batch = self.get_anns_batch(img_ids, &mut reader)?;
+
+            for (img_id, anns) in img_ids.iter().zip(anns_batch.iter()) {
+                if anns.is_empty() && !options.write_empty {
+                    continue;
+                }
+
+                let (width, height) = self
+                    .image_size(*img_id)
+                    .filter(|(w, h)| *w > 0 && *h > 0)
+                    .ok_or(invalid_data(
+                        format!("Image id: {} has no valid width and height", img_id).as_str(),
+                    ))?;
+                let (width, height) = (width as f64, height as f64);
+
+                let item = self.get_item_dict(*img_id, &mut reader)?;
+                let file_name = item
+                    .get("file_name")
+                    .and_then(|v| v.as_str())
+                    .map(Path::new)
+                    .filter(|v| v.file_stem().is_some())
+                    .ok_or(invalid_data(
+                        format!("Image id: {} has no file_name", img_id).as_str(),
+                    ))?;
+                let path = label_path(out_dir, file_name).ok_or(invalid_data(
+                    format!(
+                        "The file_name {} of image id: {} is not a relative path",
+                        file_name.display(),
+                        img_id
+                    )
+                    .as_str(),
+                ))?;
+                if !written.insert(path.clone()) {
+                    let msg = format!(
+                        "Image id: {} has the same label file {} as another image",
+                        img_id,
+                        path.display()
+                    );
+                    return Err(invalid_data(msg.as_str()));
+                }
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut writer = BufWriter::new(File::create(path)?);
+
+                for ann in anns {
+                    let label = ann
+                        .get("category_id")
+                        .and_then(|v| v.as_i64())
+                        .and_then(|v| labels.get(&v));
+                    if let (Some(label), Some([x, y, w, h])) = (label, parse_bbox(ann)) {
+                        writeln!(
+                            writer,
+                            "{} {:.6} {:.6} {:.6} {:.6}",
+                            label,
+                            (x + w / 2.0) / width,
+                            (y + h / 2.0) / height,
+                            w / width,
+                            h / height
+                        )?;
+                    }
+                }
+                writer.flush()?;
+            }
+        }
+
+        let mut names = vec![String::new(); labels.values().max().map_or(0, |v| v + 1)];
+        for category in self.unique_categories()? {
+            let label = category
+                .get("id")
+                .and_then(|v| v.as_i64())
+                .and_then(|v| labels.get(&v));
+            let name = category.get("name").and_then(|v| v.as_str());
+            if let (Some(label), Some(name)) = (label, name) {
+                names[*label] = yaml_quote(name);
+            }
+        }
+
+        let mut writer = BufWriter::new(File::create(out_dir.join("data.yaml"))?);
+        writeln!(writer, "nc: {}", names.len())?;
+        writeln!(writer, "names: [{}]", names.join(", "))?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, fs::read_to_string};
+
+    use crate::test_utils::{prepare, EXAMPLE};
+
+    #[test]
+    fn test_export_yolo() {
+        let (mut reader, coco_page_mapper) = prepare(EXAMPLE);
+        let out_dir = temp_dir().join(format!("yolo-{}", std::process::id()));
+
+        coco_page_mapper.export_yolo(&mut reader, &out_dir).unwrap();
+
+        let line = "1 0.350000 0.500000 0.300000 0.200000";
+        assert_eq!(
+            read_to_string(out_dir.join("a.txt")).unwrap(),
+            format!("{}\n{}\n{}\n", line, line, line)
+        );
+        assert_eq!(
+            read_to_string(out_dir.join("b.txt")).unwrap(),
+            format!("{}\n{}\n", line, line)
+        );
+        assert_eq!(
+            read_to_string(out_dir.join("data.yaml")).unwrap(),
+            "nc: 3\nnames: ['a', 'b', 'c']\n"
+        );
+
+        std::fs::remove_dir_all(out_dir).unwrap();
+    }
+    #[test]
+    fn test_duplicate_category_ids() {
+        let example = EXAMPLE.replacen(
+            r#"{"id":2,"name":"b","#,
+            r#"{"id":1,"name":"x","supercategory":""},{"id":2,"name":"b","#,
+            1,
+        );
+        assert_ne!(example, EXAMPLE);
+        let (mut reader, coco_page_mapper) = prepare(&example);
+        let out_dir = temp_dir().join(format!("yolo-dup-{}", std::process::id()));
+
+        coco_page_mapper.export_yolo(&mut reader, &out_dir).unwrap();
+
+        let line = "1 0.350000 0.500000 0.300000 0.200000";
+        assert_eq!(
+            read_to_string(out_dir.join("b.txt")).unwrap(),
+            format!("{}\n{}\n", line, line)
+        );
+        assert_eq!(
+            read_to_string(out_dir.join("data.yaml")).unwrap(),
+            "nc: 3\nnames: ['a', 'b', 'c']\n"
+        );
+
+        std::fs::remove_dir_all(out_dir).unwrap();
+    }
+    #[test]
+    fn test_file_name_directories() {
+        let example = EXAMPLE
+            .replacen(r#""a.jpg""#, r#""train/x.jpg""#, 1)
+            .replacen(r#""b.jpg""#, r#""val/x.jpg""#, 1);
+        let (mut reader, coco_page_mapper) = prepare(&example);
+        let out_dir = temp_dir().join(format!("yolo-dirs-{}", std::process::id()));
+
+        coco_page_mapper.export_yolo(&mut reader, &out_dir).unwrap();
+        assert_eq!(
+            read_to_string(out_dir.join("train/x.txt"))
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        assert_eq!(
+            read_to_string(out_dir.join("val/x.txt"))
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+        std::fs::remove_dir_all(&out_dir).unwrap();
+
+        for (a, b) in [
+            ("x.jpg", "x.png"),
+            ("x.jpg", "../x.jpg"),
+            ("x.jpg", "/tmp/x.jpg"),
+        ] {
+            let example = EXAMPLE
+                .replacen(r#""a.jpg""#, &format!("{:?}", a), 1)
+                .replacen(r#""b.jpg""#, &format!("{:?}", b), 1);
+            let (mut reader, coco_page_mapper) = prepare(&example);
+            assert!(coco_page_mapper.export_yolo(&mut reader, &out_dir).is_err());
+            std::fs::remove_dir_all(&out_dir).unwrap();
+        }
+    }
+}